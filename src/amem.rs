@@ -0,0 +1,187 @@
+use crate::ProcessRunner;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// amem の呼び出しに失敗した理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AmemError {
+    /// `amem` を起動できない (インストールされていない、実行できないなど)
+    NotInstalled { detail: String },
+    /// `amem` が非ゼロで終了した
+    NonZeroExit {
+        command: String,
+        code: Option<i32>,
+        detail: String,
+    },
+    /// `amem today --json` の出力を JSON として解釈できない
+    MalformedOutput { detail: String },
+}
+
+impl fmt::Display for AmemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AmemError::NotInstalled { detail } => write!(f, "amem is not available: {}", detail),
+            AmemError::NonZeroExit {
+                command, detail, ..
+            } => write!(f, "{} exited with error:\n{}", command, detail),
+            AmemError::MalformedOutput { detail } => {
+                write!(f, "Malformed amem today output: {}", detail)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AmemError {}
+
+/// `amem --version` の確認を打ち切るまでの既定の時間
+pub const DEFAULT_AMEM_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Results of the `--version` probe, keyed by the amem binary.
+static AVAILABLE: LazyLock<tokio::sync::Mutex<HashMap<PathBuf, bool>>> =
+    LazyLock::new(Default::default);
+
+/// Probes `<binary> --version` once per process. Concurrent callers wait for
+/// the first probe instead of spawning their own.
+pub(crate) async fn is_available(
+    binary: &Path,
+    timeout: Duration,
+    runner: &dyn ProcessRunner,
+) -> bool {
+    let mut cached = AVAILABLE.lock().await;
+    if let Some(&available) = cached.get(binary) {
+        return available;
+    }
+    let available = probe(binary, timeout, runner).await;
+    cached.insert(binary.to_path_buf(), available);
+    available
+}
+
+/// Runs `<binary> --version`; a probe that outlives `timeout` is killed and
+/// counts as unavailable.
+pub(crate) async fn probe(binary: &Path, timeout: Duration, runner: &dyn ProcessRunner) -> bool {
+    let mut command = Command::new(binary);
+    command
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let output = runner.output(&mut command);
+    matches!(tokio::time::timeout(timeout, output).await, Ok(Ok(o)) if o.status.success())
+}
+
+pub(crate) async fn reset_availability() {
+    AVAILABLE.lock().await.clear();
+}
+
+/// Runs `<binary> today --json` and parses the result.
+pub(crate) async fn fetch_today(
+    binary: &Path,
+    runner: &dyn ProcessRunner,
+) -> Result<AmemContext, AmemError> {
+    let mut command = Command::new(binary);
+    command
+        .arg("today")
+        .arg("--json")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let output = runner
+        .output(&mut command)
+        .await
+        .map_err(|e| AmemError::NotInstalled {
+            detail: e.to_string(),
+        })?;
+    if !output.status.success() {
+        return Err(AmemError::NonZeroExit {
+            command: "amem today".to_string(),
+            code: output.status.code(),
+            detail: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    AmemContext::from_json(&output.stdout)
+}
+
+/// `amem today --json` が返す記憶コンテキスト
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AmemContext {
+    pub owner_profile: Option<String>,
+    pub agent_soul: Option<String>,
+    pub activity: Option<String>,
+    pub agent_memories: Option<String>,
+}
+
+impl AmemContext {
+    /// `amem today --json` の出力を解釈します
+    pub fn from_json(json: &[u8]) -> Result<Self, AmemError> {
+        let today: serde_json::Value =
+            serde_json::from_slice(json).map_err(|e| AmemError::MalformedOutput {
+                detail: e.to_string(),
+            })?;
+        let field = |key: &str| today[key].as_str().map(str::to_string);
+        Ok(Self {
+            owner_profile: field("owner_profile"),
+            agent_soul: field("agent_soul"),
+            activity: field("activity"),
+            agent_memories: field("agent_memories"),
+        })
+    }
+
+    /// シードで注入する Markdown 形式のスナップショット (存在するフィールドのみ)
+    pub fn to_markdown(&self) -> String {
+        let sections = [
+            ("Owner Profile", &self.owner_profile),
+            ("Agent Soul", &self.agent_soul),
+            ("Recent Activities", &self.activity),
+            ("Important Memories (P0)", &self.agent_memories),
+        ];
+        let mut context = String::new();
+        for (title, body) in sections {
+            let Some(body) = body else { continue };
+            if !context.is_empty() {
+                context.push('\n');
+            }
+            context.push_str("## ");
+            context.push_str(title);
+            context.push('\n');
+            context.push_str(body);
+            context.push('\n');
+        }
+        context
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_json_reads_each_field() {
+        let context = AmemContext::from_json(
+            br#"{"owner_profile":"likes rust","activity":"shipped v1","agent_memories":null,"extra":1}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            context,
+            AmemContext {
+                owner_profile: Some("likes rust".into()),
+                agent_soul: None,
+                activity: Some("shipped v1".into()),
+                agent_memories: None,
+            }
+        );
+        assert_eq!(
+            context.to_markdown(),
+            "## Owner Profile\nlikes rust\n\n## Recent Activities\nshipped v1\n"
+        );
+        assert!(matches!(
+            AmemContext::from_json(b"not json"),
+            Err(AmemError::MalformedOutput { .. })
+        ));
+    }
+}
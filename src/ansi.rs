@@ -0,0 +1,84 @@
+/// Streaming ANSI escape sequence remover. The parser state survives across
+/// `push` calls, so a sequence split between two chunks is still removed.
+#[derive(Debug, Default)]
+pub(crate) struct AnsiStripper {
+    state: State,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    #[default]
+    Text,
+    /// Saw ESC, waiting for the sequence introducer.
+    Escape,
+    /// Inside `ESC [ ... <final byte>`.
+    Csi,
+    /// Inside `ESC ] ... (BEL | ESC \)`.
+    Osc,
+    /// Saw ESC inside an OSC string.
+    OscEscape,
+}
+
+impl AnsiStripper {
+    pub(crate) fn push(&mut self, chunk: &str) -> String {
+        let mut out = String::with_capacity(chunk.len());
+        for ch in chunk.chars() {
+            self.state = match (self.state, ch) {
+                (State::Text, '\u{1b}') => State::Escape,
+                (State::Text, '\u{9b}') => State::Csi,
+                (State::Text, _) => {
+                    out.push(ch);
+                    State::Text
+                }
+                (State::Escape, '[') => State::Csi,
+                (State::Escape, ']') => State::Osc,
+                // Two-character sequences such as `ESC =` or `ESC 7`.
+                (State::Escape, _) => State::Text,
+                (State::Csi, '\u{40}'..='\u{7e}') => State::Text,
+                (State::Csi, _) => State::Csi,
+                (State::Osc, '\u{07}') => State::Text,
+                (State::Osc, '\u{1b}') => State::OscEscape,
+                (State::Osc, _) => State::Osc,
+                (State::OscEscape, '\\') => State::Text,
+                (State::OscEscape, _) => State::Osc,
+            };
+        }
+        out
+    }
+}
+
+/// 文字列から ANSI エスケープシーケンスを取り除きます
+pub fn strip_ansi(text: &str) -> String {
+    AnsiStripper::default().push(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_ansi_removes_color_and_osc_sequences() {
+        let text =
+            "\u{1b}[1;31mError\u{1b}[0m: \u{1b}]0;title\u{07}done\u{1b}]8;;http://x\u{1b}\\link";
+        assert_eq!(strip_ansi(text), "Error: donelink");
+    }
+
+    #[test]
+    fn test_ansi_stripper_handles_sequences_split_across_chunks() {
+        let text = "plain \u{1b}[38;5;208morange\u{1b}[0m text";
+        for split in 0..=text.len() {
+            if !text.is_char_boundary(split) {
+                continue;
+            }
+            let mut stripper = AnsiStripper::default();
+            let mut out = stripper.push(&text[..split]);
+            out.push_str(&stripper.push(&text[split..]));
+            assert_eq!(out, "plain orange text", "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn test_strip_ansi_keeps_multibyte_text() {
+        assert_eq!(strip_ansi("\u{1b}[32m完了\u{1b}[0m"), "完了");
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{AgentError, AgentExecutor, AgentProvider, ExecOptions, SessionManager};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// `AgentBackend` のメソッドが返す Future
+pub type BackendFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// セッションを持つエージェントの実行方法。
+///
+/// `SessionManager::with_backend` でツールに登録すると、そのツールのシードと再開は CLI の
+/// 代わりにこの実装を通ります。セッションの保持・TTL・永続化は `SessionManager` が行います
+pub trait AgentBackend: Send + Sync {
+    /// 初期化プロンプトで新しいセッションを始め、その session id を返します
+    fn seed<'a>(
+        &'a self,
+        prompt: &'a str,
+        options: &'a ExecOptions,
+    ) -> BackendFuture<'a, Result<String, AgentError>>;
+
+    /// `session_id` のセッションで `prompt` を実行し、出力を `on_chunk` に配信します
+    fn execute_stream<'a>(
+        &'a self,
+        session_id: &'a str,
+        prompt: &'a str,
+        options: &'a ExecOptions,
+        on_chunk: &'a mut (dyn FnMut(String) + Send),
+    ) -> BackendFuture<'a, Result<(), AgentError>>;
+}
+
+/// 公式 CLI を起動する実装。`SessionManager` が登録なしで使うものと同じコマンドを組み立てますが、
+/// 失効したセッションの再シードや Gemini のモデルのフォールバックは行いません
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CliBackend(pub AgentProvider);
+
+impl AgentBackend for CliBackend {
+    fn seed<'a>(
+        &'a self,
+        prompt: &'a str,
+        options: &'a ExecOptions,
+    ) -> BackendFuture<'a, Result<String, AgentError>> {
+        Box::pin(async move {
+            let seeded = SessionManager::seed_with_fallbacks(&self.0, prompt, options).await?;
+            Ok(seeded.id)
+        })
+    }
+
+    fn execute_stream<'a>(
+        &'a self,
+        session_id: &'a str,
+        prompt: &'a str,
+        options: &'a ExecOptions,
+        on_chunk: &'a mut (dyn FnMut(String) + Send),
+    ) -> BackendFuture<'a, Result<(), AgentError>> {
+        Box::pin(async move {
+            let provider = &self.0;
+            let mut command = SessionManager::resume_command(
+                provider,
+                session_id,
+                prompt,
+                options.model.as_deref(),
+                options,
+            )?;
+            let input = options.stdin_prompt(provider, prompt);
+            if *provider == AgentProvider::Codex {
+                let output =
+                    AgentExecutor::spawn_and_wait_with_input(&mut command, options, input).await?;
+                if !output.status.success() {
+                    return Err(AgentError::NonZeroExit {
+                        command: provider.command_name().to_string(),
+                        code: output.status.code(),
+                        detail: AgentExecutor::failure_detail(&output),
+                    });
+                }
+                let response =
+                    SessionManager::extract_response(&options.clean_output(&output.stdout))
+                        .ok_or_else(|| {
+                            AgentError::ResponseMissing("codex exec resume".to_string())
+                        })?;
+                on_chunk(response);
+                return Ok(());
+            }
+
+            let mut raw = String::new();
+            let run = if options.json_response {
+                AgentExecutor::stream_command(&mut command, options, input, &mut |chunk| {
+                    raw.push_str(&chunk)
+                })
+                .await?
+            } else {
+                AgentExecutor::stream_command(&mut command, options, input, &mut |chunk| {
+                    on_chunk(chunk)
+                })
+                .await?
+            };
+            run.check(provider.command_name())?;
+            if !raw.is_empty() {
+                on_chunk(SessionManager::extract_response(&raw).unwrap_or(raw));
+            }
+            Ok(())
+        })
+    }
+}
+
+/// プロセスを起動せず、受け取ったプロンプトをそのまま返す実装 (`AgentProvider::Mock` と同じ応答です)
+#[derive(Debug, Default)]
+pub struct MockBackend {
+    seeds: AtomicUsize,
+}
+
+impl AgentBackend for MockBackend {
+    fn seed<'a>(
+        &'a self,
+        _prompt: &'a str,
+        _options: &'a ExecOptions,
+    ) -> BackendFuture<'a, Result<String, AgentError>> {
+        let seed = self.seeds.fetch_add(1, Ordering::Relaxed) + 1;
+        Box::pin(async move { Ok(format!("mock-{seed}")) })
+    }
+
+    fn execute_stream<'a>(
+        &'a self,
+        _session_id: &'a str,
+        prompt: &'a str,
+        _options: &'a ExecOptions,
+        on_chunk: &'a mut (dyn FnMut(String) + Send),
+    ) -> BackendFuture<'a, Result<(), AgentError>> {
+        Box::pin(async move {
+            on_chunk("Mock: ".into());
+            on_chunk(format!("received your prompt '{}'.", prompt));
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,164 @@
+use crate::{AgentError, AgentProvider, SessionManager};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const BUNDLE_VERSION: u32 = 1;
+const REDACTED: &str = "[REDACTED]";
+
+/// 会話を共有するためのセッション + トランスクリプトのバンドル
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionBundle {
+    pub version: u32,
+    pub provider: AgentProvider,
+    pub session_id: Option<String>,
+    pub model: Option<String>,
+    pub transcript: String,
+    pub amem_context: Option<String>,
+    /// UNIX epoch seconds
+    pub exported_at: u64,
+}
+
+fn secret_patterns() -> &'static [Regex] {
+    static PATTERNS: OnceLock<Vec<Regex>> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        [
+            // key = value / key: value style assignments
+            r#"(?i)\b(api[_-]?key|[a-z_]*token|secret|password|passwd)\b(\s*[:=]\s*)("[^"]*"|'[^']*'|\S+)"#,
+            r"(?i)\bbearer\s+[A-Za-z0-9._~+/=-]{8,}",
+            r"\bsk-(ant-)?[A-Za-z0-9_-]{16,}",
+            r"\bAIza[0-9A-Za-z_-]{30,}",
+            r"\bgh[pousr]_[A-Za-z0-9]{20,}",
+            r"\bxox[abprs]-[A-Za-z0-9-]{10,}",
+        ]
+        .iter()
+        .map(|p| Regex::new(p).expect("secret pattern must compile"))
+        .collect()
+    })
+}
+
+/// API キーやトークンらしき文字列を `[REDACTED]` に置き換えます
+pub fn redact_secrets(text: &str) -> String {
+    let mut redacted = text.to_string();
+    for (idx, pattern) in secret_patterns().iter().enumerate() {
+        redacted = if idx == 0 {
+            // Keep the key name so the reader still knows what was removed.
+            pattern
+                .replace_all(&redacted, format!("${{1}}${{2}}{}", REDACTED))
+                .into_owned()
+        } else {
+            pattern.replace_all(&redacted, REDACTED).into_owned()
+        };
+    }
+    redacted
+}
+
+impl SessionBundle {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("SessionBundle is always serializable")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub async fn write_to_path(&self, path: &Path) -> Result<(), AgentError> {
+        tokio::fs::write(path, self.to_json()).await?;
+        Ok(())
+    }
+
+    pub async fn read_from_path(path: &Path) -> Result<Self, AgentError> {
+        let json = tokio::fs::read_to_string(path).await?;
+        Self::from_json(&json).map_err(|source| AgentError::MalformedBundle {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+impl SessionManager {
+    /// 現在のセッション情報とトランスクリプトを、秘密情報を伏せたバンドルにまとめます
+    pub async fn export_bundle(
+        &self,
+        provider: AgentProvider,
+        model: Option<String>,
+        transcript: &str,
+        amem_context: Option<&str>,
+    ) -> SessionBundle {
+        let exported_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        SessionBundle {
+            version: BUNDLE_VERSION,
+            session_id: self.session_id(&provider).await,
+            provider,
+            model,
+            transcript: redact_secrets(transcript),
+            amem_context: amem_context.map(redact_secrets),
+            exported_at,
+        }
+    }
+
+    /// バンドルの session id を取り込み、次のターンでその会話を再開できるようにします
+    pub async fn import_bundle(&self, bundle: &SessionBundle) {
+        if let Some(id) = &bundle.session_id {
+            self.set_session_id(bundle.provider.clone(), id.clone())
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_secrets_masks_common_credentials() {
+        let text = "api_key=abc123 and OPENAI sk-abcdefghijklmnop1234 plus Bearer eyJhbGciOi.xyz";
+        let redacted = redact_secrets(text);
+        assert!(!redacted.contains("abc123"));
+        assert!(!redacted.contains("sk-abcdefghijklmnop1234"));
+        assert!(!redacted.contains("eyJhbGciOi.xyz"));
+        assert!(redacted.contains("api_key=[REDACTED]"));
+    }
+
+    #[test]
+    fn test_redact_secrets_leaves_plain_text_untouched() {
+        let text = "Refactored the session manager; no secrets here.";
+        assert_eq!(redact_secrets(text), text);
+    }
+
+    #[tokio::test]
+    async fn test_bundle_roundtrip_through_export_and_import() {
+        let source = SessionManager::new();
+        source
+            .set_session_id(AgentProvider::Claude, "claude-session-1".to_string())
+            .await;
+        let bundle = source
+            .export_bundle(
+                AgentProvider::Claude,
+                Some("claude-sonnet-4-6".to_string()),
+                "user: hi (token: s3cr3t)\nassistant: hello",
+                Some("## Owner Profile\nyui"),
+            )
+            .await;
+        assert!(!bundle.transcript.contains("s3cr3t"));
+
+        let path =
+            std::env::temp_dir().join(format!("acore-test-{}-bundle.json", std::process::id()));
+        bundle.write_to_path(&path).await.unwrap();
+        let restored = SessionBundle::read_from_path(&path).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(restored, bundle);
+
+        let target = SessionManager::new();
+        target.import_bundle(&restored).await;
+        assert_eq!(
+            target.session_id(&AgentProvider::Claude).await,
+            Some("claude-session-1".to_string())
+        );
+    }
+}
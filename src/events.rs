@@ -0,0 +1,293 @@
+use crate::SessionManager;
+use crate::lines::LineFramer;
+
+/// 行単位に組み立て直したエージェント出力のイベント
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgentEvent {
+    /// JSON 出力に含まれていた session id / thread id
+    SessionId(String),
+    /// 応答テキストの断片
+    Delta(String),
+    /// 応答の終了。`response` は最終結果 (Claude の `result` など)、なければそれまでの `Delta` を連結したもの
+    Done { response: String },
+    /// エージェントがホスト側のツール呼び出しを要求した
+    ToolCall(ToolCall),
+    /// イベントを取り出せなかった JSON 行 (`system` メッセージや未知の `type` など)
+    Unknown(serde_json::Value),
+    /// JSON として解釈できなかった行
+    Raw(String),
+}
+
+/// エージェントが要求したツール呼び出し
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    /// 呼び出しの引数 (文字列で届いた場合は JSON として解釈できればその値)
+    pub arguments: serde_json::Value,
+}
+
+impl ToolCall {
+    /// `{"type":"tool_call"|"tool_use","id":..,"name":..,"arguments"|"input":..}` 形式の値を解釈します
+    fn from_value(value: &serde_json::Value) -> Option<Self> {
+        let kind = value.get("type").and_then(|t| t.as_str());
+        if !matches!(kind, Some("tool_call" | "tool_use" | "function_call")) {
+            return None;
+        }
+        let name = value.get("name").and_then(|n| n.as_str())?;
+        let id = ["id", "call_id", "tool_call_id"]
+            .iter()
+            .find_map(|key| value.get(*key).and_then(|v| v.as_str()))
+            .unwrap_or(name);
+        let arguments = match value.get("arguments").or_else(|| value.get("input")) {
+            Some(serde_json::Value::String(raw)) => {
+                serde_json::from_str(raw).unwrap_or_else(|_| serde_json::Value::String(raw.clone()))
+            }
+            Some(arguments) => arguments.clone(),
+            None => serde_json::Value::Null,
+        };
+        Some(Self {
+            id: id.to_string(),
+            name: name.to_string(),
+            arguments,
+        })
+    }
+}
+
+/// Concatenated `text` blocks of a Claude `assistant` message.
+fn assistant_text(value: &serde_json::Value) -> Option<String> {
+    let blocks = value.get("message")?.get("content")?.as_array()?;
+    let text: String = blocks
+        .iter()
+        .filter(|block| block.get("type").and_then(|t| t.as_str()) == Some("text"))
+        .filter_map(|block| block.get("text").and_then(|t| t.as_str()))
+        .collect();
+    (!text.is_empty()).then_some(text)
+}
+
+/// Tool calls carried by a line: either the line itself, or the `tool_use`
+/// blocks of a Claude `assistant` message.
+fn tool_calls(value: &serde_json::Value) -> Vec<ToolCall> {
+    if let Some(call) = ToolCall::from_value(value) {
+        return vec![call];
+    }
+    value
+        .get("message")
+        .and_then(|m| m.get("content"))
+        .and_then(|c| c.as_array())
+        .map(|blocks| blocks.iter().filter_map(ToolCall::from_value).collect())
+        .unwrap_or_default()
+}
+
+/// Reassembles raw stream chunks into complete lines and turns each line into
+/// `AgentEvent`s. A trailing partial line is held until the next `push`.
+#[derive(Debug, Default)]
+pub(crate) struct EventParser {
+    lines: LineFramer,
+    done: bool,
+    /// Text of every `Delta` so far, the fallback `Done` response.
+    text: String,
+    session_id: Option<String>,
+}
+
+impl EventParser {
+    pub(crate) fn push(&mut self, chunk: &str, emit: &mut impl FnMut(AgentEvent)) {
+        for line in self.lines.push(chunk) {
+            self.parse_line(&line, emit);
+        }
+    }
+
+    /// Flushes any unterminated last line and guarantees exactly one `Done`.
+    pub(crate) fn finish(&mut self, emit: &mut impl FnMut(AgentEvent)) {
+        if let Some(line) = self.lines.finish() {
+            self.parse_line(&line, emit);
+        }
+        if !self.done {
+            self.done = true;
+            emit(AgentEvent::Done {
+                response: std::mem::take(&mut self.text),
+            });
+        }
+    }
+
+    fn parse_line(&mut self, line: &str, emit: &mut impl FnMut(AgentEvent)) {
+        if line.trim().is_empty() {
+            return;
+        }
+        let Ok(value) = serde_json::from_str::<serde_json::Value>(line) else {
+            emit(AgentEvent::Raw(line.to_string()));
+            return;
+        };
+        if !value.is_object() {
+            emit(AgentEvent::Raw(line.to_string()));
+            return;
+        }
+
+        let mut events = Vec::new();
+        // stream-json repeats the session id on most lines; report it once.
+        if let Some(id) = SessionManager::extract_session_id(line)
+            && self.session_id.as_ref() != Some(&id)
+        {
+            self.session_id = Some(id.clone());
+            events.push(AgentEvent::SessionId(id));
+        }
+        if let Some(text) = SessionManager::extract_response(line).or_else(|| delta_text(&value)) {
+            self.text.push_str(&text);
+            events.push(AgentEvent::Delta(text));
+        }
+        events.extend(tool_calls(&value).into_iter().map(AgentEvent::ToolCall));
+        let kind = value.get("type").and_then(|t| t.as_str());
+        if matches!(kind, Some("result" | "turn.completed")) && !self.done {
+            self.done = true;
+            let response = match value.get("result").and_then(|r| r.as_str()) {
+                Some(result) => result.to_string(),
+                None => std::mem::take(&mut self.text),
+            };
+            events.push(AgentEvent::Done { response });
+        }
+        if events.is_empty() {
+            events.push(AgentEvent::Unknown(value));
+        }
+        events.into_iter().for_each(emit);
+    }
+}
+
+fn delta_text(value: &serde_json::Value) -> Option<String> {
+    // `claude --include-partial-messages` wraps API events in `stream_event`.
+    if let Some(event) = value
+        .get("event")
+        .filter(|_| value.get("type").and_then(|t| t.as_str()) == Some("stream_event"))
+    {
+        return delta_text(event);
+    }
+    if value.get("type").and_then(|t| t.as_str()) == Some("assistant") {
+        return assistant_text(value);
+    }
+    if let Some(text) = value
+        .get("delta")
+        .and_then(|d| d.get("text"))
+        .and_then(|t| t.as_str())
+    {
+        return Some(text.to_string());
+    }
+    let is_assistant = value.get("role").and_then(|r| r.as_str()) == Some("assistant");
+    if is_assistant && let Some(content) = value.get("content").and_then(|c| c.as_str()) {
+        return Some(content.to_string());
+    }
+    value
+        .get("text")
+        .and_then(|t| t.as_str())
+        .map(|t| t.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_chunks(chunks: &[&str]) -> Vec<AgentEvent> {
+        let mut parser = EventParser::default();
+        let mut events = Vec::new();
+        let mut emit = |event| events.push(event);
+        for chunk in chunks {
+            parser.push(chunk, &mut emit);
+        }
+        parser.finish(&mut emit);
+        events
+    }
+
+    #[test]
+    fn test_json_line_split_across_chunks_is_reassembled() {
+        let events = parse_chunks(&[r#"{"session_id":"abc","#, r#""response":"hi"}"#, "\n"]);
+        assert_eq!(
+            events,
+            vec![
+                AgentEvent::SessionId("abc".into()),
+                AgentEvent::Delta("hi".into()),
+                AgentEvent::Done {
+                    response: "hi".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stream_json_deltas_and_result_emit_single_done() {
+        let events = parse_chunks(&[
+            "{\"type\":\"message\",\"role\":\"assistant\",\"content\":\"Hel\",\"delta\":true}\n",
+            "{\"type\":\"message\",\"role\":\"assistant\",\"content\":\"lo\",\"delta\":true}\n",
+            "{\"type\":\"result\",\"status\":\"success\"}\n",
+        ]);
+        assert_eq!(
+            events,
+            vec![
+                AgentEvent::Delta("Hel".into()),
+                AgentEvent::Delta("lo".into()),
+                AgentEvent::Done {
+                    response: "Hello".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_claude_stream_json_yields_typed_events() {
+        let events = parse_chunks(&[
+            "{\"type\":\"system\",\"subtype\":\"init\",\"session_id\":\"s1\"}\n{\"type\":\"stream_event\",\"session_id\":\"s1\",\"event\":{\"type\":\"content_block_delta\",\"delta\":{\"type\":\"text_delta\",\"text\":\"Hi\"}}}\n",
+            "{\"type\":\"assistant\",\"session_id\":\"s1\",\"message\":{\"content\":[{\"type\":\"text\",\"text\":\" there\"}]}}\n{\"type\":\"ping\"}\n",
+            "{\"type\":\"result\",\"subtype\":\"success\",\"result\":\"Hi there\",\"session_id\":\"s1\"}",
+        ]);
+        assert_eq!(
+            events,
+            vec![
+                AgentEvent::SessionId("s1".into()),
+                AgentEvent::Delta("Hi".into()),
+                AgentEvent::Delta(" there".into()),
+                AgentEvent::Unknown(serde_json::json!({ "type": "ping" })),
+                AgentEvent::Done {
+                    response: "Hi there".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tool_calls_are_parsed_from_generic_and_claude_lines() {
+        let events = parse_chunks(&[
+            "{\"type\":\"tool_call\",\"id\":\"c1\",\"name\":\"ls\",\"arguments\":\"{\\\"dir\\\":\\\"src\\\"}\"}\n",
+            "{\"type\":\"assistant\",\"message\":{\"content\":[{\"type\":\"tool_use\",\"id\":\"t1\",\"name\":\"Read\",\"input\":{\"path\":\"a.rs\"}}]}}\n",
+        ]);
+        assert_eq!(
+            events,
+            vec![
+                AgentEvent::ToolCall(ToolCall {
+                    id: "c1".into(),
+                    name: "ls".into(),
+                    arguments: serde_json::json!({ "dir": "src" }),
+                }),
+                AgentEvent::ToolCall(ToolCall {
+                    id: "t1".into(),
+                    name: "Read".into(),
+                    arguments: serde_json::json!({ "path": "a.rs" }),
+                }),
+                AgentEvent::Done {
+                    response: String::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plain_text_lines_are_raw() {
+        let events = parse_chunks(&["line one\nline ", "two"]);
+        assert_eq!(
+            events,
+            vec![
+                AgentEvent::Raw("line one".into()),
+                AgentEvent::Raw("line two".into()),
+                AgentEvent::Done {
+                    response: String::new(),
+                },
+            ]
+        );
+    }
+}
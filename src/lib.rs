@@ -1,1116 +1,9853 @@
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::process::Stdio;
-use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
-use tokio::process::Command;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdout, Command};
 use tokio::sync::Mutex;
+use tokio_stream::Stream;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::Instrument;
 
+mod amem;
+mod ansi;
+mod backend;
+mod bundle;
+mod events;
+mod lines;
+mod metrics;
+mod runner;
+mod sections;
+mod timestamp;
+
+pub use amem::{AmemContext, AmemError, DEFAULT_AMEM_PROBE_TIMEOUT};
+use ansi::AnsiStripper;
+pub use ansi::strip_ansi;
+pub use backend::{AgentBackend, BackendFuture, CliBackend, MockBackend};
+pub use bundle::{SessionBundle, redact_secrets};
+use events::EventParser;
+pub use events::{AgentEvent, ToolCall};
+use lines::LineFramer;
+pub use metrics::ExecMetrics;
+pub use runner::{ProcessRunner, RunnerFuture, TokioRunner};
+use sections::SectionSplitter;
+pub use sections::StreamItem;
+pub use timestamp::{Clock, PromptTimestamp, TimestampTarget};
+pub use tokio_util::sync::CancellationToken;
+
+/// 対応するエージェント CLI
+///
+/// `main.rs` と同じ名前で利用できることをここで保証します:
+///
+/// ```
+/// use acore::{AgentExecutor, AgentProvider};
+///
+/// let provider = AgentProvider::Gemini;
+/// assert_eq!(provider.command_name(), "gemini");
+/// let _run = AgentExecutor::execute_stream(provider, "hello", |chunk| print!("{}", chunk));
+/// ```
 #[derive(Debug, Serialize, Deserialize, Clone, Hash, Eq, PartialEq)]
 pub enum AgentProvider {
     Gemini,
     Claude,
     Codex,
     OpenCode,
+    Cursor,
+    Aider,
     Dummy,
     Mock,
 }
 
 impl AgentProvider {
-    pub fn command_name(&self) -> &str {
+    /// すべてのプロバイダー (宣言順)
+    pub const ALL: [AgentProvider; 8] = [
+        AgentProvider::Gemini,
+        AgentProvider::Claude,
+        AgentProvider::Codex,
+        AgentProvider::OpenCode,
+        AgentProvider::Cursor,
+        AgentProvider::Aider,
+        AgentProvider::Dummy,
+        AgentProvider::Mock,
+    ];
+
+    pub fn command_name(&self) -> &'static str {
         match self {
             AgentProvider::Gemini => "gemini",
             AgentProvider::Claude => "claude",
             AgentProvider::Codex => "codex",
             AgentProvider::OpenCode => "opencode",
+            AgentProvider::Cursor => "cursor-agent",
+            AgentProvider::Aider => "aider",
             AgentProvider::Dummy => "dummy-bot",
             AgentProvider::Mock => "mock-agent",
         }
     }
-}
 
-#[derive(Clone)]
-pub struct SessionManager {
-    session_ids: Arc<Mutex<HashMap<AgentProvider, String>>>,
-}
+    /// 起動するバイナリのパス。`overrides` に登録がなければ `command_name()` を PATH から探します。
+    pub fn resolve_binary(&self, overrides: &HashMap<AgentProvider, PathBuf>) -> PathBuf {
+        overrides
+            .get(self)
+            .cloned()
+            .unwrap_or_else(|| PathBuf::from(self.command_name()))
+    }
 
-impl Default for SessionManager {
-    fn default() -> Self {
-        Self::new()
+    /// CLI がインストールされているかどうかを `--version` で確認します (`has_amem` と同じ方法)。
+    /// 10 秒以内に応答しない CLI は利用できないものとして扱います。
+    /// プロセス内で動く Dummy と Mock は常に利用可能です
+    pub async fn is_available(&self) -> bool {
+        self.is_available_with_options(&ExecOptions::default())
+            .await
     }
-}
 
-impl SessionManager {
-    pub fn new() -> Self {
-        Self {
-            session_ids: Arc::new(Mutex::new(HashMap::new())),
+    /// `is_available` と同じ確認を、`options` の `command_overrides` と `runner` を使って行います
+    pub async fn is_available_with_options(&self, options: &ExecOptions) -> bool {
+        if matches!(self, AgentProvider::Dummy | AgentProvider::Mock) {
+            return true;
         }
+        AgentExecutor::version_output(self, options)
+            .await
+            .is_some_and(|output| output.status.success())
     }
 
-    fn model_args_for_provider(provider: &AgentProvider, model: Option<&str>) -> Vec<String> {
-        let Some(model) = model.map(str::trim).filter(|m| !m.is_empty()) else {
-            return Vec::new();
-        };
+    /// インストールされている CLI を並行して調べ、宣言順で返します。
+    /// プロセス内で動く Dummy と Mock は含みません
+    pub async fn available_tools() -> Vec<AgentProvider> {
+        let mut probes = tokio::task::JoinSet::new();
+        let installable = Self::ALL.into_iter().enumerate().filter(|(_, provider)| {
+            !matches!(provider, AgentProvider::Dummy | AgentProvider::Mock)
+        });
+        for (index, provider) in installable {
+            probes.spawn(async move { (index, provider.is_available().await, provider) });
+        }
+        let mut available = Vec::new();
+        while let Some(probe) = probes.join_next().await {
+            if let Ok((index, true, provider)) = probe {
+                available.push((index, provider));
+            }
+        }
+        available.sort_by_key(|(index, _)| *index);
+        available
+            .into_iter()
+            .map(|(_, provider)| provider)
+            .collect()
+    }
 
-        match provider {
+    /// CLI が保存済みセッションの再開に対応しているかどうか。
+    /// 対応していないツールは `execute_with_resume` でも毎回単発実行になります。
+    pub fn supports_resume(&self) -> bool {
+        !matches!(
+            self,
+            AgentProvider::Aider | AgentProvider::Dummy | AgentProvider::Mock
+        )
+    }
+
+    /// このツールが対応している機能
+    pub fn capabilities(&self) -> Capabilities {
+        let real_tool = !matches!(self, AgentProvider::Dummy | AgentProvider::Mock);
+        Capabilities {
+            resume: self.supports_resume(),
+            json_output: matches!(
+                self,
+                AgentProvider::Gemini
+                    | AgentProvider::Claude
+                    | AgentProvider::Codex
+                    | AgentProvider::Cursor
+            ),
+            model_selection: real_tool && self.model_arg().is_some(),
+            thinking_budget: *self == AgentProvider::Claude,
+            stdin_prompt: self.supports_stdin_prompt(),
+            permission_modes: [PermissionMode::Yolo, PermissionMode::Restricted]
+                .iter()
+                .any(|mode| !mode.args(self).is_empty()),
+        }
+    }
+
+    /// プロンプトを引数の代わりに標準入力から読めるかどうか
+    /// (Claude は `--print` で、Codex は `-` を指定して標準入力を読みます)
+    pub fn supports_stdin_prompt(&self) -> bool {
+        matches!(self, AgentProvider::Claude | AgentProvider::Codex)
+    }
+
+    /// モデルを指定するフラグ。モデルを選べないツール (Dummy / Mock) は `None` です
+    pub fn model_arg(&self) -> Option<&'static str> {
+        match self {
             AgentProvider::Gemini
             | AgentProvider::Claude
             | AgentProvider::Codex
-            | AgentProvider::OpenCode => {
-                vec!["--model".to_string(), model.to_string()]
-            }
-            AgentProvider::Dummy | AgentProvider::Mock => Vec::new(),
+            | AgentProvider::OpenCode
+            | AgentProvider::Cursor
+            | AgentProvider::Aider => Some("--model"),
+            AgentProvider::Dummy | AgentProvider::Mock => None,
         }
     }
 
-    fn apply_model_args(command: &mut Command, provider: &AgentProvider, model: Option<&str>) {
-        for arg in Self::model_args_for_provider(provider, model) {
-            command.arg(arg);
-        }
+    /// プロンプトを argv に渡す前の既定のエスケープ方法。
+    /// シェルを介さずに直接起動するため、すべてのツールで既定はエスケープなしです。
+    pub fn prompt_escaping(&self) -> PromptEscaping {
+        PromptEscaping::None
     }
+}
 
-    fn is_gemini_capacity_error(detail: &str) -> bool {
-        let lower = detail.to_ascii_lowercase();
-        lower.contains("no capacity available for model")
-            || (lower.contains("status 429") && lower.contains("gemini"))
-            || (lower.contains("attempt 1 failed with status 429") && lower.contains("gemini"))
+/// ツールが対応している機能 ([`AgentProvider::capabilities`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Capabilities {
+    /// 保存済みセッションの再開 (`execute_with_resume`)
+    pub resume: bool,
+    /// JSON 形式の出力 (session id や応答の取り出しに使います)
+    pub json_output: bool,
+    /// `ExecOptions::with_model` によるモデルの指定
+    pub model_selection: bool,
+    /// `ExecOptions::with_thinking_budget` による拡張思考のトークン上限
+    pub thinking_budget: bool,
+    /// `ExecOptions::with_prompt_via_stdin` による標準入力からのプロンプト
+    pub stdin_prompt: bool,
+    /// `PermissionMode` による許可の切り替え
+    pub permission_modes: bool,
+}
+
+impl Capabilities {
+    /// 表示用の (名前, 対応しているか) の一覧
+    pub fn entries(&self) -> [(&'static str, bool); 6] {
+        [
+            ("resume", self.resume),
+            ("json", self.json_output),
+            ("model", self.model_selection),
+            ("thinking", self.thinking_budget),
+            ("stdin", self.stdin_prompt),
+            ("permissions", self.permission_modes),
+        ]
     }
+}
 
-    fn gemini_capacity_fallback_models(model: Option<&str>) -> &'static [&'static str] {
-        match model.map(str::trim).filter(|m| !m.is_empty()) {
-            None | Some("auto-gemini-3") => &["gemini-2.5-flash", "gemini-2.5-pro"],
-            Some("gemini-2.5-flash") => &["gemini-2.5-pro"],
-            _ => &[],
+/// 未知のプロバイダー名を表すパースエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseAgentProviderError(pub String);
+
+impl fmt::Display for ParseAgentProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown provider '{}' (expected one of: gemini, claude, codex, opencode, cursor, aider, mock)",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseAgentProviderError {}
+
+impl std::str::FromStr for AgentProvider {
+    type Err = ParseAgentProviderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "gemini" => Ok(AgentProvider::Gemini),
+            "claude" => Ok(AgentProvider::Claude),
+            "codex" => Ok(AgentProvider::Codex),
+            "opencode" => Ok(AgentProvider::OpenCode),
+            "cursor" | "cursor-agent" => Ok(AgentProvider::Cursor),
+            "aider" => Ok(AgentProvider::Aider),
+            "mock" => Ok(AgentProvider::Mock),
+            _ => Err(ParseAgentProviderError(s.to_string())),
         }
     }
+}
 
-    fn gemini_should_retry_with_fallback(
-        provider: &AgentProvider,
-        requested_model: Option<&str>,
-        detail: &str,
-    ) -> bool {
-        *provider == AgentProvider::Gemini
-            && Self::is_gemini_capacity_error(detail)
-            && !Self::gemini_capacity_fallback_models(requested_model).is_empty()
+/// `AgentProvider` の旧名。すべての API は `AgentProvider` を使います。
+#[deprecated(note = "use `AgentProvider`")]
+pub type AgentTool = AgentProvider;
+
+/// エージェントプロセスのライフサイクルイベント (チャンク出力とは独立に通知されます)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lifecycle {
+    Spawned {
+        pid: Option<u32>,
+    },
+    /// 起動から最初の出力バイトまでの経過時間
+    FirstByte {
+        at: Duration,
+    },
+    Exited {
+        code: Option<i32>,
+    },
+    Killed {
+        reason: CancelReason,
+    },
+    /// シードターンが成功した。`prompt` は送信した初期化プロンプトそのもの
+    Seeded {
+        prompt: String,
+    },
+}
+
+pub type LifecycleCallback = Arc<dyn Fn(Lifecycle) + Send + Sync>;
+
+/// 出力の途中で実行を打ち切るかどうかを判定する述語 (それまでの標準出力全体を受け取ります)
+pub type StopPredicate = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// セッションが破棄されたとき (TTL 切れ、ターン上限、明示的なリセット) に、ツールと session id を受け取るコールバック
+pub type EvictCallback = Arc<dyn Fn(AgentProvider, String) + Send + Sync>;
+
+/// フックに渡される実行対象のツールとプロンプト
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookContext {
+    pub provider: AgentProvider,
+    pub prompt: String,
+}
+
+impl HookContext {
+    /// ログに残すためにトークンや API キーを伏せたプロンプト
+    pub fn redacted_prompt(&self) -> String {
+        redact_secrets(&self.prompt)
     }
+}
 
-    fn find_in_json_output<T, F>(output: &str, mut pick: F) -> Option<T>
-    where
-        F: FnMut(&serde_json::Value) -> Option<T>,
-    {
-        if let Ok(v) = serde_json::from_str::<serde_json::Value>(output)
-            && let Some(found) = pick(&v)
-        {
-            return Some(found);
-        }
+/// `after_execute` フックに渡される実行結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookOutcome {
+    pub duration: Duration,
+    /// 失敗した場合はそのエラーメッセージ
+    pub error: Option<String>,
+}
 
-        for (idx, ch) in output.char_indices() {
-            if ch != '{' {
-                continue;
+pub type HookFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// 実行前に呼ばれるフック。`Err` を返すと起動せずに `AgentError::HookFailed` で中断します
+pub type BeforeHook = Arc<dyn Fn(HookContext) -> HookFuture<Result<(), String>> + Send + Sync>;
+
+/// 実行後に成否を問わず呼ばれるフック
+pub type AfterHook = Arc<dyn Fn(HookContext, HookOutcome) -> HookFuture<()> + Send + Sync>;
+
+/// Sums the spawn-to-exit windows reported through lifecycle events.
+#[derive(Debug, Default)]
+struct ProcessTiming {
+    spawned_at: Option<Instant>,
+    total: Duration,
+    exit_code: Option<i32>,
+}
+
+impl ProcessTiming {
+    fn observe(&mut self, event: &Lifecycle) {
+        match event {
+            Lifecycle::Spawned { .. } => self.spawned_at = Some(Instant::now()),
+            Lifecycle::Exited { code } => {
+                self.exit_code = *code;
+                self.close();
             }
-            let mut de = serde_json::Deserializer::from_str(&output[idx..]);
-            if let Ok(v) = serde_json::Value::deserialize(&mut de)
-                && let Some(found) = pick(&v)
-            {
-                return Some(found);
+            Lifecycle::Killed { .. } => {
+                self.exit_code = None;
+                self.close();
             }
+            Lifecycle::FirstByte { .. } | Lifecycle::Seeded { .. } => {}
         }
+    }
 
-        None
+    fn close(&mut self) {
+        if let Some(spawned) = self.spawned_at.take() {
+            self.total += spawned.elapsed();
+        }
     }
+}
 
-    pub fn extract_session_id(output: &str) -> Option<String> {
-        Self::find_in_json_output(output, |v| {
-            if let Some(id) = v.get("session_id").and_then(|v| v.as_str()) {
-                return Some(id.to_string());
+/// 実行が中断された理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CancelReason {
+    UserRequested,
+    Timeout,
+    IdleTimeout,
+    Predicate,
+    Budget,
+    Shutdown,
+}
+
+impl fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            CancelReason::UserRequested => "cancelled by user",
+            CancelReason::Timeout => "timed out",
+            CancelReason::IdleTimeout => "no output before idle timeout",
+            CancelReason::Predicate => "stop predicate matched",
+            CancelReason::Budget => "budget exhausted",
+            CancelReason::Shutdown => "shutting down",
+        };
+        f.write_str(text)
+    }
+}
+
+#[derive(Debug)]
+pub enum AgentError {
+    SpawnFailed {
+        command: String,
+        source: std::io::Error,
+    },
+    NonZeroExit {
+        command: String,
+        code: Option<i32>,
+        detail: String,
+    },
+    SeedFailed(String),
+    SessionIdMissing,
+    /// シードの応答に `MEMORY_READY` が含まれず、amem のスナップショットを読み込んだか確認できなかった
+    MemoryNotLoaded {
+        provider: AgentProvider,
+    },
+    ResponseMissing(String),
+    InvalidOption(String),
+    /// プロンプトに NUL 文字が含まれていた (引数でも標準入力でもツールに正しく渡せません)
+    PromptContainsNul {
+        offset: usize,
+    },
+    /// `before_execute` フックが実行を拒否した
+    HookFailed(String),
+    /// ツールが対応していない機能を要求した
+    UnsupportedFeature {
+        provider: AgentProvider,
+        feature: &'static str,
+    },
+    Cancelled {
+        reason: CancelReason,
+    },
+    MalformedSessionStore {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    MalformedBundle {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    /// amem の呼び出しに失敗した
+    Amem(AmemError),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for AgentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AgentError::SpawnFailed { command, source } => {
+                write!(f, "Failed to spawn {}: {}", command, source)
             }
-            if let Some(id) = v.get("sessionId").and_then(|v| v.as_str()) {
-                return Some(id.to_string());
+            AgentError::NonZeroExit {
+                command, detail, ..
+            } => write!(f, "{} exited with error:\n{}", command, detail),
+            AgentError::SeedFailed(detail) => write!(f, "Seed turn failed: {}", detail),
+            AgentError::SessionIdMissing => {
+                f.write_str("Failed to extract session_id from seed turn.")
             }
-            if let Some(id) = v.get("thread_id").and_then(|v| v.as_str()) {
-                return Some(id.to_string());
+            AgentError::MemoryNotLoaded { provider } => write!(
+                f,
+                "{} seed reply did not acknowledge the memory context (MEMORY_READY missing)",
+                provider.command_name()
+            ),
+            AgentError::ResponseMissing(command) => {
+                write!(
+                    f,
+                    "Failed to extract response from {} JSON output.",
+                    command
+                )
             }
-            if let Some(id) = v.get("threadId").and_then(|v| v.as_str()) {
-                return Some(id.to_string());
+            AgentError::InvalidOption(detail) => write!(f, "Invalid option: {}", detail),
+            AgentError::PromptContainsNul { offset } => write!(
+                f,
+                "Prompt contains a null byte at byte offset {}; agent CLIs cannot receive it",
+                offset
+            ),
+            AgentError::HookFailed(detail) => write!(f, "before_execute hook failed: {}", detail),
+            AgentError::UnsupportedFeature { provider, feature } => {
+                write!(
+                    f,
+                    "{} does not support {}",
+                    provider.command_name(),
+                    feature
+                )
             }
-            None
-        })
-    }
-
-    pub fn extract_response(output: &str) -> Option<String> {
-        Self::find_in_json_output(output, |v| {
-            if let Some(res) = v.get("response").and_then(|v| v.as_str()) {
-                return Some(res.to_string());
+            AgentError::Cancelled { reason } => write!(f, "Execution cancelled: {}", reason),
+            AgentError::MalformedSessionStore { path, source } => {
+                write!(f, "Malformed session store {}: {}", path.display(), source)
             }
-            if let Some(item) = v.get("item") {
-                let is_agent_message =
-                    item.get("type").and_then(|t| t.as_str()) == Some("agent_message");
-                if is_agent_message && let Some(text) = item.get("text").and_then(|t| t.as_str()) {
-                    return Some(text.to_string());
-                }
+            AgentError::MalformedBundle { path, source } => {
+                write!(f, "Malformed session bundle {}: {}", path.display(), source)
             }
-            None
-        })
+            AgentError::Amem(e) => write!(f, "{}", e),
+            AgentError::Io(e) => write!(f, "{}", e),
+        }
     }
+}
 
-    pub async fn execute_with_resume<F>(
-        &self,
-        provider: AgentProvider,
-        prompt: &str,
-        on_chunk: F,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
-    where
-        F: FnMut(String) + Send + 'static,
-    {
-        self.execute_with_resume_with_model(provider, None, prompt, on_chunk)
-            .await
+/// 非ゼロ終了したエージェントの情報 (エラー分類器に渡されます)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitInfo {
+    pub command: String,
+    pub code: Option<i32>,
+}
+
+/// 失敗の分類。再試行や認証エラーの判定に使います
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    /// ネットワーク断や過負荷など、再試行すれば成功し得る失敗
+    Retryable,
+    /// レート制限 (再試行可能)
+    RateLimited,
+    /// 認証・ログインが必要
+    Auth,
+    /// 再試行しても成功しない失敗
+    Fatal,
+}
+
+impl ErrorClass {
+    pub fn is_retryable(self) -> bool {
+        matches!(self, ErrorClass::Retryable | ErrorClass::RateLimited)
     }
 
-    pub async fn execute_with_resume_with_model<F>(
-        &self,
-        provider: AgentProvider,
-        model: Option<String>,
-        prompt: &str,
-        mut on_chunk: F,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
-    where
-        F: FnMut(String) + Send + 'static,
-    {
-        if provider == AgentProvider::Dummy {
-            on_chunk(prompt.to_string());
-            return Ok(());
+    /// 既定の分類器。stderr に含まれる代表的なメッセージと、`HTTP 429` や `status: 503` のように
+    /// status・HTTP などに続く HTTP ステータスコードで判定します
+    pub fn classify_default(_exit: &ExitInfo, stderr: &str) -> ErrorClass {
+        // A bare "429" may just as well be a line number or part of an id, so
+        // codes only count right after a status-like word.
+        static STATUS_CODE: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(
+                r#"\b(?:http(?:/\d(?:\.\d)?)?|status(?: code)?|code|error)["\s:=]*(\d{3})\b"#,
+            )
+            .unwrap()
+        });
+        const RATE_LIMIT_PATTERNS: &[&str] = &[
+            "rate limit",
+            "rate-limit",
+            "too many requests",
+            "no capacity available",
+            "quota exceeded",
+        ];
+        const RETRYABLE_PATTERNS: &[&str] = &[
+            "overloaded",
+            "service unavailable",
+            "connection reset",
+            "econnreset",
+            "etimedout",
+            "network error",
+        ];
+        const AUTH_PATTERNS: &[&str] = &[
+            "unauthorized",
+            "invalid api key",
+            "not logged in",
+            "please log in",
+            "please login",
+            "authentication",
+        ];
+        let stderr = stderr.to_ascii_lowercase();
+        let status = |code: &str| {
+            STATUS_CODE
+                .captures_iter(&stderr)
+                .any(|captures| &captures[1] == code)
+        };
+        let matches = |patterns: &[&str]| patterns.iter().any(|p| stderr.contains(p));
+        if status("429") || matches(RATE_LIMIT_PATTERNS) {
+            ErrorClass::RateLimited
+        } else if status("503") || matches(RETRYABLE_PATTERNS) {
+            ErrorClass::Retryable
+        } else if status("401") || matches(AUTH_PATTERNS) {
+            ErrorClass::Auth
+        } else {
+            ErrorClass::Fatal
         }
+    }
+}
 
-        if provider == AgentProvider::Mock {
-            on_chunk("Mock: ".into());
-            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
-            on_chunk(format!("received your prompt '{}'.", prompt));
-            return Ok(());
+/// 非ゼロ終了とその stderr から `ErrorClass` を決める分類器
+pub type ErrorClassifier = Arc<dyn Fn(&ExitInfo, &str) -> ErrorClass + Send + Sync>;
+
+impl AgentError {
+    /// `classifier` (未指定なら `ErrorClass::classify_default`) でエラーを分類します。
+    /// 非ゼロ終了以外 (バイナリが見つからない `SpawnFailed` など) は常に `Fatal` です
+    pub fn classify(&self, classifier: Option<&ErrorClassifier>) -> ErrorClass {
+        let AgentError::NonZeroExit {
+            command,
+            code,
+            detail,
+        } = self
+        else {
+            return ErrorClass::Fatal;
+        };
+        let exit = ExitInfo {
+            command: command.clone(),
+            code: *code,
+        };
+        match classifier {
+            Some(classifier) => classifier(&exit, detail),
+            None => ErrorClass::classify_default(&exit, detail),
         }
+    }
 
-        let mut session_ids = self.session_ids.lock().await;
-        let cmd = provider.command_name();
-        let requested_model = model.as_deref();
-        let mut current_id = session_ids.get(&provider).cloned();
-        let mut active_model = model.clone();
-
-        if current_id.is_none() {
-            let init_prompt = AgentExecutor::build_init_prompt().await;
-            let mut seed_models: Vec<Option<String>> = vec![active_model.clone()];
-            if provider == AgentProvider::Gemini {
-                for fallback in Self::gemini_capacity_fallback_models(requested_model) {
-                    seed_models.push(Some((*fallback).to_string()));
+    /// 再試行すれば成功し得る一時的な失敗 (レート制限、過負荷、ネットワーク断) かどうか。
+    /// バイナリが見つからない `SpawnFailed` などは再試行しません
+    pub fn is_transient(&self) -> bool {
+        self.classify(None).is_retryable()
+    }
+
+    /// 既定の分類器で認証エラーと判定されるかどうか
+    pub fn is_auth_failure(&self) -> bool {
+        self.classify(None) == ErrorClass::Auth
+    }
+}
+
+impl std::error::Error for AgentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AgentError::SpawnFailed { source, .. } => Some(source),
+            AgentError::MalformedSessionStore { source, .. }
+            | AgentError::MalformedBundle { source, .. } => Some(source),
+            AgentError::Amem(e) => Some(e),
+            AgentError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<AmemError> for AgentError {
+    fn from(e: AmemError) -> Self {
+        AgentError::Amem(e)
+    }
+}
+
+impl From<std::io::Error> for AgentError {
+    fn from(e: std::io::Error) -> Self {
+        AgentError::Io(e)
+    }
+}
+
+/// Unix で子プロセスに `setrlimit` で課すリソース上限
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// アドレス空間の上限 (`RLIMIT_AS`)
+    pub max_memory_bytes: Option<u64>,
+    /// CPU 時間の上限 (`RLIMIT_CPU`)
+    pub cpu_time_secs: Option<u64>,
+}
+
+impl ResourceLimits {
+    fn is_empty(&self) -> bool {
+        self.max_memory_bytes.is_none() && self.cpu_time_secs.is_none()
+    }
+
+    #[cfg(unix)]
+    fn apply(&self, command: &mut Command) {
+        if self.is_empty() {
+            return;
+        }
+        let limits = *self;
+        // SAFETY: the closure only calls `setrlimit`, which is async-signal-safe,
+        // and does not allocate between fork and exec.
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(bytes) = limits.max_memory_bytes {
+                    set_rlimit(libc::RLIMIT_AS, bytes)?;
                 }
-            }
+                if let Some(secs) = limits.cpu_time_secs {
+                    set_rlimit(libc::RLIMIT_CPU, secs)?;
+                }
+                Ok(())
+            });
+        }
+    }
 
-            let mut last_seed_detail: Option<String> = None;
-            for candidate_model in seed_models {
-                let mut seed_cmd = Command::new(cmd);
-                // stdin must be null so CLI tools (especially claude) do not try to
-                // call setRawMode on an inherited non-TTY stdin (which causes EIO when
-                // running as a background service / Discord adapter).
-                seed_cmd
-                    .stdin(Stdio::null())
-                    .stdout(Stdio::piped())
-                    .stderr(Stdio::piped());
-
-                match provider {
-                    AgentProvider::Gemini => {
-                        seed_cmd
-                            .arg("--approval-mode")
-                            .arg("yolo")
-                            .arg("--output-format")
-                            .arg("json");
-                        Self::apply_model_args(
-                            &mut seed_cmd,
-                            &provider,
-                            candidate_model.as_deref(),
-                        );
-                        seed_cmd.arg("-p").arg(&init_prompt);
-                    }
-                    AgentProvider::Claude => {
-                        seed_cmd
-                            .arg("--dangerously-skip-permissions")
-                            .arg("--output-format")
-                            .arg("json")
-                            .arg("--print");
-                        Self::apply_model_args(
-                            &mut seed_cmd,
-                            &provider,
-                            candidate_model.as_deref(),
-                        );
-                        seed_cmd.arg(&init_prompt);
-                    }
-                    AgentProvider::Codex => {
-                        seed_cmd.arg("exec").arg("--json");
-                        Self::apply_model_args(
-                            &mut seed_cmd,
-                            &provider,
-                            candidate_model.as_deref(),
-                        );
-                        seed_cmd.arg(&init_prompt);
-                    }
-                    _ => {
-                        Self::apply_model_args(
-                            &mut seed_cmd,
-                            &provider,
-                            candidate_model.as_deref(),
-                        );
-                        seed_cmd.arg(&init_prompt);
+    #[cfg(not(unix))]
+    fn apply(&self, _command: &mut Command) {}
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(all(unix, not(all(target_os = "linux", target_env = "gnu"))))]
+type RlimitResource = libc::c_int;
+
+#[cfg(unix)]
+fn set_rlimit(resource: RlimitResource, value: u64) -> std::io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: value as libc::rlim_t,
+        rlim_max: value as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// プロンプトを argv に渡す前のエスケープ方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PromptEscaping {
+    /// プロンプトをそのまま渡します
+    #[default]
+    None,
+    /// POSIX シェルの単一引用符で囲みます (`'` は `'\''` に置き換えます)
+    ShellQuote,
+    /// `\` `"` `$` `` ` `` の前にバックスラッシュを付けます
+    Backslash,
+}
+
+impl PromptEscaping {
+    pub fn apply(self, prompt: &str) -> String {
+        match self {
+            Self::None => prompt.to_string(),
+            Self::ShellQuote => format!("'{}'", prompt.replace('\'', "'\\''")),
+            Self::Backslash => {
+                let mut escaped = String::with_capacity(prompt.len());
+                for c in prompt.chars() {
+                    if matches!(c, '\\' | '"' | '$' | '`') {
+                        escaped.push('\\');
                     }
+                    escaped.push(c);
                 }
+                escaped
+            }
+        }
+    }
+}
 
-                let output = seed_cmd.output().await?;
-                if !output.status.success() {
-                    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
-                    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                    let detail = if !stderr.is_empty() {
-                        stderr
-                    } else if !stdout.is_empty() {
-                        format!("stdout: {}", stdout)
-                    } else {
-                        "no stdout/stderr output".to_string()
-                    };
-                    last_seed_detail = Some(detail.clone());
-                    if Self::gemini_should_retry_with_fallback(
-                        &provider,
-                        candidate_model.as_deref(),
-                        &detail,
-                    ) {
+/// 記録前に収集した出力へ適用する空白の正規化
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputNormalization {
+    /// 出力をそのまま使います
+    None,
+    /// 先頭と末尾の空白・改行を取り除きます
+    #[default]
+    Trim,
+    /// 連続する空行を 1 行にまとめ、先頭と末尾の空行を取り除きます
+    CollapseBlankLines,
+}
+
+impl OutputNormalization {
+    pub fn apply(self, output: &str) -> String {
+        match self {
+            Self::None => output.to_string(),
+            Self::Trim => output.trim().to_string(),
+            Self::CollapseBlankLines => {
+                let mut lines: Vec<&str> = Vec::new();
+                for line in output.lines() {
+                    let line = line.trim_end();
+                    if line.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
                         continue;
                     }
-                    return Err(format!("Seed turn failed: {}", detail).into());
+                    lines.push(line);
                 }
-
-                let out_str = String::from_utf8_lossy(&output.stdout);
-                if let Some(id) = Self::extract_session_id(&out_str) {
-                    session_ids.insert(provider.clone(), id.clone());
-                    current_id = Some(id);
-                    active_model = candidate_model;
-                    break;
+                if lines.last() == Some(&"") {
+                    lines.pop();
                 }
-                last_seed_detail = Some("Failed to extract session_id from seed turn.".to_string());
+                lines.join("\n")
             }
+        }
+    }
+}
 
-            if current_id.is_none() {
-                return Err(last_seed_detail
-                    .unwrap_or_else(|| "Failed to extract session_id from seed turn.".to_string())
-                    .into());
-            }
+/// 標準出力の読み取り方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadStrategy {
+    /// 届いた分だけ `read()` してすぐに配信します (既定)。最初のバイトまでの遅延が最小です
+    #[default]
+    Chunked,
+    /// `BufReader` で改行まで読んでから 1 行ずつ配信します。行の途中で分割されないため
+    /// 行単位の解析が簡単になりますが、短い行が大量に流れるとコールバックが増えます
+    /// (80 バイト行 64 MB の計測で `Chunked` 約 1.2 GB/s に対し約 0.6 GB/s)
+    Lines,
+}
+
+/// エージェントに与える操作の許可
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PermissionMode {
+    /// 確認なしですべての操作を許可します (既定、`--approval-mode yolo` / `--dangerously-skip-permissions` など)
+    #[default]
+    Yolo,
+    /// 許可をスキップするフラグを付けず、各ツールの既定の確認に従います
+    Default,
+    /// 許可のスキップに加え、対応するツールでは読み取り専用のモードを指定します
+    /// (Claude は `--permission-mode plan`、Codex は `--sandbox read-only`)
+    Restricted,
+}
+
+impl PermissionMode {
+    /// このモードで `provider` に渡す引数
+    pub fn args(self, provider: &AgentProvider) -> &'static [&'static str] {
+        match (self, provider) {
+            (PermissionMode::Yolo, AgentProvider::Gemini) => &["--approval-mode", "yolo"],
+            (PermissionMode::Yolo, AgentProvider::Claude) => &["--dangerously-skip-permissions"],
+            (PermissionMode::Yolo, AgentProvider::Cursor) => &["--force"],
+            (PermissionMode::Yolo, AgentProvider::Aider) => &["--yes-always"],
+            (PermissionMode::Restricted, AgentProvider::Gemini) => &["--approval-mode", "default"],
+            (PermissionMode::Restricted, AgentProvider::Claude) => &["--permission-mode", "plan"],
+            (PermissionMode::Restricted, AgentProvider::Codex) => &["--sandbox", "read-only"],
+            _ => &[],
         }
+    }
+}
 
-        let mut command = Command::new(cmd);
-        command
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
-        let id = current_id.unwrap();
+/// 未知の許可モード名を表すパースエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsePermissionModeError(pub String);
 
-        match provider {
-            AgentProvider::Gemini => {
-                command
-                    .arg("--approval-mode")
-                    .arg("yolo")
-                    .arg("--resume")
-                    .arg(id);
-                Self::apply_model_args(&mut command, &provider, active_model.as_deref());
-                command.arg("-p").arg(prompt);
-            }
-            AgentProvider::Claude => {
-                command
-                    .arg("--dangerously-skip-permissions")
-                    .arg("--resume")
-                    .arg(id)
-                    .arg("--print");
-                Self::apply_model_args(&mut command, &provider, active_model.as_deref());
-                command.arg(prompt);
-            }
-            AgentProvider::Codex => {
-                command.arg("exec").arg("resume").arg("--json");
-                Self::apply_model_args(&mut command, &provider, active_model.as_deref());
-                command.arg(id).arg(prompt);
-            }
-            _ => {
-                Self::apply_model_args(&mut command, &provider, active_model.as_deref());
-                command.arg(prompt);
-            }
-        }
+impl fmt::Display for ParsePermissionModeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown permission mode '{}' (expected one of: yolo, default, restricted)",
+            self.0
+        )
+    }
+}
 
-        if provider == AgentProvider::Codex {
-            let output = command.output().await?;
-            if !output.status.success() {
-                let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
-                let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                let detail = if !stderr.is_empty() {
-                    stderr
-                } else if !stdout.is_empty() {
-                    format!("stdout: {}", stdout)
-                } else {
-                    "no stdout/stderr output".to_string()
-                };
-                return Err(format!("codex exited with error:\n{}", detail).into());
-            }
+impl std::error::Error for ParsePermissionModeError {}
 
-            let out_str = String::from_utf8_lossy(&output.stdout);
-            if let Some(response) = Self::extract_response(&out_str) {
-                on_chunk(response);
-                return Ok(());
-            }
+impl std::str::FromStr for PermissionMode {
+    type Err = ParsePermissionModeError;
 
-            return Err("Failed to extract response from codex exec resume JSON output.".into());
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "yolo" => Ok(PermissionMode::Yolo),
+            "default" => Ok(PermissionMode::Default),
+            "restricted" => Ok(PermissionMode::Restricted),
+            _ => Err(ParsePermissionModeError(s.to_string())),
         }
+    }
+}
 
-        let mut child = command
-            .spawn()
-            .map_err(|e| format!("Failed to spawn {}: {}", cmd, e))?;
-        let mut stdout = child.stdout.take().ok_or("Failed to open stdout")?;
-        let stderr = child.stderr.take().ok_or("Failed to open stderr")?;
-        let mut err_reader = BufReader::new(stderr).lines();
-
-        let mut buffer = [0; 1024];
-        let mut saw_output = false;
-        loop {
-            let n = stdout.read(&mut buffer).await?;
-            if n == 0 {
-                break;
-            }
-            let chunk = String::from_utf8_lossy(&buffer[..n]).to_string();
-            saw_output = true;
-            on_chunk(chunk);
-        }
+/// 標準出力の読み取りバッファの既定サイズ (バイト)
+pub const DEFAULT_BUFFER_SIZE: usize = 1024;
 
-        let status = child.wait().await?;
-        if !status.success() {
-            let mut err_msg = String::new();
-            while let Ok(Some(line)) = err_reader.next_line().await {
-                err_msg.push_str(&line);
-                err_msg.push('\n');
-            }
-            if !saw_output
-                && Self::gemini_should_retry_with_fallback(
-                    &provider,
-                    active_model.as_deref(),
-                    &err_msg,
-                )
-            {
-                for fallback in Self::gemini_capacity_fallback_models(active_model.as_deref()) {
-                    let fallback_model = Some((*fallback).to_string());
-                    if fallback_model == active_model {
-                        continue;
-                    }
-                    return Box::pin(self.execute_with_resume_with_model(
-                        provider,
-                        fallback_model,
-                        prompt,
-                        on_chunk,
-                    ))
-                    .await;
-                }
-            }
-            return Err(format!("{} exited with error:\n{}", cmd, err_msg).into());
-        }
+/// 解決後のバイナリごとの `--version` の結果。`Clone` は同じキャッシュを共有します
+#[derive(Debug, Clone, Default)]
+pub struct VersionCache {
+    inner: Arc<Mutex<HashMap<PathBuf, Option<String>>>>,
+}
 
-        Ok(())
+impl VersionCache {
+    pub fn new() -> Self {
+        Self::default()
     }
 }
 
-pub struct AgentExecutor;
+/// 1 回の実行に適用するオプション
+#[derive(Clone, Default)]
+pub struct ExecOptions {
+    pub model: Option<String>,
+    pub on_lifecycle: Option<LifecycleCallback>,
+    /// JSON から session id を取得できなかった場合に、シード応答のテキストへ適用する正規表現。
+    /// キャプチャグループ 1 があればそれを、なければマッチ全体を id とします。
+    pub session_id_from_reply: Option<Regex>,
+    /// 起動から終了までの上限時間。超過すると子プロセスを kill して
+    /// `AgentError::Cancelled { reason: CancelReason::Timeout }` を返します。
+    pub timeout: Option<Duration>,
+    pub stop_when: Option<StopPredicate>,
+    /// シードターンで送る初期化プロンプト。`None` の場合は実行ごとに amem から構築します。
+    pub init_prompt: Option<String>,
+    pub limits: ResourceLimits,
+    /// 出力から ANSI エスケープシーケンスを取り除く (既定は無効で生の出力を維持します)
+    pub strip_ansi: bool,
+    /// 記録前の出力の正規化。`None` の場合は呼び出し側の既定 (要約は `Trim`) を使います。
+    pub normalize: Option<OutputNormalization>,
+    /// 起動するエージェントの作業ディレクトリ。`None` の場合は呼び出し元のディレクトリを継承します。
+    pub cwd: Option<PathBuf>,
+    /// 起動するエージェントに追加する環境変数。継承した環境を置き換えず、同名の変数だけを上書きします。
+    /// 値はエラーメッセージやログに含めません (エラーに含まれるのはコマンド名のみです)。
+    pub envs: HashMap<String, String>,
+    /// キャンセルされると子プロセスを kill して
+    /// `AgentError::Cancelled { reason: CancelReason::UserRequested }` を返します。
+    pub cancel: Option<CancellationToken>,
+    /// コード中心の対話ではコード変更と判断に焦点を当てた要約指示に切り替えます (既定は無効)
+    pub code_aware_summary: bool,
+    /// 設定すると、`execute_with_resume_result` は停止理由が `MaxTokens` の間セッションを再開して続きを連結します
+    pub auto_continue: Option<AutoContinue>,
+    /// ツールごとに起動するバイナリのパスを差し替えます。見つからない場合の
+    /// `AgentError::SpawnFailed` には解決後のパスが含まれます。
+    pub command_overrides: HashMap<AgentProvider, PathBuf>,
+    /// 標準出力を読み取るバッファの大きさ (バイト)。`None` の場合は [`DEFAULT_BUFFER_SIZE`] を使います。
+    /// 0 を指定すると実行時に `AgentError::InvalidOption` を返します。
+    pub buffer_size: Option<usize>,
+    /// ツールごとのプロンプトのエスケープ方法。登録がなければ
+    /// [`AgentProvider::prompt_escaping`] の既定 (エスケープなし) を使います。
+    pub prompt_escaping: HashMap<AgentProvider, PromptEscaping>,
+    /// 設定すると、各ターンの成否・所要時間・トークン数をここに記録します
+    pub metrics: Option<ExecMetrics>,
+    /// シードで注入する amem コンテキストを囲む区切り
+    pub context_delimiters: ContextDelimiters,
+    /// 拡張思考に使うトークン数の上限。対応していないツールでは
+    /// `AgentError::UnsupportedFeature` を返します (Claude は `MAX_THINKING_TOKENS` で渡します)
+    pub thinking_budget: Option<u32>,
+    /// 設定すると、`execute_stream_items` はこの文字列で始まる行で出力をセクションに分けます
+    pub section_delimiter: Option<String>,
+    /// 再試行の判定に使うエラー分類器。`None` の場合は `ErrorClass::classify_default` を使います
+    pub error_classifier: Option<ErrorClassifier>,
+    /// 標準出力の読み取り方法 (既定は `ReadStrategy::Chunked`)
+    pub read_strategy: ReadStrategy,
+    /// 再開ターンを JSON 出力で実行し、`response` だけをコールバックへ渡します (既定は無効)。
+    /// 出力は終了までバッファされ、`response` を取り出せない場合は生の出力をそのまま渡します。
+    pub json_response: bool,
+    /// 各ターンの起動前に呼ばれるフック (認証の更新やクォータ確認など)
+    pub before_execute: Option<BeforeHook>,
+    /// 各ターンの終了後に成否を問わず呼ばれるフック
+    pub after_execute: Option<AfterHook>,
+    /// 再開する会話 (既定はツールごとに 1 つの会話)
+    pub conversation: ConversationId,
+    /// エージェントに与える操作の許可 (既定は `PermissionMode::Yolo`)
+    pub permission_mode: PermissionMode,
+    /// シードの応答に `MEMORY_READY` が無ければ `AgentError::MemoryNotLoaded` で失敗させます。
+    /// 無効 (既定) の場合は警告をログに出してセッションをそのまま使います
+    pub require_memory_ready: bool,
+    /// 対応するツール ([`AgentProvider::supports_stdin_prompt`]) ではプロンプトを引数ではなく
+    /// 標準入力で渡し、ARG_MAX を超える長いプロンプトでも起動できるようにします (既定は無効)。
+    /// 対応していないツールは従来どおり引数で渡します
+    pub prompt_via_stdin: bool,
+    /// 標準入力を閉じて EOF を伝えるまでの時間。`None` (既定) の場合は、プロンプトを
+    /// 標準入力で渡すときは書き込み後すぐに、それ以外は起動時から閉じたままにします。
+    /// 入力を待って止まるツールは、この時間が過ぎると EOF を受け取って先へ進みます
+    pub stdin_eof_delay: Option<Duration>,
+    /// シードターンが実行された場合、送信した初期化プロンプトを `RunResult::seed_prompt` に含めます (既定は無効)
+    pub include_seed_prompt: bool,
+    /// エージェントのプロセスを起動する方法 (`None` は `TokioRunner`)
+    pub runner: Option<Arc<dyn ProcessRunner>>,
+    /// プロンプト (またはシードの初期化プロンプト) の先頭に現在の日時の行を付けます (既定は無効)
+    pub timestamp: Option<PromptTimestamp>,
+    /// 起動する amem のバイナリ (`None` は PATH 上の `amem`)
+    pub amem_binary: Option<PathBuf>,
+    /// `amem --version` の応答を待つ時間 (`None` は [`DEFAULT_AMEM_PROBE_TIMEOUT`])。
+    /// 時間内に終わらない amem は使えないものとして扱います
+    pub amem_probe_timeout: Option<Duration>,
+    /// `RunResult::tool_version` の問い合わせ結果を保持するキャッシュ。`None` の場合は
+    /// 毎回 `--version` を実行します (`SessionManager` は自身のキャッシュを使います)
+    pub version_cache: Option<VersionCache>,
+}
 
-impl AgentExecutor {
-    pub async fn has_amem() -> bool {
-        Command::new("amem")
-            .arg("--version")
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()
-            .await
-            .map(|s| s.success())
-            .unwrap_or(false)
+impl ExecOptions {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    /// amem の記憶から Snapshot 文字列を取得します
-    pub async fn fetch_context() -> String {
-        let mut context = String::new();
-        if !Self::has_amem().await {
-            return context;
-        }
+    pub fn with_conversation(mut self, conversation: impl Into<ConversationId>) -> Self {
+        self.conversation = conversation.into();
+        self
+    }
 
-        let output = match Command::new("amem")
-            .arg("today")
-            .arg("--json")
-            .output()
-            .await
-        {
-            Ok(o) => o,
-            Err(_) => return context,
-        };
-        if !output.status.success() {
-            return context;
-        }
+    pub fn with_timestamp(mut self, timestamp: PromptTimestamp) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
 
-        let today: serde_json::Value = match serde_json::from_slice(&output.stdout) {
-            Ok(t) => t,
-            Err(_) => return context,
-        };
+    pub fn with_amem_binary(mut self, binary: impl Into<PathBuf>) -> Self {
+        self.amem_binary = Some(binary.into());
+        self
+    }
 
-        if let Some(profile) = today["owner_profile"].as_str() {
-            context.push_str("## Owner Profile\n");
-            context.push_str(profile);
-            context.push('\n');
-        }
-        if let Some(soul) = today["agent_soul"].as_str() {
-            context.push_str("\n## Agent Soul\n");
-            context.push_str(soul);
-            context.push('\n');
-        }
-        if let Some(activity) = today["activity"].as_str() {
-            context.push_str("\n## Recent Activities\n");
-            context.push_str(activity);
-            context.push('\n');
-        }
-        if let Some(memories) = today["agent_memories"].as_str() {
-            context.push_str("\n## Important Memories (P0)\n");
-            context.push_str(memories);
-            context.push('\n');
-        }
-        context
+    pub fn with_amem_probe_timeout(mut self, timeout: Duration) -> Self {
+        self.amem_probe_timeout = Some(timeout);
+        self
     }
 
-    /// amem の記憶から初期化用プロンプトを構築します
-    pub async fn build_init_prompt() -> String {
-        let context = Self::fetch_context().await;
-        if context.is_empty() {
-            return String::from(
-                "Load this amem snapshot for the next interactive session and reply exactly `MEMORY_READY`.\n\n(amem context is empty or unavailable)",
-            );
-        }
-        format!(
-            "Load this amem snapshot for the next interactive session and reply exactly `MEMORY_READY`.\n\n{}",
-            context
-        )
+    pub fn with_version_cache(mut self, cache: VersionCache) -> Self {
+        self.version_cache = Some(cache);
+        self
     }
 
-    pub async fn execute_stream<F>(
-        provider: AgentProvider,
-        prompt: &str,
-        mut on_chunk: F,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
-    where
-        F: FnMut(String) + Send + 'static,
-    {
-        if provider == AgentProvider::Dummy {
-            on_chunk(prompt.to_string());
-            return Ok(());
-        }
+    /// The runner for processes started outside `spawn`, defaulting to `TokioRunner`.
+    fn process_runner(&self) -> Arc<dyn ProcessRunner> {
+        self.runner.clone().unwrap_or_else(|| Arc::new(TokioRunner))
+    }
 
-        if provider == AgentProvider::Mock {
-            on_chunk("Mock stream: pong".into());
-            return Ok(());
-        }
+    fn amem_binary(&self) -> &Path {
+        self.amem_binary
+            .as_deref()
+            .unwrap_or_else(|| Path::new("amem"))
+    }
 
-        if provider == AgentProvider::Codex {
-            let output = Command::new(provider.command_name())
-                .arg("exec")
-                .arg("--json")
-                .arg(prompt)
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .output()
-                .await?;
+    pub fn with_runner(mut self, runner: impl ProcessRunner + 'static) -> Self {
+        self.runner = Some(Arc::new(runner));
+        self
+    }
 
-            if !output.status.success() {
-                let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
-                let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                let detail = if !stderr.is_empty() {
-                    stderr
-                } else if !stdout.is_empty() {
-                    format!("stdout: {}", stdout)
-                } else {
-                    "no stdout/stderr output".to_string()
-                };
-                return Err(format!("codex exec failed: {}", detail).into());
-            }
+    pub fn with_include_seed_prompt(mut self, include: bool) -> Self {
+        self.include_seed_prompt = include;
+        self
+    }
 
-            let out_str = String::from_utf8_lossy(&output.stdout);
-            if let Some(response) = SessionManager::extract_response(&out_str) {
-                on_chunk(response);
-                return Ok(());
-            }
+    pub fn with_stdin_eof_delay(mut self, delay: Duration) -> Self {
+        self.stdin_eof_delay = Some(delay);
+        self
+    }
 
-            return Err("Failed to extract response from codex exec JSON output.".into());
-        }
+    pub fn with_prompt_via_stdin(mut self, enabled: bool) -> Self {
+        self.prompt_via_stdin = enabled;
+        self
+    }
 
-        let mut child = Command::new(provider.command_name())
-            .arg(prompt)
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
+    pub fn with_require_memory_ready(mut self, require: bool) -> Self {
+        self.require_memory_ready = require;
+        self
+    }
 
-        let mut stdout = child.stdout.take().ok_or("Failed to open stdout")?;
-        let mut buffer = [0; 1024];
+    pub fn with_permission_mode(mut self, mode: PermissionMode) -> Self {
+        self.permission_mode = mode;
+        self
+    }
 
-        loop {
-            let n = stdout.read(&mut buffer).await?;
-            if n == 0 {
-                break;
-            }
-            let chunk = String::from_utf8_lossy(&buffer[..n]).to_string();
-            on_chunk(chunk);
-        }
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
 
-        let _ = child.wait().await?;
-        Ok(())
+    pub fn with_session_id_from_reply(mut self, pattern: Regex) -> Self {
+        self.session_id_from_reply = Some(pattern);
+        self
     }
 
-    pub async fn summarize_and_record(
-        provider: AgentProvider,
-        transcript: &str,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        if provider == AgentProvider::Mock || provider == AgentProvider::Dummy {
-            return Ok(());
-        }
-        if transcript.is_empty() || !Self::has_amem().await {
-            return Ok(());
-        }
-        let prompt = format!(
-            "対話内容をAgentの活動ログとして1行で要約せよ：\n{}",
-            transcript
-        );
-        let output = if provider == AgentProvider::Codex {
-            Command::new(provider.command_name())
-                .arg("exec")
-                .arg("--json")
-                .arg(&prompt)
-                .output()
-                .await?
-        } else {
-            Command::new(provider.command_name())
-                .arg(&prompt)
-                .output()
-                .await?
-        };
+    pub fn with_init_prompt(mut self, prepared: &PreparedInitPrompt) -> Self {
+        self.init_prompt = Some(prepared.prompt.clone());
+        self
+    }
 
-        let line = if provider == AgentProvider::Codex {
-            SessionManager::extract_response(&String::from_utf8_lossy(&output.stdout))
-                .unwrap_or_default()
-                .trim()
-                .to_string()
-        } else {
-            String::from_utf8_lossy(&output.stdout).trim().to_string()
-        };
-        if !line.is_empty() {
-            let _ = Command::new("amem")
-                .arg("keep")
-                .arg(line)
-                .arg("--kind")
-                .arg("activity")
-                .arg("--source")
-                .arg("yuiclaw")
-                .status()
-                .await;
-        }
-        Ok(())
+    pub fn with_limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::{Arc, Mutex as StdMutex};
+    pub fn with_json_response(mut self, enabled: bool) -> Self {
+        self.json_response = enabled;
+        self
+    }
 
-    // ─── AgentProvider tests ───────────────────────────────────────────────────────
+    pub fn with_strip_ansi(mut self, strip: bool) -> Self {
+        self.strip_ansi = strip;
+        self
+    }
 
-    #[test]
-    fn test_agent_provider_command_name_gemini() {
-        assert_eq!(AgentProvider::Gemini.command_name(), "gemini");
+    pub fn with_auto_continue(mut self, config: AutoContinue) -> Self {
+        self.auto_continue = Some(config);
+        self
     }
 
-    #[test]
-    fn test_agent_provider_command_name_claude() {
-        assert_eq!(AgentProvider::Claude.command_name(), "claude");
+    pub fn with_code_aware_summary(mut self, enabled: bool) -> Self {
+        self.code_aware_summary = enabled;
+        self
     }
 
-    #[test]
-    fn test_agent_provider_command_name_codex() {
-        assert_eq!(AgentProvider::Codex.command_name(), "codex");
+    pub fn with_cancel_token(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
     }
 
-    #[test]
-    fn test_agent_provider_command_name_opencode() {
-        assert_eq!(AgentProvider::OpenCode.command_name(), "opencode");
+    pub fn with_env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.envs.insert(key.into(), value.into());
+        self
     }
 
-    #[test]
-    fn test_agent_provider_command_name_mock() {
-        assert_eq!(AgentProvider::Mock.command_name(), "mock-agent");
+    pub fn with_cwd(mut self, cwd: impl Into<PathBuf>) -> Self {
+        self.cwd = Some(cwd.into());
+        self
     }
 
-    #[test]
-    fn test_agent_provider_command_name_dummy() {
-        assert_eq!(AgentProvider::Dummy.command_name(), "dummy-bot");
+    pub fn with_command_override(
+        mut self,
+        provider: AgentProvider,
+        binary: impl Into<PathBuf>,
+    ) -> Self {
+        self.command_overrides.insert(provider, binary.into());
+        self
     }
 
-    #[test]
-    fn test_agent_provider_equality() {
-        assert_eq!(AgentProvider::Gemini, AgentProvider::Gemini);
-        assert_eq!(AgentProvider::Claude, AgentProvider::Claude);
-        assert_eq!(AgentProvider::Codex, AgentProvider::Codex);
-        assert_eq!(AgentProvider::OpenCode, AgentProvider::OpenCode);
-        assert_eq!(AgentProvider::Dummy, AgentProvider::Dummy);
-        assert_eq!(AgentProvider::Mock, AgentProvider::Mock);
-        assert_ne!(AgentProvider::Gemini, AgentProvider::Claude);
-        assert_ne!(AgentProvider::Codex, AgentProvider::OpenCode);
-        assert_ne!(AgentProvider::Dummy, AgentProvider::Mock);
-        assert_ne!(AgentProvider::Mock, AgentProvider::Gemini);
+    pub fn with_prompt_escaping(
+        mut self,
+        provider: AgentProvider,
+        escaping: PromptEscaping,
+    ) -> Self {
+        self.prompt_escaping.insert(provider, escaping);
+        self
     }
 
-    #[test]
-    fn test_agent_provider_clone() {
-        let provider = AgentProvider::Gemini;
-        let cloned = provider.clone();
-        assert_eq!(provider, cloned);
+    pub fn with_error_classifier<F>(mut self, classifier: F) -> Self
+    where
+        F: Fn(&ExitInfo, &str) -> ErrorClass + Send + Sync + 'static,
+    {
+        self.error_classifier = Some(Arc::new(classifier));
+        self
     }
 
-    #[test]
-    fn test_agent_provider_can_be_used_as_hashmap_key() {
-        let mut map = HashMap::new();
-        map.insert(AgentProvider::Gemini, "session-abc");
-        map.insert(AgentProvider::Claude, "session-xyz");
-        assert_eq!(map.get(&AgentProvider::Gemini), Some(&"session-abc"));
-        assert_eq!(map.get(&AgentProvider::Claude), Some(&"session-xyz"));
-        assert_eq!(map.get(&AgentProvider::Codex), None);
+    pub fn before_execute<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(HookContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.before_execute = Some(Arc::new(move |context| Box::pin(hook(context))));
+        self
     }
 
-    #[test]
-    fn test_agent_provider_debug_format() {
-        assert_eq!(format!("{:?}", AgentProvider::Gemini), "Gemini");
-        assert_eq!(format!("{:?}", AgentProvider::Claude), "Claude");
-        assert_eq!(format!("{:?}", AgentProvider::Codex), "Codex");
-        assert_eq!(format!("{:?}", AgentProvider::OpenCode), "OpenCode");
-        assert_eq!(format!("{:?}", AgentProvider::Dummy), "Dummy");
-        assert_eq!(format!("{:?}", AgentProvider::Mock), "Mock");
+    pub fn after_execute<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(HookContext, HookOutcome) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.after_execute = Some(Arc::new(move |context, outcome| {
+            Box::pin(hook(context, outcome))
+        }));
+        self
     }
 
-    #[test]
-    fn test_model_args_for_provider_gemini() {
-        assert_eq!(
-            SessionManager::model_args_for_provider(
-                &AgentProvider::Gemini,
-                Some("gemini-2.5-flash-lite")
-            ),
-            vec!["--model".to_string(), "gemini-2.5-flash-lite".to_string()]
-        );
+    pub fn with_section_delimiter(mut self, delimiter: impl Into<String>) -> Self {
+        self.section_delimiter = Some(delimiter.into());
+        self
     }
 
-    #[test]
-    fn test_model_args_for_provider_claude() {
-        assert_eq!(
-            SessionManager::model_args_for_provider(
-                &AgentProvider::Claude,
-                Some("claude-sonnet-4-6")
-            ),
-            vec!["--model".to_string(), "claude-sonnet-4-6".to_string()]
-        );
+    pub fn with_thinking_budget(mut self, tokens: u32) -> Self {
+        self.thinking_budget = Some(tokens);
+        self
     }
 
-    #[test]
-    fn test_model_args_for_provider_codex() {
-        assert_eq!(
-            SessionManager::model_args_for_provider(&AgentProvider::Codex, Some("gpt-5.3-codex")),
-            vec!["--model".to_string(), "gpt-5.3-codex".to_string()]
-        );
+    pub fn with_context_delimiters(mut self, delimiters: ContextDelimiters) -> Self {
+        self.context_delimiters = delimiters;
+        self
     }
 
-    #[test]
-    fn test_model_args_for_provider_none_returns_empty() {
-        assert!(SessionManager::model_args_for_provider(&AgentProvider::Gemini, None).is_empty());
+    pub fn with_metrics(mut self, metrics: ExecMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
     }
 
-    #[test]
-    fn test_is_gemini_capacity_error_detects_no_capacity_message() {
-        let detail = "Attempt 1 failed with status 429. Retrying with backoff... GaxiosError: No capacity available for model gemini-2.5-flash-lite on the server";
-        assert!(SessionManager::is_gemini_capacity_error(detail));
+    pub fn with_read_strategy(mut self, strategy: ReadStrategy) -> Self {
+        self.read_strategy = strategy;
+        self
     }
 
-    #[test]
-    fn test_is_gemini_capacity_error_ignores_unrelated_errors() {
-        let detail = "Bridge is not running. Please start it with 'acomm --bridge'.";
-        assert!(!SessionManager::is_gemini_capacity_error(detail));
+    pub fn with_buffer_size(mut self, bytes: usize) -> Self {
+        self.buffer_size = Some(bytes);
+        self
+    }
+
+    pub fn with_normalization(mut self, normalization: OutputNormalization) -> Self {
+        self.normalize = Some(normalization);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn stop_when<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.stop_when = Some(Arc::new(predicate));
+        self
+    }
+
+    pub fn on_lifecycle<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(Lifecycle) + Send + Sync + 'static,
+    {
+        self.on_lifecycle = Some(Arc::new(callback));
+        self
+    }
+
+    /// Chains `observer` after any lifecycle callback already registered.
+    fn tap_lifecycle(mut self, observer: impl Fn(&Lifecycle) + Send + Sync + 'static) -> Self {
+        let inner = self.on_lifecycle.take();
+        self.on_lifecycle = Some(Arc::new(move |event| {
+            observer(&event);
+            if let Some(inner) = &inner {
+                inner(event);
+            }
+        }));
+        self
+    }
+
+    /// Creates a `Command` for the provider's binary, honouring
+    /// `command_overrides` and the provider's thinking budget setting.
+    fn command_for(&self, provider: &AgentProvider) -> Result<Command, AgentError> {
+        let mut command = Command::new(provider.resolve_binary(&self.command_overrides));
+        if let Some(budget) = self.thinking_budget {
+            match provider {
+                // Claude Code reads the extended thinking budget from the environment.
+                AgentProvider::Claude => {
+                    command.env("MAX_THINKING_TOKENS", budget.to_string());
+                }
+                AgentProvider::Dummy | AgentProvider::Mock => {}
+                _ => {
+                    return Err(AgentError::UnsupportedFeature {
+                        provider: provider.clone(),
+                        feature: "thinking_budget",
+                    });
+                }
+            }
+        }
+        Ok(command)
+    }
+
+    /// The turn prompt with the timestamp line prepended when configured.
+    fn stamped_prompt(&self, prompt: &str) -> String {
+        match &self.timestamp {
+            Some(timestamp) if timestamp.stamps_prompt() => timestamp.apply(prompt),
+            _ => prompt.to_string(),
+        }
+    }
+
+    /// Escapes a prompt for the provider's argv according to `prompt_escaping`.
+    fn prompt_arg(&self, provider: &AgentProvider, prompt: &str) -> String {
+        self.prompt_escaping
+            .get(provider)
+            .copied()
+            .unwrap_or_else(|| provider.prompt_escaping())
+            .apply(prompt)
+    }
+
+    /// The prompt to write to stdin instead of passing it as an argument.
+    fn stdin_prompt(&self, provider: &AgentProvider, prompt: &str) -> Option<String> {
+        self.prompt_on_stdin(provider)
+            .then(|| self.prompt_arg(provider, prompt))
+    }
+
+    fn prompt_on_stdin(&self, provider: &AgentProvider) -> bool {
+        self.prompt_via_stdin && provider.supports_stdin_prompt()
+    }
+
+    /// Appends the positional prompt, or the tool's stdin placeholder when the
+    /// prompt goes through stdin.
+    fn push_prompt(&self, command: &mut Command, provider: &AgentProvider, prompt_arg: &str) {
+        match (self.prompt_on_stdin(provider), provider) {
+            (true, AgentProvider::Codex) => {
+                command.arg("-");
+            }
+            (true, _) => {}
+            (false, _) => {
+                command.arg(prompt_arg);
+            }
+        }
+    }
+
+    /// Applies the per-process settings (resource limits, ...) to a command
+    /// before it is spawned.
+    fn configure(&self, command: &mut Command) {
+        self.limits.apply(command);
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+        command.envs(&self.envs);
+    }
+
+    /// Allocates the read buffer, rejecting a zero size before anything is spawned.
+    fn read_buffer(&self) -> Result<Vec<u8>, AgentError> {
+        match self.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE) {
+            0 => Err(AgentError::InvalidOption(
+                "buffer_size must be greater than zero".to_string(),
+            )),
+            size => Ok(vec![0; size]),
+        }
+    }
+
+    fn ansi_stripper(&self) -> Option<AnsiStripper> {
+        self.strip_ansi.then(AnsiStripper::default)
+    }
+
+    /// Cleans buffered (non-streamed) output before it is parsed.
+    fn clean_output(&self, raw: &[u8]) -> String {
+        let text = String::from_utf8_lossy(raw);
+        if self.strip_ansi {
+            strip_ansi(&text)
+        } else {
+            text.into_owned()
+        }
+    }
+
+    /// Copy of these options whose `timeout` is whatever remains until
+    /// `deadline`, so several child runs share one overall budget.
+    fn until(&self, deadline: Option<tokio::time::Instant>) -> Result<ExecOptions, AgentError> {
+        let Some(deadline) = deadline else {
+            return Ok(self.clone());
+        };
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        if remaining.is_zero() {
+            return Err(AgentError::Cancelled {
+                reason: CancelReason::Timeout,
+            });
+        }
+        Ok(ExecOptions {
+            timeout: Some(remaining),
+            ..self.clone()
+        })
+    }
+
+    /// Resolves once the run must stop early: the cancel token fired or
+    /// `deadline` passed. Never resolves when neither is configured.
+    async fn interrupted(&self, deadline: Option<tokio::time::Instant>) -> CancelReason {
+        let cancelled = async {
+            match &self.cancel {
+                Some(token) => token.cancelled().await,
+                None => std::future::pending().await,
+            }
+        };
+        let timed_out = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = cancelled => CancelReason::UserRequested,
+            _ = timed_out => CancelReason::Timeout,
+        }
+    }
+
+    /// Runs `turn` between the before/after hooks. A failing before hook
+    /// returns `HookFailed` without running the turn or the after hook.
+    async fn with_hooks<T>(
+        &self,
+        provider: &AgentProvider,
+        prompt: &str,
+        turn: impl Future<Output = Result<T, AgentError>>,
+    ) -> Result<T, AgentError> {
+        if self.before_execute.is_none() && self.after_execute.is_none() {
+            return turn.await;
+        }
+        let context = HookContext {
+            provider: provider.clone(),
+            prompt: prompt.to_string(),
+        };
+        if let Some(hook) = &self.before_execute {
+            hook(context.clone())
+                .await
+                .map_err(AgentError::HookFailed)?;
+        }
+        let started = Instant::now();
+        let result = turn.await;
+        if let Some(hook) = &self.after_execute {
+            let outcome = HookOutcome {
+                duration: started.elapsed(),
+                error: result.as_ref().err().map(ToString::to_string),
+            };
+            hook(context, outcome).await;
+        }
+        result
+    }
+
+    fn emit(&self, event: Lifecycle) {
+        if let Some(callback) = &self.on_lifecycle {
+            callback(event);
+        }
+    }
+}
+
+/// Checks a prompt before any hook runs or process spawns. Fails with
+/// `AgentError::PromptContainsNul` when it contains a NUL, which makes
+/// `Command::arg` fail with an opaque spawn error and is silently truncated by
+/// most CLIs reading stdin. Every execute, resume and seed entry point calls
+/// this first.
+fn validate_prompt(prompt: &str) -> Result<(), AgentError> {
+    match prompt.find('\0') {
+        Some(offset) => Err(AgentError::PromptContainsNul { offset }),
+        None => Ok(()),
+    }
+}
+
+/// 注入した記憶コンテキストの範囲を示す区切り
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextDelimiters {
+    pub open: String,
+    pub close: String,
+}
+
+impl Default for ContextDelimiters {
+    fn default() -> Self {
+        Self {
+            open: "<memory-context>".to_string(),
+            close: "</memory-context>".to_string(),
+        }
+    }
+}
+
+impl ContextDelimiters {
+    pub fn new(open: impl Into<String>, close: impl Into<String>) -> Self {
+        Self {
+            open: open.into(),
+            close: close.into(),
+        }
+    }
+
+    /// `context` を区切りで囲み、その後ろに `prompt` を続けます
+    pub fn wrap(&self, context: &str, prompt: &str) -> String {
+        format!(
+            "{}\n{}\n{}\n\n{}",
+            self.open,
+            context.trim_end(),
+            self.close,
+            prompt
+        )
+    }
+}
+
+/// `amem keep` に渡す記録の属性
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordOptions {
+    /// `--source` (既定は `yuiclaw`)
+    pub source: String,
+    /// `--kind` (既定は `activity`)
+    pub kind: String,
+    /// `--tag` として 1 つずつ渡すタグ
+    pub tags: Vec<String>,
+    /// amem が使えない場合に記録を省略せず `AgentError::Amem` を返します (既定は無効)
+    pub require_amem: bool,
+    /// 要約の指示。`{transcript}` の位置に対話内容を埋め込みます (なければ末尾に改行して続けます)。
+    /// `None` の場合は既定の日本語の指示を使います。
+    pub summary_template: Option<String>,
+    /// 設定すると、`execute_with_draft` は実行前にこの kind で作業中の記録を残し、
+    /// 終了後に `kind` で結果を記録します (既定は無効)
+    pub draft_kind: Option<String>,
+}
+
+impl Default for RecordOptions {
+    fn default() -> Self {
+        Self {
+            source: "yuiclaw".to_string(),
+            kind: "activity".to_string(),
+            tags: Vec::new(),
+            require_amem: false,
+            summary_template: None,
+            draft_kind: None,
+        }
+    }
+}
+
+impl RecordOptions {
+    pub fn with_source(mut self, source: impl Into<String>) -> Self {
+        self.source = source.into();
+        self
+    }
+
+    pub fn with_kind(mut self, kind: impl Into<String>) -> Self {
+        self.kind = kind.into();
+        self
+    }
+
+    pub fn with_tag(mut self, tag: impl Into<String>) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
+
+    pub fn with_require_amem(mut self, require: bool) -> Self {
+        self.require_amem = require;
+        self
+    }
+
+    pub fn with_summary_template(mut self, template: impl Into<String>) -> Self {
+        self.summary_template = Some(template.into());
+        self
+    }
+
+    pub fn with_draft_kind(mut self, kind: impl Into<String>) -> Self {
+        self.draft_kind = Some(kind.into());
+        self
+    }
+
+    /// 要約を依頼するプロンプト
+    pub fn summary_prompt(&self, transcript: &str, code_aware: bool) -> String {
+        match &self.summary_template {
+            Some(template) if template.contains("{transcript}") => {
+                template.replace("{transcript}", transcript)
+            }
+            Some(template) => format!("{}\n{}", template, transcript),
+            None => AgentExecutor::summary_prompt(transcript, code_aware),
+        }
+    }
+}
+
+/// 一度だけ amem から取得して使い回す初期化プロンプト
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreparedInitPrompt {
+    /// `fetch_context` が返した amem スナップショット
+    pub context: String,
+    pub prompt: String,
+    /// amem から取得できなかった場合の理由 (その場合 `context` は空です)
+    pub amem_error: Option<AmemError>,
+}
+
+/// `execute_until_done` の継続ループ設定
+#[derive(Debug, Clone)]
+pub struct ContinueLoop {
+    /// 2 回目以降のターンで送るプロンプト
+    pub continuation_prompt: String,
+    /// エージェントが完了を示すために出力するマーカー
+    pub done_marker: String,
+    /// 安全のための最大ターン数
+    pub max_iterations: usize,
+}
+
+impl Default for ContinueLoop {
+    fn default() -> Self {
+        Self {
+            continuation_prompt:
+                "Continue with the task. When it is fully complete, reply with `TASK_DONE`."
+                    .to_string(),
+            done_marker: "TASK_DONE".to_string(),
+            max_iterations: 10,
+        }
+    }
+}
+
+/// `max_tokens` で打ち切られた応答を自動で続けさせる設定
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutoContinue {
+    /// 続きを要求するときに送るプロンプト
+    pub continuation_prompt: String,
+    /// 続きを要求する最大回数
+    pub max_continuations: usize,
+}
+
+impl Default for AutoContinue {
+    fn default() -> Self {
+        Self {
+            continuation_prompt: "Continue exactly where you left off, without repeating anything."
+                .to_string(),
+            max_continuations: 3,
+        }
+    }
+}
+
+/// 一時的な失敗を指数バックオフで再試行する設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// 最初の試行を含む最大試行回数
+    pub max_attempts: u32,
+    /// 1 回目の再試行までの待ち時間。以後は再試行ごとに `backoff_factor` 倍になります
+    pub base_delay: Duration,
+    /// 再試行ごとに待ち時間に掛ける倍率 (既定は 2)
+    pub backoff_factor: u32,
+    /// 起動の失敗 (`AgentError::SpawnFailed`) も再試行します。バイナリが見つからない、
+    /// 実行権限が無いといった再試行しても直らない失敗は除きます (既定は無効)
+    pub retry_spawn_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            backoff_factor: 2,
+            retry_spawn_errors: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// `retry` 回目 (1 始まり) の再試行の前に待つ時間
+    pub fn delay_for(&self, retry: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(self.backoff_factor.saturating_pow(retry.saturating_sub(1)))
+    }
+
+    /// すべての試行が失敗した場合に、各再試行の前に待つ時間の列
+    pub fn delays(&self) -> Vec<Duration> {
+        (1..self.max_attempts)
+            .map(|retry| self.delay_for(retry))
+            .collect()
+    }
+
+    /// `err` で失敗した試行を再試行するかどうか。終了コード付きの失敗は `classifier`
+    /// (既定は `ErrorClass::classify_default`) で判定し、`SessionIdMissing` などは再試行しません
+    pub fn should_retry(&self, err: &AgentError, classifier: Option<&ErrorClassifier>) -> bool {
+        match err {
+            AgentError::SpawnFailed { source, .. } => {
+                self.retry_spawn_errors
+                    && !matches!(
+                        source.kind(),
+                        std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied
+                    )
+            }
+            err => err.classify(classifier).is_retryable(),
+        }
+    }
+}
+
+/// 生成が止まった理由
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StopReason {
+    EndTurn,
+    /// 出力トークンの上限で打ち切られた (続きを要求できます)
+    MaxTokens,
+    ToolUse,
+    StopSequence,
+    Other(String),
+}
+
+impl StopReason {
+    fn parse(raw: &str) -> Self {
+        match raw.to_ascii_lowercase().as_str() {
+            "end_turn" | "stop" | "completed" => StopReason::EndTurn,
+            "max_tokens" | "length" => StopReason::MaxTokens,
+            "tool_use" | "tool_calls" => StopReason::ToolUse,
+            "stop_sequence" => StopReason::StopSequence,
+            _ => StopReason::Other(raw.to_string()),
+        }
+    }
+}
+
+/// 1 ターンの実行結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunResult {
+    /// ストリームされた出力全体 (自動継続したターンを含みます)
+    pub output: String,
+    /// 各ターンの応答テキストを連結したもの (JSON の `response` があればそれを、なければ出力そのものを使います)
+    pub response: String,
+    /// 最後のターンの停止理由
+    pub stop_reason: Option<StopReason>,
+    /// `AutoContinue` によって追加で実行したターン数
+    pub continuations: usize,
+    /// 応答を生成した CLI のバージョン (`--version` の出力)。取得できなかった場合は `None`
+    pub tool_version: Option<String>,
+    /// 実際に使われたモデル。出力のメタデータを優先し、なければ `ExecOptions::model` を使います
+    pub model: Option<String>,
+    /// 最後に終了したプロセスの終了コード
+    pub exit_code: Option<i32>,
+    /// 起動から終了待ちまでに費やした時間 (シードや自動継続を含む全プロセスの合計)
+    pub duration: Duration,
+    /// `output` のバイト数
+    pub stdout_bytes: usize,
+    /// 実行後にこのツールが保持している session id
+    pub session_id: Option<String>,
+    /// この実行でシードした場合に送信した初期化プロンプト (`ExecOptions::include_seed_prompt` が有効なときのみ)
+    pub seed_prompt: Option<String>,
+}
+
+impl RunResult {
+    /// ログに残すためにトークンや API キーを伏せた初期化プロンプト
+    pub fn redacted_seed_prompt(&self) -> Option<String> {
+        self.seed_prompt.as_deref().map(redact_secrets)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopOutcome {
+    pub iterations: usize,
+    /// `false` when the loop stopped because `max_iterations` was reached.
+    pub completed: bool,
+    pub last_output: String,
+}
+
+/// `run_agent_loop` の結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentLoopOutcome {
+    pub iterations: usize,
+    /// ホスト側で処理したツール呼び出しの数
+    pub tool_calls: usize,
+    /// `false` when the loop stopped because `max_iterations` was reached.
+    pub completed: bool,
+    /// 最後のターンの応答テキスト
+    pub answer: String,
+}
+
+/// 同じツールで独立に保持する会話の識別子。既定値 (空文字列) は従来の単一セッションです
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ConversationId(String);
+
+impl ConversationId {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for ConversationId {
+    fn from(id: &str) -> Self {
+        Self::new(id)
+    }
+}
+
+impl From<String> for ConversationId {
+    fn from(id: String) -> Self {
+        Self(id)
+    }
+}
+
+impl fmt::Display for ConversationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Sessions are tracked per tool and conversation.
+type SessionKey = (AgentProvider, ConversationId);
+
+fn default_key(provider: &AgentProvider) -> SessionKey {
+    (provider.clone(), ConversationId::default())
+}
+
+/// Re-keys a per-tool map (the persisted format) under the default conversation.
+fn keyed<V>(map: HashMap<AgentProvider, V>) -> HashMap<SessionKey, V> {
+    keyed_in(&ConversationId::default(), map)
+}
+
+fn keyed_in<V>(
+    conversation: &ConversationId,
+    map: HashMap<AgentProvider, V>,
+) -> HashMap<SessionKey, V> {
+    map.into_iter()
+        .map(|(provider, value)| ((provider, conversation.clone()), value))
+        .collect()
+}
+
+/// Splits `map` into the default conversation's entries, keyed by tool, and
+/// the other conversations' entries, grouped by conversation.
+fn split_conversations<V: Clone>(
+    map: &HashMap<SessionKey, V>,
+) -> (
+    HashMap<AgentProvider, V>,
+    HashMap<ConversationId, HashMap<AgentProvider, V>>,
+) {
+    let mut default = HashMap::new();
+    let mut others: HashMap<ConversationId, HashMap<AgentProvider, V>> = HashMap::new();
+    for ((provider, conversation), value) in map {
+        let entries = if *conversation == ConversationId::default() {
+            &mut default
+        } else {
+            others.entry(conversation.clone()).or_default()
+        };
+        entries.insert(provider.clone(), value.clone());
+    }
+    (default, others)
+}
+
+/// シード時に記録したセッションと最初のプロンプト
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub provider: AgentProvider,
+    pub session_id: String,
+    pub first_prompt: String,
+}
+
+/// プロセスをまたいで引き継ぐ `SessionManager` の状態 (実行中の子プロセスは含みません)。
+/// `sessions` などは既定の会話の分で、それ以外の会話は `conversations` に入ります
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ManagerState {
+    pub sessions: HashMap<AgentProvider, String>,
+    pub turn_counts: HashMap<AgentProvider, usize>,
+    pub history: Vec<SessionRecord>,
+    #[serde(default)]
+    pub usage: HashMap<AgentProvider, UsageTotals>,
+    #[serde(default)]
+    pub conversations: HashMap<ConversationId, ConversationState>,
+}
+
+/// 既定以外の `ConversationId` の会話の状態 (ツールごと)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConversationState {
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub sessions: HashMap<AgentProvider, String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub turn_counts: HashMap<AgentProvider, usize>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub usage: HashMap<AgentProvider, UsageTotals>,
+}
+
+impl ConversationState {
+    /// Groups the per-conversation maps from `split_conversations`.
+    fn collect(
+        sessions: HashMap<ConversationId, HashMap<AgentProvider, String>>,
+        turn_counts: HashMap<ConversationId, HashMap<AgentProvider, usize>>,
+        usage: HashMap<ConversationId, HashMap<AgentProvider, UsageTotals>>,
+    ) -> HashMap<ConversationId, Self> {
+        let mut states: HashMap<ConversationId, Self> = HashMap::new();
+        for (conversation, sessions) in sessions {
+            states.entry(conversation).or_default().sessions = sessions;
+        }
+        for (conversation, turn_counts) in turn_counts {
+            states.entry(conversation).or_default().turn_counts = turn_counts;
+        }
+        for (conversation, usage) in usage {
+            states.entry(conversation).or_default().usage = usage;
+        }
+        states
+    }
+}
+
+/// Session ids, turn counts and usage totals keyed by tool and conversation.
+type KeyedMaps = (
+    HashMap<SessionKey, String>,
+    HashMap<SessionKey, usize>,
+    HashMap<SessionKey, UsageTotals>,
+);
+
+/// Re-keys the default conversation's per-tool maps and every entry of
+/// `conversations` under their full session keys.
+fn keyed_maps(
+    sessions: HashMap<AgentProvider, String>,
+    turn_counts: HashMap<AgentProvider, usize>,
+    usage: HashMap<AgentProvider, UsageTotals>,
+    conversations: HashMap<ConversationId, ConversationState>,
+) -> KeyedMaps {
+    let mut maps = (keyed(sessions), keyed(turn_counts), keyed(usage));
+    for (conversation, state) in conversations {
+        maps.0.extend(keyed_in(&conversation, state.sessions));
+        maps.1.extend(keyed_in(&conversation, state.turn_counts));
+        maps.2.extend(keyed_in(&conversation, state.usage));
+    }
+    maps
+}
+
+/// 1 回の実行で JSON 出力に報告されたトークン数と費用
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// ツールが報告した費用 (USD、Claude の `total_cost_usd` など)。報告しないツールでは `None`
+    pub cost_usd: Option<f64>,
+}
+
+/// 会話ごとのトークン数と費用の累計 (セッションを張り直しても引き継ぎます)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageTotals {
+    /// 使用量が報告された実行 (シードを含みます) の数
+    pub turns: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// 報告された費用の合計 (USD)。費用を報告しないツールの分は含みません
+    pub cost_usd: f64,
+}
+
+impl UsageTotals {
+    pub fn total_tokens(&self) -> u64 {
+        self.input_tokens + self.output_tokens
+    }
+
+    pub fn add(&mut self, usage: &TokenUsage) {
+        self.turns += 1;
+        self.input_tokens += usage.input_tokens;
+        self.output_tokens += usage.output_tokens;
+        self.cost_usd += usage.cost_usd.unwrap_or(0.0);
+    }
+}
+
+/// The session store file. Stores written before usage was tracked are a bare
+/// map of session ids.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SessionStore {
+    Full {
+        sessions: HashMap<AgentProvider, String>,
+        #[serde(default)]
+        usage: HashMap<AgentProvider, UsageTotals>,
+        /// Sessions and usage of the non-default conversations.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        conversations: HashMap<ConversationId, ConversationState>,
+    },
+    Legacy(HashMap<AgentProvider, String>),
+}
+
+/// A successful seed turn.
+struct SeedOutcome {
+    id: String,
+    model: Option<String>,
+    usage: Option<TokenUsage>,
+}
+
+/// `execute_batch` の結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchOutcome {
+    /// 各プロンプトの出力 (入力と同じ順序)
+    pub outputs: Vec<String>,
+    /// それ以前のプロンプトと重複していたプロンプトの位置。実行自体は省略しません。
+    pub duplicates: Vec<usize>,
+}
+
+#[derive(Clone)]
+pub struct SessionManager {
+    session_ids: Arc<Mutex<HashMap<SessionKey, String>>>,
+    history: Arc<Mutex<Vec<SessionRecord>>>,
+    turn_counts: Arc<Mutex<HashMap<SessionKey, usize>>>,
+    last_used: Arc<Mutex<HashMap<SessionKey, Instant>>>,
+    max_turns_per_session: Option<usize>,
+    session_ttl: Option<Duration>,
+    max_sessions: Option<usize>,
+    on_evict: Option<EvictCallback>,
+    persist_path: Option<PathBuf>,
+    max_reseed: usize,
+    /// Per-call settings applied when the caller's options leave them unset.
+    defaults: ExecOptions,
+    tool_versions: VersionCache,
+    /// One lock per conversation, held while that conversation's seed turn runs.
+    seed_locks: Arc<Mutex<HashMap<SessionKey, Arc<Mutex<()>>>>>,
+    /// Tools whose seed and resume turns go through a registered backend.
+    backends: HashMap<AgentProvider, Arc<dyn AgentBackend>>,
+    usage: Arc<Mutex<HashMap<SessionKey, UsageTotals>>>,
+}
+
+impl Default for SessionManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `SessionManager` の設定をまとめて組み立てるビルダー。
+/// 作業ディレクトリや環境変数などの実行設定は、呼び出しごとの `ExecOptions` で
+/// 指定されていない場合の既定値になります
+#[derive(Clone, Default)]
+pub struct SessionManagerBuilder {
+    manager: SessionManager,
+}
+
+impl SessionManagerBuilder {
+    /// 起動するエージェントの作業ディレクトリ
+    pub fn cwd(mut self, cwd: impl Into<PathBuf>) -> Self {
+        self.manager.defaults.cwd = Some(cwd.into());
+        self
+    }
+
+    /// 1 ターンの上限時間
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.manager.defaults.timeout = Some(timeout);
+        self
+    }
+
+    /// 起動するエージェントに追加する環境変数 (呼び出しごとの同名の変数が優先されます)
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.manager.defaults.envs.insert(key.into(), value.into());
+        self
+    }
+
+    /// ツールのバイナリの差し替え (呼び出しごとの登録が優先されます)
+    pub fn command_override(mut self, provider: AgentProvider, binary: impl Into<PathBuf>) -> Self {
+        self.manager = self.manager.with_command_override(provider, binary);
+        self
+    }
+
+    /// 標準出力を読み取るバッファの大きさ (バイト)
+    pub fn buffer_size(mut self, bytes: usize) -> Self {
+        self.manager.defaults.buffer_size = Some(bytes);
+        self
+    }
+
+    /// `amem --version` の応答を待つ時間 (呼び出しごとの指定が優先されます)
+    pub fn amem_probe_timeout(mut self, timeout: Duration) -> Self {
+        self.manager.defaults.amem_probe_timeout = Some(timeout);
+        self
+    }
+
+    /// エージェントのプロセスを起動する方法 (呼び出しごとの指定が優先されます)
+    pub fn runner(mut self, runner: impl ProcessRunner + 'static) -> Self {
+        self.manager.defaults.runner = Some(Arc::new(runner));
+        self
+    }
+
+    pub fn session_ttl(mut self, ttl: Duration) -> Self {
+        self.manager = self.manager.with_session_ttl(ttl);
+        self
+    }
+
+    pub fn max_sessions(mut self, max_sessions: usize) -> Self {
+        self.manager = self.manager.with_max_sessions(max_sessions);
+        self
+    }
+
+    pub fn max_turns_per_session(mut self, max_turns: usize) -> Self {
+        self.manager = self.manager.with_max_turns_per_session(max_turns);
+        self
+    }
+
+    pub fn max_reseed(mut self, max_reseed: usize) -> Self {
+        self.manager = self.manager.with_max_reseed(max_reseed);
+        self
+    }
+
+    pub fn backend(
+        mut self,
+        provider: AgentProvider,
+        backend: impl AgentBackend + 'static,
+    ) -> Self {
+        self.manager = self.manager.with_backend(provider, backend);
+        self
+    }
+
+    pub fn on_evict<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(AgentProvider, String) + Send + Sync + 'static,
+    {
+        self.manager = self.manager.on_evict(callback);
+        self
+    }
+
+    pub fn build(self) -> SessionManager {
+        self.manager
+    }
+}
+
+impl SessionManager {
+    pub fn new() -> Self {
+        Self {
+            session_ids: Arc::new(Mutex::new(HashMap::new())),
+            history: Arc::new(Mutex::new(Vec::new())),
+            turn_counts: Arc::new(Mutex::new(HashMap::new())),
+            last_used: Arc::new(Mutex::new(HashMap::new())),
+            max_turns_per_session: None,
+            session_ttl: None,
+            max_sessions: None,
+            on_evict: None,
+            persist_path: None,
+            max_reseed: DEFAULT_MAX_RESEED,
+            defaults: ExecOptions::default(),
+            tool_versions: VersionCache::new(),
+            seed_locks: Arc::new(Mutex::new(HashMap::new())),
+            backends: HashMap::new(),
+            usage: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// `provider` のシードと再開を CLI ではなく `backend` で実行します
+    pub fn with_backend(
+        mut self,
+        provider: AgentProvider,
+        backend: impl AgentBackend + 'static,
+    ) -> Self {
+        self.backends.insert(provider, Arc::new(backend));
+        self
+    }
+
+    /// `path` から session id を読み込み、以後は新しいセッションをシードするたびに同じファイルへ保存します
+    pub async fn with_persistence(path: impl Into<PathBuf>) -> Result<Self, AgentError> {
+        let path = path.into();
+        let mut manager = Self::load_from_path(&path).await?;
+        manager.persist_path = Some(path);
+        Ok(manager)
+    }
+
+    /// 最後に使われてから `ttl` を超えたセッションを破棄し、次の呼び出しで新しくシードします
+    pub fn with_session_ttl(mut self, ttl: Duration) -> Self {
+        self.session_ttl = Some(ttl);
+        self
+    }
+
+    /// 保持するセッション数の上限を設定します。超えると最も長く使われていないセッションを破棄し、
+    /// そのツールは次の呼び出しで新しくシードします
+    pub fn with_max_sessions(mut self, max_sessions: usize) -> Self {
+        self.max_sessions = Some(max_sessions);
+        self
+    }
+
+    /// このマネージャーが起動するツールのバイナリを差し替えます。
+    /// 呼び出しごとの `ExecOptions::command_overrides` に同じツールの登録があればそちらを優先します
+    pub fn with_command_override(
+        mut self,
+        provider: AgentProvider,
+        binary: impl Into<PathBuf>,
+    ) -> Self {
+        self.defaults
+            .command_overrides
+            .insert(provider, binary.into());
+        self
+    }
+
+    /// 設定をまとめて指定するためのビルダー
+    pub fn builder() -> SessionManagerBuilder {
+        SessionManagerBuilder::default()
+    }
+
+    /// Merges the manager-level defaults under the per-call options.
+    fn with_manager_defaults(&self, options: &ExecOptions) -> ExecOptions {
+        let mut merged = options.clone();
+        let defaults = &self.defaults;
+        for (provider, binary) in &defaults.command_overrides {
+            merged
+                .command_overrides
+                .entry(provider.clone())
+                .or_insert_with(|| binary.clone());
+        }
+        for (key, value) in &defaults.envs {
+            merged
+                .envs
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+        merged.cwd = merged.cwd.or_else(|| defaults.cwd.clone());
+        merged.timeout = merged.timeout.or(defaults.timeout);
+        merged.buffer_size = merged.buffer_size.or(defaults.buffer_size);
+        merged.runner = merged.runner.or_else(|| defaults.runner.clone());
+        merged.amem_binary = merged.amem_binary.or_else(|| defaults.amem_binary.clone());
+        merged.amem_probe_timeout = merged.amem_probe_timeout.or(defaults.amem_probe_timeout);
+        merged.version_cache = merged
+            .version_cache
+            .or_else(|| defaults.version_cache.clone())
+            .or_else(|| Some(self.tool_versions.clone()));
+        merged
+    }
+
+    /// 再開しようとしたセッションが CLI 側で失効していたときに、シードし直して再試行する回数の上限 (既定は 1、0 で無効)
+    pub fn with_max_reseed(mut self, max_reseed: usize) -> Self {
+        self.max_reseed = max_reseed;
+        self
+    }
+
+    /// セッションが破棄されたときに呼ばれるコールバックを登録します
+    pub fn on_evict<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(AgentProvider, String) + Send + Sync + 'static,
+    {
+        self.on_evict = Some(Arc::new(callback));
+        self
+    }
+
+    /// 1 セッションで再開できるターン数の上限を設定します。超過すると次の呼び出しで新しいセッションをシードします
+    pub fn with_max_turns_per_session(mut self, max_turns: usize) -> Self {
+        self.max_turns_per_session = Some(max_turns);
+        self
+    }
+
+    /// JSON ファイルから session id を読み込みます。ファイルが存在しない場合は空のマネージャーを返します
+    pub async fn load_from_path(path: &Path) -> Result<Self, AgentError> {
+        let bytes = match tokio::fs::read(path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let store =
+            serde_json::from_slice(&bytes).map_err(|source| AgentError::MalformedSessionStore {
+                path: path.to_path_buf(),
+                source,
+            })?;
+        let (sessions, usage, conversations) = match store {
+            SessionStore::Full {
+                sessions,
+                usage,
+                conversations,
+            } => (sessions, usage, conversations),
+            SessionStore::Legacy(sessions) => (sessions, HashMap::new(), HashMap::new()),
+        };
+        let (session_ids, _, usage) = keyed_maps(sessions, HashMap::new(), usage, conversations);
+        Ok(Self {
+            session_ids: Arc::new(Mutex::new(session_ids)),
+            usage: Arc::new(Mutex::new(usage)),
+            ..Self::new()
+        })
+    }
+
+    /// 各会話の session id と使用量の累計を JSON ファイルに保存します。一時ファイルに書いてから rename するため、
+    /// 書き込み途中でプロセスが落ちても既存のファイルは壊れません
+    pub async fn save_to_path(&self, path: &Path) -> Result<(), AgentError> {
+        let json = {
+            let (sessions, other_sessions) = split_conversations(&*self.session_ids.lock().await);
+            let (usage, other_usage) = split_conversations(&*self.usage.lock().await);
+            let store = SessionStore::Full {
+                sessions,
+                usage,
+                conversations: ConversationState::collect(
+                    other_sessions,
+                    HashMap::new(),
+                    other_usage,
+                ),
+            };
+            serde_json::to_vec_pretty(&store).map_err(std::io::Error::other)?
+        };
+        // Turns persist concurrently, so every save writes its own temp file.
+        static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(format!(".{}-{}.tmp", std::process::id(), n));
+        let tmp_path = path.with_file_name(tmp_name);
+        tokio::fs::write(&tmp_path, json).await?;
+        if let Err(e) = tokio::fs::rename(&tmp_path, path).await {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    async fn persist(&self) -> Result<(), AgentError> {
+        match &self.persist_path {
+            Some(path) => self.save_to_path(path).await,
+            None => Ok(()),
+        }
+    }
+
+    /// セッション、ターン数、履歴をまとめてスナップショットします
+    pub async fn export_state(&self) -> ManagerState {
+        let (sessions, other_sessions) = split_conversations(&*self.session_ids.lock().await);
+        let (turn_counts, other_turn_counts) = split_conversations(&*self.turn_counts.lock().await);
+        let (usage, other_usage) = split_conversations(&*self.usage.lock().await);
+        ManagerState {
+            sessions,
+            turn_counts,
+            history: self.history.lock().await.clone(),
+            usage,
+            conversations: ConversationState::collect(
+                other_sessions,
+                other_turn_counts,
+                other_usage,
+            ),
+        }
+    }
+
+    /// `export_state` で取得した状態で現在の状態を置き換えます
+    pub async fn import_state(&self, state: ManagerState) {
+        let (session_ids, turn_counts, usage) = keyed_maps(
+            state.sessions,
+            state.turn_counts,
+            state.usage,
+            state.conversations,
+        );
+        *self.turn_counts.lock().await = turn_counts;
+        // Idle timers restart in the new process.
+        self.last_used.lock().await.clear();
+        *self.session_ids.lock().await = session_ids;
+        *self.history.lock().await = state.history;
+        *self.usage.lock().await = usage;
+    }
+
+    /// 指定したツールと会話で報告されたトークン数と費用の累計を返します。
+    /// 使用量は JSON 出力 (シード、`ExecOptions::with_json_response`、Codex) から読み取ります
+    pub async fn usage_for(
+        &self,
+        provider: &AgentProvider,
+        conversation: &ConversationId,
+    ) -> UsageTotals {
+        let key = (provider.clone(), conversation.clone());
+        self.usage
+            .lock()
+            .await
+            .get(&key)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Adds a run's reported usage to the conversation's totals and persists
+    /// them whenever they changed.
+    async fn record_usage(
+        &self,
+        key: &SessionKey,
+        usage: Option<TokenUsage>,
+    ) -> Result<(), AgentError> {
+        if self.add_usage(key, usage).await {
+            self.persist().await?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the totals changed (a run that reported no usage
+    /// leaves them as they were).
+    async fn add_usage(&self, key: &SessionKey, usage: Option<TokenUsage>) -> bool {
+        let Some(usage) = usage else {
+            return false;
+        };
+        self.usage
+            .lock()
+            .await
+            .entry(key.clone())
+            .or_default()
+            .add(&usage);
+        true
+    }
+
+    /// 指定したツールの既定の会話で確立済みの session id を返します
+    pub async fn session_id(&self, provider: &AgentProvider) -> Option<String> {
+        self.conversation_session_id(provider, &ConversationId::default())
+            .await
+    }
+
+    /// 指定したツールと会話で確立済みの session id を返します
+    pub async fn conversation_session_id(
+        &self,
+        provider: &AgentProvider,
+        conversation: &ConversationId,
+    ) -> Option<String> {
+        let key = (provider.clone(), conversation.clone());
+        self.session_ids.lock().await.get(&key).cloned()
+    }
+
+    /// 以前の実行で保存した session id を既定の会話に復元し、次回のシードターンを省略します
+    pub async fn set_session_id(&self, provider: AgentProvider, id: String) {
+        self.set_session(default_key(&provider), id).await;
+    }
+
+    async fn set_session(&self, key: SessionKey, id: String) {
+        let evicted = {
+            let mut turn_counts = self.turn_counts.lock().await;
+            let mut session_ids = self.session_ids.lock().await;
+            let mut last_used = self.last_used.lock().await;
+            turn_counts.remove(&key);
+            last_used.insert(key.clone(), Instant::now());
+            session_ids.insert(key.clone(), id);
+            let mut evicted = Vec::new();
+            while self.max_sessions.is_some_and(|max| session_ids.len() > max) {
+                // Sessions restored without a timestamp count as the oldest.
+                let Some(lru) = session_ids
+                    .keys()
+                    .filter(|candidate| **candidate != key)
+                    .min_by_key(|candidate| last_used.get(*candidate))
+                    .cloned()
+                else {
+                    break;
+                };
+                turn_counts.remove(&lru);
+                last_used.remove(&lru);
+                if let Some(id) = session_ids.remove(&lru) {
+                    evicted.push((lru, id));
+                }
+            }
+            evicted
+        };
+        for ((provider, _), id) in evicted {
+            self.notify_evicted(&provider, id);
+        }
+    }
+
+    /// 指定したツールの既定の会話のセッションを破棄します。次の呼び出しは新しいセッションをシードします
+    pub async fn clear_session(&self, provider: &AgentProvider) {
+        self.clear_conversation(provider, &ConversationId::default())
+            .await;
+    }
+
+    /// 指定したツールと会話のセッションを破棄します
+    pub async fn clear_conversation(
+        &self,
+        provider: &AgentProvider,
+        conversation: &ConversationId,
+    ) {
+        let key = (provider.clone(), conversation.clone());
+        let removed = {
+            let mut turn_counts = self.turn_counts.lock().await;
+            let mut session_ids = self.session_ids.lock().await;
+            turn_counts.remove(&key);
+            self.last_used.lock().await.remove(&key);
+            session_ids.remove(&key)
+        };
+        if let Some(id) = removed {
+            self.notify_evicted(provider, id);
+        }
+    }
+
+    /// すべてのツールのセッションを破棄します
+    pub async fn clear_all(&self) {
+        let removed: Vec<(SessionKey, String)> = {
+            let mut turn_counts = self.turn_counts.lock().await;
+            let mut session_ids = self.session_ids.lock().await;
+            turn_counts.clear();
+            self.last_used.lock().await.clear();
+            session_ids.drain().collect()
+        };
+        for ((provider, _), id) in removed {
+            self.notify_evicted(&provider, id);
+        }
+    }
+
+    /// 現在セッションを保持しているツールの一覧 (コマンド名順)
+    pub async fn active_tools(&self) -> Vec<AgentProvider> {
+        let mut tools: Vec<AgentProvider> = self
+            .session_ids
+            .lock()
+            .await
+            .keys()
+            .map(|(provider, _)| provider.clone())
+            .collect();
+        tools.sort_by_key(|provider| provider.command_name());
+        tools.dedup();
+        tools
+    }
+
+    /// Forgets `id` if it is still the conversation's current session, so the
+    /// next turn seeds a fresh one. A concurrent re-seed is left untouched.
+    async fn drop_stale_session(&self, key: &SessionKey, id: &str) {
+        let removed = {
+            let mut turn_counts = self.turn_counts.lock().await;
+            let mut session_ids = self.session_ids.lock().await;
+            if session_ids.get(key).map(String::as_str) != Some(id) {
+                return;
+            }
+            turn_counts.remove(key);
+            self.last_used.lock().await.remove(key);
+            session_ids.remove(key)
+        };
+        if let Some(id) = removed {
+            self.notify_evicted(&key.0, id);
+        }
+    }
+
+    /// Known "session not found / expired" messages printed by each CLI when
+    /// `--resume <id>` refers to a session it no longer has.
+    fn session_expired(provider: &AgentProvider, stderr: &str) -> bool {
+        let patterns: &[&str] = match provider {
+            AgentProvider::Claude => &["no conversation found with session id"],
+            AgentProvider::Gemini => &[
+                "invalid session identifier",
+                "no previous sessions found",
+                "session not found",
+            ],
+            AgentProvider::Codex => &["no rollout found", "session not found"],
+            AgentProvider::Cursor => &["session not found", "chat not found"],
+            _ => &[],
+        };
+        let stderr = stderr.to_ascii_lowercase();
+        patterns.iter().any(|pattern| stderr.contains(pattern))
+    }
+
+    fn notify_evicted(&self, provider: &AgentProvider, id: String) {
+        if let Some(callback) = &self.on_evict {
+            callback(provider.clone(), id);
+        }
+    }
+
+    /// セッションを最初のプロンプトと共に記録し、そのツールの既定の会話の現在のセッションにします
+    pub async fn record_session(&self, provider: AgentProvider, id: String, first_prompt: &str) {
+        self.record_conversation(default_key(&provider), id, first_prompt)
+            .await;
+    }
+
+    async fn record_conversation(&self, key: SessionKey, id: String, first_prompt: &str) {
+        self.history.lock().await.push(SessionRecord {
+            provider: key.0.clone(),
+            session_id: id.clone(),
+            first_prompt: first_prompt.to_string(),
+        });
+        self.set_session(key, id).await;
+    }
+
+    /// 最初のプロンプトに `query` を含む (大文字小文字を区別しない) 過去のセッションを新しい順に返します
+    pub async fn find_session_by_prompt(&self, query: &str) -> Vec<SessionRecord> {
+        let query = query.to_lowercase();
+        self.history
+            .lock()
+            .await
+            .iter()
+            .rev()
+            .filter(|record| record.first_prompt.to_lowercase().contains(&query))
+            .cloned()
+            .collect()
+    }
+
+    async fn seed_lock(&self, key: &SessionKey) -> Arc<Mutex<()>> {
+        Arc::clone(self.seed_locks.lock().await.entry(key.clone()).or_default())
+    }
+
+    /// Returns the session to resume for this turn and counts the turn against
+    /// it, or `None` once the session has expired or used up
+    /// `max_turns_per_session` and the caller should seed a fresh one.
+    async fn claim_turn(&self, key: &SessionKey) -> Option<String> {
+        let evicted = {
+            let mut turn_counts = self.turn_counts.lock().await;
+            let mut session_ids = self.session_ids.lock().await;
+            let mut last_used = self.last_used.lock().await;
+            let id = session_ids.get(key).cloned()?;
+            let turns = turn_counts.entry(key.clone()).or_insert(0);
+            let expired = self
+                .session_ttl
+                .is_some_and(|ttl| last_used.get(key).is_some_and(|used| used.elapsed() > ttl));
+            if !expired && self.max_turns_per_session.is_none_or(|max| *turns < max) {
+                *turns += 1;
+                last_used.insert(key.clone(), Instant::now());
+                return Some(id);
+            }
+            session_ids.remove(key);
+            turn_counts.remove(key);
+            last_used.remove(key);
+            id
+        };
+        self.notify_evicted(&key.0, evicted);
+        None
+    }
+
+    fn model_args_for_provider(provider: &AgentProvider, model: Option<&str>) -> Vec<String> {
+        let Some(model) = model.map(str::trim).filter(|m| !m.is_empty()) else {
+            return Vec::new();
+        };
+        match provider.model_arg() {
+            Some(flag) => vec![flag.to_string(), model.to_string()],
+            None => Vec::new(),
+        }
+    }
+
+    fn apply_model_args(command: &mut Command, provider: &AgentProvider, model: Option<&str>) {
+        for arg in Self::model_args_for_provider(provider, model) {
+            command.arg(arg);
+        }
+    }
+
+    fn is_gemini_capacity_error(detail: &str) -> bool {
+        let lower = detail.to_ascii_lowercase();
+        lower.contains("no capacity available for model")
+            || (lower.contains("status 429") && lower.contains("gemini"))
+            || (lower.contains("attempt 1 failed with status 429") && lower.contains("gemini"))
+    }
+
+    fn gemini_capacity_fallback_models(model: Option<&str>) -> &'static [&'static str] {
+        match model.map(str::trim).filter(|m| !m.is_empty()) {
+            None | Some("auto-gemini-3") => &["gemini-2.5-flash", "gemini-2.5-pro"],
+            Some("gemini-2.5-flash") => &["gemini-2.5-pro"],
+            _ => &[],
+        }
+    }
+
+    fn gemini_should_retry_with_fallback(
+        provider: &AgentProvider,
+        requested_model: Option<&str>,
+        detail: &str,
+    ) -> bool {
+        *provider == AgentProvider::Gemini
+            && Self::is_gemini_capacity_error(detail)
+            && !Self::gemini_capacity_fallback_models(requested_model).is_empty()
+    }
+
+    fn find_in_json_output<T, F>(output: &str, mut pick: F) -> Option<T>
+    where
+        F: FnMut(&serde_json::Value) -> Option<T>,
+    {
+        if let Ok(v) = serde_json::from_str::<serde_json::Value>(output)
+            && let Some(found) = pick(&v)
+        {
+            return Some(found);
+        }
+
+        for (idx, ch) in output.char_indices() {
+            if ch != '{' {
+                continue;
+            }
+            let mut de = serde_json::Deserializer::from_str(&output[idx..]);
+            if let Ok(v) = serde_json::Value::deserialize(&mut de)
+                && let Some(found) = pick(&v)
+            {
+                return Some(found);
+            }
+        }
+
+        None
+    }
+
+    pub fn extract_session_id(output: &str) -> Option<String> {
+        Self::find_in_json_output(output, |v| {
+            if let Some(id) = v.get("session_id").and_then(|v| v.as_str()) {
+                return Some(id.to_string());
+            }
+            if let Some(id) = v.get("sessionId").and_then(|v| v.as_str()) {
+                return Some(id.to_string());
+            }
+            if let Some(id) = v.get("thread_id").and_then(|v| v.as_str()) {
+                return Some(id.to_string());
+            }
+            if let Some(id) = v.get("threadId").and_then(|v| v.as_str()) {
+                return Some(id.to_string());
+            }
+            None
+        })
+    }
+
+    /// Fallback for tools that do not print a session id in JSON: scan the
+    /// seed reply text (or the raw output if no JSON response exists).
+    pub fn extract_session_id_from_reply(output: &str, pattern: &Regex) -> Option<String> {
+        let reply = Self::extract_response(output).unwrap_or_else(|| output.to_string());
+        let caps = pattern.captures(&reply)?;
+        caps.get(1)
+            .or_else(|| caps.get(0))
+            .map(|m| m.as_str().to_string())
+            .filter(|id| !id.is_empty())
+    }
+
+    /// Whether the seed reply carries the `MEMORY_READY` acknowledgement asked
+    /// for by the init prompt.
+    fn memory_acknowledged(output: &str) -> bool {
+        Self::extract_response(output)
+            .as_deref()
+            .unwrap_or(output)
+            .contains(MEMORY_READY)
+    }
+
+    fn extract_seed_session_id(output: &str, options: &ExecOptions) -> Option<String> {
+        Self::extract_session_id(output).or_else(|| {
+            options
+                .session_id_from_reply
+                .as_ref()
+                .and_then(|pattern| Self::extract_session_id_from_reply(output, pattern))
+        })
+    }
+
+    /// Reads the stop reason from JSON output (`stop_reason` for Claude,
+    /// `finishReason` / `finish_reason` for Gemini and OpenAI-style tools).
+    pub fn extract_stop_reason(output: &str) -> Option<StopReason> {
+        Self::find_in_json_output(output, |v| {
+            ["stop_reason", "finishReason", "finish_reason"]
+                .iter()
+                .find_map(|key| v.get(*key).and_then(|r| r.as_str()))
+                .map(StopReason::parse)
+        })
+    }
+
+    /// Reads the model name from JSON output: a top-level `model`, or the
+    /// first key of Claude's `modelUsage` / Gemini's `stats.models`.
+    pub fn extract_model(output: &str) -> Option<String> {
+        Self::find_in_json_output(output, |v| {
+            if let Some(model) = v.get("model").and_then(|m| m.as_str()) {
+                return Some(model.to_string());
+            }
+            [
+                v.get("modelUsage"),
+                v.get("stats").and_then(|s| s.get("models")),
+            ]
+            .into_iter()
+            .flatten()
+            .find_map(|usage| usage.as_object()?.keys().next().cloned())
+        })
+    }
+
+    /// Sums the token usage reported in JSON output: `usage.input_tokens` +
+    /// `usage.output_tokens` (Claude, Codex) or `stats.models.*.tokens.total` (Gemini).
+    fn extract_token_total(output: &str) -> Option<u64> {
+        Self::find_in_json_output(output, |v| {
+            if let Some(usage) = v.get("usage").filter(|u| u.is_object()) {
+                let count = |key: &str| usage.get(key).and_then(|n| n.as_u64());
+                return match (count("input_tokens"), count("output_tokens")) {
+                    (None, None) => None,
+                    (input, output) => Some(input.unwrap_or(0) + output.unwrap_or(0)),
+                };
+            }
+            let models = v.get("stats")?.get("models")?.as_object()?;
+            Some(
+                models
+                    .values()
+                    .filter_map(|m| m.get("tokens")?.get("total")?.as_u64())
+                    .sum(),
+            )
+        })
+    }
+
+    /// JSON 出力に報告されたトークン数と費用を取り出します: `usage.input_tokens` / `usage.output_tokens`
+    /// (Claude, Codex) または `stats.models.*.tokens.prompt` / `candidates` (Gemini)、費用は `total_cost_usd`
+    pub fn extract_usage(output: &str) -> Option<TokenUsage> {
+        Self::find_in_json_output(output, |v| {
+            let cost_usd = v.get("total_cost_usd").and_then(|c| c.as_f64());
+            if let Some(usage) = v.get("usage").filter(|u| u.is_object()) {
+                let count = |key: &str| usage.get(key).and_then(|n| n.as_u64());
+                let (input, output) = (count("input_tokens"), count("output_tokens"));
+                if input.is_none() && output.is_none() {
+                    return None;
+                }
+                return Some(TokenUsage {
+                    input_tokens: input.unwrap_or(0),
+                    output_tokens: output.unwrap_or(0),
+                    cost_usd,
+                });
+            }
+            let models = v.get("stats")?.get("models")?.as_object()?;
+            let count = |key: &str| {
+                models
+                    .values()
+                    .filter_map(|m| m.get("tokens")?.get(key)?.as_u64())
+                    .sum()
+            };
+            Some(TokenUsage {
+                input_tokens: count("prompt"),
+                output_tokens: count("candidates"),
+                cost_usd,
+            })
+        })
+    }
+
+    /// CLI のバージョン文字列を返します。解決後のバイナリごとに初回だけ `--version` を実行してキャッシュします
+    pub async fn tool_version(
+        &self,
+        provider: &AgentProvider,
+        options: &ExecOptions,
+    ) -> Option<String> {
+        AgentExecutor::cached_version(provider, &self.with_manager_defaults(options)).await
+    }
+
+    pub fn extract_response(output: &str) -> Option<String> {
+        Self::find_in_json_output(output, |v| {
+            if let Some(res) = v.get("response").and_then(|v| v.as_str()) {
+                return Some(res.to_string());
+            }
+            if let Some(item) = v.get("item") {
+                let is_agent_message =
+                    item.get("type").and_then(|t| t.as_str()) == Some("agent_message");
+                if is_agent_message && let Some(text) = item.get("text").and_then(|t| t.as_str()) {
+                    return Some(text.to_string());
+                }
+            }
+            None
+        })
+    }
+
+    /// Runs the seed turn (with Gemini capacity fallbacks) and returns the
+    /// captured session id together with the model that produced it.
+    async fn run_seed(
+        &self,
+        provider: &AgentProvider,
+        options: &ExecOptions,
+    ) -> Result<SeedOutcome, AgentError> {
+        tracing::info!(tool = provider.command_name(), "seed turn started");
+        let mut init_prompt = match &options.init_prompt {
+            Some(prompt) => prompt.clone(),
+            None => AgentExecutor::build_init_prompt_with_options(options).await,
+        };
+        if let Some(timestamp) = options.timestamp.as_ref().filter(|t| t.stamps_seed()) {
+            init_prompt = timestamp.apply(&init_prompt);
+        }
+        validate_prompt(&init_prompt)?;
+        tracing::trace!(prompt = %init_prompt, "seed prompt");
+        let seeded = match self.backends.get(provider) {
+            Some(backend) => {
+                let seeded = backend.seed(&init_prompt, options).await;
+                if seeded.is_ok() {
+                    options.emit(Lifecycle::Seeded {
+                        prompt: init_prompt.clone(),
+                    });
+                }
+                seeded.map(|id| SeedOutcome {
+                    id,
+                    model: options.model.clone(),
+                    usage: None,
+                })
+            }
+            None => Self::seed_with_fallbacks(provider, &init_prompt, options).await,
+        };
+        match &seeded {
+            Ok(seeded) => tracing::info!(
+                tool = provider.command_name(),
+                session_id = %seeded.id,
+                model = ?seeded.model,
+                "seed turn finished"
+            ),
+            Err(e) => {
+                tracing::warn!(tool = provider.command_name(), error = %e, "seed turn failed")
+            }
+        }
+        seeded
+    }
+
+    async fn seed_with_fallbacks(
+        provider: &AgentProvider,
+        init_prompt: &str,
+        options: &ExecOptions,
+    ) -> Result<SeedOutcome, AgentError> {
+        let requested_model = options.model.as_deref();
+        let mut seed_models: Vec<Option<String>> = vec![options.model.clone()];
+        if *provider == AgentProvider::Gemini {
+            for fallback in Self::gemini_capacity_fallback_models(requested_model) {
+                seed_models.push(Some((*fallback).to_string()));
+            }
+        }
+
+        let seed_arg = options.prompt_arg(provider, init_prompt);
+        let mut last_seed_error: Option<AgentError> = None;
+        for candidate_model in seed_models {
+            let mut seed_cmd = options.command_for(provider)?;
+            // stdin must be null so CLI tools (especially claude) do not try to
+            // call setRawMode on an inherited non-TTY stdin (which causes EIO when
+            // running as a background service / Discord adapter).
+            seed_cmd
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+            options.configure(&mut seed_cmd);
+
+            let permission_args = options.permission_mode.args(provider);
+            match provider {
+                AgentProvider::Gemini => {
+                    seed_cmd
+                        .args(permission_args)
+                        .arg("--output-format")
+                        .arg("json");
+                    Self::apply_model_args(&mut seed_cmd, provider, candidate_model.as_deref());
+                    seed_cmd.arg("-p").arg(&seed_arg);
+                }
+                AgentProvider::Claude => {
+                    seed_cmd
+                        .args(permission_args)
+                        .arg("--output-format")
+                        .arg("json")
+                        .arg("--print");
+                    Self::apply_model_args(&mut seed_cmd, provider, candidate_model.as_deref());
+                    options.push_prompt(&mut seed_cmd, provider, &seed_arg);
+                }
+                AgentProvider::Codex => {
+                    seed_cmd.arg("exec").args(permission_args).arg("--json");
+                    Self::apply_model_args(&mut seed_cmd, provider, candidate_model.as_deref());
+                    options.push_prompt(&mut seed_cmd, provider, &seed_arg);
+                }
+                AgentProvider::Cursor => {
+                    seed_cmd
+                        .args(permission_args)
+                        .arg("--output-format")
+                        .arg("json")
+                        .arg("--print");
+                    Self::apply_model_args(&mut seed_cmd, provider, candidate_model.as_deref());
+                    options.push_prompt(&mut seed_cmd, provider, &seed_arg);
+                }
+                _ => {
+                    Self::apply_model_args(&mut seed_cmd, provider, candidate_model.as_deref());
+                    options.push_prompt(&mut seed_cmd, provider, &seed_arg);
+                }
+            }
+
+            let input = options.stdin_prompt(provider, init_prompt);
+            let output =
+                AgentExecutor::spawn_and_wait_with_input(&mut seed_cmd, options, input).await?;
+            if !output.status.success() {
+                let detail = AgentExecutor::failure_detail(&output);
+                let retry = Self::gemini_should_retry_with_fallback(
+                    provider,
+                    candidate_model.as_deref(),
+                    &detail,
+                );
+                last_seed_error = Some(AgentError::SeedFailed(detail));
+                if retry {
+                    continue;
+                }
+                break;
+            }
+
+            let out_str = options.clean_output(&output.stdout);
+            if let Some(id) = Self::extract_seed_session_id(&out_str, options) {
+                if !Self::memory_acknowledged(&out_str) {
+                    if options.require_memory_ready {
+                        return Err(AgentError::MemoryNotLoaded {
+                            provider: provider.clone(),
+                        });
+                    }
+                    tracing::warn!(
+                        tool = provider.command_name(),
+                        session_id = %id,
+                        "seed reply did not contain MEMORY_READY"
+                    );
+                }
+                options.emit(Lifecycle::Seeded {
+                    prompt: init_prompt.to_string(),
+                });
+                return Ok(SeedOutcome {
+                    id,
+                    model: candidate_model,
+                    usage: Self::extract_usage(&out_str),
+                });
+            }
+            last_seed_error = Some(AgentError::SessionIdMissing);
+        }
+
+        Err(last_seed_error.unwrap_or(AgentError::SessionIdMissing))
+    }
+
+    /// 事前に構築した初期化プロンプトを使って、まだセッションの無いツールをシードします
+    pub async fn warm_sessions(
+        &self,
+        providers: &[AgentProvider],
+        prepared: &PreparedInitPrompt,
+    ) -> Result<(), AgentError> {
+        let options = self.with_manager_defaults(&ExecOptions::new().with_init_prompt(prepared));
+        for provider in providers {
+            if !provider.supports_resume() {
+                continue;
+            }
+            let key = default_key(provider);
+            // Hold the seed lock so a concurrent first-contact turn does not
+            // seed the same conversation a second time.
+            let seed_lock = self.seed_lock(&key).await;
+            let _seeding = seed_lock.lock().await;
+            if self.session_ids.lock().await.contains_key(&key) {
+                continue;
+            }
+            let seeded = self.run_seed(provider, &options).await?;
+            self.set_session(key.clone(), seeded.id).await;
+            self.add_usage(&key, seeded.usage).await;
+            self.persist().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn execute_with_resume<F>(
+        &self,
+        provider: AgentProvider,
+        prompt: &str,
+        on_chunk: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        self.execute_with_resume_with_model(provider, None, prompt, on_chunk)
+            .await
+    }
+
+    pub async fn execute_with_resume_with_model<F>(
+        &self,
+        provider: AgentProvider,
+        model: Option<String>,
+        prompt: &str,
+        on_chunk: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        let options = ExecOptions {
+            model,
+            ..ExecOptions::default()
+        };
+        self.execute_with_resume_with_options(provider, prompt, &options, on_chunk)
+            .await
+    }
+
+    /// `conversation` の会話を再開して 1 ターン実行します。同じツールでも会話ごとに別のセッションを保持します
+    pub async fn execute_with_resume_in<F>(
+        &self,
+        provider: AgentProvider,
+        conversation: impl Into<ConversationId>,
+        prompt: &str,
+        on_chunk: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        let options = ExecOptions::new().with_conversation(conversation);
+        self.execute_with_resume_with_options(provider, prompt, &options, on_chunk)
+            .await
+    }
+
+    /// セッションを再開して 1 ターン実行します。stderr は stdout と並行して読み切るため、
+    /// 大量の stderr を出すエージェントでもパイプが詰まらず、失敗時はその全文を
+    /// `AgentError::NonZeroExit` の `detail` に含めます
+    pub async fn execute_with_resume_with_options<F>(
+        &self,
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+        on_chunk: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        validate_prompt(prompt)?;
+        let options = &self.with_manager_defaults(options);
+        let prompt = &options.stamped_prompt(prompt);
+        let span = tracing::info_span!(
+            "execute_with_resume",
+            tool = provider.command_name(),
+            conversation = %options.conversation
+        );
+        tracing::trace!(parent: &span, prompt, "resume prompt");
+        let turn = self.metered_resume_turn(provider.clone(), prompt, options, on_chunk);
+        options
+            .with_hooks(&provider, prompt, turn)
+            .instrument(span)
+            .await
+    }
+
+    async fn metered_resume_turn<F>(
+        &self,
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+        mut on_chunk: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        let Some(metrics) = options.metrics.clone() else {
+            return self
+                .resume_turn(provider, prompt, options, on_chunk, self.max_reseed)
+                .await;
+        };
+        let started = Instant::now();
+        let collected = Arc::new(std::sync::Mutex::new(String::new()));
+        let sink = Arc::clone(&collected);
+        let result = self
+            .resume_turn(
+                provider.clone(),
+                prompt,
+                options,
+                move |chunk| {
+                    sink.lock().unwrap().push_str(&chunk);
+                    on_chunk(chunk);
+                },
+                self.max_reseed,
+            )
+            .await;
+        let tokens = Self::extract_token_total(&collected.lock().unwrap());
+        metrics.record(&provider, started.elapsed(), result.is_ok(), tokens);
+        result
+    }
+
+    /// One resumed turn without metrics bookkeeping; the Gemini capacity
+    /// fallback and expired-session re-seed recurse here so a retried turn is
+    /// recorded once. `reseeds_left` bounds the re-seed recursion.
+    async fn resume_turn<F>(
+        &self,
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+        mut on_chunk: F,
+        reseeds_left: usize,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        if provider == AgentProvider::Dummy {
+            let started = Instant::now();
+            options.emit(Lifecycle::Spawned { pid: None });
+            options.emit(Lifecycle::FirstByte {
+                at: started.elapsed(),
+            });
+            on_chunk(prompt.to_string());
+            options.emit(Lifecycle::Exited { code: Some(0) });
+            return Ok(());
+        }
+
+        let deadline = options
+            .timeout
+            .map(|timeout| tokio::time::Instant::now() + timeout);
+
+        if let Some(backend) = self.backends.get(&provider) {
+            let key = (provider.clone(), options.conversation.clone());
+            let (id, model) = self
+                .session_for_turn(&key, prompt, options, deadline)
+                .await?;
+            let options = &ExecOptions {
+                model,
+                ..options.until(deadline)?
+            };
+            return backend
+                .execute_stream(&id, prompt, options, &mut on_chunk)
+                .await;
+        }
+
+        if provider == AgentProvider::Mock {
+            let started = Instant::now();
+            options.emit(Lifecycle::Spawned { pid: None });
+            options.emit(Lifecycle::FirstByte {
+                at: started.elapsed(),
+            });
+            on_chunk("Mock: ".into());
+            tokio::select! {
+                biased;
+                _ = tokio::time::sleep(std::time::Duration::from_millis(10)) => {}
+                reason = options.interrupted(deadline) => {
+                    options.emit(Lifecycle::Killed { reason });
+                    return Err(AgentError::Cancelled { reason });
+                }
+            }
+            on_chunk(format!("received your prompt '{}'.", prompt));
+            options.emit(Lifecycle::Exited { code: Some(0) });
+            return Ok(());
+        }
+
+        if !provider.supports_resume() {
+            // Tools without a resume flag (aider) run each turn single-shot.
+            let mut command = AgentExecutor::one_shot_command(&provider, prompt, options)?;
+            let input = options.stdin_prompt(&provider, prompt);
+            AgentExecutor::stream_command(&mut command, options, input, &mut on_chunk)
+                .await?
+                .check(provider.command_name())?;
+            return Ok(());
+        }
+
+        // The lock only guards the map itself; holding it across a subprocess
+        // would serialize every provider behind the slowest running agent.
+        let cmd = provider.command_name();
+        let key = (provider.clone(), options.conversation.clone());
+        let (id, active_model) = self
+            .session_for_turn(&key, prompt, options, deadline)
+            .await?;
+
+        let mut command =
+            Self::resume_command(&provider, &id, prompt, active_model.as_deref(), options)?;
+
+        let options = &options.until(deadline)?;
+        let input = options.stdin_prompt(&provider, prompt);
+        if provider == AgentProvider::Codex {
+            let output =
+                AgentExecutor::spawn_and_wait_with_input(&mut command, options, input).await?;
+            if !output.status.success() {
+                if reseeds_left > 0
+                    && Self::session_expired(&provider, &String::from_utf8_lossy(&output.stderr))
+                {
+                    self.drop_stale_session(&key, &id).await;
+                    return Box::pin(self.resume_turn(
+                        provider,
+                        prompt,
+                        options,
+                        on_chunk,
+                        reseeds_left - 1,
+                    ))
+                    .await;
+                }
+                return Err(AgentError::NonZeroExit {
+                    command: cmd.to_string(),
+                    code: output.status.code(),
+                    detail: AgentExecutor::failure_detail(&output),
+                });
+            }
+
+            let out_str = options.clean_output(&output.stdout);
+            if let Some(response) = Self::extract_response(&out_str) {
+                self.record_usage(&key, Self::extract_usage(&out_str))
+                    .await?;
+                on_chunk(response);
+                return Ok(());
+            }
+
+            return Err(AgentError::ResponseMissing("codex exec resume".to_string()));
+        }
+
+        // In JSON mode the envelope is only parseable once complete, so the
+        // output is buffered and delivered as a single chunk on success.
+        let mut raw = String::new();
+        let run = if options.json_response {
+            AgentExecutor::stream_command(&mut command, options, input, &mut |chunk| {
+                raw.push_str(&chunk)
+            })
+            .await?
+        } else {
+            AgentExecutor::stream_command(&mut command, options, input, &mut on_chunk).await?
+        };
+        if !run.status.success() {
+            if !run.saw_output && reseeds_left > 0 && Self::session_expired(&provider, &run.stderr)
+            {
+                self.drop_stale_session(&key, &id).await;
+                return Box::pin(self.resume_turn(
+                    provider,
+                    prompt,
+                    options,
+                    on_chunk,
+                    reseeds_left - 1,
+                ))
+                .await;
+            }
+            if !run.saw_output
+                && Self::gemini_should_retry_with_fallback(
+                    &provider,
+                    active_model.as_deref(),
+                    &run.stderr,
+                )
+            {
+                for fallback in Self::gemini_capacity_fallback_models(active_model.as_deref()) {
+                    let fallback_model = Some((*fallback).to_string());
+                    if fallback_model == active_model {
+                        continue;
+                    }
+                    let fallback_options = ExecOptions {
+                        model: fallback_model,
+                        ..options.clone()
+                    };
+                    return Box::pin(self.resume_turn(
+                        provider,
+                        prompt,
+                        &fallback_options,
+                        on_chunk,
+                        reseeds_left,
+                    ))
+                    .await;
+                }
+            }
+            run.check(cmd)?;
+        }
+
+        if !raw.is_empty() {
+            self.record_usage(&key, Self::extract_usage(&raw)).await?;
+            on_chunk(Self::extract_response(&raw).unwrap_or(raw));
+        }
+        Ok(())
+    }
+
+    /// Claims a turn on the conversation's session, seeding it first when it
+    /// has none. Returns the session id and the model the turn should use.
+    async fn session_for_turn(
+        &self,
+        key: &SessionKey,
+        prompt: &str,
+        options: &ExecOptions,
+        deadline: Option<tokio::time::Instant>,
+    ) -> Result<(String, Option<String>), AgentError> {
+        if let Some(id) = self.claim_turn(key).await {
+            return Ok((id, options.model.clone()));
+        }
+        // Concurrent first-contact turns queue on the conversation's seed
+        // lock; whoever gets it second finds the session already seeded and
+        // resumes it instead of seeding again.
+        let seed_lock = self.seed_lock(key).await;
+        let _seeding = seed_lock.lock().await;
+        if let Some(id) = self.claim_turn(key).await {
+            return Ok((id, options.model.clone()));
+        }
+        let seeded = self.run_seed(&key.0, &options.until(deadline)?).await?;
+        self.record_conversation(key.clone(), seeded.id.clone(), prompt)
+            .await;
+        self.turn_counts.lock().await.insert(key.clone(), 1);
+        self.add_usage(key, seeded.usage).await;
+        self.persist().await?;
+        Ok((seeded.id, seeded.model))
+    }
+
+    /// Builds the command that resumes session `id` with `prompt`.
+    fn resume_command(
+        provider: &AgentProvider,
+        id: &str,
+        prompt: &str,
+        model: Option<&str>,
+        options: &ExecOptions,
+    ) -> Result<Command, AgentError> {
+        let mut command = options.command_for(provider)?;
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        options.configure(&mut command);
+        let prompt_arg = options.prompt_arg(provider, prompt);
+
+        let permission_args = options.permission_mode.args(provider);
+        match provider {
+            AgentProvider::Gemini => {
+                command.args(permission_args).arg("--resume").arg(id);
+                if options.json_response {
+                    command.arg("--output-format").arg("json");
+                }
+                Self::apply_model_args(&mut command, provider, model);
+                command.arg("-p").arg(&prompt_arg);
+            }
+            AgentProvider::Claude => {
+                command.args(permission_args).arg("--resume").arg(id);
+                if options.json_response {
+                    command.arg("--output-format").arg("json");
+                }
+                command.arg("--print");
+                Self::apply_model_args(&mut command, provider, model);
+                options.push_prompt(&mut command, provider, &prompt_arg);
+            }
+            AgentProvider::Codex => {
+                command
+                    .arg("exec")
+                    .arg("resume")
+                    .args(permission_args)
+                    .arg("--json");
+                Self::apply_model_args(&mut command, provider, model);
+                command.arg(id);
+                options.push_prompt(&mut command, provider, &prompt_arg);
+            }
+            AgentProvider::Cursor => {
+                command
+                    .args(permission_args)
+                    .arg("--resume")
+                    .arg(id)
+                    .arg("--print");
+                Self::apply_model_args(&mut command, provider, model);
+                options.push_prompt(&mut command, provider, &prompt_arg);
+            }
+            _ => {
+                Self::apply_model_args(&mut command, provider, model);
+                options.push_prompt(&mut command, provider, &prompt_arg);
+            }
+        }
+        Ok(command)
+    }
+
+    /// 完了マーカーが出力されるか `max_iterations` に達するまでセッションを再開し続けます
+    pub async fn execute_until_done<F>(
+        &self,
+        provider: AgentProvider,
+        prompt: &str,
+        config: &ContinueLoop,
+        options: &ExecOptions,
+        mut on_iteration: F,
+    ) -> Result<LoopOutcome, AgentError>
+    where
+        F: FnMut(usize, &str),
+    {
+        let mut outcome = LoopOutcome {
+            iterations: 0,
+            completed: false,
+            last_output: String::new(),
+        };
+
+        while outcome.iterations < config.max_iterations {
+            let turn_prompt = if outcome.iterations == 0 {
+                prompt
+            } else {
+                config.continuation_prompt.as_str()
+            };
+            let collected = Arc::new(std::sync::Mutex::new(String::new()));
+            let sink = Arc::clone(&collected);
+            self.execute_with_resume_with_options(
+                provider.clone(),
+                turn_prompt,
+                options,
+                move |chunk| sink.lock().unwrap().push_str(&chunk),
+            )
+            .await?;
+
+            outcome.iterations += 1;
+            outcome.last_output = std::mem::take(&mut *collected.lock().unwrap());
+            on_iteration(outcome.iterations, &outcome.last_output);
+
+            if outcome.last_output.contains(&config.done_marker) {
+                outcome.completed = true;
+                break;
+            }
+        }
+
+        Ok(outcome)
+    }
+
+    /// セッションを再開して 1 ターン実行し、ストリームされた出力全体を返します
+    pub async fn execute_with_resume_collect(
+        &self,
+        provider: AgentProvider,
+        prompt: &str,
+    ) -> Result<String, AgentError> {
+        self.execute_with_resume_collect_with_options(
+            provider,
+            prompt,
+            &ExecOptions::default(),
+            |_| {},
+        )
+        .await
+    }
+
+    /// 再試行可能な失敗 (`RetryPolicy::should_retry` で判定し、終了コード付きの失敗は `options.error_classifier`、
+    /// 既定は `AgentError::is_transient` と同じ) のときだけ `policy` に従って再試行します。すべての試行が失敗した場合は最後のエラーを返します。`options.timeout` は試行ごとに適用されます。
+    /// 出力は試行ごとにバッファし、成功した試行の分だけを終了後に `on_chunk` へ配信します
+    pub async fn execute_with_retry<F>(
+        &self,
+        provider: AgentProvider,
+        prompt: &str,
+        policy: &RetryPolicy,
+        on_chunk: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        self.execute_with_retry_with_options(
+            provider,
+            prompt,
+            policy,
+            &ExecOptions::default(),
+            on_chunk,
+        )
+        .await
+    }
+
+    pub async fn execute_with_retry_with_options<F>(
+        &self,
+        provider: AgentProvider,
+        prompt: &str,
+        policy: &RetryPolicy,
+        options: &ExecOptions,
+        mut on_chunk: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        let mut retry = 0;
+        loop {
+            // A failed attempt may already have streamed part of its output,
+            // so chunks are only handed on once the attempt has succeeded.
+            let buffered = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let sink = Arc::clone(&buffered);
+            let result = self
+                .execute_with_resume_with_options(provider.clone(), prompt, options, move |chunk| {
+                    sink.lock().unwrap().push(chunk)
+                })
+                .await;
+            match result {
+                Ok(()) => {
+                    let chunks = std::mem::take(&mut *buffered.lock().unwrap());
+                    chunks.into_iter().for_each(&mut on_chunk);
+                    return Ok(());
+                }
+                Err(err)
+                    if policy.should_retry(&err, options.error_classifier.as_ref())
+                        && retry + 1 < policy.max_attempts =>
+                {
+                    retry += 1;
+                    let delay = tokio::time::sleep(policy.delay_for(retry));
+                    tokio::select! {
+                        biased;
+                        _ = delay => {}
+                        reason = options.interrupted(None) => {
+                            return Err(AgentError::Cancelled { reason });
+                        }
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// チャンクを `on_chunk` に配信しつつ、成功時は配信したものと同じ出力全体を返します。
+    /// `options.normalize` を設定した場合は、返す出力をそれで正規化します
+    pub async fn execute_with_resume_collect_with_options<F>(
+        &self,
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+        mut on_chunk: F,
+    ) -> Result<String, AgentError>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        let collected = Arc::new(std::sync::Mutex::new(String::new()));
+        let sink = Arc::clone(&collected);
+        self.execute_with_resume_with_options(provider, prompt, options, move |chunk| {
+            sink.lock().unwrap().push_str(&chunk);
+            on_chunk(chunk);
+        })
+        .await?;
+        let collected = std::mem::take(&mut *collected.lock().unwrap());
+        Ok(match options.normalize {
+            Some(normalization) => normalization.apply(&collected),
+            None => collected,
+        })
+    }
+
+    /// セッションを再開して 1 ターン実行し、出力を完結した行ごとに (改行を除いて) 配信します。
+    /// 改行で終わらない最後の行は終了時に配信します
+    pub async fn execute_with_resume_lines<F>(
+        &self,
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+        on_line: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        let framed = Arc::new(std::sync::Mutex::new((LineFramer::default(), on_line)));
+        let sink = Arc::clone(&framed);
+        self.execute_with_resume_with_options(provider, prompt, options, move |chunk| {
+            let (framer, on_line) = &mut *sink.lock().unwrap();
+            framer.push(&chunk).into_iter().for_each(on_line);
+        })
+        .await?;
+        let (framer, on_line) = &mut *framed.lock().unwrap();
+        framer.finish().into_iter().for_each(on_line);
+        Ok(())
+    }
+
+    /// セッションを再開して 1 ターン実行し、チャンクを配信しつつ出力全体と停止理由を返します。
+    /// `options.auto_continue` があれば、`MaxTokens` で止まった応答の続きを要求して連結します
+    pub async fn execute_with_resume_result<F>(
+        &self,
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+        on_chunk: F,
+    ) -> Result<RunResult, AgentError>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        let on_chunk = Arc::new(std::sync::Mutex::new(on_chunk));
+        let timing = Arc::new(std::sync::Mutex::new(ProcessTiming::default()));
+        let seed_prompt = Arc::new(std::sync::Mutex::new(None));
+        let options = &options.clone().tap_lifecycle({
+            let timing = Arc::clone(&timing);
+            let seed_prompt = Arc::clone(&seed_prompt);
+            let include_seed_prompt = options.include_seed_prompt;
+            move |event| {
+                if let Lifecycle::Seeded { prompt } = event
+                    && include_seed_prompt
+                {
+                    *seed_prompt.lock().unwrap() = Some(prompt.clone());
+                }
+                timing.lock().unwrap().observe(event)
+            }
+        });
+        let mut result = RunResult {
+            output: String::new(),
+            response: String::new(),
+            stop_reason: None,
+            continuations: 0,
+            tool_version: None,
+            model: None,
+            exit_code: None,
+            duration: Duration::ZERO,
+            stdout_bytes: 0,
+            session_id: None,
+            seed_prompt: None,
+        };
+        let mut turn_prompt = prompt;
+        loop {
+            let collected = Arc::new(std::sync::Mutex::new(String::new()));
+            let sink = Arc::clone(&collected);
+            let forward = Arc::clone(&on_chunk);
+            self.execute_with_resume_with_options(
+                provider.clone(),
+                turn_prompt,
+                options,
+                move |chunk| {
+                    sink.lock().unwrap().push_str(&chunk);
+                    (forward.lock().unwrap())(chunk);
+                },
+            )
+            .await?;
+            let output = std::mem::take(&mut *collected.lock().unwrap());
+            result.response += &Self::extract_response(&output).unwrap_or_else(|| output.clone());
+            result.stop_reason = Self::extract_stop_reason(&output);
+            if let Some(model) = Self::extract_model(&output) {
+                result.model = Some(model);
+            }
+            result.output += &output;
+
+            let Some(config) = &options.auto_continue else {
+                break;
+            };
+            if result.stop_reason != Some(StopReason::MaxTokens)
+                || result.continuations >= config.max_continuations
+            {
+                break;
+            }
+            result.continuations += 1;
+            turn_prompt = &config.continuation_prompt;
+        }
+        if result.model.is_none() {
+            result.model = options.model.clone();
+        }
+        {
+            let timing = timing.lock().unwrap();
+            result.exit_code = timing.exit_code;
+            result.duration = timing.total;
+        }
+        result.stdout_bytes = result.output.len();
+        result.tool_version = self.tool_version(&provider, options).await;
+        result.session_id = self
+            .conversation_session_id(&provider, &options.conversation)
+            .await;
+        result.seed_prompt = seed_prompt.lock().unwrap().take();
+        Ok(result)
+    }
+
+    /// 複数のプロンプトを同じセッションで順番に実行し、重複したプロンプトを報告します
+    pub async fn execute_batch(
+        &self,
+        provider: AgentProvider,
+        prompts: &[&str],
+        options: &ExecOptions,
+    ) -> Result<BatchOutcome, AgentError> {
+        let mut outputs = Vec::with_capacity(prompts.len());
+        for prompt in prompts {
+            outputs.push(
+                self.execute_with_resume_collect_with_options(
+                    provider.clone(),
+                    prompt,
+                    options,
+                    |_| {},
+                )
+                .await?,
+            );
+        }
+        Ok(BatchOutcome {
+            outputs,
+            duplicates: Self::duplicate_prompts(prompts),
+        })
+    }
+
+    /// 同じプロンプトを複数のツールで同時に実行し、入力と同じ順序で結果を返します。
+    /// `max_parallel` を指定すると、同時に走るターン数をその数までに制限します
+    pub async fn fan_out(
+        &self,
+        providers: &[AgentProvider],
+        prompt: &str,
+        options: &ExecOptions,
+        max_parallel: Option<usize>,
+    ) -> Vec<(AgentProvider, Result<String, AgentError>)> {
+        let permits = max_parallel.unwrap_or(providers.len()).max(1);
+        let semaphore = Arc::new(tokio::sync::Semaphore::new(permits));
+        let mut tasks = tokio::task::JoinSet::new();
+        for (idx, provider) in providers.iter().cloned().enumerate() {
+            let manager = self.clone();
+            let semaphore = Arc::clone(&semaphore);
+            let prompt = prompt.to_string();
+            let options = options.clone();
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let result = manager
+                    .execute_with_resume_collect_with_options(
+                        provider.clone(),
+                        &prompt,
+                        &options,
+                        |_| {},
+                    )
+                    .await;
+                (idx, provider, result)
+            });
+        }
+
+        let mut results: Vec<_> = tasks.join_all().await;
+        results.sort_by_key(|(idx, _, _)| *idx);
+        results
+            .into_iter()
+            .map(|(_, provider, result)| (provider, result))
+            .collect()
+    }
+
+    /// 前後の空白を無視して、先に出現したプロンプトと同じものの位置を返します
+    pub fn duplicate_prompts(prompts: &[&str]) -> Vec<usize> {
+        let mut seen = std::collections::HashSet::new();
+        prompts
+            .iter()
+            .enumerate()
+            .filter(|(_, prompt)| !seen.insert(prompt.trim()))
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    /// ホスト側で実行したツールの結果を、ラベル付きプロンプトとしてセッションの次のターンに渡します
+    pub async fn execute_with_tool_result<F>(
+        &self,
+        provider: AgentProvider,
+        tool_call_id: &str,
+        result: &serde_json::Value,
+        options: &ExecOptions,
+        on_chunk: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        // None of the supported CLIs accept a native tool-result argument on
+        // resume, so every provider gets the labeled prompt.
+        let prompt = Self::format_tool_result(tool_call_id, result);
+        self.execute_with_resume_with_options(provider, &prompt, options, on_chunk)
+            .await
+    }
+
+    /// ツール呼び出しを `tool_handler` で処理して結果をセッションへ返す、というターンを
+    /// エージェントがツールを呼ばずに応答するか `max_iterations` に達するまで繰り返します
+    pub async fn run_agent_loop<H, Fut>(
+        &self,
+        provider: AgentProvider,
+        prompt: &str,
+        max_iterations: usize,
+        options: &ExecOptions,
+        tool_handler: H,
+    ) -> Result<AgentLoopOutcome, AgentError>
+    where
+        H: Fn(ToolCall) -> Fut,
+        Fut: Future<Output = serde_json::Value>,
+    {
+        let mut outcome = AgentLoopOutcome {
+            iterations: 0,
+            tool_calls: 0,
+            completed: false,
+            answer: String::new(),
+        };
+        let mut turn_prompt = prompt.to_string();
+        while outcome.iterations < max_iterations {
+            let output = self
+                .execute_with_resume_collect_with_options(
+                    provider.clone(),
+                    &turn_prompt,
+                    options,
+                    |_| {},
+                )
+                .await?;
+            outcome.iterations += 1;
+
+            let mut parser = EventParser::default();
+            let mut calls = Vec::new();
+            let mut answer = String::new();
+            let mut collect = |event| match event {
+                AgentEvent::ToolCall(call) => calls.push(call),
+                AgentEvent::Done { response } => answer = response,
+                _ => {}
+            };
+            parser.push(&output, &mut collect);
+            parser.finish(&mut collect);
+            outcome.answer = if answer.is_empty() {
+                output.trim().to_string()
+            } else {
+                answer
+            };
+            if calls.is_empty() {
+                outcome.completed = true;
+                break;
+            }
+
+            // Every call of the turn is answered before resuming, in order.
+            let mut results = Vec::with_capacity(calls.len());
+            for call in calls {
+                let id = call.id.clone();
+                let result = tool_handler(call).await;
+                results.push(Self::format_tool_result(&id, &result));
+                outcome.tool_calls += 1;
+            }
+            turn_prompt = results.join("\n\n");
+        }
+        Ok(outcome)
+    }
+
+    /// ツール結果を次のターンに送るプロンプトへ整形します
+    pub fn format_tool_result(tool_call_id: &str, result: &serde_json::Value) -> String {
+        let body = serde_json::to_string_pretty(result).unwrap_or_else(|_| result.to_string());
+        format!(
+            "Result of tool call `{}` (JSON):\n<tool_result id=\"{}\">\n{}\n</tool_result>",
+            tool_call_id, tool_call_id, body
+        )
+    }
+}
+
+/// 出力チャンクの送信元ストリーム
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum OutputSource {
+    Stdout,
+    Stderr,
+}
+
+/// 送信元でタグ付けされた出力チャンク
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaggedChunk {
+    pub source: OutputSource,
+    pub text: String,
+}
+
+/// Incremental UTF-8 decoder that carries an incomplete trailing code point
+/// over to the next read instead of replacing it with `U+FFFD`.
+#[derive(Debug, Default)]
+struct Utf8ChunkDecoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8ChunkDecoder {
+    fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut decoded = String::new();
+        loop {
+            match std::str::from_utf8(&self.pending) {
+                Ok(valid) => {
+                    decoded.push_str(valid);
+                    self.pending.clear();
+                    return decoded;
+                }
+                Err(e) => {
+                    let valid_up_to = e.valid_up_to();
+                    // Safe: the prefix was just validated.
+                    decoded.push_str(std::str::from_utf8(&self.pending[..valid_up_to]).unwrap());
+                    match e.error_len() {
+                        // Incomplete sequence at the end: wait for more bytes.
+                        None => {
+                            self.pending.drain(..valid_up_to);
+                            return decoded;
+                        }
+                        // Genuinely invalid bytes: replace them and keep going.
+                        Some(len) => {
+                            decoded.push(char::REPLACEMENT_CHARACTER);
+                            self.pending.drain(..valid_up_to + len);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Flushes whatever is left at EOF, replacing a truncated sequence.
+    fn finish(&mut self) -> String {
+        let rest = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        rest
+    }
+}
+
+/// 出力を書き込んだ一時ファイル。drop するとファイルを削除します
+#[derive(Debug)]
+pub struct TempOutput {
+    path: PathBuf,
+}
+
+impl TempOutput {
+    fn create() -> std::io::Result<(Self, std::fs::File)> {
+        Self::create_in(&std::env::temp_dir())
+    }
+
+    /// Creates a fresh file in `dir`, which may be shared with other users.
+    /// `create_new` refuses existing paths (including planted symlinks), so a
+    /// taken name is skipped rather than truncated.
+    fn create_in(dir: &Path) -> std::io::Result<(Self, std::fs::File)> {
+        static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        loop {
+            let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let path = dir.join(format!("acore-output-{}-{}.txt", std::process::id(), n));
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => return Ok((Self { path }, file)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempOutput {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Aborts a background task (e.g. the stderr reader) when the run that owns
+/// it returns early.
+struct AbortOnDrop(tokio::task::AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Result of a streamed child run; `stderr` holds what was collected for error reporting.
+struct StreamedRun {
+    status: std::process::ExitStatus,
+    saw_output: bool,
+    stderr: String,
+}
+
+impl StreamedRun {
+    /// Turns a non-zero exit into `AgentError::NonZeroExit` carrying the
+    /// collected stderr.
+    fn check(self, command: &str) -> Result<StreamedRun, AgentError> {
+        if self.status.success() {
+            return Ok(self);
+        }
+        Err(AgentError::NonZeroExit {
+            command: command.to_string(),
+            code: self.status.code(),
+            detail: self.stderr,
+        })
+    }
+}
+
+pub struct AgentExecutor;
+
+const DEFAULT_MAX_RESEED: usize = 1;
+const MEMORY_READY: &str = "MEMORY_READY";
+const VERSION_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Upper bound on the `amem keep` processes `keep_many` runs at once.
+const MAX_CONCURRENT_KEEPS: usize = 8;
+const SUMMARY_INSTRUCTION: &str = "対話内容をAgentの活動ログとして1行で要約せよ：";
+const CODE_SUMMARY_INSTRUCTION: &str =
+    "対話内容で行われたコード変更と設計判断をAgentの活動ログとして1行で要約せよ：";
+
+impl AgentExecutor {
+    fn failure_detail(output: &std::process::Output) -> String {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !stderr.is_empty() {
+            stderr
+        } else if !stdout.is_empty() {
+            format!("stdout: {}", stdout)
+        } else {
+            "no stdout/stderr output".to_string()
+        }
+    }
+
+    fn one_shot_command(
+        provider: &AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+    ) -> Result<Command, AgentError> {
+        let mut command = options.command_for(provider)?;
+        match provider {
+            AgentProvider::Codex => {
+                command.arg("exec").arg("--json");
+            }
+            AgentProvider::Cursor => {
+                command.arg("--print");
+            }
+            // Claude only reads its prompt from stdin in print mode.
+            AgentProvider::Claude if options.prompt_on_stdin(provider) => {
+                command.arg("--print");
+            }
+            AgentProvider::Aider => {
+                command
+                    .args(options.permission_mode.args(provider))
+                    .arg("--no-pretty");
+            }
+            _ => {}
+        }
+        SessionManager::apply_model_args(&mut command, provider, options.model.as_deref());
+        if *provider == AgentProvider::Aider {
+            command.arg("--message");
+        }
+        options.push_prompt(
+            &mut command,
+            provider,
+            &options.prompt_arg(provider, prompt),
+        );
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        options.configure(&mut command);
+        Ok(command)
+    }
+
+    async fn cancel_child(
+        child: &mut Child,
+        options: &ExecOptions,
+        reason: CancelReason,
+    ) -> AgentError {
+        let _ = child.kill().await;
+        options.emit(Lifecycle::Killed { reason });
+        AgentError::Cancelled { reason }
+    }
+
+    /// Spawns `command`, logging the program at debug and its arguments (which
+    /// carry the prompt) only at trace. `input` is written to stdin from a
+    /// separate task, so a child that answers before reading all of it cannot
+    /// deadlock against a full stdout pipe; stdin is then held open for
+    /// `options.stdin_eof_delay` and closed. The child is killed if the
+    /// returned handle is dropped, so dropping a turn's future mid-run does
+    /// not leave the agent running (and spending quota) in the background.
+    fn spawn(
+        command: &mut Command,
+        options: &ExecOptions,
+        input: Option<String>,
+    ) -> Result<Child, AgentError> {
+        let program = command
+            .as_std()
+            .get_program()
+            .to_string_lossy()
+            .into_owned();
+        tracing::trace!(args = ?command.as_std().get_args().collect::<Vec<_>>(), "agent arguments");
+        let eof_delay = options.stdin_eof_delay;
+        if input.is_some() || eof_delay.is_some() {
+            command.stdin(Stdio::piped());
+        }
+        command.kill_on_drop(true);
+        let spawned = match &options.runner {
+            Some(runner) => runner.spawn(command),
+            None => TokioRunner.spawn(command),
+        };
+        let mut child = spawned.map_err(|source| AgentError::SpawnFailed {
+            command: program.clone(),
+            source,
+        })?;
+        tracing::debug!(command = %program, pid = ?child.id(), "spawned agent");
+        if let Some(mut stdin) = child.stdin.take() {
+            tokio::spawn(async move {
+                if let Some(input) = input {
+                    // A child that exits without reading stdin is reported by its exit status.
+                    let _ = stdin.write_all(input.as_bytes()).await;
+                }
+                if let Some(delay) = eof_delay {
+                    tokio::time::sleep(delay).await;
+                }
+            });
+        }
+        Ok(child)
+    }
+
+    fn trace_exit(status: &std::process::ExitStatus, started: Instant) {
+        tracing::info!(
+            code = ?status.code(),
+            success = status.success(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "agent exited"
+        );
+    }
+
+    /// Spawns `command` and streams its stdout to `on_chunk`, honouring the
+    /// timeout and stop predicate from `options`. Stderr is read to the end on
+    /// a separate task for the whole run and returned in `StreamedRun::stderr`.
+    async fn stream_command<F>(
+        command: &mut Command,
+        options: &ExecOptions,
+        input: Option<String>,
+        on_chunk: &mut F,
+    ) -> Result<StreamedRun, AgentError>
+    where
+        F: FnMut(String),
+    {
+        let mut buffer = options.read_buffer()?;
+        let mut child = Self::spawn(command, options, input)?;
+        let started = Instant::now();
+        let deadline = options
+            .timeout
+            .map(|timeout| tokio::time::Instant::now() + timeout);
+        options.emit(Lifecycle::Spawned { pid: child.id() });
+
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| std::io::Error::other("Failed to open stdout"))?;
+        let mut stdout = BufReader::with_capacity(buffer.len(), stdout);
+        let mut line = Vec::new();
+        let mut stderr = child
+            .stderr
+            .take()
+            .ok_or_else(|| std::io::Error::other("Failed to open stderr"))?;
+        // Drain stderr alongside stdout; a child that fills the stderr pipe
+        // would otherwise block before closing stdout.
+        let stderr_task = tokio::spawn(async move {
+            let mut collected = Vec::new();
+            let _ = stderr.read_to_end(&mut collected).await;
+            String::from_utf8_lossy(&collected).into_owned()
+        });
+        let _stderr_guard = AbortOnDrop(stderr_task.abort_handle());
+
+        let mut decoder = Utf8ChunkDecoder::default();
+        let mut stripper = options.ansi_stripper();
+        let mut saw_output = false;
+        let mut seen = String::new();
+        loop {
+            let read = tokio::select! {
+                biased;
+                read = Self::read_stdout(&mut stdout, options.read_strategy, &mut buffer, &mut line) => read,
+                reason = options.interrupted(deadline) => {
+                    return Err(Self::cancel_child(&mut child, options, reason).await);
+                }
+            };
+            let n = read?;
+            if n > 0 && !saw_output {
+                options.emit(Lifecycle::FirstByte {
+                    at: started.elapsed(),
+                });
+                saw_output = true;
+            }
+            let chunk = if n == 0 {
+                decoder.finish()
+            } else {
+                match options.read_strategy {
+                    ReadStrategy::Chunked => decoder.push(&buffer[..n]),
+                    ReadStrategy::Lines => decoder.push(&line),
+                }
+            };
+            let chunk = match stripper.as_mut() {
+                Some(stripper) => stripper.push(&chunk),
+                None => chunk,
+            };
+            if !chunk.is_empty() {
+                if options.stop_when.is_some() {
+                    seen.push_str(&chunk);
+                }
+                on_chunk(chunk);
+                if let Some(stop) = &options.stop_when
+                    && stop(&seen)
+                {
+                    return Err(
+                        Self::cancel_child(&mut child, options, CancelReason::Predicate).await,
+                    );
+                }
+            }
+            if n == 0 {
+                break;
+            }
+        }
+
+        let status = tokio::select! {
+            biased;
+            status = child.wait() => status?,
+            reason = options.interrupted(deadline) => {
+                return Err(Self::cancel_child(&mut child, options, reason).await);
+            }
+        };
+        options.emit(Lifecycle::Exited {
+            code: status.code(),
+        });
+        Self::trace_exit(&status, started);
+
+        let stderr = stderr_task.await.unwrap_or_default();
+        Ok(StreamedRun {
+            status,
+            saw_output,
+            stderr,
+        })
+    }
+
+    /// Like `stream_command`, but reads stdout and stderr in a single
+    /// `select!` loop so chunks reach `on_output` in arrival order. The loop
+    /// is `biased` towards stdout, which keeps the ordering deterministic when
+    /// both pipes are readable at once.
+    async fn stream_command_merged<F>(
+        command: &mut Command,
+        options: &ExecOptions,
+        input: Option<String>,
+        on_output: &mut F,
+    ) -> Result<StreamedRun, AgentError>
+    where
+        F: FnMut(OutputSource, String),
+    {
+        let mut out_buffer = options.read_buffer()?;
+        let mut err_buffer = options.read_buffer()?;
+        let mut child = Self::spawn(command, options, input)?;
+        let started = Instant::now();
+        options.emit(Lifecycle::Spawned { pid: child.id() });
+
+        let mut stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| std::io::Error::other("Failed to open stdout"))?;
+        let mut stderr = child
+            .stderr
+            .take()
+            .ok_or_else(|| std::io::Error::other("Failed to open stderr"))?;
+
+        let deadline = options
+            .timeout
+            .map(|timeout| tokio::time::Instant::now() + timeout);
+        let interrupted = options.interrupted(deadline);
+        tokio::pin!(interrupted);
+
+        let mut out_decoder = Utf8ChunkDecoder::default();
+        let mut err_decoder = Utf8ChunkDecoder::default();
+        let mut out_stripper = options.ansi_stripper();
+        let mut err_stripper = options.ansi_stripper();
+        let mut stdout_open = true;
+        let mut stderr_open = true;
+        let mut saw_output = false;
+        let mut seen = String::new();
+        let mut err_msg = String::new();
+        while stdout_open || stderr_open {
+            let (source, n) = tokio::select! {
+                biased;
+                read = stdout.read(&mut out_buffer), if stdout_open => (OutputSource::Stdout, read?),
+                read = stderr.read(&mut err_buffer), if stderr_open => (OutputSource::Stderr, read?),
+                reason = &mut interrupted => {
+                    return Err(Self::cancel_child(&mut child, options, reason).await);
+                }
+            };
+            match source {
+                OutputSource::Stdout => {
+                    if n > 0 && !saw_output {
+                        options.emit(Lifecycle::FirstByte {
+                            at: started.elapsed(),
+                        });
+                        saw_output = true;
+                    }
+                    let chunk = if n == 0 {
+                        stdout_open = false;
+                        out_decoder.finish()
+                    } else {
+                        out_decoder.push(&out_buffer[..n])
+                    };
+                    let chunk = match out_stripper.as_mut() {
+                        Some(stripper) => stripper.push(&chunk),
+                        None => chunk,
+                    };
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    if options.stop_when.is_some() {
+                        seen.push_str(&chunk);
+                    }
+                    on_output(OutputSource::Stdout, chunk);
+                    if let Some(stop) = &options.stop_when
+                        && stop(&seen)
+                    {
+                        return Err(Self::cancel_child(
+                            &mut child,
+                            options,
+                            CancelReason::Predicate,
+                        )
+                        .await);
+                    }
+                }
+                OutputSource::Stderr => {
+                    let chunk = if n == 0 {
+                        stderr_open = false;
+                        err_decoder.finish()
+                    } else {
+                        err_decoder.push(&err_buffer[..n])
+                    };
+                    let chunk = match err_stripper.as_mut() {
+                        Some(stripper) => stripper.push(&chunk),
+                        None => chunk,
+                    };
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    err_msg.push_str(&chunk);
+                    on_output(OutputSource::Stderr, chunk);
+                }
+            }
+        }
+
+        let status = tokio::select! {
+            biased;
+            status = child.wait() => status?,
+            reason = &mut interrupted => {
+                return Err(Self::cancel_child(&mut child, options, reason).await);
+            }
+        };
+        options.emit(Lifecycle::Exited {
+            code: status.code(),
+        });
+        Self::trace_exit(&status, started);
+        Ok(StreamedRun {
+            status,
+            saw_output,
+            stderr: err_msg,
+        })
+    }
+
+    /// Reads the next piece of stdout: whatever is available (`Chunked`) or
+    /// up to and including the next newline (`Lines`, into `line`).
+    async fn read_stdout(
+        stdout: &mut BufReader<ChildStdout>,
+        strategy: ReadStrategy,
+        buffer: &mut [u8],
+        line: &mut Vec<u8>,
+    ) -> std::io::Result<usize> {
+        match strategy {
+            ReadStrategy::Chunked => stdout.read(buffer).await,
+            ReadStrategy::Lines => {
+                line.clear();
+                stdout.read_until(b'\n', line).await
+            }
+        }
+    }
+
+    /// `options.version_cache` があれば解決後のバイナリごとに初回だけ `probe_version` を実行します
+    async fn cached_version(provider: &AgentProvider, options: &ExecOptions) -> Option<String> {
+        let Some(cache) = &options.version_cache else {
+            return Self::probe_version(provider, options).await;
+        };
+        let binary = provider.resolve_binary(&options.command_overrides);
+        if let Some(cached) = cache.inner.lock().await.get(&binary) {
+            return cached.clone();
+        }
+        let version = Self::probe_version(provider, options).await;
+        cache.inner.lock().await.insert(binary, version.clone());
+        version
+    }
+
+    /// `<binary> --version` の最初の空でない行を返します。プロセス内のツールは起動せずに固定の値を返します
+    async fn probe_version(provider: &AgentProvider, options: &ExecOptions) -> Option<String> {
+        if matches!(provider, AgentProvider::Dummy | AgentProvider::Mock) {
+            return Some(format!(
+                "{} {}",
+                provider.command_name(),
+                env!("CARGO_PKG_VERSION")
+            ));
+        }
+        let output = Self::version_output(provider, options).await?;
+        if !output.status.success() {
+            return None;
+        }
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .map(str::to_string)
+    }
+
+    /// Runs `<binary> --version`, giving up after `VERSION_PROBE_TIMEOUT`.
+    async fn version_output(
+        provider: &AgentProvider,
+        options: &ExecOptions,
+    ) -> Option<std::process::Output> {
+        let mut command = options.command_for(provider).ok()?;
+        command
+            .arg("--version")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        command.kill_on_drop(true);
+        options.configure(&mut command);
+        let runner = options.process_runner();
+        let output = runner.output(&mut command);
+        tokio::time::timeout(VERSION_PROBE_TIMEOUT, output)
+            .await
+            .ok()?
+            .ok()
+    }
+
+    /// Buffered counterpart of `Command::output()` that still reports
+    /// `Spawned` / `Exited` lifecycle events and honours `options.timeout`,
+    /// writing `input` to the child's stdin first when given.
+    async fn spawn_and_wait_with_input(
+        command: &mut Command,
+        options: &ExecOptions,
+        input: Option<String>,
+    ) -> Result<std::process::Output, AgentError> {
+        let mut child = Self::spawn(command, options, input)?;
+        let started = Instant::now();
+        let deadline = options
+            .timeout
+            .map(|timeout| tokio::time::Instant::now() + timeout);
+        options.emit(Lifecycle::Spawned { pid: child.id() });
+
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let drain = async {
+            let mut out = Vec::new();
+            let mut err = Vec::new();
+            let (out_read, err_read) = tokio::join!(
+                async {
+                    match stdout {
+                        Some(mut pipe) => pipe.read_to_end(&mut out).await.map(|_| ()),
+                        None => Ok(()),
+                    }
+                },
+                async {
+                    match stderr {
+                        Some(mut pipe) => pipe.read_to_end(&mut err).await.map(|_| ()),
+                        None => Ok(()),
+                    }
+                }
+            );
+            out_read?;
+            err_read?;
+            Ok::<_, std::io::Error>((out, err))
+        };
+        let (stdout, stderr) = tokio::select! {
+            biased;
+            drained = drain => drained?,
+            reason = options.interrupted(deadline) => {
+                return Err(Self::cancel_child(&mut child, options, reason).await);
+            }
+        };
+        let status = tokio::select! {
+            biased;
+            status = child.wait() => status?,
+            reason = options.interrupted(deadline) => {
+                return Err(Self::cancel_child(&mut child, options, reason).await);
+            }
+        };
+        options.emit(Lifecycle::Exited {
+            code: status.code(),
+        });
+        Self::trace_exit(&status, started);
+        Ok(std::process::Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// このマシンで使えるツールを並行して調べ、宣言順で返します ([`AgentProvider::available_tools`])
+    pub async fn available_tools() -> Vec<AgentProvider> {
+        AgentProvider::available_tools().await
+    }
+
+    /// amem が使えるかどうか。`amem --version` の確認はプロセスごとに 1 回だけ行い、結果を使い回します
+    pub async fn has_amem() -> bool {
+        Self::has_amem_with_options(&ExecOptions::default()).await
+    }
+
+    /// `has_amem` と同じですが、`ExecOptions::amem_binary` と `amem_probe_timeout` に従います
+    /// (確認の結果はバイナリごとに使い回します)
+    pub async fn has_amem_with_options(options: &ExecOptions) -> bool {
+        let timeout = options
+            .amem_probe_timeout
+            .unwrap_or(DEFAULT_AMEM_PROBE_TIMEOUT);
+        amem::is_available(
+            options.amem_binary(),
+            timeout,
+            options.process_runner().as_ref(),
+        )
+        .await
+    }
+
+    /// `has_amem` のキャッシュを破棄し、次の呼び出しで再確認させます (amem を後からインストールした場合やテスト用)
+    pub async fn reset_amem_cache() {
+        amem::reset_availability().await;
+    }
+
+    /// amem の記憶を取得します。amem が使えない、または出力を解釈できない場合は `None` を返します
+    pub async fn fetch_amem_context() -> Option<AmemContext> {
+        Self::try_fetch_amem_context().await.ok()
+    }
+
+    /// `fetch_amem_context` と同じですが、失敗の理由を `AmemError` で返します
+    pub async fn try_fetch_amem_context() -> Result<AmemContext, AmemError> {
+        amem::fetch_today(Path::new("amem"), &TokioRunner).await
+    }
+
+    /// amem の記憶から Snapshot 文字列を取得します
+    pub async fn fetch_context() -> String {
+        Self::fetch_amem_context()
+            .await
+            .map(|context| context.to_markdown())
+            .unwrap_or_default()
+    }
+
+    /// amem の記憶から初期化用プロンプトを構築します
+    pub async fn build_init_prompt() -> String {
+        Self::build_init_prompt_with(&ContextDelimiters::default()).await
+    }
+
+    /// 指定した区切りで amem コンテキストを囲んだ初期化プロンプトを構築します
+    pub async fn build_init_prompt_with(delimiters: &ContextDelimiters) -> String {
+        let options = ExecOptions {
+            context_delimiters: delimiters.clone(),
+            ..ExecOptions::default()
+        };
+        Self::build_init_prompt_with_options(&options).await
+    }
+
+    /// `ExecOptions::amem_binary` の amem から記憶を取得し、`context_delimiters` で囲んだ初期化プロンプトを構築します
+    pub async fn build_init_prompt_with_options(options: &ExecOptions) -> String {
+        let runner = options.process_runner();
+        let context = amem::fetch_today(options.amem_binary(), runner.as_ref())
+            .await
+            .map(|context| context.to_markdown())
+            .unwrap_or_default();
+        Self::init_prompt_from_context(&context, &options.context_delimiters)
+    }
+
+    /// amem を一度だけ呼び出し、複数ツールのシードで再利用できる初期化プロンプトを返します
+    pub async fn prepare_init_prompt() -> PreparedInitPrompt {
+        Self::prepare_init_prompt_with_options(&ExecOptions::default()).await
+    }
+
+    /// `prepare_init_prompt` と同じですが、`ExecOptions::amem_binary` の amem と `context_delimiters` を使います
+    pub async fn prepare_init_prompt_with_options(options: &ExecOptions) -> PreparedInitPrompt {
+        let runner = options.process_runner();
+        let (context, amem_error) =
+            match amem::fetch_today(options.amem_binary(), runner.as_ref()).await {
+                Ok(context) => (context.to_markdown(), None),
+                Err(e) => (String::new(), Some(e)),
+            };
+        let prompt = Self::init_prompt_from_context(&context, &options.context_delimiters);
+        PreparedInitPrompt {
+            context,
+            prompt,
+            amem_error,
+        }
+    }
+
+    fn init_prompt_from_context(context: &str, delimiters: &ContextDelimiters) -> String {
+        let context = if context.is_empty() {
+            "(amem context is empty or unavailable)"
+        } else {
+            context
+        };
+        let instruction = format!(
+            "Load the amem snapshot between {} and {} above for the next interactive session and reply exactly `MEMORY_READY`.",
+            delimiters.open, delimiters.close
+        );
+        delimiters.wrap(context, &instruction)
+    }
+
+    /// 記憶コンテキストを区切りで囲み、ユーザーのプロンプトをその後ろに置いた再注入用のプロンプトを返します
+    pub fn prompt_with_context(
+        context: &str,
+        prompt: &str,
+        delimiters: &ContextDelimiters,
+    ) -> String {
+        delimiters.wrap(context, prompt)
+    }
+
+    pub async fn execute_stream<F>(
+        provider: AgentProvider,
+        prompt: &str,
+        on_chunk: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        Self::execute_stream_with_options(provider, prompt, &ExecOptions::default(), on_chunk).await
+    }
+
+    pub async fn execute_stream_with_options<F>(
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+        on_chunk: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send,
+    {
+        validate_prompt(prompt)?;
+        let prompt = &options.stamped_prompt(prompt);
+        let turn = Self::metered_turn(provider.clone(), prompt, options, on_chunk);
+        options.with_hooks(&provider, prompt, turn).await
+    }
+
+    async fn metered_turn<F>(
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+        mut on_chunk: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send,
+    {
+        let Some(metrics) = &options.metrics else {
+            return Self::stream_turn(provider, prompt, options, on_chunk).await;
+        };
+        let started = Instant::now();
+        let mut collected = String::new();
+        let result = Self::stream_turn(provider.clone(), prompt, options, |chunk| {
+            collected.push_str(&chunk);
+            on_chunk(chunk);
+        })
+        .await;
+        let tokens = SessionManager::extract_token_total(&collected);
+        metrics.record(&provider, started.elapsed(), result.is_ok(), tokens);
+        result
+    }
+
+    async fn stream_turn<F>(
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+        mut on_chunk: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send,
+    {
+        if provider == AgentProvider::Dummy {
+            let started = Instant::now();
+            options.emit(Lifecycle::Spawned { pid: None });
+            options.emit(Lifecycle::FirstByte {
+                at: started.elapsed(),
+            });
+            on_chunk(prompt.to_string());
+            options.emit(Lifecycle::Exited { code: Some(0) });
+            return Ok(());
+        }
+
+        if provider == AgentProvider::Mock {
+            let started = Instant::now();
+            options.emit(Lifecycle::Spawned { pid: None });
+            options.emit(Lifecycle::FirstByte {
+                at: started.elapsed(),
+            });
+            on_chunk("Mock stream: pong".into());
+            options.emit(Lifecycle::Exited { code: Some(0) });
+            return Ok(());
+        }
+
+        if provider == AgentProvider::Codex {
+            let mut command = Self::one_shot_command(&provider, prompt, options)?;
+            let input = options.stdin_prompt(&provider, prompt);
+            let output = Self::spawn_and_wait_with_input(&mut command, options, input).await?;
+
+            if !output.status.success() {
+                return Err(AgentError::NonZeroExit {
+                    command: "codex exec".to_string(),
+                    code: output.status.code(),
+                    detail: Self::failure_detail(&output),
+                });
+            }
+
+            let out_str = options.clean_output(&output.stdout);
+            if let Some(response) = SessionManager::extract_response(&out_str) {
+                on_chunk(response);
+                return Ok(());
+            }
+
+            return Err(AgentError::ResponseMissing("codex exec".to_string()));
+        }
+
+        let mut command = Self::one_shot_command(&provider, prompt, options)?;
+        let input = options.stdin_prompt(&provider, prompt);
+        Self::stream_command(&mut command, options, input, &mut on_chunk)
+            .await?
+            .check(provider.command_name())?;
+        Ok(())
+    }
+
+    /// 出力を一時ファイルへ書き出し、そのファイルを返します。戻り値を drop するとファイルは削除されます
+    pub async fn execute_stream_to_tempfile(
+        provider: AgentProvider,
+        prompt: &str,
+    ) -> Result<TempOutput, AgentError> {
+        Self::execute_stream_to_tempfile_with_options(provider, prompt, &ExecOptions::default())
+            .await
+    }
+
+    pub async fn execute_stream_to_tempfile_with_options(
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+    ) -> Result<TempOutput, AgentError> {
+        use std::io::Write;
+
+        let (output, file) = TempOutput::create()?;
+        let mut writer = std::io::BufWriter::new(file);
+        let mut write_error = None;
+        Self::execute_stream_with_options(provider, prompt, options, |chunk| {
+            if write_error.is_none()
+                && let Err(e) = writer.write_all(chunk.as_bytes())
+            {
+                write_error = Some(e);
+            }
+        })
+        .await?;
+        if let Some(e) = write_error {
+            return Err(e.into());
+        }
+        writer.flush()?;
+        Ok(output)
+    }
+
+    /// コールバックの代わりに、デコード済みチャンクを `Stream` として返します。
+    /// 子プロセスのエラーは最後の要素として `Err` で届きます。ストリームを drop すると実行はキャンセルされます。
+    pub fn execute_stream_iter(
+        provider: AgentProvider,
+        prompt: &str,
+    ) -> impl Stream<Item = Result<String, AgentError>> + Send + use<> {
+        Self::execute_stream_iter_with_options(provider, prompt, &ExecOptions::default())
+    }
+
+    pub fn execute_stream_iter_with_options(
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+    ) -> impl Stream<Item = Result<String, AgentError>> + Send + use<> {
+        // The read loop delivers chunks synchronously, so the channel is
+        // unbounded; a dropped receiver cancels the child instead. The stream
+        // cancels a child of the caller's token so other runs sharing that
+        // token keep going.
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let token = options
+            .cancel
+            .as_ref()
+            .map_or_else(CancellationToken::new, CancellationToken::child_token);
+        let options = options.clone().with_cancel_token(token.clone());
+        let prompt = prompt.to_string();
+        tokio::spawn(async move {
+            // Stop the agent as soon as the stream is dropped, even while it
+            // is not printing anything.
+            let watcher = tokio::spawn({
+                let tx = tx.clone();
+                async move {
+                    tx.closed().await;
+                    token.cancel();
+                }
+            });
+            let _watcher = AbortOnDrop(watcher.abort_handle());
+            let chunks = tx.clone();
+            let result =
+                Self::execute_stream_with_options(provider, &prompt, &options, move |chunk| {
+                    let _ = chunks.send(Ok(chunk));
+                })
+                .await;
+            if let Err(err) = result {
+                let _ = tx.send(Err(err));
+            }
+        });
+        UnboundedReceiverStream::new(rx)
+    }
+
+    /// 単発で実行し、応答全体を返します。JSON 出力に `response` があればそれを、なければ前後の空白を除いた標準出力を返します
+    pub async fn execute(provider: AgentProvider, prompt: &str) -> Result<String, AgentError> {
+        Self::execute_with_options(provider, prompt, &ExecOptions::default()).await
+    }
+
+    pub async fn execute_with_options(
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+    ) -> Result<String, AgentError> {
+        let mut output = String::new();
+        Self::execute_stream_with_options(provider, prompt, options, |chunk| {
+            output.push_str(&chunk)
+        })
+        .await?;
+        Ok(SessionManager::extract_response(&output).unwrap_or_else(|| output.trim().to_string()))
+    }
+
+    /// チャンクをコールバックへ配信しながら単発で実行し、終了後に出力を解析した `RunResult` を返します
+    pub async fn execute_with_result<F>(
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+        mut on_chunk: F,
+    ) -> Result<RunResult, AgentError>
+    where
+        F: FnMut(String) + Send,
+    {
+        let timing = Arc::new(std::sync::Mutex::new(ProcessTiming::default()));
+        let options = &options.clone().tap_lifecycle({
+            let timing = Arc::clone(&timing);
+            move |event| timing.lock().unwrap().observe(event)
+        });
+        let mut output = String::new();
+        Self::execute_stream_with_options(provider.clone(), prompt, options, |chunk| {
+            output.push_str(&chunk);
+            on_chunk(chunk);
+        })
+        .await?;
+        let tool_version = Self::cached_version(&provider, options).await;
+        let timing = timing.lock().unwrap();
+        Ok(RunResult {
+            response: SessionManager::extract_response(&output).unwrap_or_else(|| output.clone()),
+            stop_reason: SessionManager::extract_stop_reason(&output),
+            continuations: 0,
+            tool_version,
+            model: SessionManager::extract_model(&output).or_else(|| options.model.clone()),
+            exit_code: timing.exit_code,
+            duration: timing.total,
+            stdout_bytes: output.len(),
+            session_id: SessionManager::extract_session_id(&output),
+            seed_prompt: None,
+            output,
+        })
+    }
+
+    /// 出力を改行で区切り、完結した行ごとに (改行を除いて) 配信します。
+    /// 改行で終わらない最後の行は終了時に配信します
+    pub async fn execute_stream_lines<F>(
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+        mut on_line: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send,
+    {
+        let mut framer = LineFramer::default();
+        Self::execute_stream_with_options(provider, prompt, options, |chunk| {
+            framer.push(&chunk).into_iter().for_each(&mut on_line)
+        })
+        .await?;
+        framer.finish().into_iter().for_each(on_line);
+        Ok(())
+    }
+
+    /// 出力を行単位に組み立て直し、JSON 行を `AgentEvent` に変換して配信します
+    pub async fn execute_events<F>(
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+        mut on_event: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(AgentEvent) + Send,
+    {
+        let mut parser = EventParser::default();
+        Self::execute_stream_with_options(provider, prompt, options, |chunk| {
+            parser.push(&chunk, &mut on_event)
+        })
+        .await?;
+        parser.finish(&mut on_event);
+        Ok(())
+    }
+
+    /// `section_delimiter` が設定されていればセクションが完結するたびに `StreamItem::Section` を、
+    /// なければ各チャンクを `StreamItem::Chunk` として配信します
+    pub async fn execute_stream_items<F>(
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+        mut on_item: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(StreamItem) + Send,
+    {
+        let Some(delimiter) = &options.section_delimiter else {
+            return Self::execute_stream_with_options(provider, prompt, options, |chunk| {
+                on_item(StreamItem::Chunk(chunk))
+            })
+            .await;
+        };
+        let mut splitter = SectionSplitter::new(delimiter.as_str());
+        Self::execute_stream_with_options(provider, prompt, options, |chunk| {
+            splitter.push(&chunk, &mut on_item)
+        })
+        .await?;
+        splitter.finish(&mut on_item);
+        Ok(())
+    }
+
+    /// stdout と stderr を 1 つの select ループで読み、到着順にタグ付けして配信します
+    pub async fn execute_stream_merged<F>(
+        provider: AgentProvider,
+        prompt: &str,
+        options: &ExecOptions,
+        mut on_output: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(TaggedChunk) + Send + 'static,
+    {
+        let mut emit = |source: OutputSource, text: String| on_output(TaggedChunk { source, text });
+
+        if provider == AgentProvider::Dummy {
+            let started = Instant::now();
+            options.emit(Lifecycle::Spawned { pid: None });
+            options.emit(Lifecycle::FirstByte {
+                at: started.elapsed(),
+            });
+            emit(OutputSource::Stdout, prompt.to_string());
+            options.emit(Lifecycle::Exited { code: Some(0) });
+            return Ok(());
+        }
+
+        if provider == AgentProvider::Mock {
+            let started = Instant::now();
+            options.emit(Lifecycle::Spawned { pid: None });
+            options.emit(Lifecycle::FirstByte {
+                at: started.elapsed(),
+            });
+            emit(OutputSource::Stdout, "Mock stream: ".into());
+            emit(OutputSource::Stderr, "mock: warming up\n".into());
+            emit(OutputSource::Stdout, "pong".into());
+            options.emit(Lifecycle::Exited { code: Some(0) });
+            return Ok(());
+        }
+
+        let mut command = Self::one_shot_command(&provider, prompt, options)?;
+        let input = options.stdin_prompt(&provider, prompt);
+        if provider == AgentProvider::Codex {
+            // Codex is buffered (JSONL), so arrival order is unknown; report
+            // stderr before the extracted response.
+            let output = Self::spawn_and_wait_with_input(&mut command, options, input).await?;
+            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+            if !stderr.is_empty() {
+                emit(OutputSource::Stderr, stderr);
+            }
+            if !output.status.success() {
+                return Err(AgentError::NonZeroExit {
+                    command: "codex exec".to_string(),
+                    code: output.status.code(),
+                    detail: Self::failure_detail(&output),
+                });
+            }
+            let out_str = options.clean_output(&output.stdout);
+            if let Some(response) = SessionManager::extract_response(&out_str) {
+                emit(OutputSource::Stdout, response);
+                return Ok(());
+            }
+            return Err(AgentError::ResponseMissing("codex exec".to_string()));
+        }
+
+        Self::stream_command_merged(&mut command, options, input, &mut emit)
+            .await?
+            .check(provider.command_name())?;
+        Ok(())
+    }
+
+    /// 要約用のプロンプトを組み立てます。`code_aware` が有効でコード中心の対話なら、コード変更向けの指示を使います
+    pub fn summary_prompt(transcript: &str, code_aware: bool) -> String {
+        let instruction = if code_aware && Self::is_code_heavy(transcript) {
+            CODE_SUMMARY_INSTRUCTION
+        } else {
+            SUMMARY_INSTRUCTION
+        };
+        format!("{}\n{}", instruction, transcript)
+    }
+
+    /// Treats a transcript as code-heavy when at least 30% of its non-blank
+    /// lines sit inside ``` fences or carry inline `code` spans.
+    fn is_code_heavy(transcript: &str) -> bool {
+        let mut in_fence = false;
+        let mut lines = 0;
+        let mut code_lines = 0;
+        for line in transcript.lines().filter(|l| !l.trim().is_empty()) {
+            lines += 1;
+            let is_fence = line.trim_start().starts_with("```");
+            if is_fence {
+                in_fence = !in_fence;
+            }
+            if is_fence || in_fence || line.matches('`').count() >= 2 {
+                code_lines += 1;
+            }
+        }
+        lines > 0 && code_lines * 10 >= lines * 3
+    }
+
+    pub async fn summarize_and_record(
+        provider: AgentProvider,
+        transcript: &str,
+    ) -> Result<(), AgentError> {
+        Self::summarize_and_record_with_options(provider, transcript, &ExecOptions::default()).await
+    }
+
+    pub async fn summarize_and_record_with_options(
+        provider: AgentProvider,
+        transcript: &str,
+        options: &ExecOptions,
+    ) -> Result<(), AgentError> {
+        Self::summarize_and_record_as(provider, transcript, &RecordOptions::default(), options)
+            .await
+    }
+
+    /// 要約を `record` の source / kind / tags で amem に記録します。`options.normalize` を設定した場合は
+    /// 要約の前に対話内容もそれで正規化します。
+    /// `amem keep` が失敗した場合は `AgentError::Amem` を返します。amem が使えない場合は
+    /// `RecordOptions::require_amem` が有効なときだけエラーにし、それ以外は何もせずに戻ります
+    pub async fn summarize_and_record_as(
+        provider: AgentProvider,
+        transcript: &str,
+        record: &RecordOptions,
+        options: &ExecOptions,
+    ) -> Result<(), AgentError> {
+        if provider == AgentProvider::Mock || provider == AgentProvider::Dummy {
+            return Ok(());
+        }
+        let transcript = match options.normalize {
+            Some(normalization) => normalization.apply(transcript),
+            None => transcript.to_string(),
+        };
+        if transcript.is_empty() {
+            return Ok(());
+        }
+        if !Self::has_amem_with_options(options).await {
+            if record.require_amem {
+                return Err(AmemError::NotInstalled {
+                    detail: "amem --version failed".to_string(),
+                }
+                .into());
+            }
+            return Ok(());
+        }
+        let line = Self::summarize(provider, &transcript, record, options).await?;
+        if line.is_empty() {
+            return Ok(());
+        }
+        Self::keep(
+            options.amem_binary(),
+            &line,
+            record,
+            options.process_runner().as_ref(),
+        )
+        .await
+    }
+
+    /// 複数の記録を `record` の source / kind / tags でまとめて amem に記録します。
+    /// amem には一括登録のコマンドが無いため、`amem keep` を最大 8 個まで並行して起動し、すべての終了を待ちます
+    /// (記録される順序は保証しません)。空の行は記録しません。失敗した記録があれば最初のエラーを返します。
+    /// amem が使えない場合は `RecordOptions::require_amem` が有効なときだけエラーにします
+    pub async fn keep_many<S: AsRef<str>>(
+        lines: &[S],
+        record: &RecordOptions,
+    ) -> Result<(), AgentError> {
+        Self::keep_many_with_options(lines, record, &ExecOptions::default()).await
+    }
+
+    /// `keep_many` と同じですが、`ExecOptions::amem_binary` と `amem_probe_timeout` に従います
+    pub async fn keep_many_with_options<S: AsRef<str>>(
+        lines: &[S],
+        record: &RecordOptions,
+        options: &ExecOptions,
+    ) -> Result<(), AgentError> {
+        if lines.iter().all(|line| line.as_ref().trim().is_empty()) {
+            return Ok(());
+        }
+        if !Self::has_amem_with_options(options).await {
+            if record.require_amem {
+                return Err(AmemError::NotInstalled {
+                    detail: "amem --version failed".to_string(),
+                }
+                .into());
+            }
+            return Ok(());
+        }
+        let amem = options.amem_binary();
+        let runner = options.process_runner();
+        let slots = Arc::new(tokio::sync::Semaphore::new(MAX_CONCURRENT_KEEPS));
+        let mut keeps = tokio::task::JoinSet::new();
+        for line in lines.iter().map(|line| line.as_ref().trim()) {
+            if line.is_empty() {
+                continue;
+            }
+            let (amem, line, record) = (amem.to_path_buf(), line.to_string(), record.clone());
+            let (slots, runner) = (Arc::clone(&slots), Arc::clone(&runner));
+            keeps.spawn(async move {
+                let _slot = slots
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore is never closed");
+                Self::keep(&amem, &line, &record, runner.as_ref()).await
+            });
+        }
+        keeps.join_all().await.into_iter().collect()
+    }
+
+    async fn keep(
+        amem: &Path,
+        line: &str,
+        record: &RecordOptions,
+        runner: &dyn ProcessRunner,
+    ) -> Result<(), AgentError> {
+        let output = runner
+            .output(&mut Self::keep_command(amem, line, record))
+            .await
+            .map_err(|e| AmemError::NotInstalled {
+                detail: e.to_string(),
+            })?;
+        if !output.status.success() {
+            return Err(AmemError::NonZeroExit {
+                command: "amem keep".to_string(),
+                code: output.status.code(),
+                detail: Self::failure_detail(&output),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// `record.draft_kind` が設定されていれば、実行前に作業中の記録を amem に残し、
+    /// 終了後に成否を `record.kind` で記録してから実行結果を返します。2 つの記録は
+    /// 同じ `draft:<id>` タグで対応付けるため、途中でプロセスが落ちても着手した記録が残ります。
+    /// amem が使えない場合は `RecordOptions::require_amem` が有効なときだけエラーにします
+    pub async fn execute_with_draft<F>(
+        provider: AgentProvider,
+        prompt: &str,
+        record: &RecordOptions,
+        options: &ExecOptions,
+        on_chunk: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send,
+    {
+        let amem = if record.draft_kind.is_some() && Self::has_amem_with_options(options).await {
+            Some(options.amem_binary())
+        } else if record.draft_kind.is_some() && record.require_amem {
+            return Err(AmemError::NotInstalled {
+                detail: "amem --version failed".to_string(),
+            }
+            .into());
+        } else {
+            None
+        };
+        Self::execute_drafted(amem, provider, prompt, record, options, on_chunk).await
+    }
+
+    async fn execute_drafted<F>(
+        amem: Option<&Path>,
+        provider: AgentProvider,
+        prompt: &str,
+        record: &RecordOptions,
+        options: &ExecOptions,
+        on_chunk: F,
+    ) -> Result<(), AgentError>
+    where
+        F: FnMut(String) + Send,
+    {
+        let (Some(amem), Some(draft_kind)) = (amem, &record.draft_kind) else {
+            return Self::execute_stream_with_options(provider, prompt, options, on_chunk).await;
+        };
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let tagged = record
+            .clone()
+            .with_tag(format!("draft:{}-{}", std::process::id(), nanos));
+        let task: String = prompt
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .take(80)
+            .collect();
+
+        let runner = options.process_runner();
+        let draft = tagged.clone().with_kind(draft_kind);
+        let drafted = Self::keep(
+            amem,
+            &format!("in progress: {task}"),
+            &draft,
+            runner.as_ref(),
+        )
+        .await;
+        if record.require_amem {
+            drafted?;
+        }
+        let result = Self::execute_stream_with_options(provider, prompt, options, on_chunk).await;
+        let line = match &result {
+            Ok(()) => format!("completed: {task}"),
+            Err(e) => format!("failed: {task} ({e})"),
+        };
+        let finalized = Self::keep(amem, &line, &tagged, runner.as_ref()).await;
+        result?;
+        if record.require_amem {
+            finalized?;
+        }
+        Ok(())
+    }
+
+    /// 対話内容を `record` の指示で要約し、正規化した結果を返します (amem には記録しません)
+    pub async fn summarize(
+        provider: AgentProvider,
+        transcript: &str,
+        record: &RecordOptions,
+        options: &ExecOptions,
+    ) -> Result<String, AgentError> {
+        let prompt = record.summary_prompt(transcript, options.code_aware_summary);
+        validate_prompt(&prompt)?;
+        let summary = match provider {
+            AgentProvider::Mock | AgentProvider::Dummy => {
+                Self::execute_with_options(provider, &prompt, options).await?
+            }
+            _ => {
+                let mut command = Self::one_shot_command(&provider, &prompt, options)?;
+                let input = options.stdin_prompt(&provider, &prompt);
+                let output = Self::spawn_and_wait_with_input(&mut command, options, input).await?;
+                if !output.status.success() {
+                    return Err(AgentError::NonZeroExit {
+                        command: provider.command_name().to_string(),
+                        code: output.status.code(),
+                        detail: Self::failure_detail(&output),
+                    });
+                }
+                if provider == AgentProvider::Codex {
+                    SessionManager::extract_response(&String::from_utf8_lossy(&output.stdout))
+                        .unwrap_or_default()
+                } else {
+                    String::from_utf8_lossy(&output.stdout).into_owned()
+                }
+            }
+        };
+        Ok(options.normalize.unwrap_or_default().apply(&summary))
+    }
+
+    fn keep_command(amem: &Path, line: &str, record: &RecordOptions) -> Command {
+        let mut command = Command::new(amem);
+        command
+            .arg("keep")
+            .arg(line)
+            .arg("--kind")
+            .arg(&record.kind)
+            .arg("--source")
+            .arg(&record.source);
+        for tag in &record.tags {
+            command.arg("--tag").arg(tag);
+        }
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex as StdMutex};
+
+    // ─── AgentProvider tests ───────────────────────────────────────────────────────
+
+    #[test]
+    fn test_agent_provider_command_name_gemini() {
+        assert_eq!(AgentProvider::Gemini.command_name(), "gemini");
+    }
+
+    #[test]
+    fn test_agent_provider_command_name_claude() {
+        assert_eq!(AgentProvider::Claude.command_name(), "claude");
+    }
+
+    #[test]
+    fn test_agent_provider_command_name_codex() {
+        assert_eq!(AgentProvider::Codex.command_name(), "codex");
+    }
+
+    #[test]
+    fn test_agent_provider_command_name_opencode() {
+        assert_eq!(AgentProvider::OpenCode.command_name(), "opencode");
+    }
+
+    #[test]
+    fn test_agent_provider_command_name_cursor_and_aider() {
+        assert_eq!(AgentProvider::Cursor.command_name(), "cursor-agent");
+        assert_eq!(AgentProvider::Aider.command_name(), "aider");
+    }
+
+    #[tokio::test]
+    async fn test_mock_is_always_available() {
+        assert!(AgentProvider::Mock.is_available().await);
+        assert!(AgentProvider::Dummy.is_available().await);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_is_available_with_options_uses_the_override_and_gives_up_on_a_hang() {
+        let log = temp_path("available-probe.log");
+        let _ = std::fs::remove_file(&log);
+        let ready = fake_agent(
+            "available-ready.sh",
+            &format!("echo ready >> {}\necho '1.0.0'\n", log.display()),
+        );
+        let hung = fake_agent("available-hung.sh", "exec sleep 600\n");
+        let with_ready = ExecOptions::new().with_command_override(AgentProvider::Codex, &ready);
+        let with_hung = ExecOptions::new().with_command_override(AgentProvider::Codex, &hung);
+        let missing = ExecOptions::new()
+            .with_command_override(AgentProvider::Codex, temp_path("available-missing"));
+        let available = AgentProvider::Codex
+            .is_available_with_options(&with_ready)
+            .await;
+        let absent = AgentProvider::Codex
+            .is_available_with_options(&missing)
+            .await;
+        // The paused clock jumps straight to the probe deadline while the stub sleeps.
+        tokio::time::pause();
+        let hanging = AgentProvider::Codex
+            .is_available_with_options(&with_hung)
+            .await;
+        let probes = std::fs::read_to_string(&log).unwrap_or_default();
+        for path in [&log, &ready, &hung] {
+            let _ = std::fs::remove_file(path);
+        }
+
+        assert!(available);
+        assert!(!hanging);
+        assert!(!absent);
+        assert_eq!(probes, "ready\n");
+    }
+
+    #[tokio::test]
+    async fn test_available_tools_excludes_in_process_tools_in_declaration_order() {
+        let available = AgentProvider::available_tools().await;
+        assert!(!available.contains(&AgentProvider::Dummy));
+        assert!(!available.contains(&AgentProvider::Mock));
+        assert_eq!(AgentExecutor::available_tools().await, available);
+        let positions: Vec<usize> = available
+            .iter()
+            .map(|p| AgentProvider::ALL.iter().position(|q| q == p).unwrap())
+            .collect();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[tokio::test]
+    async fn test_summarize_uses_record_summary_template() {
+        let record = RecordOptions::default()
+            .with_summary_template("Summarize in one English line: {transcript}");
+        let summary = AgentExecutor::summarize(
+            AgentProvider::Dummy,
+            "User: hi Assistant: hello",
+            &record,
+            &ExecOptions::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            summary,
+            "Summarize in one English line: User: hi Assistant: hello"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_failed_summary_is_an_error_and_nothing_is_recorded() {
+        let log = temp_path("failed-summary-keep.log");
+        let _ = std::fs::remove_file(&log);
+        let amem = fake_agent(
+            "failed-summary-amem.sh",
+            &format!(
+                "[ \"$1\" = keep ] || exit 0\necho \"$2\" >> {}\n",
+                log.display()
+            ),
+        );
+        let claude = fake_agent(
+            "failed-summary-claude.sh",
+            "echo 'Error: model overloaded'\necho 'try again later' >&2\nexit 2\n",
+        );
+        let options = ExecOptions::new()
+            .with_command_override(AgentProvider::Claude, &claude)
+            .with_amem_binary(&amem);
+        let result = AgentExecutor::summarize_and_record_as(
+            AgentProvider::Claude,
+            "User: hi Assistant: hello",
+            &RecordOptions::default(),
+            &options,
+        )
+        .await;
+        let kept = std::fs::read_to_string(&log).unwrap_or_default();
+        let nul = AgentExecutor::summarize(
+            AgentProvider::Claude,
+            "bad\0transcript",
+            &RecordOptions::default(),
+            &options,
+        )
+        .await;
+        for path in [&log, &amem, &claude] {
+            let _ = std::fs::remove_file(path);
+        }
+
+        assert!(matches!(
+            result,
+            Err(AgentError::NonZeroExit { code: Some(2), ref detail, .. }) if detail == "try again later"
+        ));
+        assert_eq!(kept, "");
+        assert!(matches!(nul, Err(AgentError::PromptContainsNul { .. })));
+    }
+
+    #[test]
+    fn test_default_record_options_keep_the_builtin_summary_prompt() {
+        let transcript = "We discussed the roadmap for next week.";
+        assert_eq!(
+            RecordOptions::default().summary_prompt(transcript, false),
+            AgentExecutor::summary_prompt(transcript, false)
+        );
+        assert_eq!(
+            RecordOptions::default()
+                .with_summary_template("Summarize:")
+                .summary_prompt(transcript, false),
+            format!("Summarize:\n{transcript}")
+        );
+    }
+
+    #[test]
+    fn test_keep_command_uses_record_options() {
+        let record = RecordOptions::default()
+            .with_source("acore")
+            .with_kind("note")
+            .with_tag("cli")
+            .with_tag("daily");
+        let command = AgentExecutor::keep_command(Path::new("amem"), "did things", &record);
+        let args: Vec<_> = command
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            args,
+            [
+                "keep",
+                "did things",
+                "--kind",
+                "note",
+                "--source",
+                "acore",
+                "--tag",
+                "cli",
+                "--tag",
+                "daily"
+            ]
+        );
+        assert_eq!(RecordOptions::default().source, "yuiclaw");
+        assert_eq!(RecordOptions::default().kind, "activity");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_prompt_larger_than_arg_max_is_piped_through_stdin() {
+        let script = fake_agent("stdin-prompt.sh", "echo \"args: $*\"\nwc -c\n");
+        // Linux caps a single argument at 128 KiB and the whole argv at ~2 MiB.
+        let prompt = "x".repeat(4 * 1024 * 1024);
+        let options = ExecOptions::new().with_command_override(AgentProvider::Claude, &script);
+
+        let as_arg =
+            AgentExecutor::execute_with_options(AgentProvider::Claude, &prompt, &options).await;
+        let piped = AgentExecutor::execute_with_options(
+            AgentProvider::Claude,
+            &prompt,
+            &options.clone().with_prompt_via_stdin(true),
+        )
+        .await;
+        let _ = std::fs::remove_file(&script);
+
+        assert!(
+            matches!(as_arg, Err(AgentError::SpawnFailed { .. })),
+            "{as_arg:?}"
+        );
+        let piped = piped.unwrap();
+        assert!(piped.starts_with("args: --print\n"), "{piped}");
+        assert!(piped.contains(&prompt.len().to_string()), "{piped}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_agent_waiting_on_stdin_gets_eof_and_completes() {
+        let script = "cat; echo done";
+        let mut output = String::new();
+        let run = AgentExecutor::stream_command(
+            &mut sh_command(script),
+            &ExecOptions::new().with_timeout(Duration::from_secs(2)),
+            None,
+            &mut |chunk| output.push_str(&chunk),
+        )
+        .await
+        .unwrap();
+        assert!(run.status.success());
+        assert_eq!(output, "done\n");
+
+        let started = Instant::now();
+        let mut output = String::new();
+        AgentExecutor::stream_command(
+            &mut sh_command(script),
+            &ExecOptions::new()
+                .with_timeout(Duration::from_secs(2))
+                .with_stdin_eof_delay(Duration::from_millis(200)),
+            Some("typed".to_string()),
+            &mut |chunk| output.push_str(&chunk),
+        )
+        .await
+        .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(output, "typeddone\n");
+    }
+
+    #[tokio::test]
+    async fn test_prompt_with_null_byte_is_rejected_before_spawning() {
+        let err = AgentExecutor::execute_stream(AgentProvider::Mock, "bad\0prompt", |_| {})
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::PromptContainsNul { offset: 3 }));
+        assert!(err.to_string().contains("null byte"));
+
+        let options = ExecOptions::new()
+            .with_prompt_via_stdin(true)
+            .with_command_override(AgentProvider::Claude, "/nonexistent/claude");
+        let err = SessionManager::new()
+            .execute_with_resume_with_options(AgentProvider::Claude, "\0", &options, |_| {})
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::PromptContainsNul { offset: 0 }));
+
+        let seeding = ExecOptions {
+            init_prompt: Some("init\0".to_string()),
+            ..options
+        };
+        let err = SessionManager::new()
+            .execute_with_resume_with_options(AgentProvider::Claude, "hi", &seeding, |_| {})
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::PromptContainsNul { offset: 4 }));
+    }
+
+    #[test]
+    fn test_stdin_prompt_uses_codex_placeholder_and_keeps_aider_argument() {
+        let options = ExecOptions::new().with_prompt_via_stdin(true);
+        let args = |provider: AgentProvider| -> Vec<String> {
+            AgentExecutor::one_shot_command(&provider, "hi", &options)
+                .unwrap()
+                .as_std()
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect()
+        };
+        assert_eq!(args(AgentProvider::Codex), ["exec", "--json", "-"]);
+        assert_eq!(args(AgentProvider::Claude), ["--print"]);
+        assert_eq!(
+            args(AgentProvider::Aider).last().map(String::as_str),
+            Some("hi")
+        );
+    }
+
+    #[test]
+    fn test_capabilities_reflect_per_tool_flags() {
+        let claude = AgentProvider::Claude.capabilities();
+        assert!(claude.entries().iter().all(|(_, supported)| *supported));
+        let aider = AgentProvider::Aider.capabilities();
+        assert!(!aider.resume && !aider.json_output && !aider.stdin_prompt);
+        assert!(aider.model_selection && aider.permission_modes);
+        let mock = AgentProvider::Mock.capabilities();
+        assert!(mock.entries().iter().all(|(_, supported)| !*supported));
+    }
+
+    #[test]
+    fn test_agent_provider_aider_does_not_support_resume() {
+        assert!(AgentProvider::Cursor.supports_resume());
+        assert!(!AgentProvider::Aider.supports_resume());
+    }
+
+    #[test]
+    fn test_one_shot_command_for_aider_passes_prompt_as_message() {
+        let command = AgentExecutor::one_shot_command(
+            &AgentProvider::Aider,
+            "fix the bug",
+            &ExecOptions::default(),
+        )
+        .unwrap();
+        let args: Vec<_> = command
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            args,
+            ["--yes-always", "--no-pretty", "--message", "fix the bug"]
+        );
+    }
+
+    #[test]
+    fn test_one_shot_command_inserts_model_flag_before_prompt() {
+        let args = |provider: AgentProvider| -> Vec<String> {
+            let options = ExecOptions::new().with_model("gpt-x");
+            AgentExecutor::one_shot_command(&provider, "hi", &options)
+                .unwrap()
+                .as_std()
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect()
+        };
+        assert_eq!(args(AgentProvider::Claude), ["--model", "gpt-x", "hi"]);
+        assert_eq!(
+            args(AgentProvider::Aider),
+            [
+                "--yes-always",
+                "--no-pretty",
+                "--model",
+                "gpt-x",
+                "--message",
+                "hi"
+            ]
+        );
+        assert_eq!(
+            args(AgentProvider::Codex),
+            ["exec", "--json", "--model", "gpt-x", "hi"]
+        );
+    }
+
+    #[test]
+    fn test_model_arg_is_none_only_for_builtin_tools() {
+        for provider in AgentProvider::ALL {
+            let expected = match provider {
+                AgentProvider::Dummy | AgentProvider::Mock => None,
+                _ => Some("--model"),
+            };
+            assert_eq!(provider.model_arg(), expected, "{provider:?}");
+        }
+    }
+
+    #[test]
+    fn test_agent_provider_command_name_mock() {
+        assert_eq!(AgentProvider::Mock.command_name(), "mock-agent");
+    }
+
+    #[test]
+    fn test_agent_provider_command_name_dummy() {
+        assert_eq!(AgentProvider::Dummy.command_name(), "dummy-bot");
+    }
+
+    #[test]
+    fn test_agent_provider_equality() {
+        assert_eq!(AgentProvider::Gemini, AgentProvider::Gemini);
+        assert_eq!(AgentProvider::Claude, AgentProvider::Claude);
+        assert_eq!(AgentProvider::Codex, AgentProvider::Codex);
+        assert_eq!(AgentProvider::OpenCode, AgentProvider::OpenCode);
+        assert_eq!(AgentProvider::Dummy, AgentProvider::Dummy);
+        assert_eq!(AgentProvider::Mock, AgentProvider::Mock);
+        assert_ne!(AgentProvider::Gemini, AgentProvider::Claude);
+        assert_ne!(AgentProvider::Codex, AgentProvider::OpenCode);
+        assert_ne!(AgentProvider::Dummy, AgentProvider::Mock);
+        assert_ne!(AgentProvider::Mock, AgentProvider::Gemini);
+    }
+
+    #[test]
+    fn test_agent_provider_clone() {
+        let provider = AgentProvider::Gemini;
+        let cloned = provider.clone();
+        assert_eq!(provider, cloned);
+    }
+
+    #[test]
+    fn test_agent_provider_can_be_used_as_hashmap_key() {
+        let mut map = HashMap::new();
+        map.insert(AgentProvider::Gemini, "session-abc");
+        map.insert(AgentProvider::Claude, "session-xyz");
+        assert_eq!(map.get(&AgentProvider::Gemini), Some(&"session-abc"));
+        assert_eq!(map.get(&AgentProvider::Claude), Some(&"session-xyz"));
+        assert_eq!(map.get(&AgentProvider::Codex), None);
+    }
+
+    #[test]
+    fn test_agent_provider_debug_format() {
+        assert_eq!(format!("{:?}", AgentProvider::Gemini), "Gemini");
+        assert_eq!(format!("{:?}", AgentProvider::Claude), "Claude");
+        assert_eq!(format!("{:?}", AgentProvider::Codex), "Codex");
+        assert_eq!(format!("{:?}", AgentProvider::OpenCode), "OpenCode");
+        assert_eq!(format!("{:?}", AgentProvider::Dummy), "Dummy");
+        assert_eq!(format!("{:?}", AgentProvider::Mock), "Mock");
+    }
+
+    #[test]
+    fn test_model_args_for_provider_gemini() {
+        assert_eq!(
+            SessionManager::model_args_for_provider(
+                &AgentProvider::Gemini,
+                Some("gemini-2.5-flash-lite")
+            ),
+            vec!["--model".to_string(), "gemini-2.5-flash-lite".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_model_args_for_provider_claude() {
+        assert_eq!(
+            SessionManager::model_args_for_provider(
+                &AgentProvider::Claude,
+                Some("claude-sonnet-4-6")
+            ),
+            vec!["--model".to_string(), "claude-sonnet-4-6".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_model_args_for_provider_codex() {
+        assert_eq!(
+            SessionManager::model_args_for_provider(&AgentProvider::Codex, Some("gpt-5.3-codex")),
+            vec!["--model".to_string(), "gpt-5.3-codex".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_model_args_for_provider_none_returns_empty() {
+        assert!(SessionManager::model_args_for_provider(&AgentProvider::Gemini, None).is_empty());
+    }
+
+    #[test]
+    fn test_is_gemini_capacity_error_detects_no_capacity_message() {
+        let detail = "Attempt 1 failed with status 429. Retrying with backoff... GaxiosError: No capacity available for model gemini-2.5-flash-lite on the server";
+        assert!(SessionManager::is_gemini_capacity_error(detail));
+    }
+
+    #[test]
+    fn test_is_gemini_capacity_error_ignores_unrelated_errors() {
+        let detail = "Bridge is not running. Please start it with 'acomm --bridge'.";
+        assert!(!SessionManager::is_gemini_capacity_error(detail));
+    }
+
+    #[test]
+    fn test_gemini_capacity_fallback_models_for_auto_gemini_3() {
+        assert_eq!(
+            SessionManager::gemini_capacity_fallback_models(Some("auto-gemini-3")),
+            &["gemini-2.5-flash", "gemini-2.5-pro"]
+        );
+    }
+
+    #[test]
+    fn test_gemini_capacity_fallback_models_for_flash() {
+        assert_eq!(
+            SessionManager::gemini_capacity_fallback_models(Some("gemini-2.5-flash")),
+            &["gemini-2.5-pro"]
+        );
+    }
+
+    #[test]
+    fn test_gemini_should_retry_with_fallback_only_for_gemini_capacity_errors() {
+        let detail = "No capacity available for model gemini-2.5-flash-lite on the server";
+        assert!(SessionManager::gemini_should_retry_with_fallback(
+            &AgentProvider::Gemini,
+            Some("auto-gemini-3"),
+            detail,
+        ));
+        assert!(!SessionManager::gemini_should_retry_with_fallback(
+            &AgentProvider::Claude,
+            Some("claude-sonnet-4-6"),
+            detail,
+        ));
+        assert!(!SessionManager::gemini_should_retry_with_fallback(
+            &AgentProvider::Gemini,
+            Some("gemini-2.5-pro"),
+            "permission denied",
+        ));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_agent_tool_alias_is_agent_provider() {
+        let tool: AgentTool = AgentTool::Claude;
+        let provider: AgentProvider = tool.clone();
+        assert_eq!(provider, AgentProvider::Claude);
+        assert_eq!(
+            std::any::TypeId::of::<AgentTool>(),
+            std::any::TypeId::of::<AgentProvider>()
+        );
+    }
+
+    // ─── AgentProvider FromStr tests ──────────────────────────────────────────────
+
+    #[test]
+    fn test_agent_provider_from_str_accepts_known_names_case_insensitively() {
+        assert_eq!("gemini".parse(), Ok(AgentProvider::Gemini));
+        assert_eq!("Claude".parse(), Ok(AgentProvider::Claude));
+        assert_eq!("CODEX".parse(), Ok(AgentProvider::Codex));
+        assert_eq!("OpenCode".parse(), Ok(AgentProvider::OpenCode));
+        assert_eq!("cursor".parse(), Ok(AgentProvider::Cursor));
+        assert_eq!("cursor-agent".parse(), Ok(AgentProvider::Cursor));
+        assert_eq!("Aider".parse(), Ok(AgentProvider::Aider));
+        assert_eq!("mock".parse(), Ok(AgentProvider::Mock));
+    }
+
+    #[test]
+    fn test_agent_provider_from_str_rejects_unknown_names() {
+        let err = "claud".parse::<AgentProvider>().unwrap_err();
+        assert_eq!(err, ParseAgentProviderError("claud".to_string()));
+        assert!(err.to_string().contains("claud"));
+    }
+
+    // ─── AgentProvider JSON serialization tests ───────────────────────────────────
+
+    #[test]
+    fn test_agent_provider_serialize() {
+        let json = serde_json::to_string(&AgentProvider::Gemini).unwrap();
+        assert_eq!(json, r#""Gemini""#);
+    }
+
+    #[test]
+    fn test_agent_provider_deserialize() {
+        let provider: AgentProvider = serde_json::from_str(r#""Claude""#).unwrap();
+        assert_eq!(provider, AgentProvider::Claude);
+    }
+
+    #[test]
+    fn test_agent_provider_roundtrip_all_variants() {
+        for provider in [
+            AgentProvider::Gemini,
+            AgentProvider::Claude,
+            AgentProvider::Codex,
+            AgentProvider::OpenCode,
+            AgentProvider::Cursor,
+            AgentProvider::Aider,
+            AgentProvider::Dummy,
+            AgentProvider::Mock,
+        ] {
+            let json = serde_json::to_string(&provider).unwrap();
+            let roundtrip: AgentProvider = serde_json::from_str(&json).unwrap();
+            assert_eq!(provider, roundtrip);
+        }
+    }
+
+    // ─── SessionManager::extract_session_id tests ─────────────────────────────
+
+    #[test]
+    fn test_extract_session_id() {
+        let json_output = r#"{"session_id": "test-uuid-1234", "status": "ok"}"#;
+        assert_eq!(
+            SessionManager::extract_session_id(json_output),
+            Some("test-uuid-1234".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_session_id_camel_case() {
+        let json_output = r#"{"sessionId": "camel-uuid-5678", "status": "ok"}"#;
+        assert_eq!(
+            SessionManager::extract_session_id(json_output),
+            Some("camel-uuid-5678".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_session_id_snake_case_takes_priority() {
+        // Both fields present: snake_case should win (checked first)
+        let json_output = r#"{"session_id": "snake-id", "sessionId": "camel-id"}"#;
+        assert_eq!(
+            SessionManager::extract_session_id(json_output),
+            Some("snake-id".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_session_id_missing_field() {
+        let json_output = r#"{"status": "ok", "response": "hello"}"#;
+        assert_eq!(SessionManager::extract_session_id(json_output), None);
+    }
+
+    #[test]
+    fn test_extract_session_id_invalid_json() {
+        assert_eq!(SessionManager::extract_session_id("not valid json"), None);
+    }
+
+    #[test]
+    fn test_extract_session_id_with_prefixed_logs_and_pretty_json() {
+        let output = r#"YOLO mode is enabled.
+Loaded cached credentials.
+{
+  "session_id": "prefixed-uuid",
+  "response": "MEMORY_READY"
+}"#;
+        assert_eq!(
+            SessionManager::extract_session_id(output),
+            Some("prefixed-uuid".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_session_id_from_codex_jsonl_thread_started() {
+        let output = r#"{"type":"thread.started","thread_id":"019c8d31-7d21-76d1-8a4e-2b3443def1c6"}
+{"type":"turn.started"}
+{"type":"item.completed","item":{"id":"item_1","type":"agent_message","text":"MEMORY_READY"}}"#;
+        assert_eq!(
+            SessionManager::extract_session_id(output),
+            Some("019c8d31-7d21-76d1-8a4e-2b3443def1c6".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_session_id_empty_string() {
+        assert_eq!(SessionManager::extract_session_id(""), None);
+    }
+
+    #[test]
+    fn test_extract_session_id_empty_json_object() {
+        assert_eq!(SessionManager::extract_session_id("{}"), None);
+    }
+
+    #[test]
+    fn test_extract_session_id_non_string_value() {
+        let json_output = r#"{"session_id": 12345}"#;
+        assert_eq!(SessionManager::extract_session_id(json_output), None);
+    }
+
+    #[test]
+    fn test_extract_session_id_null_value() {
+        let json_output = r#"{"session_id": null}"#;
+        assert_eq!(SessionManager::extract_session_id(json_output), None);
+    }
+
+    #[test]
+    fn test_extract_session_id_from_reply_uses_capture_group() {
+        let seed_output = r#"{"response": "MEMORY_READY (conversation: conv-42abc)"}"#;
+        let pattern = Regex::new(r"conversation: ([\w-]+)").unwrap();
+        assert_eq!(
+            SessionManager::extract_session_id_from_reply(seed_output, &pattern),
+            Some("conv-42abc".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_session_id_from_reply_plain_text_whole_match() {
+        let pattern = Regex::new(r"sess-[0-9]+").unwrap();
+        assert_eq!(
+            SessionManager::extract_session_id_from_reply("MEMORY_READY sess-0017", &pattern),
+            Some("sess-0017".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_seed_session_id_prefers_json_then_reply_pattern() {
+        let options =
+            ExecOptions::new().with_session_id_from_reply(Regex::new(r"id=(\S+)").unwrap());
+        assert_eq!(
+            SessionManager::extract_seed_session_id(
+                r#"{"session_id": "json-id", "response": "id=reply-id"}"#,
+                &options
+            ),
+            Some("json-id".to_string())
+        );
+        assert_eq!(
+            SessionManager::extract_seed_session_id(r#"{"response": "id=reply-id"}"#, &options),
+            Some("reply-id".to_string())
+        );
+        assert_eq!(
+            SessionManager::extract_seed_session_id(
+                r#"{"response": "id=reply-id"}"#,
+                &ExecOptions::default()
+            ),
+            None
+        );
+    }
+
+    // ─── SessionManager::extract_response tests ───────────────────────────────
+
+    #[test]
+    fn test_extract_response() {
+        let json_output = r#"{"session_id": "abc", "response": "Hello, world!"}"#;
+        assert_eq!(
+            SessionManager::extract_response(json_output),
+            Some("Hello, world!".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_response_missing_field() {
+        let json_output = r#"{"session_id": "abc"}"#;
+        assert_eq!(SessionManager::extract_response(json_output), None);
+    }
+
+    #[test]
+    fn test_extract_response_invalid_json() {
+        assert_eq!(SessionManager::extract_response("not json"), None);
+    }
+
+    #[test]
+    fn test_extract_response_with_prefixed_logs_and_pretty_json() {
+        let output = r#"YOLO mode is enabled.
+[ERROR] [IDEClient] Failed to connect.
+{
+  "session_id": "abc",
+  "response": "Hello"
+}"#;
+        assert_eq!(
+            SessionManager::extract_response(output),
+            Some("Hello".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_response_from_codex_jsonl_agent_message() {
+        let output = r#"{"type":"thread.started","thread_id":"019c8d31-7d21-76d1-8a4e-2b3443def1c6"}
+{"type":"turn.started"}
+{"type":"item.completed","item":{"id":"item_0","type":"reasoning","text":"hidden"}}
+{"type":"item.completed","item":{"id":"item_1","type":"agent_message","text":"SECOND"}}"#;
+        assert_eq!(
+            SessionManager::extract_response(output),
+            Some("SECOND".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_response_empty_string() {
+        assert_eq!(SessionManager::extract_response(""), None);
+    }
+
+    #[test]
+    fn test_extract_response_empty_value() {
+        let json_output = r#"{"response": ""}"#;
+        assert_eq!(
+            SessionManager::extract_response(json_output),
+            Some("".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_response_multiline_value() {
+        let json_output = r#"{"response": "line1\nline2\nline3"}"#;
+        assert_eq!(
+            SessionManager::extract_response(json_output),
+            Some("line1\nline2\nline3".to_string())
+        );
+    }
+
+    #[test]
+    fn test_extract_response_non_string_value() {
+        let json_output = r#"{"response": 42}"#;
+        assert_eq!(SessionManager::extract_response(json_output), None);
+    }
+
+    // ─── SessionManager::extract_stop_reason tests ────────────────────────────
+
+    #[test]
+    fn test_extract_stop_reason_maps_each_variant() {
+        let cases = [
+            (
+                r#"{"type":"result","stop_reason":"end_turn"}"#,
+                StopReason::EndTurn,
+            ),
+            (r#"{"stop_reason":"max_tokens"}"#, StopReason::MaxTokens),
+            (r#"{"stop_reason":"tool_use"}"#, StopReason::ToolUse),
+            (
+                r#"{"stop_reason":"stop_sequence"}"#,
+                StopReason::StopSequence,
+            ),
+            (r#"{"finishReason":"MAX_TOKENS"}"#, StopReason::MaxTokens),
+            (r#"{"finish_reason":"tool_calls"}"#, StopReason::ToolUse),
+            (r#"{"finishReason":"STOP"}"#, StopReason::EndTurn),
+            (
+                r#"{"finishReason":"SAFETY"}"#,
+                StopReason::Other("SAFETY".to_string()),
+            ),
+        ];
+        for (output, expected) in cases {
+            assert_eq!(
+                SessionManager::extract_stop_reason(output),
+                Some(expected),
+                "output: {}",
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn test_extract_stop_reason_none_for_plain_text() {
+        assert_eq!(SessionManager::extract_stop_reason("just text"), None);
+    }
+
+    #[tokio::test]
+    async fn test_execute_with_resume_result_reads_stop_reason_from_output() {
+        let mgr = SessionManager::new();
+        let result = mgr
+            .execute_with_resume_result(
+                AgentProvider::Dummy,
+                r#"{"response":"partial","stop_reason":"max_tokens"}"#,
+                &ExecOptions::default(),
+                |_| {},
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.stop_reason, Some(StopReason::MaxTokens));
+        assert!(result.output.contains("partial"));
+        assert_eq!(result.response, "partial");
+    }
+
+    #[tokio::test]
+    async fn test_auto_continue_assembles_truncated_response() {
+        let mgr = SessionManager::new();
+        // Dummy echoes each prompt, so the continuation prompt doubles as the
+        // agent's continued reply.
+        let options = ExecOptions::new().with_auto_continue(AutoContinue {
+            continuation_prompt: r#"{"response":" world","stop_reason":"end_turn"}"#.to_string(),
+            max_continuations: 3,
+        });
+        let chunks = Arc::new(StdMutex::new(0));
+        let counter = Arc::clone(&chunks);
+        let result = mgr
+            .execute_with_resume_result(
+                AgentProvider::Dummy,
+                r#"{"response":"Hello,","stop_reason":"max_tokens"}"#,
+                &options,
+                move |_| *counter.lock().unwrap() += 1,
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.response, "Hello, world");
+        assert_eq!(result.continuations, 1);
+        assert_eq!(result.stop_reason, Some(StopReason::EndTurn));
+        assert_eq!(*chunks.lock().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_auto_continue_stops_at_cap() {
+        let mgr = SessionManager::new();
+        let truncated = r#"{"response":"more","stop_reason":"max_tokens"}"#;
+        let options = ExecOptions::new().with_auto_continue(AutoContinue {
+            continuation_prompt: truncated.to_string(),
+            max_continuations: 2,
+        });
+        let result = mgr
+            .execute_with_resume_result(AgentProvider::Dummy, truncated, &options, |_| {})
+            .await
+            .unwrap();
+        assert_eq!(result.continuations, 2);
+        assert_eq!(result.response, "moremoremore");
+        assert_eq!(result.stop_reason, Some(StopReason::MaxTokens));
+    }
+
+    #[tokio::test]
+    async fn test_run_result_reports_tool_version_and_configured_model() {
+        let mgr = SessionManager::new();
+        let options = ExecOptions::new().with_model("mock-model-1");
+        let result = mgr
+            .execute_with_resume_result(AgentProvider::Mock, "hi", &options, |_| {})
+            .await
+            .unwrap();
+        assert_eq!(
+            result.tool_version,
+            Some(format!("mock-agent {}", env!("CARGO_PKG_VERSION")))
+        );
+        assert_eq!(result.model.as_deref(), Some("mock-model-1"));
+    }
+
+    #[tokio::test]
+    async fn test_run_result_prefers_model_from_output_metadata() {
+        let mgr = SessionManager::new();
+        let options = ExecOptions::new().with_model("requested");
+        let result = mgr
+            .execute_with_resume_result(
+                AgentProvider::Dummy,
+                r#"{"response":"ok","model":"gemini-2.5-flash"}"#,
+                &options,
+                |_| {},
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.model.as_deref(), Some("gemini-2.5-flash"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_result_reports_exit_code_duration_and_session() {
+        let script = fake_agent("timed-claude.sh", "sleep 0.05\necho 'resumed ok'\n");
+        let mgr = SessionManager::new();
+        mgr.set_session_id(AgentProvider::Claude, "s-1".to_string())
+            .await;
+        let (options, events) = lifecycle_recorder();
+        let options = options.with_command_override(AgentProvider::Claude, &script);
+        let result = mgr
+            .execute_with_resume_result(AgentProvider::Claude, "hi", &options, |_| {})
+            .await;
+        let _ = std::fs::remove_file(&script);
+        let result = result.unwrap();
+        assert_eq!(result.exit_code, Some(0));
+        assert!(
+            result.duration >= Duration::from_millis(50),
+            "{:?}",
+            result.duration
+        );
+        assert_eq!(result.stdout_bytes, "resumed ok\n".len());
+        assert_eq!(result.session_id.as_deref(), Some("s-1"));
+        // The caller's own lifecycle callback still sees the run.
+        assert!(
+            events
+                .lock()
+                .unwrap()
+                .contains(&Lifecycle::Exited { code: Some(0) })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_with_result_streams_chunks_and_parses_output() {
+        let prompt = r#"{"session_id":"s-9","response":"parsed answer","model":"m-1"}"#;
+        let mut chunks = Vec::new();
+        let result = AgentExecutor::execute_with_result(
+            AgentProvider::Dummy,
+            prompt,
+            &ExecOptions::new(),
+            |chunk| chunks.push(chunk),
+        )
+        .await
+        .unwrap();
+        assert_eq!(chunks, [prompt]);
+        assert_eq!(result.output, prompt);
+        assert_eq!(result.response, "parsed answer");
+        assert_eq!(result.session_id.as_deref(), Some("s-9"));
+        assert_eq!(result.model.as_deref(), Some("m-1"));
+        assert_eq!(result.exit_code, Some(0));
+        assert_eq!(result.stdout_bytes, prompt.len());
+    }
+
+    #[test]
+    fn test_extract_model_from_usage_maps() {
+        assert_eq!(
+            SessionManager::extract_model(
+                r#"{"type":"result","modelUsage":{"claude-sonnet-4-5":{"inputTokens":3}}}"#
+            )
+            .as_deref(),
+            Some("claude-sonnet-4-5")
+        );
+        assert_eq!(
+            SessionManager::extract_model(r#"{"stats":{"models":{"gemini-2.5-pro":{}}}}"#)
+                .as_deref(),
+            Some("gemini-2.5-pro")
+        );
+        assert_eq!(SessionManager::extract_model("plain text"), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_tool_version_is_fetched_once_per_binary() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = temp_path("fake-claude-version.sh");
+        let calls = temp_path("fake-claude-version.calls");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho x >> '{}'\necho '1.2.3 (Claude Code)'\n",
+                calls.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mgr = SessionManager::new();
+        let options = ExecOptions::new().with_command_override(AgentProvider::Claude, &script);
+        let first = mgr.tool_version(&AgentProvider::Claude, &options).await;
+        let second = mgr.tool_version(&AgentProvider::Claude, &options).await;
+        let invocations = std::fs::read_to_string(&calls).unwrap_or_default();
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&calls);
+        assert_eq!(first.as_deref(), Some("1.2.3 (Claude Code)"));
+        assert_eq!(second, first);
+        assert_eq!(invocations.lines().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_execute_with_result_probes_the_version_once_after_an_accepted_turn() {
+        let calls = temp_path("result-version.calls");
+        let _ = std::fs::remove_file(&calls);
+        let script = fake_agent(
+            "result-version.sh",
+            &format!(
+                r#"case "$*" in
+  --version) echo version >> {}; echo '2.0.0 (Claude Code)' ;;
+  *) echo hello ;;
+esac
+"#,
+                calls.display()
+            ),
+        );
+        let options = ExecOptions::new()
+            .with_command_override(AgentProvider::Claude, &script)
+            .with_version_cache(VersionCache::new());
+        let rejected = AgentExecutor::execute_with_result(
+            AgentProvider::Claude,
+            "hi",
+            &options
+                .clone()
+                .before_execute(|_| async { Err("quota exhausted".to_string()) }),
+            |_| {},
+        )
+        .await;
+        let probes_after_rejection = std::fs::read_to_string(&calls).unwrap_or_default();
+        let mut versions = Vec::new();
+        for _ in 0..2 {
+            let result =
+                AgentExecutor::execute_with_result(AgentProvider::Claude, "hi", &options, |_| {})
+                    .await
+                    .unwrap();
+            versions.push(result.tool_version);
+        }
+        let probes = std::fs::read_to_string(&calls).unwrap_or_default();
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&calls);
+
+        assert!(matches!(rejected, Err(AgentError::HookFailed(_))));
+        assert_eq!(probes_after_rejection, "");
+        assert_eq!(probes, "version\n");
+        let version = Some("2.0.0 (Claude Code)".to_string());
+        assert_eq!(versions, [version.clone(), version]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_resume_result_probes_the_version_only_after_an_accepted_turn() {
+        let calls = temp_path("resume-result-version.calls");
+        let _ = std::fs::remove_file(&calls);
+        let script = fake_agent(
+            "resume-result-version.sh",
+            &format!(
+                r#"case "$*" in
+  --version) echo version >> {}; echo '3.1.0 (Claude Code)' ;;
+  *"--output-format json"*) echo '{{"session_id":"r-1","response":"MEMORY_READY"}}' ;;
+  *) echo hello ;;
+esac
+"#,
+                calls.display()
+            ),
+        );
+        let mgr = SessionManager::new();
+        let options = ExecOptions::new()
+            .with_command_override(AgentProvider::Claude, &script)
+            .with_amem_binary(temp_path("resume-result-version-no-amem"));
+        let rejected = mgr
+            .execute_with_resume_result(
+                AgentProvider::Claude,
+                "hi",
+                &options
+                    .clone()
+                    .before_execute(|_| async { Err("quota exhausted".to_string()) }),
+                |_| {},
+            )
+            .await;
+        let probes_after_rejection = std::fs::read_to_string(&calls).unwrap_or_default();
+        let accepted = mgr
+            .execute_with_resume_result(AgentProvider::Claude, "hi", &options, |_| {})
+            .await
+            .unwrap();
+        let probes = std::fs::read_to_string(&calls).unwrap_or_default();
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&calls);
+
+        assert!(matches!(rejected, Err(AgentError::HookFailed(_))));
+        assert_eq!(probes_after_rejection, "");
+        assert_eq!(probes, "version\n");
+        assert_eq!(
+            accepted.tool_version.as_deref(),
+            Some("3.1.0 (Claude Code)")
+        );
+    }
+
+    // ─── SessionManager state tests ───────────────────────────────────────────
+
+    #[tokio::test]
+    async fn test_session_manager_new_has_empty_sessions() {
+        let mgr = SessionManager::new();
+        let sessions = mgr.session_ids.lock().await;
+        assert!(sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_session_manager_clone_shares_state() {
+        let mgr = SessionManager::new();
+        let cloned = mgr.clone();
+        // Insert into original
+        mgr.session_ids
+            .lock()
+            .await
+            .insert(default_key(&AgentProvider::Gemini), "shared-id".to_string());
+        // Clone should see the same value (Arc-shared)
+        let val = cloned
+            .session_ids
+            .lock()
+            .await
+            .get(&default_key(&AgentProvider::Gemini))
+            .cloned();
+        assert_eq!(val, Some("shared-id".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_session_id_is_none_for_unused_tool() {
+        let mgr = SessionManager::new();
+        assert_eq!(mgr.session_id(&AgentProvider::Claude).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_set_session_id_is_returned_by_getter() {
+        let mgr = SessionManager::new();
+        mgr.set_session_id(AgentProvider::Claude, "restored-id".to_string())
+            .await;
+        assert_eq!(
+            mgr.session_id(&AgentProvider::Claude).await,
+            Some("restored-id".to_string())
+        );
+        assert_eq!(mgr.session_id(&AgentProvider::Gemini).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_max_turns_per_session_forces_reseed_on_third_call() {
+        let mgr = SessionManager::new().with_max_turns_per_session(2);
+        mgr.set_session_id(AgentProvider::Gemini, "old-session".to_string())
+            .await;
+        assert_eq!(
+            mgr.claim_turn(&default_key(&AgentProvider::Gemini))
+                .await
+                .as_deref(),
+            Some("old-session")
+        );
+        assert_eq!(
+            mgr.claim_turn(&default_key(&AgentProvider::Gemini))
+                .await
+                .as_deref(),
+            Some("old-session")
+        );
+        // The third turn must go back through the seed path.
+        assert_eq!(
+            mgr.claim_turn(&default_key(&AgentProvider::Gemini)).await,
+            None
+        );
+        assert_eq!(mgr.session_id(&AgentProvider::Gemini).await, None);
+    }
+
+    type Evictions = Arc<StdMutex<Vec<(AgentProvider, String)>>>;
+
+    fn eviction_recorder() -> (
+        Evictions,
+        impl Fn(AgentProvider, String) + Send + Sync + 'static,
+    ) {
+        let evicted = Arc::new(StdMutex::new(Vec::new()));
+        let sink = Arc::clone(&evicted);
+        (evicted, move |provider, id| {
+            sink.lock().unwrap().push((provider, id))
+        })
+    }
+
+    #[tokio::test]
+    async fn test_on_evict_fires_when_session_ttl_expires() {
+        let (evicted, callback) = eviction_recorder();
+        let mgr = SessionManager::new()
+            .with_session_ttl(Duration::from_millis(10))
+            .on_evict(callback);
+        mgr.set_session_id(AgentProvider::Gemini, "stale".to_string())
+            .await;
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        assert_eq!(
+            mgr.claim_turn(&default_key(&AgentProvider::Gemini)).await,
+            None
+        );
+        assert_eq!(
+            *evicted.lock().unwrap(),
+            [(AgentProvider::Gemini, "stale".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_active_tools_and_clear_all() {
+        let (evicted, callback) = eviction_recorder();
+        let mgr = SessionManager::new().on_evict(callback);
+        assert!(mgr.active_tools().await.is_empty());
+        mgr.set_session_id(AgentProvider::Gemini, "g-1".to_string())
+            .await;
+        mgr.set_session_id(AgentProvider::Claude, "c-1".to_string())
+            .await;
+        assert_eq!(
+            mgr.active_tools().await,
+            [AgentProvider::Claude, AgentProvider::Gemini]
+        );
+
+        mgr.clear_all().await;
+        assert!(mgr.active_tools().await.is_empty());
+        assert_eq!(mgr.session_id(&AgentProvider::Gemini).await, None);
+        let mut evicted = evicted.lock().unwrap().clone();
+        evicted.sort_by_key(|(provider, _)| provider.command_name());
+        assert_eq!(
+            evicted,
+            [
+                (AgentProvider::Claude, "c-1".to_string()),
+                (AgentProvider::Gemini, "g-1".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_sessions_evicts_least_recently_used() {
+        let (evicted, callback) = eviction_recorder();
+        let mgr = SessionManager::new()
+            .with_max_sessions(2)
+            .on_evict(callback);
+        mgr.set_session_id(AgentProvider::Claude, "c".into()).await;
+        mgr.set_session_id(AgentProvider::Gemini, "g".into()).await;
+        // Resuming Claude makes Gemini the least recently used session.
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        assert_eq!(
+            mgr.claim_turn(&default_key(&AgentProvider::Claude))
+                .await
+                .as_deref(),
+            Some("c")
+        );
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        mgr.set_session_id(AgentProvider::Codex, "x".into()).await;
+
+        assert_eq!(
+            *evicted.lock().unwrap(),
+            vec![(AgentProvider::Gemini, "g".to_string())]
+        );
+        assert_eq!(
+            mgr.active_tools().await,
+            vec![AgentProvider::Claude, AgentProvider::Codex]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_on_evict_fires_on_explicit_reset() {
+        let (evicted, callback) = eviction_recorder();
+        let mgr = SessionManager::new().on_evict(callback);
+        mgr.set_session_id(AgentProvider::Claude, "c-1".to_string())
+            .await;
+        mgr.clear_session(&AgentProvider::Claude).await;
+        // Clearing a tool without a session does not fire again.
+        mgr.clear_session(&AgentProvider::Claude).await;
+
+        assert_eq!(mgr.session_id(&AgentProvider::Claude).await, None);
+        assert_eq!(
+            *evicted.lock().unwrap(),
+            [(AgentProvider::Claude, "c-1".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_turns_are_unbounded_by_default() {
+        let mgr = SessionManager::new();
+        mgr.set_session_id(AgentProvider::Claude, "long-lived".to_string())
+            .await;
+        for _ in 0..20 {
+            assert!(
+                mgr.claim_turn(&default_key(&AgentProvider::Claude))
+                    .await
+                    .is_some()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_find_session_by_prompt_matches_substring() {
+        let mgr = SessionManager::new();
+        mgr.record_session(
+            AgentProvider::Gemini,
+            "g-1".to_string(),
+            "Refactor the parser module",
+        )
+        .await;
+        mgr.record_session(
+            AgentProvider::Claude,
+            "c-1".to_string(),
+            "Write release notes for v2",
+        )
+        .await;
+
+        let found = mgr.find_session_by_prompt("PARSER").await;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].provider, AgentProvider::Gemini);
+        assert_eq!(found[0].session_id, "g-1");
+        assert!(mgr.find_session_by_prompt("deploy").await.is_empty());
+    }
+
+    // ─── Retry tests ──────────────────────────────────────────────────────────
+
+    #[test]
+    fn test_retry_policy_backoff_doubles() {
+        let policy = RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(100),
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.delay_for(1), Duration::from_millis(100));
+        assert_eq!(policy.delay_for(2), Duration::from_millis(200));
+        assert_eq!(policy.delay_for(3), Duration::from_millis(400));
+
+        let tripling = RetryPolicy {
+            backoff_factor: 3,
+            ..policy
+        };
+        assert_eq!(
+            tripling.delays(),
+            [100, 300, 900].map(Duration::from_millis)
+        );
+    }
+
+    #[test]
+    fn test_retry_policy_only_retries_transient_spawn_errors_when_enabled() {
+        let spawn_failed = |kind: std::io::ErrorKind| AgentError::SpawnFailed {
+            command: "claude".to_string(),
+            source: std::io::Error::from(kind),
+        };
+        let policy = RetryPolicy::default();
+        assert!(!policy.should_retry(&spawn_failed(std::io::ErrorKind::WouldBlock), None));
+        assert!(!policy.should_retry(&AgentError::SessionIdMissing, None));
+
+        let policy = RetryPolicy {
+            retry_spawn_errors: true,
+            ..policy
+        };
+        assert!(policy.should_retry(&spawn_failed(std::io::ErrorKind::WouldBlock), None));
+        assert!(!policy.should_retry(&spawn_failed(std::io::ErrorKind::NotFound), None));
+        assert!(!policy.should_retry(&AgentError::SessionIdMissing, None));
+    }
+
+    #[test]
+    fn test_is_transient_classifies_errors() {
+        let exit = |detail: &str| AgentError::NonZeroExit {
+            command: "claude".to_string(),
+            code: Some(1),
+            detail: detail.to_string(),
+        };
+        assert!(exit("API Error: 429 Too Many Requests").is_transient());
+        assert!(exit("Error: Overloaded").is_transient());
+        assert!(!exit("Invalid API key").is_transient());
+        let missing = AgentError::SpawnFailed {
+            command: "claude".to_string(),
+            source: std::io::Error::from(std::io::ErrorKind::NotFound),
+        };
+        assert!(!missing.is_transient());
+    }
+
+    /// A fake agent that fails with `stderr` until it has been run `failures` times.
+    #[cfg(unix)]
+    fn flaky_agent(name: &str, failures: usize, stderr: &str) -> (PathBuf, PathBuf) {
+        let calls = temp_path(&format!("{name}.calls"));
+        let _ = std::fs::remove_file(&calls);
+        let script = fake_agent(
+            name,
+            &format!(
+                "echo x >> '{calls}'\nif [ $(wc -l < '{calls}') -le {failures} ]; then echo partial; echo '{stderr}' >&2; exit 1; fi\necho ok\n",
+                calls = calls.display(),
+            ),
+        );
+        (script, calls)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_execute_with_retry_recovers_from_rate_limit() {
+        let (script, calls) = flaky_agent("flaky-aider.sh", 2, "429 Too Many Requests");
+        let mgr = SessionManager::new();
+        let options = ExecOptions::new().with_command_override(AgentProvider::Aider, &script);
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        let received = Arc::new(StdMutex::new(String::new()));
+        let sink = Arc::clone(&received);
+        let result = mgr
+            .execute_with_retry_with_options(
+                AgentProvider::Aider,
+                "hi",
+                &policy,
+                &options,
+                move |c| sink.lock().unwrap().push_str(&c),
+            )
+            .await;
+        let attempts = std::fs::read_to_string(&calls).unwrap().lines().count();
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&calls);
+        result.unwrap();
+        assert_eq!(attempts, 3);
+        assert_eq!(*received.lock().unwrap(), "ok\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_execute_with_retry_surfaces_last_error_after_max_attempts() {
+        let (script, calls) = flaky_agent("always-limited-aider.sh", 99, "rate limit exceeded");
+        let mgr = SessionManager::new();
+        let options = ExecOptions::new().with_command_override(AgentProvider::Aider, &script);
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        let result = mgr
+            .execute_with_retry_with_options(AgentProvider::Aider, "hi", &policy, &options, |_| {})
+            .await;
+        let attempts = std::fs::read_to_string(&calls).unwrap().lines().count();
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&calls);
+        assert!(matches!(result, Err(AgentError::NonZeroExit { .. })));
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn test_default_classifier_distinguishes_auth_and_rate_limit() {
+        let exit = |detail: &str| AgentError::NonZeroExit {
+            command: "gemini".to_string(),
+            code: Some(1),
+            detail: detail.to_string(),
+        };
+        assert_eq!(
+            exit("Quota exceeded: 429").classify(None),
+            ErrorClass::RateLimited
+        );
+        assert_eq!(
+            exit("Error: Invalid API key. Please log in.").classify(None),
+            ErrorClass::Auth
+        );
+        assert!(exit("401 Unauthorized").is_auth_failure());
+        assert_eq!(exit("syntax error").classify(None), ErrorClass::Fatal);
+        assert_eq!(exit("HTTP 429").classify(None), ErrorClass::RateLimited);
+        assert_eq!(
+            exit("upstream returned status: 503").classify(None),
+            ErrorClass::Retryable
+        );
+        assert_eq!(exit(r#"{"code":401}"#).classify(None), ErrorClass::Auth);
+        assert_eq!(
+            exit("unexpected token at line 429, request 5031234").classify(None),
+            ErrorClass::Fatal
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_custom_error_classifier_controls_retry() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        let mgr = SessionManager::new();
+
+        // The default classifier treats this message as fatal.
+        let (script, calls) = flaky_agent("widget-aider.sh", 1, "widget backend hiccup");
+        let options = ExecOptions::new().with_command_override(AgentProvider::Aider, &script);
+        let result = mgr
+            .execute_with_retry_with_options(AgentProvider::Aider, "hi", &policy, &options, |_| {})
+            .await;
+        assert!(matches!(result, Err(AgentError::NonZeroExit { .. })));
+
+        let _ = std::fs::remove_file(&calls);
+        let options = options.with_error_classifier(|exit, stderr| {
+            assert_eq!(exit.code, Some(1));
+            if stderr.contains("widget backend hiccup") {
+                ErrorClass::Retryable
+            } else {
+                ErrorClass::Fatal
+            }
+        });
+        let result = mgr
+            .execute_with_retry_with_options(AgentProvider::Aider, "hi", &policy, &options, |_| {})
+            .await;
+        let attempts = std::fs::read_to_string(&calls).unwrap().lines().count();
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&calls);
+        result.unwrap();
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn test_execute_with_retry_does_not_retry_missing_binary() {
+        let mgr = SessionManager::new();
+        let options = ExecOptions::new()
+            .with_command_override(AgentProvider::Aider, temp_path("missing-aider-binary"));
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(60),
+            ..RetryPolicy::default()
+        };
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            mgr.execute_with_retry_with_options(
+                AgentProvider::Aider,
+                "hi",
+                &policy,
+                &options,
+                |_| {},
+            ),
+        )
+        .await
+        .expect("a missing binary must not be retried");
+        assert!(matches!(result, Err(AgentError::SpawnFailed { .. })));
+    }
+
+    // ─── Process runner tests ─────────────────────────────────────────────────
+
+    /// Records each command line and answers with the first reply whose
+    /// pattern occurs in it, without ever running the real CLI.
+    #[cfg(unix)]
+    #[derive(Default)]
+    struct ScriptedRunner {
+        replies: Vec<(&'static str, &'static str)>,
+        calls: StdMutex<Vec<String>>,
+    }
+
+    #[cfg(unix)]
+    impl ScriptedRunner {
+        fn reply(&self, command: &Command) -> &'static str {
+            let std = command.as_std();
+            let line = std::iter::once(std.get_program())
+                .chain(std.get_args())
+                .map(|part| part.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" ");
+            let reply = self
+                .replies
+                .iter()
+                .find(|(pattern, _)| line.contains(pattern))
+                .map_or("", |(_, reply)| reply);
+            self.calls.lock().unwrap().push(line);
+            reply
+        }
+    }
+
+    #[cfg(unix)]
+    impl ProcessRunner for Arc<ScriptedRunner> {
+        fn spawn(&self, command: &mut Command) -> std::io::Result<Child> {
+            let mut canned = sh_command("printf '%s\\n' \"$0\"");
+            canned.arg(self.reply(command)).kill_on_drop(true);
+            canned.spawn()
+        }
+
+        fn output<'a>(&'a self, command: &'a mut Command) -> RunnerFuture<'a> {
+            use std::os::unix::process::ExitStatusExt;
+
+            let output = std::process::Output {
+                status: std::process::ExitStatus::from_raw(0),
+                stdout: format!("{}\n", self.reply(command)).into_bytes(),
+                stderr: Vec::new(),
+            };
+            Box::pin(async move { Ok(output) })
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_scripted_runner_covers_gemini_seed_and_resume() {
+        let runner = Arc::new(ScriptedRunner {
+            replies: vec![
+                (
+                    "--output-format json",
+                    r#"{"session_id":"g-1","response":"MEMORY_READY"}"#,
+                ),
+                ("--resume g-1", "resumed answer"),
+            ],
+            ..ScriptedRunner::default()
+        });
+        let mgr = SessionManager::builder()
+            .runner(Arc::clone(&runner))
+            .build();
+        let options = ExecOptions {
+            init_prompt: Some("init".to_string()),
+            ..ExecOptions::new()
+        };
+        for prompt in ["first", "second"] {
+            let output = mgr
+                .execute_with_resume_collect_with_options(
+                    AgentProvider::Gemini,
+                    prompt,
+                    &options,
+                    |_| {},
+                )
+                .await
+                .unwrap();
+            assert_eq!(output, "resumed answer\n");
+        }
+
+        let calls = runner.calls.lock().unwrap();
+        assert_eq!(calls.len(), 3, "{calls:?}");
+        assert!(calls[0].starts_with("gemini --approval-mode yolo --output-format json"));
+        assert!(calls[0].ends_with("-p init"));
+        assert!(calls[1].contains("--resume g-1") && calls[1].ends_with("first"));
+        assert!(calls[2].contains("--resume g-1") && calls[2].ends_with("second"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_scripted_runner_answers_probes_and_amem_without_spawning() {
+        let amem = temp_path("scripted-runner-amem");
+        let runner = Arc::new(ScriptedRunner {
+            replies: vec![
+                ("codex --version", "codex-cli 0.9.0"),
+                ("amem today --json", r#"{"activity":"shipped v2"}"#),
+            ],
+            ..ScriptedRunner::default()
+        });
+        let options = ExecOptions::new()
+            .with_runner(Arc::clone(&runner))
+            .with_amem_binary(&amem);
+        let mgr = SessionManager::new();
+
+        assert!(
+            AgentProvider::Codex
+                .is_available_with_options(&options)
+                .await
+        );
+        assert_eq!(
+            mgr.tool_version(&AgentProvider::Codex, &options)
+                .await
+                .as_deref(),
+            Some("codex-cli 0.9.0")
+        );
+        assert!(AgentExecutor::has_amem_with_options(&options).await);
+        let prompt = AgentExecutor::build_init_prompt_with_options(&options).await;
+        assert!(prompt.contains("shipped v2"), "{prompt}");
+        AgentExecutor::keep_many_with_options(
+            &["fixed the build"],
+            &RecordOptions::default(),
+            &options,
+        )
+        .await
+        .unwrap();
+
+        let amem = amem.display().to_string();
+        let calls = runner.calls.lock().unwrap();
+        assert_eq!(calls.len(), 5, "{calls:?}");
+        assert_eq!(calls[0], "codex --version");
+        assert_eq!(calls[1], "codex --version");
+        assert_eq!(calls[2], format!("{amem} --version"));
+        assert_eq!(calls[3], format!("{amem} today --json"));
+        assert!(calls[4].starts_with(&format!("{amem} keep fixed the build")));
+    }
+
+    // ─── Agent backend tests ──────────────────────────────────────────────────
+
+    /// Answers every turn with the session id and prompt it was given.
+    #[derive(Default)]
+    struct ScriptedBackend {
+        seeds: StdMutex<Vec<String>>,
+    }
+
+    impl AgentBackend for Arc<ScriptedBackend> {
+        fn seed<'a>(
+            &'a self,
+            prompt: &'a str,
+            _options: &'a ExecOptions,
+        ) -> BackendFuture<'a, Result<String, AgentError>> {
+            let mut seeds = self.seeds.lock().unwrap();
+            seeds.push(prompt.to_string());
+            let id = format!("scripted-{}", seeds.len());
+            Box::pin(async move { Ok(id) })
+        }
+
+        fn execute_stream<'a>(
+            &'a self,
+            session_id: &'a str,
+            prompt: &'a str,
+            _options: &'a ExecOptions,
+            on_chunk: &'a mut (dyn FnMut(String) + Send),
+        ) -> BackendFuture<'a, Result<(), AgentError>> {
+            Box::pin(async move {
+                on_chunk(format!("{session_id}: {prompt}"));
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registered_backend_seeds_once_and_resumes_its_session() {
+        let backend = Arc::new(ScriptedBackend::default());
+        let mgr = SessionManager::new().with_backend(AgentProvider::Gemini, Arc::clone(&backend));
+        let options = ExecOptions {
+            init_prompt: Some("init".to_string()),
+            ..ExecOptions::new()
+        };
+        let mut outputs = Vec::new();
+        for prompt in ["first", "second"] {
+            outputs.push(
+                mgr.execute_with_resume_collect_with_options(
+                    AgentProvider::Gemini,
+                    prompt,
+                    &options,
+                    |_| {},
+                )
+                .await
+                .unwrap(),
+            );
+        }
+        assert_eq!(outputs, ["scripted-1: first", "scripted-1: second"]);
+        assert_eq!(*backend.seeds.lock().unwrap(), ["init"]);
+        assert_eq!(
+            mgr.session_id(&AgentProvider::Gemini).await.as_deref(),
+            Some("scripted-1")
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_cli_and_mock_backends_drive_the_same_manager() {
+        let script = fake_agent(
+            "backend-claude.sh",
+            r#"case "$*" in
+  *"--output-format json"*) echo '{"session_id":"c-1","response":"MEMORY_READY"}' ;;
+  *"--resume c-1"*) echo "claude resumed" ;;
+esac
+"#,
+        );
+        let mgr = SessionManager::builder()
+            .command_override(AgentProvider::Claude, &script)
+            .backend(AgentProvider::Claude, CliBackend(AgentProvider::Claude))
+            .backend(AgentProvider::Gemini, MockBackend::default())
+            .build();
+        let options = ExecOptions {
+            init_prompt: Some("init".to_string()),
+            ..ExecOptions::new()
+        };
+        let claude = mgr
+            .execute_with_resume_collect_with_options(AgentProvider::Claude, "hi", &options, |_| {})
+            .await;
+        let gemini = mgr
+            .execute_with_resume_collect_with_options(AgentProvider::Gemini, "hi", &options, |_| {})
+            .await;
+        let _ = std::fs::remove_file(&script);
+        assert_eq!(claude.unwrap(), "claude resumed\n");
+        assert_eq!(gemini.unwrap(), "Mock: received your prompt 'hi'.");
+        assert_eq!(
+            mgr.session_id(&AgentProvider::Gemini).await.as_deref(),
+            Some("mock-1")
+        );
+    }
+
+    // ─── Prompt timestamp tests ───────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn test_timestamp_line_is_prepended_to_prompt_or_seed() {
+        let clock = || std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let stamp = PromptTimestamp::new()
+            .with_format("Now: %Y-%m-%d %H:%M %z")
+            .with_utc_offset(9 * 3600)
+            .with_clock(clock);
+
+        let mut echoed = String::new();
+        let options = ExecOptions::new().with_timestamp(stamp.clone());
+        AgentExecutor::execute_stream_with_options(AgentProvider::Dummy, "hi", &options, |c| {
+            echoed.push_str(&c)
+        })
+        .await
+        .unwrap();
+        assert_eq!(echoed, "Now: 2023-11-15 07:13 +0900\n\nhi");
+
+        let mgr = SessionManager::new().with_backend(AgentProvider::Gemini, MockBackend::default());
+        let options = ExecOptions {
+            init_prompt: Some("init".to_string()),
+            ..ExecOptions::new()
+        }
+        .with_include_seed_prompt(true)
+        .with_timestamp(stamp.with_target(TimestampTarget::Seed));
+        let result = mgr
+            .execute_with_resume_result(AgentProvider::Gemini, "hi", &options, |_| {})
+            .await
+            .unwrap();
+        assert_eq!(
+            result.seed_prompt.as_deref(),
+            Some("Now: 2023-11-15 07:13 +0900\n\ninit")
+        );
+        assert_eq!(result.output, "Mock: received your prompt 'hi'.");
+    }
+
+    // ─── Expired session re-seed tests ────────────────────────────────────────
+
+    /// Writes an executable `sh` script that stands in for an agent CLI.
+    #[cfg(unix)]
+    fn fake_agent(name: &str, body: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_path(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{body}")).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[cfg(unix)]
+    const EXPIRING_CLAUDE: &str = r#"case "$*" in
+  *"--output-format json"*) echo '{"session_id":"fresh","response":"seeded"}' ;;
+  *"--resume stale"*) echo "No conversation found with session ID: stale" >&2; exit 1 ;;
+  *) echo "resumed $*" ;;
+esac
+"#;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_expired_session_is_reseeded_and_resumed_once() {
+        let script = fake_agent("expiring-claude.sh", EXPIRING_CLAUDE);
+        let (evicted, on_evict) = eviction_recorder();
+        let mgr = SessionManager::new().on_evict(on_evict);
+        mgr.set_session_id(AgentProvider::Claude, "stale".to_string())
+            .await;
+        let options = ExecOptions {
+            init_prompt: Some("init".to_string()),
+            ..ExecOptions::new().with_command_override(AgentProvider::Claude, &script)
+        };
+        let result = mgr
+            .execute_with_resume_collect_with_options(AgentProvider::Claude, "hi", &options, |_| {})
+            .await;
+        let _ = std::fs::remove_file(&script);
+        let output = result.unwrap();
+        assert!(output.contains("--resume fresh"), "got: {output}");
+        assert_eq!(
+            mgr.session_id(&AgentProvider::Claude).await.as_deref(),
+            Some("fresh")
+        );
+        assert_eq!(
+            *evicted.lock().unwrap(),
+            vec![(AgentProvider::Claude, "stale".to_string())]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_expired_session_error_is_returned_when_reseed_disabled() {
+        let script = fake_agent("expiring-claude-no-reseed.sh", EXPIRING_CLAUDE);
+        let mgr = SessionManager::new().with_max_reseed(0);
+        mgr.set_session_id(AgentProvider::Claude, "stale".to_string())
+            .await;
+        let options = ExecOptions::new().with_command_override(AgentProvider::Claude, &script);
+        let result = mgr
+            .execute_with_resume_with_options(AgentProvider::Claude, "hi", &options, |_| {})
+            .await;
+        let _ = std::fs::remove_file(&script);
+        assert!(matches!(result, Err(AgentError::NonZeroExit { .. })));
+        assert_eq!(
+            mgr.session_id(&AgentProvider::Claude).await.as_deref(),
+            Some("stale")
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reseed_is_bounded_when_every_session_expires() {
+        let script = fake_agent(
+            "always-expired-claude.sh",
+            r#"case "$*" in
+  *"--output-format json"*) echo x >> "$0.seeds"; echo '{"session_id":"stale"}' ;;
+  *) echo "No conversation found with session ID: stale" >&2; exit 1 ;;
+esac
+"#,
+        );
+        let seeds = PathBuf::from(format!("{}.seeds", script.display()));
+        let mgr = SessionManager::new().with_max_reseed(2);
+        let options = ExecOptions {
+            init_prompt: Some("init".to_string()),
+            ..ExecOptions::new().with_command_override(AgentProvider::Claude, &script)
+        };
+        let result = mgr
+            .execute_with_resume_with_options(AgentProvider::Claude, "hi", &options, |_| {})
+            .await;
+        let seed_count = std::fs::read_to_string(&seeds)
+            .unwrap_or_default()
+            .lines()
+            .count();
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&seeds);
+        assert!(matches!(result, Err(AgentError::NonZeroExit { .. })));
+        // The initial seed plus one per allowed re-seed.
+        assert_eq!(seed_count, 3);
+    }
+
+    #[test]
+    fn test_session_expired_matches_known_messages_per_tool() {
+        assert!(SessionManager::session_expired(
+            &AgentProvider::Claude,
+            "Error: No conversation found with session ID: abc"
+        ));
+        assert!(SessionManager::session_expired(
+            &AgentProvider::Gemini,
+            "Invalid session identifier \"abc\""
+        ));
+        assert!(!SessionManager::session_expired(
+            &AgentProvider::Claude,
+            "429 Too Many Requests"
+        ));
+        assert!(!SessionManager::session_expired(
+            &AgentProvider::Aider,
+            "session not found"
+        ));
+    }
+
+    // ─── JSON response mode tests ─────────────────────────────────────────────
+
+    #[cfg(unix)]
+    async fn resume_json(name: &str, body: &str) -> String {
+        let script = fake_agent(name, body);
+        let mgr = SessionManager::new();
+        mgr.set_session_id(AgentProvider::Claude, "s-1".to_string())
+            .await;
+        let options = ExecOptions::new()
+            .with_command_override(AgentProvider::Claude, &script)
+            .with_json_response(true);
+        let result = mgr
+            .execute_with_resume_collect_with_options(AgentProvider::Claude, "hi", &options, |_| {})
+            .await;
+        let _ = std::fs::remove_file(&script);
+        result.unwrap()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_json_response_mode_delivers_only_the_response() {
+        let output = resume_json(
+            "json-clean.sh",
+            r#"case "$*" in
+  *"--resume s-1 --output-format json"*) echo '{"session_id":"s-1","response":"forty-two"}' ;;
+  *) echo "unexpected args: $*" ;;
+esac
+"#,
+        )
+        .await;
+        assert_eq!(output, "forty-two");
+
+        let output = resume_json(
+            "json-multi.sh",
+            "echo '{\"type\":\"init\",\"session_id\":\"s-1\"}'\necho '{\"response\":\"done\"}'\n",
+        )
+        .await;
+        assert_eq!(output, "done");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_json_response_mode_passes_malformed_output_through() {
+        let output = resume_json("json-malformed.sh", "printf '{\"response\": \"cut off'\n").await;
+        assert_eq!(output, "{\"response\": \"cut off");
+    }
+
+    // ─── SessionManager persistence tests ─────────────────────────────────────
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("acore-test-{}-{}", std::process::id(), name))
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load_session_ids_roundtrip() {
+        let path = temp_path("sessions-roundtrip.json");
+        let mgr = SessionManager::new();
+        mgr.set_session_id(AgentProvider::Gemini, "g-1".to_string())
+            .await;
+        mgr.set_session_id(AgentProvider::Claude, "c-1".to_string())
+            .await;
+        let review = ConversationId::new("review");
+        mgr.set_session(
+            (AgentProvider::Claude, review.clone()),
+            "c-review".to_string(),
+        )
+        .await;
+        mgr.save_to_path(&path).await.unwrap();
+
+        let loaded = SessionManager::load_from_path(&path).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            loaded
+                .conversation_session_id(&AgentProvider::Claude, &review)
+                .await
+                .as_deref(),
+            Some("c-review")
+        );
+        assert_eq!(
+            loaded.session_id(&AgentProvider::Gemini).await,
+            Some("g-1".to_string())
+        );
+        assert_eq!(
+            loaded.session_id(&AgentProvider::Claude).await,
+            Some("c-1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save_to_path_replaces_file_without_leaving_temp_files() {
+        let dir = temp_path("sessions-atomic");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sessions.json");
+        std::fs::write(&path, "{}").unwrap();
+        let mgr = SessionManager::new();
+        mgr.set_session_id(AgentProvider::Claude, "c-2".to_string())
+            .await;
+        mgr.save_to_path(&path).await.unwrap();
+
+        let entries: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(entries, ["sessions.json"]);
+        assert!(saved.contains("c-2"));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_saves_to_one_path_all_succeed() {
+        let dir = temp_path("sessions-concurrent-saves");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sessions.json");
+        let mgr = SessionManager::new();
+        mgr.set_session_id(AgentProvider::Claude, "c-3".to_string())
+            .await;
+
+        let mut saves = tokio::task::JoinSet::new();
+        for _ in 0..16 {
+            let mgr = mgr.clone();
+            let path = path.clone();
+            saves.spawn(async move { mgr.save_to_path(&path).await });
+        }
+        let results = saves.join_all().await;
+        let entries = std::fs::read_dir(&dir).unwrap().count();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(results.iter().all(Result::is_ok), "{results:?}");
+        assert_eq!(entries, 1);
+        assert!(saved.contains("c-3"));
+    }
+
+    #[tokio::test]
+    async fn test_with_persistence_loads_existing_sessions() {
+        let path = temp_path("sessions-persistence.json");
+        std::fs::write(&path, r#"{"Gemini":"g-9"}"#).unwrap();
+        let mgr = SessionManager::with_persistence(&path).await.unwrap();
+        mgr.persist().await.unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            mgr.session_id(&AgentProvider::Gemini).await.as_deref(),
+            Some("g-9")
+        );
+        assert!(saved.contains("g-9"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_usage_accumulates_over_turns_and_persists() {
+        let path = temp_path("sessions-usage.json");
+        let _ = std::fs::remove_file(&path);
+        let script = fake_agent(
+            "usage-claude.sh",
+            r#"case "$*" in
+  *"--resume c-1"*) echo '{"response":"ok","usage":{"input_tokens":20,"output_tokens":10},"total_cost_usd":0.002}' ;;
+  *) echo '{"session_id":"c-1","response":"MEMORY_READY","usage":{"input_tokens":100,"output_tokens":5},"total_cost_usd":0.01}' ;;
+esac
+"#,
+        );
+        let mgr = SessionManager::with_persistence(&path)
+            .await
+            .unwrap()
+            .with_command_override(AgentProvider::Claude, &script);
+        let options = ExecOptions {
+            init_prompt: Some("init".to_string()),
+            ..ExecOptions::new()
+        }
+        .with_json_response(true);
+        for prompt in ["first", "second"] {
+            mgr.execute_with_resume_with_options(AgentProvider::Claude, prompt, &options, |_| {})
+                .await
+                .unwrap();
+        }
+        let review = ConversationId::new("review");
+        mgr.execute_with_resume_with_options(
+            AgentProvider::Claude,
+            "first",
+            &options.clone().with_conversation(review.clone()),
+            |_| {},
+        )
+        .await
+        .unwrap();
+        let conversation = ConversationId::default();
+        let totals = mgr.usage_for(&AgentProvider::Claude, &conversation).await;
+        let review_totals = mgr.usage_for(&AgentProvider::Claude, &review).await;
+        let reloaded = SessionManager::load_from_path(&path).await.unwrap();
+        let persisted = reloaded
+            .usage_for(&AgentProvider::Claude, &conversation)
+            .await;
+        let persisted_review = reloaded.usage_for(&AgentProvider::Claude, &review).await;
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(totals.turns, 3);
+        assert_eq!(totals.input_tokens, 140);
+        assert_eq!(totals.output_tokens, 25);
+        assert_eq!(totals.total_tokens(), 165);
+        assert!((totals.cost_usd - 0.014).abs() < 1e-9);
+        assert_eq!(persisted, totals);
+        assert_eq!(review_totals.turns, 2);
+        assert_eq!(review_totals.input_tokens, 120);
+        assert_eq!(persisted_review, review_totals);
+        assert_eq!(
+            reloaded.session_id(&AgentProvider::Claude).await.as_deref(),
+            Some("c-1")
+        );
+        assert_eq!(
+            mgr.usage_for(&AgentProvider::Claude, &ConversationId::new("other"))
+                .await,
+            UsageTotals::default()
+        );
+    }
+
+    #[test]
+    fn test_extract_usage_reads_gemini_stats() {
+        let output = r#"{"response":"hi","stats":{"models":{"gemini-2.5-pro":{"tokens":{"prompt":30,"candidates":12,"total":42}}}}}"#;
+        assert_eq!(
+            SessionManager::extract_usage(output),
+            Some(TokenUsage {
+                input_tokens: 30,
+                output_tokens: 12,
+                cost_usd: None,
+            })
+        );
+        assert_eq!(SessionManager::extract_usage("plain text"), None);
+    }
+
+    #[tokio::test]
+    async fn test_load_from_missing_path_is_empty() {
+        let path = temp_path("sessions-missing.json");
+        let loaded = SessionManager::load_from_path(&path).await.unwrap();
+        assert!(loaded.session_ids.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_load_from_malformed_path_is_typed_error() {
+        let path = temp_path("sessions-malformed.json");
+        std::fs::write(&path, "{not json").unwrap();
+        let result = SessionManager::load_from_path(&path).await;
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(
+            result,
+            Err(AgentError::MalformedSessionStore { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import_state_round_trips_through_json() {
+        let mgr = SessionManager::new().with_max_turns_per_session(5);
+        mgr.record_session(AgentProvider::Claude, "c-1".to_string(), "plan the release")
+            .await;
+        mgr.set_session_id(AgentProvider::Gemini, "g-1".to_string())
+            .await;
+        mgr.claim_turn(&default_key(&AgentProvider::Claude)).await;
+        mgr.claim_turn(&default_key(&AgentProvider::Claude)).await;
+        let review = (AgentProvider::Claude, ConversationId::new("review"));
+        mgr.set_session(review.clone(), "c-review".to_string())
+            .await;
+        mgr.claim_turn(&review).await;
+
+        let json = serde_json::to_string(&mgr.export_state().await).unwrap();
+        let restored = SessionManager::new();
+        restored
+            .import_state(serde_json::from_str(&json).unwrap())
+            .await;
+
+        assert_eq!(restored.export_state().await, mgr.export_state().await);
+        assert_eq!(
+            restored.session_id(&AgentProvider::Gemini).await.as_deref(),
+            Some("g-1")
+        );
+        assert_eq!(
+            restored
+                .turn_counts
+                .lock()
+                .await
+                .get(&default_key(&AgentProvider::Claude)),
+            Some(&2)
+        );
+        assert_eq!(
+            restored
+                .conversation_session_id(&AgentProvider::Claude, &review.1)
+                .await
+                .as_deref(),
+            Some("c-review")
+        );
+        assert_eq!(restored.turn_counts.lock().await.get(&review), Some(&1));
+        assert_eq!(restored.find_session_by_prompt("release").await.len(), 1);
+    }
+
+    // ─── AgentExecutor::execute_stream tests ──────────────────────────────────
+
+    #[tokio::test]
+    async fn test_execute_stream_chunks() {
+        let received = Arc::new(StdMutex::new(String::new()));
+        let received_clone = Arc::clone(&received);
+        let _ = AgentExecutor::execute_stream(AgentProvider::Mock, "test", move |chunk| {
+            received_clone.lock().unwrap().push_str(&chunk);
+        })
+        .await;
+        assert_eq!(*received.lock().unwrap(), "Mock stream: pong");
+    }
+
+    #[tokio::test]
+    async fn test_execute_stream_mock_succeeds() {
+        let result = AgentExecutor::execute_stream(AgentProvider::Mock, "any prompt", |_| {}).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_execute_stream_mock_calls_callback_once() {
+        let count = Arc::new(StdMutex::new(0usize));
+        let count_clone = Arc::clone(&count);
+        let _ = AgentExecutor::execute_stream(AgentProvider::Mock, "hello", move |_| {
+            *count_clone.lock().unwrap() += 1;
+        })
+        .await;
+        assert_eq!(*count.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_execute_stream_dummy_echoes_prompt_exactly() {
+        let received = Arc::new(StdMutex::new(String::new()));
+        let received_clone = Arc::clone(&received);
+        let _ = AgentExecutor::execute_stream(AgentProvider::Dummy, "echo me", move |chunk| {
+            received_clone.lock().unwrap().push_str(&chunk);
+        })
+        .await;
+        assert_eq!(*received.lock().unwrap(), "echo me");
+    }
+
+    #[tokio::test]
+    async fn test_execute_events_mock_yields_raw_line_then_done() {
+        let mut events = Vec::new();
+        AgentExecutor::execute_events(
+            AgentProvider::Mock,
+            "ping",
+            &ExecOptions::default(),
+            |event| events.push(event),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                AgentEvent::Raw("Mock stream: pong".to_string()),
+                AgentEvent::Done {
+                    response: String::new()
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_mock_returns_concatenated_message() {
+        let output = AgentExecutor::execute(AgentProvider::Mock, "ping")
+            .await
+            .unwrap();
+        assert_eq!(output, "Mock stream: pong");
+    }
+
+    #[tokio::test]
+    async fn test_execute_dummy_prefers_json_response_field() {
+        let output = AgentExecutor::execute(
+            AgentProvider::Dummy,
+            r#"{"session_id":"s","response":"just the answer"}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(output, "just the answer");
+    }
+
+    #[tokio::test]
+    async fn test_execute_trims_plain_text_output() {
+        let output = AgentExecutor::execute(AgentProvider::Dummy, "\n  plain answer \n")
+            .await
+            .unwrap();
+        assert_eq!(output, "plain answer");
+    }
+
+    #[tokio::test]
+    async fn test_execute_stream_iter_yields_mock_chunk() {
+        use tokio_stream::StreamExt;
+
+        let chunks: Vec<_> = AgentExecutor::execute_stream_iter(AgentProvider::Mock, "ping")
+            .collect()
+            .await;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].as_ref().unwrap(), "Mock stream: pong");
+    }
+
+    #[tokio::test]
+    async fn test_execute_stream_iter_yields_spawn_error() {
+        use tokio_stream::StreamExt;
+
+        let mut stream = AgentExecutor::execute_stream_iter(AgentProvider::Aider, "ping");
+        // aider is not installed in the test environment.
+        let first = stream.next().await.unwrap();
+        assert!(matches!(first, Err(AgentError::SpawnFailed { .. })));
+        assert!(stream.next().await.is_none());
+    }
+
+    // ─── SessionManager::execute_with_resume (Mock) tests ────────────────────
+
+    #[tokio::test]
+    async fn test_execute_with_resume_mock_succeeds() {
+        let mgr = SessionManager::new();
+        let result = mgr
+            .execute_with_resume(AgentProvider::Mock, "hello", |_| {})
+            .await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_execute_with_resume_mock_returns_prompt_in_message() {
+        let mgr = SessionManager::new();
+        let received = Arc::new(StdMutex::new(String::new()));
+        let received_clone = Arc::clone(&received);
+        let _ = mgr
+            .execute_with_resume(AgentProvider::Mock, "my prompt", move |chunk| {
+                received_clone.lock().unwrap().push_str(&chunk);
+            })
+            .await;
+        let result = received.lock().unwrap().clone();
+        assert!(
+            result.contains("my prompt"),
+            "Expected 'my prompt' in '{}'",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_with_resume_mock_does_not_store_session() {
+        let mgr = SessionManager::new();
+        let _ = mgr
+            .execute_with_resume(AgentProvider::Mock, "test", |_| {})
+            .await;
+        // Mock should not pollute the session store
+        let sessions = mgr.session_ids.lock().await;
+        assert!(sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_execute_with_resume_mock_multiple_calls_succeed() {
+        let mgr = SessionManager::new();
+        for i in 0..3 {
+            let result = mgr
+                .execute_with_resume(AgentProvider::Mock, &format!("prompt {}", i), |_| {})
+                .await;
+            assert!(result.is_ok(), "Call {} should succeed", i);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_with_resume_dummy_echoes_prompt_exactly() {
+        let mgr = SessionManager::new();
+        let received = Arc::new(StdMutex::new(String::new()));
+        let received_clone = Arc::clone(&received);
+        let _ = mgr
+            .execute_with_resume(AgentProvider::Dummy, "dummy prompt", move |chunk| {
+                received_clone.lock().unwrap().push_str(&chunk);
+            })
+            .await;
+        assert_eq!(received.lock().unwrap().as_str(), "dummy prompt");
+    }
+
+    #[tokio::test]
+    async fn test_execute_with_resume_dummy_does_not_store_session() {
+        let mgr = SessionManager::new();
+        let _ = mgr
+            .execute_with_resume(AgentProvider::Dummy, "test", |_| {})
+            .await;
+        let sessions = mgr.session_ids.lock().await;
+        assert!(sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_execute_with_resume_concurrent_calls_do_not_serialize() {
+        let mgr = SessionManager::new();
+        let events = Arc::new(StdMutex::new(Vec::new()));
+        let (first_events, second_events) = (Arc::clone(&events), Arc::clone(&events));
+        let (a, b) = tokio::join!(
+            mgr.execute_with_resume(AgentProvider::Mock, "first", move |chunk| {
+                first_events
+                    .lock()
+                    .unwrap()
+                    .push(format!("first:{}", chunk));
+            }),
+            mgr.execute_with_resume(AgentProvider::Mock, "second", move |chunk| {
+                second_events
+                    .lock()
+                    .unwrap()
+                    .push(format!("second:{}", chunk));
+            }),
+        );
+        assert!(a.is_ok() && b.is_ok());
+        // The second call starts while the first is still mid-run.
+        let events = events.lock().unwrap();
+        assert!(events[0].starts_with("first:Mock"));
+        assert!(events[1].starts_with("second:Mock"));
+        assert!(mgr.session_ids.try_lock().is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_seed_prompt_is_returned_on_first_contact_only() {
+        let script = fake_agent(
+            "seed-prompt-claude.sh",
+            r#"case "$*" in
+  *"--output-format json"*) echo '{"session_id":"s1","response":"MEMORY_READY"}' ;;
+  *) echo "resumed" ;;
+esac
+"#,
+        );
+        let mgr = SessionManager::new().with_command_override(AgentProvider::Claude, &script);
+        let options = ExecOptions {
+            init_prompt: Some("init token=sk-abcdefghijklmnopqrstuv".to_string()),
+            ..ExecOptions::new()
+        }
+        .with_include_seed_prompt(true);
+        let first = mgr
+            .execute_with_resume_result(AgentProvider::Claude, "hi", &options, |_| {})
+            .await
+            .unwrap();
+        let second = mgr
+            .execute_with_resume_result(AgentProvider::Claude, "again", &options, |_| {})
+            .await
+            .unwrap();
+        let _ = std::fs::remove_file(&script);
+        assert_eq!(
+            first.seed_prompt.as_deref(),
+            Some("init token=sk-abcdefghijklmnopqrstuv")
+        );
+        assert!(
+            !first
+                .redacted_seed_prompt()
+                .unwrap()
+                .contains("sk-abcdefghijklmnopqrstuv")
+        );
+        assert_eq!(second.seed_prompt, None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_different_tools_seed_and_resume_concurrently() {
+        const SLOW_AGENT: &str = r#"sleep 0.4
+case "$*" in
+  *"--output-format json"*) echo '{"session_id":"slow","response":"ok"}' ;;
+  *) echo "resumed" ;;
+esac
+"#;
+        let claude = fake_agent("slow-claude.sh", SLOW_AGENT);
+        let gemini = fake_agent("slow-gemini.sh", SLOW_AGENT);
+        let mgr = SessionManager::new()
+            .with_command_override(AgentProvider::Claude, &claude)
+            .with_command_override(AgentProvider::Gemini, &gemini);
+        let options = ExecOptions {
+            init_prompt: Some("init".to_string()),
+            ..ExecOptions::new()
+        };
+        let started = Instant::now();
+        let (a, b) = tokio::join!(
+            mgr.execute_with_resume_collect_with_options(
+                AgentProvider::Claude,
+                "hi",
+                &options,
+                |_| {}
+            ),
+            mgr.execute_with_resume_collect_with_options(
+                AgentProvider::Gemini,
+                "hi",
+                &options,
+                |_| {}
+            ),
+        );
+        let elapsed = started.elapsed();
+        let _ = std::fs::remove_file(&claude);
+        let _ = std::fs::remove_file(&gemini);
+        assert_eq!(a.unwrap().trim(), "resumed");
+        assert_eq!(b.unwrap().trim(), "resumed");
+        // Each call is a 0.4s seed plus a 0.4s resume; run back to back they
+        // would take 1.6s.
+        assert!(elapsed < Duration::from_millis(1400), "took {elapsed:?}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_concurrent_first_contact_calls_seed_once() {
+        let seeds = temp_path("concurrent-seeds.log");
+        let _ = std::fs::remove_file(&seeds);
+        let script = fake_agent(
+            "seed-once.sh",
+            &format!(
+                r#"case "$*" in
+  *"--output-format json"*) echo seed >> {}; sleep 0.2; echo '{{"session_id":"only","response":"ok"}}' ;;
+  *) echo "resumed $*" ;;
+esac
+"#,
+                seeds.display()
+            ),
+        );
+        let mgr = SessionManager::new();
+        let options = ExecOptions {
+            init_prompt: Some("init".to_string()),
+            ..ExecOptions::new().with_command_override(AgentProvider::Claude, &script)
+        };
+        let mut tasks = tokio::task::JoinSet::new();
+        for i in 0..8 {
+            let (mgr, options) = (mgr.clone(), options.clone());
+            tasks.spawn(async move {
+                mgr.execute_with_resume_collect_with_options(
+                    AgentProvider::Claude,
+                    &format!("turn {i}"),
+                    &options,
+                    |_| {},
+                )
+                .await
+            });
+        }
+        let outputs = tasks.join_all().await;
+        let seed_count = std::fs::read_to_string(&seeds).unwrap().lines().count();
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&seeds);
+        for output in outputs {
+            assert!(output.unwrap().contains("--resume only"));
+        }
+        assert_eq!(seed_count, 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_warm_sessions_and_a_first_turn_seed_once() {
+        let seeds = temp_path("warm-race-seeds.log");
+        let _ = std::fs::remove_file(&seeds);
+        let script = fake_agent(
+            "warm-race.sh",
+            &format!(
+                r#"case "$*" in
+  *"--output-format json"*) echo seed >> {}; sleep 0.2; echo '{{"session_id":"only","response":"MEMORY_READY"}}' ;;
+  *) echo "resumed $*" ;;
+esac
+"#,
+                seeds.display()
+            ),
+        );
+        let mgr = SessionManager::new().with_command_override(AgentProvider::Claude, &script);
+        let prepared = PreparedInitPrompt {
+            context: String::new(),
+            prompt: "init".to_string(),
+            amem_error: None,
+        };
+        let options = ExecOptions {
+            init_prompt: Some("init".to_string()),
+            ..ExecOptions::new()
+        };
+        let (warmed, turn) = tokio::join!(
+            mgr.warm_sessions(&[AgentProvider::Claude], &prepared),
+            mgr.execute_with_resume_collect_with_options(
+                AgentProvider::Claude,
+                "hi",
+                &options,
+                |_| {}
+            ),
+        );
+        let seed_count = std::fs::read_to_string(&seeds).unwrap().lines().count();
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&seeds);
+        warmed.unwrap();
+        assert!(turn.unwrap().contains("--resume only"));
+        assert_eq!(seed_count, 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_conversations_of_one_tool_keep_separate_sessions() {
+        let script = fake_agent(
+            "conversations.sh",
+            r#"case "$*" in
+  *"--output-format json"*) echo "{\"session_id\":\"s-$$\",\"response\":\"ok\"}" ;;
+  *) echo "resumed $*" ;;
+esac
+"#,
+        );
+        let mgr = SessionManager::new();
+        let base = ExecOptions {
+            init_prompt: Some("init".to_string()),
+            ..ExecOptions::new().with_command_override(AgentProvider::Claude, &script)
+        };
+        let mut outputs = Vec::new();
+        for conversation in ["review", "release", "review"] {
+            let options = base.clone().with_conversation(conversation);
+            outputs.push(
+                mgr.execute_with_resume_collect_with_options(
+                    AgentProvider::Claude,
+                    "next",
+                    &options,
+                    |_| {},
+                )
+                .await
+                .unwrap(),
+            );
+        }
+        let _ = std::fs::remove_file(&script);
+
+        let review = mgr
+            .conversation_session_id(&AgentProvider::Claude, &"review".into())
+            .await
+            .unwrap();
+        let release = mgr
+            .conversation_session_id(&AgentProvider::Claude, &"release".into())
+            .await
+            .unwrap();
+        assert_ne!(review, release);
+        assert!(outputs[0].contains(&format!("--resume {review}")));
+        assert!(outputs[1].contains(&format!("--resume {release}")));
+        assert!(outputs[2].contains(&format!("--resume {review}")));
+        assert_eq!(mgr.session_id(&AgentProvider::Claude).await, None);
+        assert_eq!(mgr.active_tools().await, vec![AgentProvider::Claude]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_seed_without_memory_ready_warns_or_fails() {
+        let script = fake_agent(
+            "ignored-memory.sh",
+            r#"case "$*" in
+  *"--output-format json"*) echo '{"session_id":"s-1","response":"Hi! How can I help?"}' ;;
+  *) echo "resumed $*" ;;
+esac
+"#,
+        );
+        let options = ExecOptions {
+            init_prompt: Some("init".to_string()),
+            ..ExecOptions::new().with_command_override(AgentProvider::Claude, &script)
+        };
+        let warned = SessionManager::new();
+        let lenient = warned
+            .execute_with_resume_collect_with_options(AgentProvider::Claude, "hi", &options, |_| {})
+            .await;
+        let strict = SessionManager::new();
+        let required = strict
+            .execute_with_resume_collect_with_options(
+                AgentProvider::Claude,
+                "hi",
+                &options.clone().with_require_memory_ready(true),
+                |_| {},
+            )
+            .await;
+        let _ = std::fs::remove_file(&script);
+
+        assert!(lenient.unwrap().contains("--resume s-1"));
+        assert!(matches!(
+            required,
+            Err(AgentError::MemoryNotLoaded {
+                provider: AgentProvider::Claude
+            })
+        ));
+        assert_eq!(strict.session_id(&AgentProvider::Claude).await, None);
+    }
+
+    #[test]
+    fn test_memory_acknowledged_reads_the_seed_response() {
+        assert!(SessionManager::memory_acknowledged(
+            r#"{"session_id":"s","response":"MEMORY_READY"}"#
+        ));
+        assert!(SessionManager::memory_acknowledged("MEMORY_READY\n"));
+        assert!(!SessionManager::memory_acknowledged(
+            r#"{"session_id":"s","response":"Hello"}"#
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_permission_mode_controls_skip_permission_flags() {
+        let script = fake_agent(
+            "permissions.sh",
+            r#"case "$*" in
+  *"--output-format json"*) echo "{\"session_id\":\"s-$$\",\"response\":\"seeded $*\"}" ;;
+  *) echo "resumed $*" ;;
+esac
+"#,
+        );
+        let mut turns = Vec::new();
+        for mode in [
+            PermissionMode::Yolo,
+            PermissionMode::Default,
+            PermissionMode::Restricted,
+        ] {
+            let options = ExecOptions {
+                init_prompt: Some("init".to_string()),
+                ..ExecOptions::new()
+                    .with_command_override(AgentProvider::Claude, &script)
+                    .with_permission_mode(mode)
+            };
+            let output = SessionManager::new()
+                .execute_with_resume_collect_with_options(
+                    AgentProvider::Claude,
+                    "next",
+                    &options,
+                    |_| {},
+                )
+                .await
+                .unwrap();
+            turns.push(output);
+        }
+        let _ = std::fs::remove_file(&script);
+
+        assert!(turns[0].contains("resumed --dangerously-skip-permissions --resume"));
+        assert!(turns[1].contains("resumed --resume"));
+        assert!(turns[2].contains("resumed --permission-mode plan --resume"));
+        assert!(
+            turns[1..]
+                .iter()
+                .all(|turn| !turn.contains("--dangerously-skip-permissions"))
+        );
+    }
+
+    #[test]
+    fn test_permission_mode_parses_and_omits_skip_flags() {
+        assert_eq!("Yolo".parse(), Ok(PermissionMode::Yolo));
+        assert_eq!("restricted".parse(), Ok(PermissionMode::Restricted));
+        assert!("paranoid".parse::<PermissionMode>().is_err());
+        for provider in [
+            AgentProvider::Gemini,
+            AgentProvider::Claude,
+            AgentProvider::Cursor,
+            AgentProvider::Aider,
+        ] {
+            assert!(!PermissionMode::Yolo.args(&provider).is_empty());
+            assert!(PermissionMode::Default.args(&provider).is_empty());
+        }
+        assert_eq!(
+            PermissionMode::Restricted.args(&AgentProvider::Gemini),
+            ["--approval-mode", "default"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_with_tool_result_round_trips_through_dummy() {
+        let mgr = SessionManager::new();
+        let result = serde_json::json!({ "files": ["a.rs", "b.rs"], "exit_code": 0 });
+        let received = Arc::new(StdMutex::new(String::new()));
+        let sink = Arc::clone(&received);
+        mgr.execute_with_tool_result(
+            AgentProvider::Dummy,
+            "call_42",
+            &result,
+            &ExecOptions::default(),
+            move |chunk| sink.lock().unwrap().push_str(&chunk),
+        )
+        .await
+        .unwrap();
+
+        let echoed = received.lock().unwrap().clone();
+        assert!(echoed.contains("call_42"));
+        let body = echoed
+            .split_once(">\n")
+            .and_then(|(_, rest)| rest.rsplit_once("\n</tool_result>"))
+            .map(|(body, _)| body)
+            .unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(parsed, result);
+    }
+
+    #[cfg(unix)]
+    const TOOL_CALLING_CLAUDE: &str = r#"case "$*" in
+  *"<tool_result id=\"call_1\">"*) echo '{"type":"result","response":"src has 2 files"}' ;;
+  *) echo '{"type":"tool_call","id":"call_1","name":"list_dir","arguments":{"path":"src"}}' ;;
+esac
+"#;
+
+    #[cfg(unix)]
+    async fn tool_loop(
+        name: &str,
+        body: &str,
+        max_iterations: usize,
+    ) -> (AgentLoopOutcome, Vec<ToolCall>) {
+        let script = fake_agent(name, body);
+        let mgr = SessionManager::new();
+        mgr.set_session_id(AgentProvider::Claude, "s-1".to_string())
+            .await;
+        let options = ExecOptions::new().with_command_override(AgentProvider::Claude, &script);
+        let calls = StdMutex::new(Vec::new());
+        let outcome = mgr
+            .run_agent_loop(
+                AgentProvider::Claude,
+                "how many files?",
+                max_iterations,
+                &options,
+                |call| {
+                    calls.lock().unwrap().push(call);
+                    async { serde_json::json!({ "files": ["lib.rs", "main.rs"] }) }
+                },
+            )
+            .await;
+        let _ = std::fs::remove_file(&script);
+        (outcome.unwrap(), calls.into_inner().unwrap())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_agent_loop_answers_tool_call_then_returns_final_answer() {
+        let (outcome, calls) = tool_loop("tool-loop.sh", TOOL_CALLING_CLAUDE, 5).await;
+        assert_eq!(
+            outcome,
+            AgentLoopOutcome {
+                iterations: 2,
+                tool_calls: 1,
+                completed: true,
+                answer: "src has 2 files".to_string(),
+            }
+        );
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "list_dir");
+        assert_eq!(calls[0].arguments, serde_json::json!({ "path": "src" }));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_agent_loop_stops_at_max_iterations() {
+        let body = "echo '{\"type\":\"tool_call\",\"id\":\"again\",\"name\":\"noop\"}'\n";
+        let (outcome, calls) = tool_loop("tool-loop-forever.sh", body, 3).await;
+        assert!(!outcome.completed);
+        assert_eq!(outcome.iterations, 3);
+        assert_eq!(calls.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_execute_with_resume_collect_mock_returns_full_message() {
+        let mgr = SessionManager::new();
+        let output = mgr
+            .execute_with_resume_collect(AgentProvider::Mock, "hello")
+            .await
+            .unwrap();
+        assert_eq!(output, "Mock: received your prompt 'hello'.");
+    }
+
+    #[tokio::test]
+    async fn test_execute_with_resume_collect_returns_exactly_what_was_streamed() {
+        let mgr = SessionManager::new();
+        let streamed = Arc::new(StdMutex::new(String::new()));
+        let sink = Arc::clone(&streamed);
+        let transcript = mgr
+            .execute_with_resume_collect_with_options(
+                AgentProvider::Mock,
+                "summarize me",
+                &ExecOptions::default(),
+                move |chunk| sink.lock().unwrap().push_str(&chunk),
+            )
+            .await
+            .unwrap();
+        assert_eq!(transcript, *streamed.lock().unwrap());
+        assert_eq!(transcript, "Mock: received your prompt 'summarize me'.");
+    }
+
+    // ─── SessionManager::execute_batch tests ──────────────────────────────────
+
+    #[tokio::test]
+    async fn test_execute_batch_flags_duplicate_prompt_but_runs_it() {
+        let mgr = SessionManager::new();
+        let outcome = mgr
+            .execute_batch(
+                AgentProvider::Dummy,
+                &["write tests", "fix lint", " write tests "],
+                &ExecOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome.outputs,
+            ["write tests", "fix lint", " write tests "]
+        );
+        assert_eq!(outcome.duplicates, [2]);
+    }
+
+    // ─── SessionManager::fan_out tests ────────────────────────────────────────
+
+    #[tokio::test]
+    async fn test_fan_out_respects_max_parallel() {
+        let running = Arc::new(StdMutex::new((0usize, 0usize)));
+        let tracker = Arc::clone(&running);
+        // Mock turns pause between `Spawned` and `Exited`, so overlapping turns
+        // show up as concurrent `Spawned` events.
+        let options = ExecOptions::new().on_lifecycle(move |event| {
+            let mut counts = tracker.lock().unwrap();
+            match event {
+                Lifecycle::Spawned { .. } => {
+                    counts.0 += 1;
+                    counts.1 = counts.1.max(counts.0);
+                }
+                Lifecycle::Exited { .. } => counts.0 -= 1,
+                _ => {}
+            }
+        });
+        let providers = vec![AgentProvider::Mock; 4];
+        let results = SessionManager::new()
+            .fan_out(&providers, "compare", &options, Some(2))
+            .await;
+
+        assert_eq!(results.len(), 4);
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        let (active, peak) = *running.lock().unwrap();
+        assert_eq!(active, 0);
+        assert_eq!(peak, 2);
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_keeps_results_in_input_order() {
+        let providers = [AgentProvider::Dummy, AgentProvider::Mock];
+        let results = SessionManager::new()
+            .fan_out(&providers, "order", &ExecOptions::default(), None)
+            .await;
+        assert_eq!(results[0].0, AgentProvider::Dummy);
+        assert_eq!(results[0].1.as_deref().unwrap(), "order");
+        assert_eq!(results[1].0, AgentProvider::Mock);
+    }
+
+    #[test]
+    fn test_duplicate_prompts_empty_for_unique_batch() {
+        assert!(SessionManager::duplicate_prompts(&["a", "b", "c"]).is_empty());
+    }
+
+    // ─── SessionManager::execute_until_done tests ─────────────────────────────
+
+    #[tokio::test]
+    async fn test_execute_until_done_stops_when_agent_signals_done() {
+        // Dummy echoes each prompt, so the agent "signals" done on the second
+        // turn, when it echoes the continuation prompt containing the marker.
+        let mgr = SessionManager::new();
+        let config = ContinueLoop {
+            continuation_prompt: "finished: ALL_DONE".to_string(),
+            done_marker: "ALL_DONE".to_string(),
+            max_iterations: 5,
+        };
+        let mut seen = Vec::new();
+        let outcome = mgr
+            .execute_until_done(
+                AgentProvider::Dummy,
+                "start working",
+                &config,
+                &ExecOptions::default(),
+                |i, output| seen.push((i, output.to_string())),
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.iterations, 2);
+        assert!(outcome.completed);
+        assert_eq!(
+            seen,
+            vec![
+                (1, "start working".to_string()),
+                (2, "finished: ALL_DONE".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_until_done_respects_max_iterations() {
+        let mgr = SessionManager::new();
+        let config = ContinueLoop {
+            continuation_prompt: "keep going".to_string(),
+            done_marker: "NEVER_PRINTED".to_string(),
+            max_iterations: 3,
+        };
+        let outcome = mgr
+            .execute_until_done(
+                AgentProvider::Mock,
+                "loop",
+                &config,
+                &ExecOptions::default(),
+                |_, _| {},
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.iterations, 3);
+        assert!(!outcome.completed);
+        assert!(outcome.last_output.contains("keep going"));
+    }
+
+    // ─── Output normalization tests ───────────────────────────────────────────
+
+    const SAMPLE_OUTPUT: &str = "\n\n  Summary line  \n\n\n\nSecond line\t\n\n";
+
+    #[test]
+    fn test_normalization_none_keeps_output() {
+        assert_eq!(
+            OutputNormalization::None.apply(SAMPLE_OUTPUT),
+            SAMPLE_OUTPUT
+        );
+    }
+
+    #[test]
+    fn test_normalization_trim_strips_both_ends() {
+        assert_eq!(
+            OutputNormalization::Trim.apply(SAMPLE_OUTPUT),
+            "Summary line  \n\n\n\nSecond line"
+        );
+    }
+
+    #[test]
+    fn test_normalization_collapse_blank_lines() {
+        assert_eq!(
+            OutputNormalization::CollapseBlankLines.apply(SAMPLE_OUTPUT),
+            "  Summary line\n\nSecond line"
+        );
+    }
+
+    #[test]
+    fn test_normalization_defaults_to_trim() {
+        assert_eq!(OutputNormalization::default(), OutputNormalization::Trim);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_normalization_applies_to_collected_and_recorded_output() {
+        let log = temp_path("normalized-keep.log");
+        let _ = std::fs::remove_file(&log);
+        let amem = fake_agent(
+            "normalized-amem.sh",
+            &format!(
+                "if [ \"$1\" = keep ]; then printf '%s|' \"$2\" >> {}; fi\n",
+                log.display()
+            ),
+        );
+        // Seeds with a session id, resumes with blank-line-heavy output and
+        // echoes one-shot (summary) prompts back.
+        let claude = fake_agent(
+            "normalized-claude.sh",
+            r#"case "$*" in
+  *"--output-format json"*) echo '{"session_id":"n-1","response":"ok"}' ;;
+  *"--resume"*) printf '\n\n  done  \n\n\n\nnext\n\n' ;;
+  *) for arg; do last=$arg; done; printf '%s' "$last" ;;
+esac
+"#,
+        );
+        let options = ExecOptions {
+            init_prompt: Some("init".to_string()),
+            ..ExecOptions::new()
+                .with_command_override(AgentProvider::Claude, &claude)
+                .with_amem_binary(&amem)
+                .with_normalization(OutputNormalization::CollapseBlankLines)
+        };
+        let collected = SessionManager::new()
+            .execute_with_resume_collect_with_options(AgentProvider::Claude, "hi", &options, |_| {})
+            .await
+            .unwrap();
+        let record = RecordOptions::default().with_summary_template("[{transcript}]");
+        AgentExecutor::summarize_and_record_as(
+            AgentProvider::Claude,
+            SAMPLE_OUTPUT,
+            &record,
+            &options,
+        )
+        .await
+        .unwrap();
+        let kept = std::fs::read_to_string(&log).unwrap();
+        for path in [&log, &amem, &claude] {
+            let _ = std::fs::remove_file(path);
+        }
+
+        assert_eq!(collected, "  done\n\nnext");
+        assert_eq!(kept, "[  Summary line\n\nSecond line]|");
+    }
+
+    // ─── Summary prompt tests ─────────────────────────────────────────────────
+
+    const CODE_HEAVY_TRANSCRIPT: &str = "Fix the off-by-one in `parse_header`.\n```rust\nfn parse_header(buf: &[u8]) -> usize {\n    buf.len() - 1\n}\n```\nDone, tests pass.";
+
+    #[test]
+    fn test_summary_prompt_uses_code_aware_instruction_for_code_heavy_transcript() {
+        let prompt = AgentExecutor::summary_prompt(CODE_HEAVY_TRANSCRIPT, true);
+        assert!(prompt.starts_with(CODE_SUMMARY_INSTRUCTION));
+        assert!(prompt.ends_with(CODE_HEAVY_TRANSCRIPT));
+    }
+
+    #[test]
+    fn test_summary_prompt_keeps_generic_instruction_when_disabled_or_prose() {
+        assert!(
+            AgentExecutor::summary_prompt(CODE_HEAVY_TRANSCRIPT, false)
+                .starts_with(SUMMARY_INSTRUCTION)
+        );
+        assert!(
+            AgentExecutor::summary_prompt("We discussed the roadmap for next week.", true)
+                .starts_with(SUMMARY_INSTRUCTION)
+        );
+    }
+
+    // ─── Prepared init prompt tests ───────────────────────────────────────────
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_warm_sessions_reuses_prepared_prompt() {
+        let log = temp_path("warm-sessions.log");
+        let _ = std::fs::remove_file(&log);
+        let amem = fake_agent(
+            "warm-amem.sh",
+            &format!(
+                "echo \"amem $*\" >> {}\necho '{{\"activity\":\"shipped v1\"}}'\n",
+                log.display()
+            ),
+        );
+        let claude = fake_agent(
+            "warm-claude.sh",
+            &format!(
+                r#"case "$*" in
+  *"shipped v1"*) echo "claude seed" >> {} ;;
+esac
+echo '{{"session_id":"warm-1","response":"MEMORY_READY"}}'
+"#,
+                log.display()
+            ),
+        );
+        let mgr = SessionManager::new().with_command_override(AgentProvider::Claude, &claude);
+        let prepared = AgentExecutor::prepare_init_prompt_with_options(
+            &ExecOptions::new().with_amem_binary(&amem),
+        )
+        .await;
+        for _ in 0..2 {
+            mgr.warm_sessions(&[AgentProvider::Claude], &prepared)
+                .await
+                .unwrap();
+        }
+        let calls = std::fs::read_to_string(&log).unwrap();
+        let _ = std::fs::remove_file(&log);
+        let _ = std::fs::remove_file(&amem);
+        let _ = std::fs::remove_file(&claude);
+
+        assert_eq!(calls, "amem today --json\nclaude seed\n");
+        assert_eq!(
+            mgr.session_id(&AgentProvider::Claude).await.as_deref(),
+            Some("warm-1")
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_warmed_sessions_are_persisted_and_count_toward_the_cap() {
+        let path = temp_path("warm-persisted.json");
+        let _ = std::fs::remove_file(&path);
+        let script = fake_agent(
+            "warm-any.sh",
+            r#"echo "{\"session_id\":\"warm-$(basename $0)\",\"response\":\"MEMORY_READY\"}"
+"#,
+        );
+        let evicted = Arc::new(StdMutex::new(Vec::new()));
+        let evicted_clone = Arc::clone(&evicted);
+        let mgr = SessionManager::with_persistence(&path)
+            .await
+            .unwrap()
+            .with_max_sessions(1)
+            .on_evict(move |provider, _| evicted_clone.lock().unwrap().push(provider))
+            .with_command_override(AgentProvider::Claude, &script)
+            .with_command_override(AgentProvider::Gemini, &script);
+        let prepared = PreparedInitPrompt {
+            context: String::new(),
+            prompt: "init".to_string(),
+            amem_error: None,
+        };
+        mgr.warm_sessions(&[AgentProvider::Claude, AgentProvider::Gemini], &prepared)
+            .await
+            .unwrap();
+        let reloaded = SessionManager::load_from_path(&path).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&script);
+
+        assert_eq!(*evicted.lock().unwrap(), [AgentProvider::Claude]);
+        assert_eq!(mgr.session_id(&AgentProvider::Claude).await, None);
+        assert!(mgr.session_id(&AgentProvider::Gemini).await.is_some());
+        assert_eq!(
+            reloaded.session_id(&AgentProvider::Gemini).await,
+            mgr.session_id(&AgentProvider::Gemini).await
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_seed_fetches_context_from_the_configured_amem() {
+        let log = temp_path("seed-amem.log");
+        let _ = std::fs::remove_file(&log);
+        let amem = fake_agent(
+            "seed-amem.sh",
+            &format!(
+                "echo \"amem $*\" >> {}\necho '{{\"activity\":\"shipped v2\"}}'\n",
+                log.display()
+            ),
+        );
+        let claude = fake_agent(
+            "seed-amem-claude.sh",
+            r#"case "$*" in
+  *"shipped v2"*) echo '{"session_id":"ctx-1","response":"MEMORY_READY"}' ;;
+  *"--output-format json"*) echo '{"session_id":"no-context","response":"MEMORY_READY"}' ;;
+  *) echo "resumed $*" ;;
+esac
+"#,
+        );
+        let options = ExecOptions::new()
+            .with_command_override(AgentProvider::Claude, &claude)
+            .with_amem_binary(&amem);
+        let output = SessionManager::new()
+            .execute_with_resume_collect_with_options(AgentProvider::Claude, "hi", &options, |_| {})
+            .await
+            .unwrap();
+        let calls = std::fs::read_to_string(&log).unwrap();
+        for path in [&log, &amem, &claude] {
+            let _ = std::fs::remove_file(path);
+        }
+        assert_eq!(calls, "amem today --json\n");
+        assert!(output.contains("--resume ctx-1"), "{output}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_prepared_prompt_uses_the_configured_delimiters() {
+        let amem = fake_agent(
+            "delimited-amem.sh",
+            "echo '{\"activity\":\"shipped v3\"}'\n",
+        );
+        let options = ExecOptions {
+            context_delimiters: ContextDelimiters::new("<<ctx>>", "<</ctx>>"),
+            ..ExecOptions::new().with_amem_binary(&amem)
+        };
+        let prepared = AgentExecutor::prepare_init_prompt_with_options(&options).await;
+        let built = AgentExecutor::build_init_prompt_with_options(&options).await;
+        let _ = std::fs::remove_file(&amem);
+        assert!(
+            prepared
+                .prompt
+                .starts_with("<<ctx>>\n## Recent Activities\nshipped v3")
+        );
+        assert_eq!(prepared.prompt, built);
+    }
+
+    #[tokio::test]
+    async fn test_prepare_init_prompt_matches_build_init_prompt() {
+        let prepared = AgentExecutor::prepare_init_prompt().await;
+        assert_eq!(
+            prepared.prompt,
+            AgentExecutor::init_prompt_from_context(
+                &prepared.context,
+                &ContextDelimiters::default()
+            )
+        );
+    }
+
+    #[test]
+    fn test_init_prompt_wraps_context_in_configured_delimiters() {
+        let delimiters = ContextDelimiters::new("<<MEMORY>>", "<</MEMORY>>");
+        let prompt = AgentExecutor::init_prompt_from_context("Owner: yui", &delimiters);
+        let open = prompt.find("<<MEMORY>>").unwrap();
+        let close = prompt.find("<</MEMORY>>").unwrap();
+        let context = prompt.find("Owner: yui").unwrap();
+        let instruction = prompt.find("MEMORY_READY").unwrap();
+        assert!(open < context && context < close);
+        assert!(instruction > close, "instruction must follow the context");
+    }
+
+    #[test]
+    fn test_prompt_with_context_keeps_user_prompt_outside_delimiters() {
+        let prompt = AgentExecutor::prompt_with_context(
+            "profile: likes tea\n",
+            "What is 2 + 2?",
+            &ContextDelimiters::default(),
+        );
+        assert_eq!(
+            prompt,
+            "<memory-context>\nprofile: likes tea\n</memory-context>\n\nWhat is 2 + 2?"
+        );
+    }
+
+    // ─── amem error tests ─────────────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn test_missing_amem_binary_is_not_installed() {
+        let err = amem::fetch_today(&temp_path("no-such-amem"), &TokioRunner)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AmemError::NotInstalled { .. }), "{err:?}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_malformed_amem_today_output_is_reported() {
+        let script = fake_agent("amem-garbage.sh", "echo 'today: not json'\n");
+        let result = amem::fetch_today(&script, &TokioRunner).await;
+        let _ = std::fs::remove_file(&script);
+        assert!(
+            matches!(result, Err(AmemError::MalformedOutput { .. })),
+            "{result:?}"
+        );
+
+        let script = fake_agent("amem-failing.sh", "echo 'db locked' >&2\nexit 3\n");
+        let result = amem::fetch_today(&script, &TokioRunner).await;
+        let _ = std::fs::remove_file(&script);
+        assert_eq!(
+            result,
+            Err(AmemError::NonZeroExit {
+                command: "amem today".to_string(),
+                code: Some(3),
+                detail: "db locked".to_string(),
+            })
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_draft_record_is_kept_before_the_run_and_finalized_after() {
+        let log = temp_path("amem-keep.log");
+        let _ = std::fs::remove_file(&log);
+        let amem = fake_agent(
+            "amem-keep.sh",
+            &format!(
+                "[ \"$1\" = keep ] || exit 0\necho \"$*\" >> {}\n",
+                log.display()
+            ),
+        );
+        // The agent prints what amem holds while it is still running.
+        let agent = fake_agent("drafted-agent.sh", &format!("cat {}\n", log.display()));
+        let record = RecordOptions::default()
+            .with_source("acore")
+            .with_draft_kind("draft");
+        let options = ExecOptions::new()
+            .with_command_override(AgentProvider::Claude, &agent)
+            .with_amem_binary(&amem);
+        let mut during = String::new();
+        AgentExecutor::execute_with_draft(
+            AgentProvider::Claude,
+            "refactor the parser\nin small steps",
+            &record,
+            &options,
+            |chunk| during.push_str(&chunk),
+        )
+        .await
+        .unwrap();
+        let after = std::fs::read_to_string(&log).unwrap();
+        for path in [&log, &amem, &agent] {
+            let _ = std::fs::remove_file(path);
+        }
+
+        assert_eq!(during.lines().count(), 1);
+        assert!(during.starts_with("keep in progress: refactor the parser --kind draft"));
+        let kept: Vec<&str> = after.lines().collect();
+        assert_eq!(kept.len(), 2);
+        assert!(kept[1].starts_with("keep completed: refactor the parser --kind activity"));
+        let tag = |line: &str| line.split("--tag ").nth(1).map(str::to_string);
+        assert!(tag(kept[0]).is_some_and(|t| t.starts_with("draft:")));
+        assert_eq!(tag(kept[0]), tag(kept[1]));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_keep_many_records_every_line_concurrently() {
+        let log = temp_path("amem-keep-many.log");
+        let _ = std::fs::remove_file(&log);
+        let amem = fake_agent(
+            "amem-keep-many.sh",
+            &format!(
+                "[ \"$1\" = keep ] || exit 0\necho \"start $2\" >> {log}\nsleep 0.2\necho \"end $2\" >> {log}\n",
+                log = log.display()
+            ),
+        );
+        let record = RecordOptions::default().with_source("acore");
+        let mut lines: Vec<String> = (0..MAX_CONCURRENT_KEEPS + 4)
+            .map(|n| format!("line-{n}"))
+            .collect();
+        lines.push(" ".to_string());
+        let options = ExecOptions::new().with_amem_binary(&amem);
+        AgentExecutor::keep_many_with_options(&lines, &record, &options)
+            .await
+            .unwrap();
+        let events = std::fs::read_to_string(&log).unwrap();
+        let _ = std::fs::remove_file(&log);
+        let _ = std::fs::remove_file(&amem);
+
+        let (mut running, mut peak) = (0usize, 0usize);
+        let mut kept = Vec::new();
+        for event in events.lines() {
+            match event.split_once(' ') {
+                Some(("start", _)) => running += 1,
+                Some(("end", line)) => {
+                    running -= 1;
+                    kept.push(line);
+                }
+                _ => panic!("unexpected event {event:?}"),
+            }
+            peak = peak.max(running);
+        }
+        kept.sort_unstable();
+        let mut expected: Vec<&str> = lines[..lines.len() - 1]
+            .iter()
+            .map(String::as_str)
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(kept, expected);
+        // Some keeps overlapped, but never more than the limit at once.
+        assert!(peak > 1, "{events}");
+        assert!(peak <= MAX_CONCURRENT_KEEPS, "{events}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_has_amem_probes_once_until_reset() {
+        let log = temp_path("amem-probes.log");
+        let _ = std::fs::remove_file(&log);
+        let amem = fake_agent(
+            "amem-probe.sh",
+            &format!("echo \"$@\" >> {}\n", log.display()),
+        );
+        let options = ExecOptions::new().with_amem_binary(&amem);
+        assert!(AgentExecutor::has_amem_with_options(&options).await);
+        assert!(AgentExecutor::has_amem_with_options(&options).await);
+        let probes_before_reset = std::fs::read_to_string(&log).unwrap().lines().count();
+
+        AgentExecutor::reset_amem_cache().await;
+        assert!(AgentExecutor::has_amem_with_options(&options).await);
+        let probes = std::fs::read_to_string(&log).unwrap();
+        let _ = std::fs::remove_file(&log);
+        let _ = std::fs::remove_file(&amem);
+        assert_eq!(probes_before_reset, 1);
+        assert_eq!(probes, "--version\n--version\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(start_paused = true)]
+    async fn test_has_amem_is_false_when_the_probe_hangs() {
+        // The paused clock jumps straight to the probe deadline while the stub sleeps.
+        let amem = fake_agent("amem-hang.sh", "exec sleep 600\n");
+        let options = ExecOptions::new()
+            .with_amem_binary(&amem)
+            .with_amem_probe_timeout(Duration::from_secs(30));
+        let available = AgentExecutor::has_amem_with_options(&options).await;
+        let _ = std::fs::remove_file(&amem);
+        assert!(!available);
+    }
+
+    // ─── UTF-8 chunk decoding tests ───────────────────────────────────────────
+
+    #[test]
+    fn test_utf8_decoder_carries_split_multibyte_character() {
+        let text = "こんにちは世界";
+        let bytes = text.as_bytes();
+        // Split inside the second character (each is 3 bytes).
+        let mut decoder = Utf8ChunkDecoder::default();
+        let mut out = decoder.push(&bytes[..4]);
+        out.push_str(&decoder.push(&bytes[4..]));
+        out.push_str(&decoder.finish());
+        assert_eq!(out, text);
+        assert!(!out.contains(char::REPLACEMENT_CHARACTER));
     }
 
     #[test]
-    fn test_gemini_capacity_fallback_models_for_auto_gemini_3() {
+    fn test_utf8_decoder_handles_every_split_point() {
+        let text = "abc日本語🎉xyz";
+        let bytes = text.as_bytes();
+        for split in 0..=bytes.len() {
+            let mut decoder = Utf8ChunkDecoder::default();
+            let mut out = decoder.push(&bytes[..split]);
+            out.push_str(&decoder.push(&bytes[split..]));
+            out.push_str(&decoder.finish());
+            assert_eq!(out, text, "split at byte {}", split);
+        }
+    }
+
+    #[test]
+    fn test_utf8_decoder_replaces_invalid_and_truncated_bytes() {
+        let mut decoder = Utf8ChunkDecoder::default();
+        assert_eq!(decoder.push(b"a\xffb"), "a\u{FFFD}b");
+        assert_eq!(decoder.push(&"日".as_bytes()[..2]), "");
+        assert_eq!(decoder.finish(), "\u{FFFD}");
+    }
+
+    #[tokio::test]
+    async fn test_utf8_decoder_reassembles_mock_reader_split_mid_character() {
+        use tokio::io::AsyncWriteExt;
+
+        let text = "ログ: 完了しました 🎉 done";
+        let (mut writer, mut reader) = tokio::io::duplex(4);
+        let feed = tokio::spawn(async move {
+            // Two-byte writes guarantee most reads end inside a code point.
+            for piece in text.as_bytes().chunks(2) {
+                writer.write_all(piece).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let mut buffer = [0; 1024];
+        let mut decoder = Utf8ChunkDecoder::default();
+        let mut out = String::new();
+        loop {
+            let n = reader.read(&mut buffer).await.unwrap();
+            if n == 0 {
+                out.push_str(&decoder.finish());
+                break;
+            }
+            out.push_str(&decoder.push(&buffer[..n]));
+        }
+        feed.await.unwrap();
+        assert_eq!(out.as_bytes(), text.as_bytes());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_stream_command_keeps_multibyte_text_across_reads() {
+        // 1023 ASCII bytes push the first kana across the 1024-byte read window.
+        let mut command = sh_command("printf '%1023s' ''; printf 'あいうえお'");
+        let mut received = String::new();
+        AgentExecutor::stream_command(&mut command, &ExecOptions::default(), None, &mut |chunk| {
+            received.push_str(&chunk)
+        })
+        .await
+        .unwrap();
+        assert!(!received.contains(char::REPLACEMENT_CHARACTER));
+        assert!(received.ends_with("あいうえお"));
+    }
+
+    // ─── ANSI stripping tests ─────────────────────────────────────────────────
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_stream_command_strips_ansi_split_across_reads_when_enabled() {
+        // The escape sequence straddles the 1024-byte read window.
+        let script = "printf '%1020s' ''; printf '\\033[38;5;208mhot\\033[0m'";
+        let mut stripped = String::new();
+        AgentExecutor::stream_command(
+            &mut sh_command(script),
+            &ExecOptions::new().with_strip_ansi(true),
+            None,
+            &mut |chunk| stripped.push_str(&chunk),
+        )
+        .await
+        .unwrap();
+        assert!(!stripped.contains('\u{1b}'));
+        assert!(stripped.ends_with("hot"));
+
+        let mut raw = String::new();
+        AgentExecutor::stream_command(
+            &mut sh_command(script),
+            &ExecOptions::default(),
+            None,
+            &mut |chunk| raw.push_str(&chunk),
+        )
+        .await
+        .unwrap();
+        assert!(raw.contains("\u{1b}[38;5;208m"));
+    }
+
+    #[test]
+    fn test_clean_output_strips_ansi_before_parsing() {
+        let raw = "\u{1b}[2m{\"session_id\": \"abc\"}\u{1b}[0m".as_bytes();
+        let cleaned = ExecOptions::new().with_strip_ansi(true).clean_output(raw);
         assert_eq!(
-            SessionManager::gemini_capacity_fallback_models(Some("auto-gemini-3")),
-            &["gemini-2.5-flash", "gemini-2.5-pro"]
+            SessionManager::extract_session_id(&cleaned),
+            Some("abc".to_string())
         );
     }
 
-    #[test]
-    fn test_gemini_capacity_fallback_models_for_flash() {
+    // ─── Lifecycle event tests ────────────────────────────────────────────────
+
+    fn lifecycle_recorder() -> (ExecOptions, Arc<StdMutex<Vec<Lifecycle>>>) {
+        let events = Arc::new(StdMutex::new(Vec::new()));
+        let events_clone = Arc::clone(&events);
+        let options = ExecOptions::new().on_lifecycle(move |event| {
+            events_clone.lock().unwrap().push(event);
+        });
+        (options, events)
+    }
+
+    #[tokio::test]
+    async fn test_execute_stream_mock_emits_lifecycle_sequence() {
+        let (options, events) = lifecycle_recorder();
+        AgentExecutor::execute_stream_with_options(AgentProvider::Mock, "hi", &options, |_| {})
+            .await
+            .unwrap();
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0], Lifecycle::Spawned { pid: None });
+        assert!(matches!(events[1], Lifecycle::FirstByte { .. }));
+        assert_eq!(events[2], Lifecycle::Exited { code: Some(0) });
+    }
+
+    #[tokio::test]
+    async fn test_execute_with_resume_mock_emits_lifecycle_sequence() {
+        let mgr = SessionManager::new();
+        let (options, events) = lifecycle_recorder();
+        mgr.execute_with_resume_with_options(AgentProvider::Mock, "hi", &options, |_| {})
+            .await
+            .unwrap();
+        let events = events.lock().unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                Lifecycle::Spawned { pid: None },
+                Lifecycle::FirstByte { .. },
+                Lifecycle::Exited { code: Some(0) },
+            ]
+        ));
+    }
+
+    // ─── Execution hook tests ─────────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn test_hooks_fire_around_mock_run() {
+        let calls = Arc::new(StdMutex::new(Vec::new()));
+        let (before, after) = (Arc::clone(&calls), Arc::clone(&calls));
+        let options = ExecOptions::new()
+            .before_execute(move |ctx: HookContext| {
+                before.lock().unwrap().push(format!(
+                    "before {} {}",
+                    ctx.provider.command_name(),
+                    ctx.redacted_prompt()
+                ));
+                async { Ok(()) }
+            })
+            .after_execute(move |ctx: HookContext, outcome: HookOutcome| {
+                after.lock().unwrap().push(format!(
+                    "after {} {:?}",
+                    ctx.provider.command_name(),
+                    outcome.error
+                ));
+                async {}
+            });
+        let output = AgentExecutor::execute_with_options(AgentProvider::Mock, "ping", &options)
+            .await
+            .unwrap();
+        assert_eq!(output, "Mock stream: pong");
         assert_eq!(
-            SessionManager::gemini_capacity_fallback_models(Some("gemini-2.5-flash")),
-            &["gemini-2.5-pro"]
+            *calls.lock().unwrap(),
+            vec![
+                "before mock-agent ping".to_string(),
+                "after mock-agent None".to_string()
+            ]
         );
     }
 
-    #[test]
-    fn test_gemini_should_retry_with_fallback_only_for_gemini_capacity_errors() {
-        let detail = "No capacity available for model gemini-2.5-flash-lite on the server";
-        assert!(SessionManager::gemini_should_retry_with_fallback(
-            &AgentProvider::Gemini,
-            Some("auto-gemini-3"),
-            detail,
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_failing_before_hook_prevents_spawn() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let marker = temp_path("hook-spawned");
+        let agent = fake_agent(
+            "hook-agent",
+            &format!("touch {}\necho ran", marker.display()),
+        );
+        let after_ran = Arc::new(AtomicBool::new(false));
+        let after_flag = Arc::clone(&after_ran);
+        let options = ExecOptions::new()
+            .with_command_override(AgentProvider::Claude, &agent)
+            .before_execute(|_| async { Err("quota exhausted".to_string()) })
+            .after_execute(move |_, _| {
+                after_flag.store(true, Ordering::SeqCst);
+                async {}
+            });
+        let err = AgentExecutor::execute_with_options(AgentProvider::Claude, "hi", &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(&err, AgentError::HookFailed(detail) if detail == "quota exhausted"));
+        assert!(!marker.exists());
+        assert!(!after_ran.load(Ordering::SeqCst));
+        let _ = std::fs::remove_file(agent);
+    }
+
+    // ─── Cancellation tests ───────────────────────────────────────────────────
+
+    #[cfg(unix)]
+    fn sh_command(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(script)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        command
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_stream_command_timeout_cancels_with_timeout_reason() {
+        let (options, events) = lifecycle_recorder();
+        let options = options.with_timeout(Duration::from_millis(100));
+        let mut command = sh_command("sleep 5");
+        let err = AgentExecutor::stream_command(&mut command, &options, None, &mut |_| {})
+            .await
+            .err()
+            .expect("a hanging child must time out");
+        assert!(matches!(
+            err,
+            AgentError::Cancelled {
+                reason: CancelReason::Timeout
+            }
         ));
-        assert!(!SessionManager::gemini_should_retry_with_fallback(
-            &AgentProvider::Claude,
-            Some("claude-sonnet-4-6"),
-            detail,
+        assert_eq!(
+            events.lock().unwrap().last(),
+            Some(&Lifecycle::Killed {
+                reason: CancelReason::Timeout
+            })
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_stream_command_cancel_token_kills_child_mid_stream() {
+        let (options, events) = lifecycle_recorder();
+        let token = CancellationToken::new();
+        let options = options.with_cancel_token(token.clone());
+        // The grandchild `sleep` also holds stderr open; the reader task must
+        // still be torn down instead of keeping the call alive.
+        let mut command = sh_command("echo started; sleep 5 & sleep 5");
+        let started = Instant::now();
+        let err =
+            AgentExecutor::stream_command(&mut command, &options, None, &mut |_| token.cancel())
+                .await
+                .err()
+                .expect("a cancelled run must not succeed");
+        assert!(started.elapsed() < Duration::from_secs(4));
+        assert!(matches!(
+            err,
+            AgentError::Cancelled {
+                reason: CancelReason::UserRequested
+            }
         ));
-        assert!(!SessionManager::gemini_should_retry_with_fallback(
-            &AgentProvider::Gemini,
-            Some("gemini-2.5-pro"),
-            "permission denied",
+        assert_eq!(
+            events.lock().unwrap().last(),
+            Some(&Lifecycle::Killed {
+                reason: CancelReason::UserRequested
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_resume_honours_cancelled_token() {
+        let token = CancellationToken::new();
+        token.cancel();
+        let options = ExecOptions::new().with_cancel_token(token);
+        let result = SessionManager::new()
+            .execute_with_resume_with_options(AgentProvider::Mock, "hi", &options, |_| {})
+            .await;
+        assert!(matches!(
+            result,
+            Err(AgentError::Cancelled {
+                reason: CancelReason::UserRequested
+            })
         ));
     }
 
-    // ─── AgentProvider JSON serialization tests ───────────────────────────────────
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_stream_command_predicate_cancels_with_predicate_reason() {
+        let options = ExecOptions::new().stop_when(|seen| seen.contains("STOP"));
+        let mut command = sh_command("echo before STOP; sleep 5; echo after");
+        let mut received = String::new();
+        let err = AgentExecutor::stream_command(&mut command, &options, None, &mut |chunk| {
+            received.push_str(&chunk)
+        })
+        .await
+        .err()
+        .expect("the predicate must cancel the run");
+        assert!(matches!(
+            err,
+            AgentError::Cancelled {
+                reason: CancelReason::Predicate
+            }
+        ));
+        assert!(received.contains("before STOP"));
+        assert!(!received.contains("after"));
+    }
 
-    #[test]
-    fn test_agent_provider_serialize() {
-        let json = serde_json::to_string(&AgentProvider::Gemini).unwrap();
-        assert_eq!(json, r#""Gemini""#);
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_spawn_and_wait_timeout_kills_buffered_child() {
+        let options = ExecOptions::new().with_timeout(Duration::from_millis(100));
+        let mut command = sh_command("sleep 5");
+        let started = Instant::now();
+        let result = AgentExecutor::spawn_and_wait_with_input(&mut command, &options, None).await;
+        assert!(matches!(
+            result,
+            Err(AgentError::Cancelled {
+                reason: CancelReason::Timeout
+            })
+        ));
+        assert!(started.elapsed() < Duration::from_secs(2));
     }
 
-    #[test]
-    fn test_agent_provider_deserialize() {
-        let provider: AgentProvider = serde_json::from_str(r#""Claude""#).unwrap();
-        assert_eq!(provider, AgentProvider::Claude);
+    #[tokio::test]
+    async fn test_execute_with_resume_mock_times_out() {
+        let mgr = SessionManager::new();
+        let options = ExecOptions::new().with_timeout(Duration::from_millis(1));
+        let result = mgr
+            .execute_with_resume_with_options(AgentProvider::Mock, "slow", &options, |_| {})
+            .await;
+        assert!(matches!(
+            result,
+            Err(AgentError::Cancelled {
+                reason: CancelReason::Timeout
+            })
+        ));
     }
 
     #[test]
-    fn test_agent_provider_roundtrip_all_variants() {
-        for provider in [
-            AgentProvider::Gemini,
-            AgentProvider::Claude,
-            AgentProvider::Codex,
-            AgentProvider::OpenCode,
-            AgentProvider::Dummy,
-            AgentProvider::Mock,
-        ] {
-            let json = serde_json::to_string(&provider).unwrap();
-            let roundtrip: AgentProvider = serde_json::from_str(&json).unwrap();
-            assert_eq!(provider, roundtrip);
-        }
+    fn test_exec_options_until_elapsed_deadline_is_timeout() {
+        let deadline = tokio::time::Instant::now() - Duration::from_millis(1);
+        assert!(matches!(
+            ExecOptions::default().until(Some(deadline)),
+            Err(AgentError::Cancelled {
+                reason: CancelReason::Timeout
+            })
+        ));
     }
 
-    // ─── SessionManager::extract_session_id tests ─────────────────────────────
+    // ─── Working directory and environment tests ──────────────────────────────
 
-    #[test]
-    fn test_extract_session_id() {
-        let json_output = r#"{"session_id": "test-uuid-1234", "status": "ok"}"#;
-        assert_eq!(
-            SessionManager::extract_session_id(json_output),
-            Some("test-uuid-1234".to_string())
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_configure_applies_cwd_to_spawned_agent() {
+        let dir = std::fs::canonicalize(std::env::temp_dir()).unwrap();
+        let options = ExecOptions::new().with_cwd(&dir);
+        let mut command = sh_command("pwd");
+        options.configure(&mut command);
+        let mut received = String::new();
+        AgentExecutor::stream_command(&mut command, &options, None, &mut |chunk| {
+            received.push_str(&chunk)
+        })
+        .await
+        .unwrap();
+        assert_eq!(received.trim_end(), dir.to_string_lossy());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_configure_adds_envs_on_top_of_inherited_environment() {
+        let options = ExecOptions::new().with_env("ACORE_TEST_API_KEY", "sk-test");
+        // `sh` is still found through the inherited PATH.
+        let mut command = sh_command("printf '%s' \"$ACORE_TEST_API_KEY\"; test -n \"$PATH\"");
+        options.configure(&mut command);
+        let mut received = String::new();
+        let run = AgentExecutor::stream_command(&mut command, &options, None, &mut |chunk| {
+            received.push_str(&chunk)
+        })
+        .await
+        .unwrap();
+        assert!(run.status.success());
+        assert_eq!(received, "sk-test");
+    }
+
+    #[tokio::test]
+    async fn test_spawn_error_does_not_leak_env_values() {
+        let options = ExecOptions::new().with_env("ACORE_TEST_API_KEY", "sk-secret-value");
+        let mut command = Command::new("acore-test-missing-binary");
+        options.configure(&mut command);
+        let err = AgentExecutor::spawn_and_wait_with_input(&mut command, &options, None)
+            .await
+            .unwrap_err();
+        assert!(!err.to_string().contains("sk-secret-value"));
+    }
+
+    #[tokio::test]
+    async fn test_mock_resume_with_cwd_succeeds() {
+        let mgr = SessionManager::new();
+        let options = ExecOptions::new().with_cwd(std::env::temp_dir());
+        let result = mgr
+            .execute_with_resume_with_options(AgentProvider::Mock, "hi", &options, |_| {})
+            .await;
+        assert!(result.is_ok());
+    }
+
+    // ─── Line framing tests ───────────────────────────────────────────────────
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_execute_stream_lines_frames_across_read_boundaries() {
+        // With a 4-byte buffer the first newline lands exactly on a read boundary.
+        let script = fake_agent("lines.sh", "printf 'abc\\n{\"k\":12}\\n\\ntail'\n");
+        let options = ExecOptions::new()
+            .with_command_override(AgentProvider::Claude, &script)
+            .with_buffer_size(4);
+        let mut lines = Vec::new();
+        let result =
+            AgentExecutor::execute_stream_lines(AgentProvider::Claude, "hi", &options, |line| {
+                lines.push(line)
+            })
+            .await;
+        let _ = std::fs::remove_file(&script);
+        result.unwrap();
+        assert_eq!(lines, ["abc", "{\"k\":12}", "", "tail"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_execute_with_resume_lines_flushes_the_unterminated_last_line() {
+        let script = fake_agent(
+            "resume-lines.sh",
+            "printf 'one\\ntw'\nsleep 0.1\nprintf 'o\\nthree'\n",
         );
+        let mgr = SessionManager::new().with_command_override(AgentProvider::Aider, &script);
+        let lines = Arc::new(StdMutex::new(Vec::new()));
+        let sink = Arc::clone(&lines);
+        let result = mgr
+            .execute_with_resume_lines(
+                AgentProvider::Aider,
+                "hi",
+                &ExecOptions::new(),
+                move |line| sink.lock().unwrap().push(line),
+            )
+            .await;
+        let _ = std::fs::remove_file(&script);
+        result.unwrap();
+        assert_eq!(*lines.lock().unwrap(), ["one", "two", "three"]);
     }
 
-    #[test]
-    fn test_extract_session_id_camel_case() {
-        let json_output = r#"{"sessionId": "camel-uuid-5678", "status": "ok"}"#;
+    // ─── Section splitting tests ──────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn test_execute_stream_items_emits_named_sections() {
+        let options = ExecOptions::new().with_section_delimiter("## ");
+        let mut items = Vec::new();
+        AgentExecutor::execute_stream_items(
+            AgentProvider::Dummy,
+            "## Answer\nParis is the capital.\n## Sources\n- encyclopedia\n",
+            &options,
+            |item| items.push(item),
+        )
+        .await
+        .unwrap();
         assert_eq!(
-            SessionManager::extract_session_id(json_output),
-            Some("camel-uuid-5678".to_string())
+            items,
+            vec![
+                StreamItem::Section {
+                    name: "Answer".to_string(),
+                    text: "Paris is the capital.".to_string(),
+                },
+                StreamItem::Section {
+                    name: "Sources".to_string(),
+                    text: "- encyclopedia".to_string(),
+                },
+            ]
         );
     }
 
-    #[test]
-    fn test_extract_session_id_snake_case_takes_priority() {
-        // Both fields present: snake_case should win (checked first)
-        let json_output = r#"{"session_id": "snake-id", "sessionId": "camel-id"}"#;
+    #[tokio::test]
+    async fn test_execute_stream_items_passes_chunks_without_delimiter() {
+        let mut items = Vec::new();
+        AgentExecutor::execute_stream_items(
+            AgentProvider::Mock,
+            "hi",
+            &ExecOptions::default(),
+            |item| items.push(item),
+        )
+        .await
+        .unwrap();
         assert_eq!(
-            SessionManager::extract_session_id(json_output),
-            Some("snake-id".to_string())
+            items,
+            vec![StreamItem::Chunk("Mock stream: pong".to_string())]
         );
     }
 
-    #[test]
-    fn test_extract_session_id_missing_field() {
-        let json_output = r#"{"status": "ok", "response": "hello"}"#;
-        assert_eq!(SessionManager::extract_session_id(json_output), None);
+    // ─── Temp file output tests ───────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn test_execute_stream_to_tempfile_writes_mock_output() {
+        let output = AgentExecutor::execute_stream_to_tempfile(AgentProvider::Mock, "ping")
+            .await
+            .unwrap();
+        let path = output.path().to_path_buf();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "Mock stream: pong");
+        drop(output);
+        assert!(!path.exists());
     }
 
+    #[cfg(unix)]
     #[test]
-    fn test_extract_session_id_invalid_json() {
-        assert_eq!(SessionManager::extract_session_id("not valid json"), None);
+    fn test_temp_output_skips_planted_paths_without_touching_them() {
+        let dir = temp_path("tempfile-planted");
+        std::fs::create_dir_all(&dir).unwrap();
+        let victim = dir.join("victim.txt");
+        std::fs::write(&victim, "keep me").unwrap();
+        let (first, _) = TempOutput::create_in(&dir).unwrap();
+        let n: u64 = first
+            .path()
+            .to_string_lossy()
+            .rsplit('-')
+            .next()
+            .and_then(|tail| tail.strip_suffix(".txt"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        // Plant symlinks on the next few names another run could hand out.
+        for next in n + 1..n + 64 {
+            let name = format!("acore-output-{}-{}.txt", std::process::id(), next);
+            std::os::unix::fs::symlink(&victim, dir.join(name)).unwrap();
+        }
+        let (second, mut file) = TempOutput::create_in(&dir).unwrap();
+        std::io::Write::write_all(&mut file, b"output").unwrap();
+        let second_is_symlink = second.path().is_symlink();
+        let victim_contents = std::fs::read_to_string(&victim).unwrap();
+        drop((first, second));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(!second_is_symlink);
+        assert_eq!(victim_contents, "keep me");
+    }
+
+    #[tokio::test]
+    async fn test_execute_stream_to_tempfile_leaves_no_file_on_error() {
+        let prefix = format!("acore-output-{}-", std::process::id());
+        let leftovers = || {
+            std::fs::read_dir(std::env::temp_dir())
+                .unwrap()
+                .filter_map(Result::ok)
+                .filter(|e| e.file_name().to_string_lossy().starts_with(&prefix))
+                .count()
+        };
+        let before = leftovers();
+        let options = ExecOptions::new()
+            .with_command_override(AgentProvider::Claude, temp_path("missing-claude-tempfile"));
+        let result = AgentExecutor::execute_stream_to_tempfile_with_options(
+            AgentProvider::Claude,
+            "hi",
+            &options,
+        )
+        .await;
+        assert!(matches!(result, Err(AgentError::SpawnFailed { .. })));
+        assert!(leftovers() <= before);
+    }
+
+    // ─── Metrics tests ────────────────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn test_metrics_text_after_mock_runs() {
+        let metrics = ExecMetrics::new();
+        let options = ExecOptions::new().with_metrics(metrics.clone());
+        let mgr = SessionManager::new();
+        mgr.execute_with_resume_with_options(AgentProvider::Mock, "one", &options, |_| {})
+            .await
+            .unwrap();
+        AgentExecutor::execute_stream_with_options(AgentProvider::Mock, "two", &options, |_| {})
+            .await
+            .unwrap();
+        let text = metrics.metrics_text();
+        assert!(text.contains("# TYPE acore_runs_total counter"));
+        assert!(text.contains("acore_runs_total{tool=\"mock-agent\"} 2"));
+        assert!(text.contains("acore_run_failures_total{tool=\"mock-agent\"} 0"));
+        assert!(text.contains("# TYPE acore_run_duration_seconds histogram"));
+        assert!(text.contains("acore_run_duration_seconds_count{tool=\"mock-agent\"} 2"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_failures_and_tokens() {
+        let metrics = ExecMetrics::new();
+        let options = ExecOptions::new().with_metrics(metrics.clone());
+        let mgr = SessionManager::new();
+        mgr.execute_with_resume_with_options(
+            AgentProvider::Dummy,
+            r#"{"response":"ok","usage":{"input_tokens":7,"output_tokens":5}}"#,
+            &options,
+            |_| {},
+        )
+        .await
+        .unwrap();
+        let cancelled = options.clone().with_cancel_token({
+            let token = CancellationToken::new();
+            token.cancel();
+            token
+        });
+        let _ = mgr
+            .execute_with_resume_with_options(AgentProvider::Mock, "hi", &cancelled, |_| {})
+            .await
+            .unwrap_err();
+        let text = metrics.metrics_text();
+        assert!(text.contains("acore_tokens_total{tool=\"dummy-bot\"} 12"));
+        assert!(text.contains("acore_run_failures_total{tool=\"mock-agent\"} 1"));
     }
 
     #[test]
-    fn test_extract_session_id_with_prefixed_logs_and_pretty_json() {
-        let output = r#"YOLO mode is enabled.
-Loaded cached credentials.
-{
-  "session_id": "prefixed-uuid",
-  "response": "MEMORY_READY"
-}"#;
-        assert_eq!(
-            SessionManager::extract_session_id(output),
-            Some("prefixed-uuid".to_string())
-        );
+    fn test_extract_token_total_from_gemini_stats() {
+        let output =
+            r#"{"response":"hi","stats":{"models":{"gemini-2.5-pro":{"tokens":{"total":42}}}}}"#;
+        assert_eq!(SessionManager::extract_token_total(output), Some(42));
+        assert_eq!(SessionManager::extract_token_total("plain"), None);
     }
 
+    // ─── Prompt escaping tests ────────────────────────────────────────────────
+
     #[test]
-    fn test_extract_session_id_from_codex_jsonl_thread_started() {
-        let output = r#"{"type":"thread.started","thread_id":"019c8d31-7d21-76d1-8a4e-2b3443def1c6"}
-{"type":"turn.started"}
-{"type":"item.completed","item":{"id":"item_1","type":"agent_message","text":"MEMORY_READY"}}"#;
-        assert_eq!(
-            SessionManager::extract_session_id(output),
-            Some("019c8d31-7d21-76d1-8a4e-2b3443def1c6".to_string())
-        );
+    fn test_prompt_escaping_defaults_to_none_for_every_provider() {
+        let options = ExecOptions::new();
+        for provider in [
+            AgentProvider::Gemini,
+            AgentProvider::Claude,
+            AgentProvider::Codex,
+            AgentProvider::OpenCode,
+            AgentProvider::Cursor,
+            AgentProvider::Aider,
+        ] {
+            assert_eq!(provider.prompt_escaping(), PromptEscaping::None);
+            assert_eq!(options.prompt_arg(&provider, "it's $HOME"), "it's $HOME");
+        }
     }
 
     #[test]
-    fn test_extract_session_id_empty_string() {
-        assert_eq!(SessionManager::extract_session_id(""), None);
+    fn test_prompt_escaping_shell_quote_and_backslash() {
+        assert_eq!(
+            PromptEscaping::ShellQuote.apply("it's $HOME"),
+            r"'it'\''s $HOME'"
+        );
+        assert_eq!(
+            PromptEscaping::Backslash.apply(r#"say "hi" to `$USER` \o/"#),
+            r#"say \"hi\" to \`\$USER\` \\o/"#
+        );
     }
 
     #[test]
-    fn test_extract_session_id_empty_json_object() {
-        assert_eq!(SessionManager::extract_session_id("{}"), None);
+    fn test_prompt_escaping_is_configured_per_provider() {
+        let options = ExecOptions::new()
+            .with_prompt_escaping(AgentProvider::Aider, PromptEscaping::ShellQuote);
+        assert_eq!(options.prompt_arg(&AgentProvider::Aider, "a b"), "'a b'");
+        assert_eq!(options.prompt_arg(&AgentProvider::Claude, "a b"), "a b");
     }
 
-    #[test]
-    fn test_extract_session_id_non_string_value() {
-        let json_output = r#"{"session_id": 12345}"#;
-        assert_eq!(SessionManager::extract_session_id(json_output), None);
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_escaped_prompt_reaches_agent_argv() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = temp_path("fake-aider-escaping.sh");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\nfor last; do :; done\nprintf '%s' \"$last\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let options = ExecOptions::new()
+            .with_command_override(AgentProvider::Aider, &script)
+            .with_prompt_escaping(AgentProvider::Aider, PromptEscaping::ShellQuote);
+        let mut received = String::new();
+        let result = AgentExecutor::execute_stream_with_options(
+            AgentProvider::Aider,
+            "it's done",
+            &options,
+            |chunk| received.push_str(&chunk),
+        )
+        .await;
+        let _ = std::fs::remove_file(&script);
+        result.unwrap();
+        assert_eq!(received, r"'it'\''s done'");
     }
 
+    // ─── Thinking budget tests ────────────────────────────────────────────────
+
     #[test]
-    fn test_extract_session_id_null_value() {
-        let json_output = r#"{"session_id": null}"#;
-        assert_eq!(SessionManager::extract_session_id(json_output), None);
+    fn test_thinking_budget_is_passed_to_claude() {
+        let options = ExecOptions::new().with_thinking_budget(8000);
+        let command = options.command_for(&AgentProvider::Claude).unwrap();
+        let budget = command
+            .as_std()
+            .get_envs()
+            .find(|(key, _)| *key == "MAX_THINKING_TOKENS")
+            .and_then(|(_, value)| value);
+        assert_eq!(budget, Some(std::ffi::OsStr::new("8000")));
     }
 
-    // ─── SessionManager::extract_response tests ───────────────────────────────
-
     #[test]
-    fn test_extract_response() {
-        let json_output = r#"{"session_id": "abc", "response": "Hello, world!"}"#;
-        assert_eq!(
-            SessionManager::extract_response(json_output),
-            Some("Hello, world!".to_string())
+    fn test_thinking_budget_is_unsupported_for_other_tools() {
+        let options = ExecOptions::new().with_thinking_budget(8000);
+        for provider in [
+            AgentProvider::Gemini,
+            AgentProvider::Codex,
+            AgentProvider::OpenCode,
+            AgentProvider::Cursor,
+            AgentProvider::Aider,
+        ] {
+            match options.command_for(&provider) {
+                Err(AgentError::UnsupportedFeature {
+                    provider: reported,
+                    feature,
+                }) => {
+                    assert_eq!(reported, provider);
+                    assert_eq!(feature, "thinking_budget");
+                }
+                other => panic!("{provider:?}: unexpected {:?}", other.map(|_| ())),
+            }
+        }
+        assert!(
+            ExecOptions::new()
+                .command_for(&AgentProvider::Gemini)
+                .is_ok()
         );
     }
 
-    #[test]
-    fn test_extract_response_missing_field() {
-        let json_output = r#"{"session_id": "abc"}"#;
-        assert_eq!(SessionManager::extract_response(json_output), None);
+    #[tokio::test]
+    async fn test_unsupported_thinking_budget_fails_before_spawn() {
+        let (options, events) = lifecycle_recorder();
+        let options = options.with_thinking_budget(1024);
+        let err = AgentExecutor::execute_stream_with_options(
+            AgentProvider::Aider,
+            "hi",
+            &options,
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "aider does not support thinking_budget");
+        assert!(events.lock().unwrap().is_empty());
     }
 
-    #[test]
-    fn test_extract_response_invalid_json() {
-        assert_eq!(SessionManager::extract_response("not json"), None);
+    // ─── Read buffer size tests ───────────────────────────────────────────────
+
+    #[cfg(unix)]
+    async fn count_chunks(buffer_size: usize) -> (usize, usize) {
+        let options = ExecOptions::new().with_buffer_size(buffer_size);
+        let mut command = sh_command("head -c 65536 /dev/zero | tr '\\0' a");
+        let mut calls = 0;
+        let mut bytes = 0;
+        AgentExecutor::stream_command(&mut command, &options, None, &mut |chunk| {
+            calls += 1;
+            bytes += chunk.len();
+        })
+        .await
+        .unwrap();
+        (calls, bytes)
     }
 
-    #[test]
-    fn test_extract_response_with_prefixed_logs_and_pretty_json() {
-        let output = r#"YOLO mode is enabled.
-[ERROR] [IDEClient] Failed to connect.
-{
-  "session_id": "abc",
-  "response": "Hello"
-}"#;
-        assert_eq!(
-            SessionManager::extract_response(output),
-            Some("Hello".to_string())
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_larger_buffer_reduces_callback_count() {
+        let (small_calls, small_bytes) = count_chunks(DEFAULT_BUFFER_SIZE).await;
+        let (large_calls, large_bytes) = count_chunks(64 * 1024).await;
+        assert_eq!(small_bytes, 65536);
+        assert_eq!(large_bytes, 65536);
+        assert!(small_calls >= 64, "got {small_calls} calls");
+        assert!(
+            large_calls < small_calls,
+            "{large_calls} calls at 64 KiB vs {small_calls} at 1 KiB"
         );
     }
 
-    #[test]
-    fn test_extract_response_from_codex_jsonl_agent_message() {
-        let output = r#"{"type":"thread.started","thread_id":"019c8d31-7d21-76d1-8a4e-2b3443def1c6"}
-{"type":"turn.started"}
-{"type":"item.completed","item":{"id":"item_0","type":"reasoning","text":"hidden"}}
-{"type":"item.completed","item":{"id":"item_1","type":"agent_message","text":"SECOND"}}"#;
-        assert_eq!(
-            SessionManager::extract_response(output),
-            Some("SECOND".to_string())
-        );
+    #[tokio::test]
+    async fn test_zero_buffer_size_is_rejected_before_spawn() {
+        let (options, events) = lifecycle_recorder();
+        let options = options.with_buffer_size(0);
+        let err = AgentExecutor::execute_stream_with_options(
+            AgentProvider::Claude,
+            "hi",
+            &options,
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, AgentError::InvalidOption(_)));
+        assert!(events.lock().unwrap().is_empty());
     }
 
-    #[test]
-    fn test_extract_response_empty_string() {
-        assert_eq!(SessionManager::extract_response(""), None);
+    // ─── Read strategy tests ──────────────────────────────────────────────────
+
+    #[cfg(unix)]
+    async fn stream_with_strategy(strategy: ReadStrategy) -> (String, usize) {
+        let options = ExecOptions::new().with_read_strategy(strategy);
+        let mut command = sh_command("seq 1 20000; printf 'tail-without-newline'");
+        let mut output = String::new();
+        let mut calls = 0;
+        AgentExecutor::stream_command(&mut command, &options, None, &mut |chunk| {
+            calls += 1;
+            output.push_str(&chunk);
+        })
+        .await
+        .unwrap();
+        (output, calls)
     }
 
-    #[test]
-    fn test_extract_response_empty_value() {
-        let json_output = r#"{"response": ""}"#;
-        assert_eq!(
-            SessionManager::extract_response(json_output),
-            Some("".to_string())
-        );
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_read_strategies_reassemble_identical_output() {
+        let (chunked, _) = stream_with_strategy(ReadStrategy::Chunked).await;
+        let (lines, line_calls) = stream_with_strategy(ReadStrategy::Lines).await;
+        assert_eq!(chunked, lines);
+        assert!(lines.starts_with("1\n2\n3\n"));
+        assert!(lines.ends_with("20000\ntail-without-newline"));
+        // One callback per line plus the unterminated tail.
+        assert_eq!(line_calls, 20001);
     }
 
+    // ─── Command override tests ───────────────────────────────────────────────
+
     #[test]
-    fn test_extract_response_multiline_value() {
-        let json_output = r#"{"response": "line1\nline2\nline3"}"#;
+    fn test_resolve_binary_falls_back_to_command_name() {
+        let mut overrides = HashMap::new();
+        overrides.insert(
+            AgentProvider::Claude,
+            PathBuf::from("/opt/claude/bin/claude"),
+        );
         assert_eq!(
-            SessionManager::extract_response(json_output),
-            Some("line1\nline2\nline3".to_string())
+            AgentProvider::Claude.resolve_binary(&overrides),
+            PathBuf::from("/opt/claude/bin/claude")
+        );
+        assert_eq!(
+            AgentProvider::Gemini.resolve_binary(&overrides),
+            PathBuf::from("gemini")
         );
     }
 
-    #[test]
-    fn test_extract_response_non_string_value() {
-        let json_output = r#"{"response": 42}"#;
-        assert_eq!(SessionManager::extract_response(json_output), None);
+    #[tokio::test]
+    async fn test_missing_override_binary_reports_resolved_path() {
+        let missing = temp_path("missing-claude-binary");
+        let options = ExecOptions::new().with_command_override(AgentProvider::Claude, &missing);
+        let err = AgentExecutor::execute_stream_with_options(
+            AgentProvider::Claude,
+            "hi",
+            &options,
+            |_| {},
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, AgentError::SpawnFailed { .. }));
+        assert!(err.to_string().contains(&*missing.to_string_lossy()));
     }
 
-    // ─── SessionManager state tests ───────────────────────────────────────────
-
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_session_manager_new_has_empty_sessions() {
-        let mgr = SessionManager::new();
-        let sessions = mgr.session_ids.lock().await;
-        assert!(sessions.is_empty());
+    async fn test_override_binary_is_spawned_with_provider_args() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let script = temp_path("fake-aider.sh");
+        std::fs::write(&script, "#!/bin/sh\necho \"$@\"\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let options = ExecOptions::new().with_command_override(AgentProvider::Aider, &script);
+        let mut received = String::new();
+        let result = AgentExecutor::execute_stream_with_options(
+            AgentProvider::Aider,
+            "hello",
+            &options,
+            |chunk| received.push_str(&chunk),
+        )
+        .await;
+        let _ = std::fs::remove_file(&script);
+        result.unwrap();
+        assert!(received.contains("--message hello"), "got: {received}");
     }
 
     #[tokio::test]
-    async fn test_session_manager_clone_shares_state() {
-        let mgr = SessionManager::new();
-        let cloned = mgr.clone();
-        // Insert into original
-        mgr.session_ids
-            .lock()
-            .await
-            .insert(AgentProvider::Gemini, "shared-id".to_string());
-        // Clone should see the same value (Arc-shared)
-        let val = cloned
-            .session_ids
-            .lock()
+    async fn test_manager_command_override_reports_resolved_path() {
+        let missing = temp_path("manager-missing-aider");
+        let mgr = SessionManager::new().with_command_override(AgentProvider::Aider, &missing);
+        let err = mgr
+            .execute_with_resume_with_options(
+                AgentProvider::Aider,
+                "hi",
+                &ExecOptions::new(),
+                |_| {},
+            )
             .await
-            .get(&AgentProvider::Gemini)
-            .cloned();
-        assert_eq!(val, Some("shared-id".to_string()));
+            .unwrap_err();
+        assert!(matches!(err, AgentError::SpawnFailed { .. }));
+        assert!(err.to_string().contains(&*missing.to_string_lossy()));
     }
 
-    // ─── AgentExecutor::execute_stream tests ──────────────────────────────────
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_per_call_override_takes_precedence_over_manager_override() {
+        let script = fake_agent("preferred-aider.sh", "echo preferred\n");
+        let mgr = SessionManager::new()
+            .with_command_override(AgentProvider::Aider, temp_path("manager-missing-aider-2"));
+        let options = ExecOptions::new().with_command_override(AgentProvider::Aider, &script);
+        let result = mgr
+            .execute_with_resume_collect_with_options(AgentProvider::Aider, "hi", &options, |_| {})
+            .await;
+        let _ = std::fs::remove_file(&script);
+        assert_eq!(result.unwrap(), "preferred\n");
+    }
 
+    #[cfg(target_os = "linux")]
     #[tokio::test]
-    async fn test_execute_stream_chunks() {
-        let received = Arc::new(StdMutex::new(String::new()));
-        let received_clone = Arc::clone(&received);
-        let _ = AgentExecutor::execute_stream(AgentProvider::Mock, "test", move |chunk| {
-            received_clone.lock().unwrap().push_str(&chunk);
-        })
-        .await;
-        assert_eq!(*received.lock().unwrap(), "Mock stream: pong");
+    async fn test_dropping_a_turn_mid_stream_kills_the_agent() {
+        let pid_file = temp_path("dropped-agent.pid");
+        let script = fake_agent(
+            "dropped-agent.sh",
+            &format!(
+                "echo $$ > {}\necho started\nexec sleep 30\n",
+                pid_file.display()
+            ),
+        );
+        let mgr = SessionManager::new().with_command_override(AgentProvider::Aider, &script);
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let mut started_tx = Some(started_tx);
+        let turn = mgr.execute_with_resume(AgentProvider::Aider, "hi", move |_| {
+            if let Some(tx) = started_tx.take() {
+                let _ = tx.send(());
+            }
+        });
+        tokio::select! {
+            _ = turn => panic!("the agent should still be running"),
+            _ = started_rx => {}
+        }
+        // The turn future has been dropped by now.
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let exited = process_exits(pid.trim()).await;
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&pid_file);
+        assert!(exited, "agent {} outlived its dropped turn", pid.trim());
+    }
+
+    /// Waits up to a second for `pid` to exit.
+    #[cfg(target_os = "linux")]
+    async fn process_exits(pid: &str) -> bool {
+        let stat = format!("/proc/{pid}/stat");
+        for _ in 0..50 {
+            // A killed child that tokio has not reaped yet shows up as a zombie.
+            let alive = std::fs::read_to_string(&stat)
+                .is_ok_and(|stat| !stat.rsplit(") ").next().unwrap_or("").starts_with('Z'));
+            if !alive {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
     }
 
+    #[cfg(target_os = "linux")]
     #[tokio::test]
-    async fn test_execute_stream_mock_succeeds() {
-        let result = AgentExecutor::execute_stream(AgentProvider::Mock, "any prompt", |_| {}).await;
-        assert!(result.is_ok());
+    async fn test_dropping_a_stream_kills_a_quiet_agent_but_not_the_callers_token() {
+        use tokio_stream::StreamExt;
+
+        let pid_file = temp_path("dropped-stream.pid");
+        let script = fake_agent(
+            "dropped-stream.sh",
+            &format!(
+                "echo $$ > {}\necho started\nexec sleep 30\n",
+                pid_file.display()
+            ),
+        );
+        let token = CancellationToken::new();
+        let options = ExecOptions::new()
+            .with_command_override(AgentProvider::Aider, &script)
+            .with_cancel_token(token.clone());
+        let mut stream =
+            AgentExecutor::execute_stream_iter_with_options(AgentProvider::Aider, "hi", &options);
+        let first = stream.next().await.unwrap().unwrap();
+        // The agent prints nothing more, so only the drop can stop it.
+        drop(stream);
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let exited = process_exits(pid.trim()).await;
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&pid_file);
+        assert_eq!(first, "started\n");
+        assert!(exited, "agent {} outlived its dropped stream", pid.trim());
+        assert!(!token.is_cancelled());
     }
 
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_execute_stream_mock_calls_callback_once() {
-        let count = Arc::new(StdMutex::new(0usize));
-        let count_clone = Arc::clone(&count);
-        let _ = AgentExecutor::execute_stream(AgentProvider::Mock, "hello", move |_| {
-            *count_clone.lock().unwrap() += 1;
-        })
-        .await;
-        assert_eq!(*count.lock().unwrap(), 1);
+    async fn test_builder_defaults_apply_under_per_call_options() {
+        let script = fake_agent(
+            "built-aider.sh",
+            "echo \"cwd=$(pwd) region=$ACORE_REGION tier=$ACORE_TIER\"\nsleep ${SLEEP:-0}\n",
+        );
+        let dir = std::env::temp_dir().canonicalize().unwrap();
+        let mgr = SessionManager::builder()
+            .cwd(&dir)
+            .env("ACORE_REGION", "eu")
+            .env("ACORE_TIER", "free")
+            .command_override(AgentProvider::Aider, &script)
+            .buffer_size(16)
+            .timeout(Duration::from_millis(300))
+            .build();
+        let output = mgr
+            .execute_with_resume_collect_with_options(
+                AgentProvider::Aider,
+                "hi",
+                &ExecOptions::new().with_env("ACORE_TIER", "pro"),
+                |_| {},
+            )
+            .await;
+        let slow = mgr
+            .execute_with_resume_with_options(
+                AgentProvider::Aider,
+                "hi",
+                &ExecOptions::new().with_env("SLEEP", "5"),
+                |_| {},
+            )
+            .await;
+        let _ = std::fs::remove_file(&script);
+
+        assert_eq!(
+            output.unwrap(),
+            format!("cwd={} region=eu tier=pro\n", dir.display())
+        );
+        assert!(matches!(
+            slow,
+            Err(AgentError::Cancelled {
+                reason: CancelReason::Timeout
+            })
+        ));
     }
 
+    // ─── Resource limit tests ─────────────────────────────────────────────────
+
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_execute_stream_dummy_echoes_prompt_exactly() {
-        let received = Arc::new(StdMutex::new(String::new()));
-        let received_clone = Arc::clone(&received);
-        let _ = AgentExecutor::execute_stream(AgentProvider::Dummy, "echo me", move |chunk| {
-            received_clone.lock().unwrap().push_str(&chunk);
+    async fn test_memory_limit_terminates_child_exceeding_it() {
+        let options = ExecOptions::new().with_limits(ResourceLimits {
+            max_memory_bytes: Some(64 * 1024 * 1024),
+            cpu_time_secs: None,
+        });
+        // Builds a ~200 MB shell variable, far beyond the 64 MiB address space.
+        let mut command =
+            sh_command("x=$(head -c 200000000 /dev/zero | tr '\\0' a); echo survived");
+        options.configure(&mut command);
+        let mut received = String::new();
+        let run = AgentExecutor::stream_command(&mut command, &options, None, &mut |chunk| {
+            received.push_str(&chunk)
         })
-        .await;
-        assert_eq!(*received.lock().unwrap(), "echo me");
+        .await
+        .unwrap();
+        assert!(!run.status.success());
+        assert!(!received.contains("survived"));
     }
 
-    // ─── SessionManager::execute_with_resume (Mock) tests ────────────────────
-
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_execute_with_resume_mock_succeeds() {
-        let mgr = SessionManager::new();
-        let result = mgr
-            .execute_with_resume(AgentProvider::Mock, "hello", |_| {})
-            .await;
-        assert!(result.is_ok());
+    async fn test_limits_allow_small_child() {
+        let options = ExecOptions::new().with_limits(ResourceLimits {
+            max_memory_bytes: Some(512 * 1024 * 1024),
+            cpu_time_secs: Some(10),
+        });
+        let mut command = sh_command("echo ok");
+        options.configure(&mut command);
+        let run = AgentExecutor::stream_command(&mut command, &options, None, &mut |_| {})
+            .await
+            .unwrap();
+        assert!(run.status.success());
     }
 
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_execute_with_resume_mock_returns_prompt_in_message() {
-        let mgr = SessionManager::new();
-        let received = Arc::new(StdMutex::new(String::new()));
-        let received_clone = Arc::clone(&received);
-        let _ = mgr
-            .execute_with_resume(AgentProvider::Mock, "my prompt", move |chunk| {
-                received_clone.lock().unwrap().push_str(&chunk);
+    async fn test_stream_failure_reports_stderr() {
+        let mut command = sh_command("echo partial; echo 'quota exceeded' >&2; exit 3");
+        let mut received = String::new();
+        let err =
+            AgentExecutor::stream_command(&mut command, &ExecOptions::default(), None, &mut |c| {
+                received.push_str(&c)
             })
-            .await;
-        let result = received.lock().unwrap().clone();
-        assert!(
-            result.contains("my prompt"),
-            "Expected 'my prompt' in '{}'",
-            result
-        );
+            .await
+            .unwrap()
+            .check("sh")
+            .err()
+            .unwrap();
+        assert_eq!(received, "partial\n");
+        match err {
+            AgentError::NonZeroExit { code, detail, .. } => {
+                assert_eq!(code, Some(3));
+                assert!(detail.contains("quota exceeded"), "detail: {detail}");
+            }
+            other => panic!("unexpected error: {other}"),
+        }
     }
 
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_execute_with_resume_mock_does_not_store_session() {
-        let mgr = SessionManager::new();
-        let _ = mgr
-            .execute_with_resume(AgentProvider::Mock, "test", |_| {})
-            .await;
-        // Mock should not pollute the session store
-        let sessions = mgr.session_ids.lock().await;
-        assert!(sessions.is_empty());
+    async fn test_stream_drains_large_stderr_while_reading_stdout() {
+        // 256 KiB of stderr overflows the pipe buffer before stdout closes.
+        let mut command = sh_command("head -c 262144 /dev/zero >&2; echo done");
+        let options = ExecOptions::new().with_timeout(Duration::from_secs(10));
+        let run = AgentExecutor::stream_command(&mut command, &options, None, &mut |_| {})
+            .await
+            .unwrap()
+            .check("sh")
+            .unwrap();
+        assert_eq!(run.stderr.len(), 262144);
     }
 
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_execute_with_resume_mock_multiple_calls_succeed() {
-        let mgr = SessionManager::new();
-        for i in 0..3 {
-            let result = mgr
-                .execute_with_resume(AgentProvider::Mock, &format!("prompt {}", i), |_| {})
-                .await;
-            assert!(result.is_ok(), "Call {} should succeed", i);
+    async fn test_stream_failure_with_large_stderr_keeps_full_detail() {
+        let mut command =
+            sh_command("echo working; head -c 131072 /dev/zero | tr '\\0' e >&2; exit 1");
+        let options = ExecOptions::new().with_timeout(Duration::from_secs(10));
+        let err = AgentExecutor::stream_command(&mut command, &options, None, &mut |_| {})
+            .await
+            .unwrap()
+            .check("sh")
+            .err()
+            .unwrap();
+        match err {
+            AgentError::NonZeroExit { detail, .. } => assert_eq!(detail.len(), 131072),
+            other => panic!("unexpected error: {other}"),
         }
     }
 
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_execute_with_resume_dummy_echoes_prompt_exactly() {
-        let mgr = SessionManager::new();
-        let received = Arc::new(StdMutex::new(String::new()));
-        let received_clone = Arc::clone(&received);
-        let _ = mgr
-            .execute_with_resume(AgentProvider::Dummy, "dummy prompt", move |chunk| {
-                received_clone.lock().unwrap().push_str(&chunk);
+    async fn test_execute_stream_keeps_stdout_while_agent_floods_stderr() {
+        let script = fake_agent(
+            "noisy-aider.sh",
+            "head -c 262144 /dev/zero | tr '\\0' w >&2\necho answer\n",
+        );
+        let options = ExecOptions::new()
+            .with_command_override(AgentProvider::Aider, &script)
+            .with_timeout(Duration::from_secs(10));
+        let mut received = String::new();
+        let result =
+            AgentExecutor::execute_stream_with_options(AgentProvider::Aider, "hi", &options, |c| {
+                received.push_str(&c)
             })
             .await;
-        assert_eq!(received.lock().unwrap().as_str(), "dummy prompt");
+        let _ = std::fs::remove_file(&script);
+        result.unwrap();
+        assert_eq!(received, "answer\n");
     }
 
+    #[cfg(unix)]
     #[tokio::test]
-    async fn test_execute_with_resume_dummy_does_not_store_session() {
-        let mgr = SessionManager::new();
-        let _ = mgr
-            .execute_with_resume(AgentProvider::Dummy, "test", |_| {})
-            .await;
-        let sessions = mgr.session_ids.lock().await;
-        assert!(sessions.is_empty());
+    async fn test_execute_stream_merged_tags_both_streams_and_reports_failure() {
+        let script = fake_agent(
+            "failing-aider.sh",
+            "echo progress >&2\necho partial\necho 'rate limited' >&2\nexit 2\n",
+        );
+        let options = ExecOptions::new().with_command_override(AgentProvider::Aider, &script);
+        let received = Arc::new(StdMutex::new(Vec::new()));
+        let sink = Arc::clone(&received);
+        let result = AgentExecutor::execute_stream_merged(
+            AgentProvider::Aider,
+            "hi",
+            &options,
+            move |chunk| sink.lock().unwrap().push(chunk),
+        )
+        .await;
+        let _ = std::fs::remove_file(&script);
+        let chunks = received.lock().unwrap();
+        let text = |source| {
+            chunks
+                .iter()
+                .filter(|c| c.source == source)
+                .map(|c| c.text.as_str())
+                .collect::<String>()
+        };
+        assert_eq!(text(OutputSource::Stdout), "partial\n");
+        assert_eq!(text(OutputSource::Stderr), "progress\nrate limited\n");
+        match result {
+            Err(AgentError::NonZeroExit { code, detail, .. }) => {
+                assert_eq!(code, Some(2));
+                assert!(detail.contains("rate limited"), "detail: {detail}");
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_agent_error_cancelled_display_mentions_reason() {
+        let err = AgentError::Cancelled {
+            reason: CancelReason::Timeout,
+        };
+        assert_eq!(err.to_string(), "Execution cancelled: timed out");
+    }
+
+    // ─── Merged stdout/stderr stream tests ────────────────────────────────────
+
+    #[tokio::test]
+    async fn test_execute_stream_merged_mock_delivers_tagged_chunks_in_order() {
+        let received = Arc::new(StdMutex::new(Vec::new()));
+        let received_clone = Arc::clone(&received);
+        AgentExecutor::execute_stream_merged(
+            AgentProvider::Mock,
+            "hi",
+            &ExecOptions::default(),
+            move |chunk| received_clone.lock().unwrap().push(chunk),
+        )
+        .await
+        .unwrap();
+        let sources: Vec<OutputSource> =
+            received.lock().unwrap().iter().map(|c| c.source).collect();
+        assert_eq!(
+            sources,
+            vec![
+                OutputSource::Stdout,
+                OutputSource::Stderr,
+                OutputSource::Stdout
+            ]
+        );
+        let stdout: String = received
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| c.source == OutputSource::Stdout)
+            .map(|c| c.text.as_str())
+            .collect();
+        assert_eq!(stdout, "Mock stream: pong");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_stream_command_merged_preserves_arrival_order() {
+        let mut command =
+            sh_command("echo out-1; sleep 0.05; echo err-1 >&2; sleep 0.05; echo out-2");
+        let mut received = Vec::new();
+        let run = AgentExecutor::stream_command_merged(
+            &mut command,
+            &ExecOptions::default(),
+            None,
+            &mut |source, text| received.push((source, text)),
+        )
+        .await
+        .unwrap();
+        assert!(run.status.success());
+        assert_eq!(
+            received,
+            vec![
+                (OutputSource::Stdout, "out-1\n".to_string()),
+                (OutputSource::Stderr, "err-1\n".to_string()),
+                (OutputSource::Stdout, "out-2\n".to_string()),
+            ]
+        );
     }
 
     // ─── AgentExecutor::build_init_prompt tests ───────────────────────────────
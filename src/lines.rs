@@ -0,0 +1,58 @@
+/// Reassembles streamed text into complete lines. Text after the last `\n`
+/// is held until a later `push` completes it or `finish` flushes it at EOF.
+#[derive(Debug, Default)]
+pub(crate) struct LineFramer {
+    pending: String,
+}
+
+impl LineFramer {
+    /// Returns the lines completed by `chunk`, without their `\n` / `\r\n`.
+    pub(crate) fn push(&mut self, chunk: &str) -> Vec<String> {
+        self.pending.push_str(chunk);
+        let Some(last_newline) = self.pending.rfind('\n') else {
+            return Vec::new();
+        };
+        let rest = self.pending.split_off(last_newline + 1);
+        let complete = std::mem::replace(&mut self.pending, rest);
+        complete
+            .lines()
+            .map(|line| line.trim_end_matches('\r').to_string())
+            .collect()
+    }
+
+    /// Returns the unterminated last line, if any.
+    pub(crate) fn finish(&mut self) -> Option<String> {
+        let line = std::mem::take(&mut self.pending);
+        (!line.is_empty()).then(|| line.trim_end_matches('\r').to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(chunks: &[&str]) -> Vec<String> {
+        let mut framer = LineFramer::default();
+        let mut lines: Vec<String> = chunks.iter().flat_map(|c| framer.push(c)).collect();
+        lines.extend(framer.finish());
+        lines
+    }
+
+    #[test]
+    fn test_newline_on_chunk_boundary_completes_line() {
+        assert_eq!(
+            frame(&["{\"a\":1}\n", "{\"b\":2}\n"]),
+            ["{\"a\":1}", "{\"b\":2}"]
+        );
+        assert_eq!(
+            frame(&["{\"a\":", "1}", "\n{\"b\"", ":2}"]),
+            ["{\"a\":1}", "{\"b\":2}"]
+        );
+    }
+
+    #[test]
+    fn test_blank_and_crlf_lines_are_kept() {
+        assert_eq!(frame(&["one\r\n\r", "\ntwo"]), ["one", "", "two"]);
+        assert!(frame(&[]).is_empty());
+    }
+}
@@ -1,43 +1,383 @@
-use acore::{AgentExecutor, AgentProvider};
+use acore::{
+    AgentExecutor, AgentProvider, CancellationToken, ExecOptions, PermissionMode, RecordOptions,
+};
 use clap::Parser;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// 実行するプロンプト
-    prompt: String,
+    #[arg(required = true)]
+    prompt: Option<String>,
+
+    /// 使用するプロバイダー (gemini, claude, codex, opencode, cursor, aider)
+    #[arg(short, long, default_value = "gemini", value_parser = AgentProvider::from_str)]
+    provider: AgentProvider,
 
-    /// 使用するプロバイダー (gemini, claude, codex, opencode)
-    #[arg(short, long, default_value = "gemini")]
-    provider: String,
+    /// 使用するモデル (`claude` の sonnet / opus など)。省略時は各ツールの既定のモデル
+    #[arg(short, long)]
+    model: Option<String>,
+
+    /// エージェントに与える操作の許可 (yolo, default, restricted)。
+    /// default / restricted では許可をスキップするフラグを付けません
+    #[arg(long, default_value = "yolo", value_parser = PermissionMode::from_str)]
+    permission_mode: PermissionMode,
 
     /// 要約して amem に記録するかどうか
     #[arg(short, long)]
     record: bool,
+
+    /// 記録時に amem へ渡す source
+    #[arg(long, default_value = "acore")]
+    source: String,
+
+    /// このマシンで使えるツールを一覧表示して終了する
+    #[arg(long, exclusive = true)]
+    list_tools: bool,
+
+    /// 出力の各行の先頭にツール名 (`[claude] ` など) を付ける
+    #[arg(long)]
+    prefix: bool,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Commands {
+    /// 各ツールが対応している機能を表示する
+    Capabilities {
+        /// 表示するプロバイダー (省略時はすべて)
+        #[arg(short, long, value_parser = AgentProvider::from_str)]
+        provider: Option<AgentProvider>,
+
+        /// 表ではなく JSON で出力する
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// `acore capabilities` の対象。`--provider` で指定できないダミーは除きます
+fn listed_providers(provider: Option<&AgentProvider>) -> Vec<AgentProvider> {
+    match provider {
+        Some(provider) => vec![provider.clone()],
+        None => AgentProvider::ALL
+            .into_iter()
+            .filter(|provider| *provider != AgentProvider::Dummy)
+            .collect(),
+    }
+}
+
+/// 機能の対応表を列をそろえたテキストにします
+fn capabilities_table(providers: &[AgentProvider]) -> String {
+    let name_width = providers
+        .iter()
+        .map(|provider| provider.command_name().len())
+        .chain(["provider".len()])
+        .max()
+        .unwrap_or_default();
+    // Column names are the same for every tool; take them from any of them.
+    let columns = AgentProvider::Mock
+        .capabilities()
+        .entries()
+        .map(|(name, _)| name);
+    let mut table = format!("{:<name_width$}  {}\n", "provider", columns.join("  "));
+    for provider in providers {
+        let cells: Vec<String> = provider
+            .capabilities()
+            .entries()
+            .iter()
+            .map(|(name, supported)| {
+                let cell = if *supported { "yes" } else { "no" };
+                format!("{cell:<width$}", width = name.len())
+            })
+            .collect();
+        let row = format!(
+            "{:<name_width$}  {}",
+            provider.command_name(),
+            cells.join("  ")
+        );
+        table.push_str(row.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// 機能の対応表を JSON の配列にします
+fn capabilities_json(providers: &[AgentProvider]) -> serde_json::Value {
+    providers
+        .iter()
+        .map(|provider| {
+            let mut entry = serde_json::to_value(provider.capabilities()).unwrap_or_default();
+            entry["provider"] = provider.command_name().into();
+            entry
+        })
+        .collect()
+}
+
+/// チャンクを書き込むたびに flush する出力先。
+/// 書き込み先が閉じられた場合 (`| head` など) は `closed` をキャンセルし、以降の書き込みを捨てます
+struct ChunkSink<W> {
+    out: W,
+    closed: CancellationToken,
+    at_line_start: Arc<AtomicBool>,
+}
+
+impl<W: Write> ChunkSink<W> {
+    fn new(out: W, closed: CancellationToken) -> Self {
+        Self {
+            out,
+            closed,
+            at_line_start: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    fn write(&mut self, chunk: &str) {
+        if self.closed.is_cancelled() || chunk.is_empty() {
+            return;
+        }
+        match self
+            .out
+            .write_all(chunk.as_bytes())
+            .and_then(|()| self.out.flush())
+        {
+            Ok(()) => self
+                .at_line_start
+                .store(chunk.ends_with('\n'), Ordering::Relaxed),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::BrokenPipe {
+                    eprintln!("acore: failed to write output: {}", e);
+                }
+                self.closed.cancel();
+            }
+        }
+    }
+}
+
+/// チャンクの境界をまたいで行頭を追跡し、各行の先頭に `prefix` を挿入します
+struct LinePrefixer {
+    prefix: String,
+    at_line_start: bool,
+}
+
+impl LinePrefixer {
+    fn new(prefix: String) -> Self {
+        Self {
+            prefix,
+            at_line_start: true,
+        }
+    }
+
+    fn apply(&mut self, chunk: &str) -> String {
+        let mut out = String::with_capacity(chunk.len());
+        for line in chunk.split_inclusive('\n') {
+            if self.at_line_start {
+                out.push_str(&self.prefix);
+            }
+            out.push_str(line);
+            self.at_line_start = line.ends_with('\n');
+        }
+        out
+    }
+}
+
+/// 記録用の対話ログを組み立てます。応答が空なら記録しません
+fn transcript(prompt: &str, output: &str) -> Option<String> {
+    if output.trim().is_empty() {
+        return None;
+    }
+    Some(format!(
+        "User: {}\nAssistant: {}",
+        prompt,
+        output.trim_end()
+    ))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let args = Args::parse();
 
-    let provider = match args.provider.as_str() {
-        "gemini" => AgentProvider::Gemini,
-        "claude" => AgentProvider::Claude,
-        "codex" => AgentProvider::Codex,
-        "opencode" => AgentProvider::OpenCode,
-        _ => AgentProvider::Gemini,
-    };
+    if let Some(Commands::Capabilities { provider, json }) = &args.command {
+        let providers = listed_providers(provider.as_ref());
+        if *json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&capabilities_json(&providers))?
+            );
+        } else {
+            print!("{}", capabilities_table(&providers));
+        }
+        return Ok(());
+    }
+
+    if args.list_tools {
+        for provider in AgentExecutor::available_tools().await {
+            println!("{}", provider.command_name());
+        }
+        return Ok(());
+    }
 
-    // ストリーミング実行（標準出力に出力）
-    AgentExecutor::execute_stream(provider.clone(), &args.prompt, |line| {
-        println!("{}", line);
-    })
-    .await?;
+    let prompt = args.prompt.as_deref().unwrap_or_default();
+    let provider = args.provider;
 
-    // 必要に応じて amem に記録
-    if args.record {
-        // 将来的に実装
+    // ストリーミング実行（チャンクごとに標準出力へ書き出して即座に flush する）
+    // 出力先が閉じられたらエージェントを kill して正常終了します
+    let pipe_closed = CancellationToken::new();
+    let mut options = ExecOptions::new()
+        .with_cancel_token(pipe_closed.clone())
+        .with_permission_mode(args.permission_mode);
+    if let Some(model) = &args.model {
+        options = options.with_model(model);
+    }
+    let mut sink = ChunkSink::new(std::io::stdout(), pipe_closed.clone());
+    let at_line_start = Arc::clone(&sink.at_line_start);
+    let mut prefixer = args
+        .prefix
+        .then(|| LinePrefixer::new(format!("[{}] ", provider.command_name())));
+    // コールバックは 'static でなくてよいので、ローカルの String に直接蓄積できます
+    let mut output = String::new();
+    let result =
+        AgentExecutor::execute_stream_with_options(provider.clone(), prompt, &options, |chunk| {
+            if args.record {
+                output.push_str(&chunk);
+            }
+            match prefixer.as_mut() {
+                Some(prefixer) => sink.write(&prefixer.apply(&chunk)),
+                None => sink.write(&chunk),
+            }
+        })
+        .await;
+
+    if pipe_closed.is_cancelled() {
+        return Ok(());
+    }
+    result?;
+    if !at_line_start.load(Ordering::Relaxed) {
+        let mut out = std::io::stdout().lock();
+        let _ = out.write_all(b"\n").and_then(|()| out.flush());
+    }
+
+    // 必要に応じて amem に記録 (amem が無い場合は summarize_and_record が何もせずに戻ります)
+    if args.record
+        && let Some(transcript) = transcript(prompt, &output)
+    {
+        let record = RecordOptions::default().with_source(args.source);
+        AgentExecutor::summarize_and_record_as(provider, &transcript, &record, &ExecOptions::new())
+            .await?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct BrokenPipeWriter {
+        writes: usize,
+    }
+
+    impl Write for BrokenPipeWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            self.writes += 1;
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_chunk_sink_stops_cleanly_on_broken_pipe() {
+        let closed = CancellationToken::new();
+        let mut sink = ChunkSink::new(BrokenPipeWriter { writes: 0 }, closed.clone());
+        sink.write("first");
+        sink.write("second");
+        assert!(closed.is_cancelled());
+        assert_eq!(sink.out.writes, 1);
+    }
+
+    #[test]
+    fn test_transcript_skips_empty_output() {
+        assert_eq!(transcript("hi", " \n"), None);
+        assert_eq!(
+            transcript("hi", "hello\n").as_deref(),
+            Some("User: hi\nAssistant: hello")
+        );
+    }
+
+    #[test]
+    fn test_line_prefixer_marks_each_line_across_chunks() {
+        let mut prefixer = LinePrefixer::new("[claude] ".to_string());
+        let out: String = ["one\ntw", "o\n", "\nthree"]
+            .iter()
+            .map(|chunk| prefixer.apply(chunk))
+            .collect();
+        assert_eq!(out, "[claude] one\n[claude] two\n[claude] \n[claude] three");
+    }
+
+    #[test]
+    fn test_model_flag_is_optional() {
+        let args =
+            Args::try_parse_from(["acore", "-p", "claude", "--model", "opus", "hi"]).unwrap();
+        assert_eq!(args.model.as_deref(), Some("opus"));
+        let args = Args::try_parse_from(["acore", "hi"]).unwrap();
+        assert_eq!(args.model, None);
+    }
+
+    #[test]
+    fn test_capabilities_subcommand_does_not_need_a_prompt() {
+        let args = Args::try_parse_from(["acore", "capabilities", "-p", "claude"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Commands::Capabilities {
+                provider: Some(AgentProvider::Claude),
+                json: false
+            })
+        ));
+        assert!(Args::try_parse_from(["acore"]).is_err());
+    }
+
+    #[test]
+    fn test_capabilities_table_aligns_columns() {
+        let table = capabilities_table(&[AgentProvider::Claude, AgentProvider::Aider]);
+        assert_eq!(
+            table,
+            "provider  resume  json  model  thinking  stdin  permissions\n\
+             claude    yes     yes   yes    yes       yes    yes\n\
+             aider     no      no    yes    no        no     yes\n"
+        );
+    }
+
+    #[test]
+    fn test_permission_mode_flag_defaults_to_yolo() {
+        let args = Args::try_parse_from(["acore", "hi"]).unwrap();
+        assert_eq!(args.permission_mode, PermissionMode::Yolo);
+        let args =
+            Args::try_parse_from(["acore", "--permission-mode", "restricted", "hi"]).unwrap();
+        assert_eq!(args.permission_mode, PermissionMode::Restricted);
+        assert!(Args::try_parse_from(["acore", "--permission-mode", "none", "hi"]).is_err());
+    }
+
+    #[test]
+    fn test_chunk_sink_tracks_line_start() {
+        let mut sink = ChunkSink::new(Vec::new(), CancellationToken::new());
+        sink.write("partial");
+        assert!(!sink.at_line_start.load(Ordering::Relaxed));
+        sink.write(" line\n");
+        assert!(sink.at_line_start.load(Ordering::Relaxed));
+        assert_eq!(sink.out, b"partial line\n");
+    }
+}
@@ -0,0 +1,133 @@
+use crate::AgentProvider;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds (seconds) of the run duration histogram buckets.
+const DURATION_BUCKETS: [f64; 10] = [0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+/// Counter name, help text and accessor, rendered in this order.
+type Counter = (&'static str, &'static str, fn(&ProviderMetrics) -> u64);
+
+const COUNTERS: [Counter; 3] = [
+    ("acore_runs_total", "Agent runs started.", |m| m.runs),
+    (
+        "acore_run_failures_total",
+        "Agent runs that returned an error.",
+        |m| m.failures,
+    ),
+    (
+        "acore_tokens_total",
+        "Tokens reported by agent output.",
+        |m| m.tokens,
+    ),
+];
+
+/// 実行回数・失敗数・所要時間・トークン数をツールごとに集計し、Prometheus 形式で出力します。
+/// `Clone` は同じ集計を共有します。
+#[derive(Debug, Clone, Default)]
+pub struct ExecMetrics {
+    inner: Arc<Mutex<BTreeMap<&'static str, ProviderMetrics>>>,
+}
+
+#[derive(Debug, Default)]
+struct ProviderMetrics {
+    runs: u64,
+    failures: u64,
+    tokens: u64,
+    /// Cumulative counts per `DURATION_BUCKETS` entry.
+    buckets: [u64; DURATION_BUCKETS.len()],
+    duration_sum: f64,
+}
+
+impl ExecMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 1 回の実行結果を記録します
+    pub fn record(
+        &self,
+        provider: &AgentProvider,
+        duration: Duration,
+        success: bool,
+        tokens: Option<u64>,
+    ) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = inner.entry(provider.command_name()).or_default();
+        entry.runs += 1;
+        if !success {
+            entry.failures += 1;
+        }
+        entry.tokens += tokens.unwrap_or(0);
+        let seconds = duration.as_secs_f64();
+        entry.duration_sum += seconds;
+        for (count, bound) in entry.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if seconds <= bound {
+                *count += 1;
+            }
+        }
+    }
+
+    /// 集計を Prometheus のテキスト形式 (`/metrics` 用) で返します
+    pub fn metrics_text(&self) -> String {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+        for (name, help, value) in COUNTERS {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            for (tool, metrics) in inner.iter() {
+                let _ = writeln!(out, "{name}{{tool=\"{tool}\"}} {}", value(metrics));
+            }
+        }
+
+        let name = "acore_run_duration_seconds";
+        let _ = writeln!(out, "# HELP {name} Wall-clock duration of agent runs.");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (tool, metrics) in inner.iter() {
+            for (count, bound) in metrics.buckets.iter().zip(DURATION_BUCKETS) {
+                let _ = writeln!(
+                    out,
+                    "{name}_bucket{{tool=\"{tool}\",le=\"{bound}\"}} {count}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{tool=\"{tool}\",le=\"+Inf\"}} {}",
+                metrics.runs
+            );
+            let _ = writeln!(
+                out,
+                "{name}_sum{{tool=\"{tool}\"}} {}",
+                metrics.duration_sum
+            );
+            let _ = writeln!(out, "{name}_count{{tool=\"{tool}\"}} {}", metrics.runs);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let metrics = ExecMetrics::new();
+        metrics.record(
+            &AgentProvider::Claude,
+            Duration::from_millis(300),
+            true,
+            Some(12),
+        );
+        metrics.record(&AgentProvider::Claude, Duration::from_secs(7), false, None);
+        let text = metrics.metrics_text();
+        assert!(text.contains("acore_run_duration_seconds_bucket{tool=\"claude\",le=\"0.1\"} 0"));
+        assert!(text.contains("acore_run_duration_seconds_bucket{tool=\"claude\",le=\"0.5\"} 1"));
+        assert!(text.contains("acore_run_duration_seconds_bucket{tool=\"claude\",le=\"10\"} 2"));
+        assert!(text.contains("acore_run_duration_seconds_bucket{tool=\"claude\",le=\"+Inf\"} 2"));
+        assert!(text.contains("acore_run_failures_total{tool=\"claude\"} 1"));
+        assert!(text.contains("acore_tokens_total{tool=\"claude\"} 12"));
+    }
+}
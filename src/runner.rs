@@ -0,0 +1,32 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::process::Output;
+use tokio::process::{Child, Command};
+
+/// [`ProcessRunner::output`] が返す Future
+pub type RunnerFuture<'a> = Pin<Box<dyn Future<Output = std::io::Result<Output>> + Send + 'a>>;
+
+/// エージェントのプロセスを起動する方法。
+///
+/// エージェントの起動はすべて [`ProcessRunner::spawn`] を、`--version` の確認や `amem` の呼び出しなど
+/// 終了まで待って出力をまとめて受け取る起動は [`ProcessRunner::output`] を通るため、テストでは実際の CLI の代わりに
+/// 引数を記録して決まった出力を返せます (`ExecOptions::with_runner`)。
+/// `command` には引数・環境変数・標準入出力の設定が済んでいます。
+pub trait ProcessRunner: Send + Sync {
+    fn spawn(&self, command: &mut Command) -> std::io::Result<Child>;
+
+    /// `command` を実行して終了を待ち、標準出力と標準エラー出力をまとめて返します
+    fn output<'a>(&'a self, command: &'a mut Command) -> RunnerFuture<'a> {
+        Box::pin(command.output())
+    }
+}
+
+/// `Command::spawn` でそのまま起動する既定の実装
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioRunner;
+
+impl ProcessRunner for TokioRunner {
+    fn spawn(&self, command: &mut Command) -> std::io::Result<Child> {
+        command.spawn()
+    }
+}
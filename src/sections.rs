@@ -0,0 +1,108 @@
+use crate::lines::LineFramer;
+
+/// `execute_stream_items` が配信する出力の単位
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamItem {
+    /// 区切りが設定されていない場合のチャンクそのまま
+    Chunk(String),
+    /// 完結したセクション。`name` は区切り行の残り (最初の区切りより前の本文は空文字列)
+    Section { name: String, text: String },
+}
+
+/// Splits streamed text into sections at lines that start with `delimiter`.
+/// Lines are buffered across chunk boundaries, and a section is emitted as
+/// soon as the next delimiter line (or the end of the stream) closes it.
+#[derive(Debug)]
+pub(crate) struct SectionSplitter {
+    delimiter: String,
+    lines: LineFramer,
+    name: String,
+    body: Vec<String>,
+}
+
+impl SectionSplitter {
+    pub(crate) fn new(delimiter: impl Into<String>) -> Self {
+        Self {
+            delimiter: delimiter.into(),
+            lines: LineFramer::default(),
+            name: String::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, chunk: &str, emit: &mut impl FnMut(StreamItem)) {
+        for line in self.lines.push(chunk) {
+            self.push_line(&line, emit);
+        }
+    }
+
+    /// Flushes an unterminated last line and emits the open section.
+    pub(crate) fn finish(&mut self, emit: &mut impl FnMut(StreamItem)) {
+        if let Some(line) = self.lines.finish() {
+            self.push_line(&line, emit);
+        }
+        self.close_section(emit);
+    }
+
+    fn push_line(&mut self, line: &str, emit: &mut impl FnMut(StreamItem)) {
+        match line.strip_prefix(self.delimiter.as_str()) {
+            Some(name) => {
+                self.close_section(emit);
+                self.name = name.trim().to_string();
+            }
+            None => self.body.push(line.to_string()),
+        }
+    }
+
+    fn close_section(&mut self, emit: &mut impl FnMut(StreamItem)) {
+        let name = std::mem::take(&mut self.name);
+        let text = std::mem::take(&mut self.body).join("\n").trim().to_string();
+        // Whitespace before the first delimiter is not a section of its own.
+        if name.is_empty() && text.is_empty() {
+            return;
+        }
+        emit(StreamItem::Section { name, text });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(delimiter: &str, chunks: &[&str]) -> Vec<StreamItem> {
+        let mut splitter = SectionSplitter::new(delimiter);
+        let mut items = Vec::new();
+        let mut emit = |item| items.push(item);
+        for chunk in chunks {
+            splitter.push(chunk, &mut emit);
+        }
+        splitter.finish(&mut emit);
+        items
+    }
+
+    fn section(name: &str, text: &str) -> StreamItem {
+        StreamItem::Section {
+            name: name.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_sections_split_across_chunk_boundaries() {
+        let items = split(
+            "## ",
+            &["## Ans", "wer\n4", "2\n#", "# Sources\nwiki\n", "docs"],
+        );
+        assert_eq!(
+            items,
+            vec![section("Answer", "42"), section("Sources", "wiki\ndocs")]
+        );
+    }
+
+    #[test]
+    fn test_text_before_first_delimiter_is_an_unnamed_section() {
+        let items = split("---", &["intro\n--- a\nbody\n"]);
+        assert_eq!(items, vec![section("", "intro"), section("a", "body")]);
+        assert_eq!(split("---", &["\n\n--- a\nx"]), vec![section("a", "x")]);
+    }
+}
@@ -0,0 +1,173 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 現在時刻を返す時計 (テストでは固定の時刻を返すものに差し替えられます)
+pub type Clock = Arc<dyn Fn() -> SystemTime + Send + Sync>;
+
+/// 日時の行を付けるプロンプト
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimestampTarget {
+    /// 各ターンのプロンプト
+    #[default]
+    Prompt,
+    /// シードの初期化プロンプト
+    Seed,
+    Both,
+}
+
+/// プロンプトの先頭に現在の日時の行を付ける設定
+#[derive(Clone)]
+pub struct PromptTimestamp {
+    /// 日時の行の書式。`%Y` `%m` `%d` `%H` `%M` `%S` `%z` (`+0900` 形式) と `%%` を置き換えます
+    pub format: String,
+    /// UTC からのずれ (秒)。既定は 0 (UTC)
+    pub utc_offset_secs: i32,
+    pub target: TimestampTarget,
+    /// `None` の場合はシステムの時計を使います
+    pub clock: Option<Clock>,
+}
+
+impl Default for PromptTimestamp {
+    fn default() -> Self {
+        Self {
+            format: "Current date and time: %Y-%m-%d %H:%M:%S %z".to_string(),
+            utc_offset_secs: 0,
+            target: TimestampTarget::default(),
+            clock: None,
+        }
+    }
+}
+
+impl PromptTimestamp {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_format(mut self, format: impl Into<String>) -> Self {
+        self.format = format.into();
+        self
+    }
+
+    pub fn with_utc_offset(mut self, secs: i32) -> Self {
+        self.utc_offset_secs = secs;
+        self
+    }
+
+    pub fn with_target(mut self, target: TimestampTarget) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn with_clock<F>(mut self, clock: F) -> Self
+    where
+        F: Fn() -> SystemTime + Send + Sync + 'static,
+    {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// 現在の日時を `format` に従って整形した行
+    pub fn line(&self) -> String {
+        let now = self
+            .clock
+            .as_ref()
+            .map_or_else(SystemTime::now, |clock| clock());
+        let secs = match now.duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        };
+        self.format_at(secs + i64::from(self.utc_offset_secs))
+    }
+
+    /// `prompt` の先頭に日時の行を付けます
+    pub fn apply(&self, prompt: &str) -> String {
+        format!("{}\n\n{}", self.line(), prompt)
+    }
+
+    pub(crate) fn stamps_prompt(&self) -> bool {
+        matches!(self.target, TimestampTarget::Prompt | TimestampTarget::Both)
+    }
+
+    pub(crate) fn stamps_seed(&self) -> bool {
+        matches!(self.target, TimestampTarget::Seed | TimestampTarget::Both)
+    }
+
+    /// Formats `local_secs` (seconds since the epoch, already shifted by the
+    /// UTC offset).
+    fn format_at(&self, local_secs: i64) -> String {
+        let (year, month, day) = civil_from_days(local_secs.div_euclid(86_400));
+        let time = local_secs.rem_euclid(86_400);
+        let offset = self.utc_offset_secs;
+        let sign = if offset < 0 { '-' } else { '+' };
+        let offset = offset.unsigned_abs();
+
+        let mut out = String::with_capacity(self.format.len() + 16);
+        let mut chars = self.format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('Y') => out.push_str(&format!("{year:04}")),
+                Some('m') => out.push_str(&format!("{month:02}")),
+                Some('d') => out.push_str(&format!("{day:02}")),
+                Some('H') => out.push_str(&format!("{:02}", time / 3600)),
+                Some('M') => out.push_str(&format!("{:02}", time % 3600 / 60)),
+                Some('S') => out.push_str(&format!("{:02}", time % 60)),
+                Some('z') => out.push_str(&format!(
+                    "{sign}{:02}{:02}",
+                    offset / 3600,
+                    offset % 3600 / 60
+                )),
+                Some('%') => out.push('%'),
+                Some(other) => {
+                    out.push('%');
+                    out.push(other);
+                }
+                None => out.push('%'),
+            }
+        }
+        out
+    }
+}
+
+/// Proleptic Gregorian date of the given day count since 1970-01-01
+/// (Howard Hinnant's `civil_from_days`).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> PromptTimestamp {
+        PromptTimestamp::new().with_clock(move || UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn test_line_formats_the_clock_in_the_configured_offset() {
+        let stamp = at(1_700_000_000);
+        assert_eq!(
+            stamp.line(),
+            "Current date and time: 2023-11-14 22:13:20 +0000"
+        );
+        let tokyo = stamp
+            .with_utc_offset(9 * 3600)
+            .with_format("%Y/%m/%d %H:%M %z (100%%)");
+        assert_eq!(tokyo.line(), "2023/11/15 07:13 +0900 (100%)");
+        let leap_day = at(951_782_400).with_utc_offset(-5 * 3600 - 1800);
+        assert!(leap_day.line().ends_with("2000-02-28 18:30:00 -0530"));
+    }
+}
@@ -0,0 +1,107 @@
+use std::process::{Command, Stdio};
+
+#[test]
+fn test_cli_streams_mock_output_with_trailing_newline() {
+    let output = Command::new(env!("CARGO_BIN_EXE_acore"))
+        .args(["--provider", "mock", "ping"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Mock stream: pong\n"
+    );
+}
+
+#[test]
+fn test_cli_exits_cleanly_when_stdout_is_closed_early() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_acore"))
+        .args(["--provider", "mock", "ping"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Close the read end before the agent writes anything, like `| head -c 0`.
+    drop(child.stdout.take());
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "status: {:?}", output.status);
+    assert!(
+        output.stderr.is_empty(),
+        "stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn test_cli_record_keeps_output_and_exits_cleanly() {
+    let output = Command::new(env!("CARGO_BIN_EXE_acore"))
+        .args(["--provider", "mock", "--record", "ping"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Mock stream: pong\n"
+    );
+}
+
+#[test]
+fn test_cli_prefix_marks_lines_with_provider_name() {
+    let output = Command::new(env!("CARGO_BIN_EXE_acore"))
+        .args(["--provider", "mock", "--prefix", "ping"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "[mock-agent] Mock stream: pong\n"
+    );
+}
+
+#[test]
+fn test_cli_capabilities_lists_mock_and_real_tools() {
+    let output = Command::new(env!("CARGO_BIN_EXE_acore"))
+        .args(["capabilities", "--provider", "mock"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "provider    resume  json  model  thinking  stdin  permissions\n\
+         mock-agent  no      no    no     no        no     no\n"
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_acore"))
+        .args(["capabilities", "--json"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let listed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let claude = listed
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|entry| entry["provider"] == "claude")
+        .unwrap();
+    assert_eq!(claude["resume"], true);
+    assert_eq!(claude["thinking_budget"], true);
+    assert!(
+        listed
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|entry| entry["provider"] != "dummy-bot")
+    );
+}
+
+#[test]
+fn test_cli_list_tools_prints_available_tools_without_a_prompt() {
+    let output = Command::new(env!("CARGO_BIN_EXE_acore"))
+        .arg("--list-tools")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains("mock-agent"));
+    assert!(!stdout.contains("dummy-bot"));
+}